@@ -3,8 +3,9 @@ use std::sync::Arc;
 use axum::{
     Router,
     extract::DefaultBodyLimit,
-    http::{HeaderValue, Method, Request, header},
-    routing::{get, patch, post},
+    http::{HeaderName, HeaderValue, Method, Request, header},
+    middleware,
+    routing::{get, patch, post, put},
 };
 use tower_http::{
     cors::{AllowOrigin, CorsLayer},
@@ -12,7 +13,7 @@ use tower_http::{
 };
 use tracing::warn;
 
-use crate::{handlers, state::AppContext};
+use crate::{handlers, rate_limit, state::AppContext};
 
 pub fn build_router(
     state: Arc<AppContext>,
@@ -39,46 +40,165 @@ pub fn build_router(
                 Method::PATCH,
                 Method::DELETE,
             ])
-            .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                HeaderName::from_static("last-event-id"),
+            ])
     };
 
     Router::new()
         .route("/health", get(handlers::health))
         .route("/admin/login", get(handlers::admin_login))
         .route("/admin", get(handlers::admin_index))
+        .route("/admin/oidc/status", get(handlers::oidc_status))
+        .route("/admin/oidc/login", get(handlers::oidc_login))
+        .route("/admin/oidc/callback", get(handlers::oidc_callback))
+        .route("/admin/oidc/logout", post(handlers::oidc_logout))
         .route(
             "/v1/admin/users",
             get(handlers::admin_list_users).post(handlers::admin_create_user),
         )
         .route(
-            "/v1/admin/users/:user_id/tokens",
+            "/v1/admin/users/{user_id}/tokens",
             post(handlers::admin_create_token),
         )
         .route(
-            "/v1/admin/users/:user_id/disabled",
+            "/v1/admin/users/{user_id}",
+            axum::routing::delete(handlers::admin_delete_user),
+        )
+        .route(
+            "/v1/admin/users/{user_id}/disabled",
             patch(handlers::admin_set_user_disabled),
         )
         .route(
-            "/v1/admin/tokens/:token_id",
+            "/v1/admin/tokens/{token_id}",
             axum::routing::delete(handlers::admin_revoke_token),
         )
+        .route(
+            "/v1/admin/quarantined-playlists",
+            get(handlers::admin_list_quarantined_playlists),
+        )
+        .route(
+            "/v1/admin/quarantined-playlists/{user_id}",
+            axum::routing::delete(handlers::admin_dismiss_quarantined_playlists),
+        )
+        .route(
+            "/v1/admin/quarantined-playlists/{user_id}/restore",
+            post(handlers::admin_restore_quarantined_playlists),
+        )
+        .route("/v1/admin/audit", get(handlers::admin_list_audit_events))
+        .route("/v1/me", get(handlers::get_me))
+        .route(
+            "/v1/me/tokens",
+            get(handlers::list_my_tokens).post(handlers::create_my_token),
+        )
+        .route(
+            "/v1/me/tokens/{token_id}",
+            patch(handlers::rename_my_token).delete(handlers::revoke_my_token),
+        )
+        .route("/v1/tokens/rotate", post(handlers::rotate_own_token))
+        .route("/v1/pair/start", post(handlers::pair_start))
+        .route("/v1/pair/approve", post(handlers::pair_approve))
+        .route("/v1/pair/poll", post(handlers::pair_poll))
         .route(
             "/v1/snapshot",
             get(handlers::get_snapshot).put(handlers::put_snapshot),
         )
         .route(
-            "/v1/state/:namespace",
-            get(handlers::get_namespace).put(handlers::put_namespace),
+            "/v1/state/{namespace}",
+            get(handlers::get_namespace)
+                .put(handlers::put_namespace)
+                .patch(handlers::patch_namespace_state),
+        )
+        .route(
+            "/v1/playlists",
+            get(handlers::get_playlists).post(handlers::post_playlist),
+        )
+        .route("/v1/playlists/import", post(handlers::import_playlist_file))
+        .route(
+            "/v1/playlists/{playlist_id}/export",
+            get(handlers::export_playlist_file),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}",
+            get(handlers::get_playlist)
+                .patch(handlers::patch_playlist)
+                .delete(handlers::delete_playlist),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}/items",
+            get(handlers::get_playlist_items).post(handlers::post_playlist_items),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}/items/{item_id}",
+            patch(handlers::patch_playlist_item).delete(handlers::delete_playlist_item),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}/shares",
+            get(handlers::get_playlist_shares),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}/shares/{user_name}",
+            put(handlers::put_playlist_share).delete(handlers::delete_playlist_share),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}/share-links",
+            get(handlers::get_share_links).post(handlers::post_share_link),
+        )
+        .route(
+            "/v1/playlists/{playlist_id}/share-links/{link_id}",
+            axum::routing::delete(handlers::delete_share_link),
+        )
+        .route("/s/{link}", get(handlers::get_linked_playlist))
+        .route("/v1/shared-playlists", get(handlers::get_shared_playlists))
+        .route(
+            "/v1/shared-playlists/{share_id}",
+            get(handlers::get_shared_playlist)
+                .patch(handlers::patch_shared_playlist)
+                .delete(handlers::delete_shared_playlist),
+        )
+        .route(
+            "/v1/shared-playlists/{share_id}/items",
+            post(handlers::post_shared_playlist_items),
+        )
+        .route(
+            "/v1/shared-playlists/{share_id}/items/{item_id}",
+            patch(handlers::patch_shared_playlist_item)
+                .delete(handlers::delete_shared_playlist_item),
+        )
+        .route("/v1/history", get(handlers::get_history))
+        .route(
+            "/v1/history/{version}/restore",
+            post(handlers::restore_history),
+        )
+        .route("/v1/devices/online", get(handlers::list_devices_online))
+        .route("/v1/commands", post(handlers::post_command))
+        .route(
+            "/v1/handoff",
+            get(handlers::get_handoff).post(handlers::offer_handoff),
+        )
+        .route(
+            "/v1/handoff/{offer_id}/claim",
+            post(handlers::claim_handoff_offer),
         )
         .route("/v1/ws", get(handlers::ws_updates))
+        .route("/v1/events", get(handlers::sse_updates))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::enforce_rate_limits,
+        ))
         .layer(DefaultBodyLimit::max(max_body_size))
         .layer(cors)
         .layer(
-            // Redact query strings from /v1/ws spans to avoid logging bearer
-            // tokens that may be passed via the `token` query parameter.
+            // Redact query strings from /v1/ws and /v1/events spans to avoid
+            // logging bearer tokens that may be passed via the `token` query
+            // parameter, and the link from public playlist links.
             TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
-                let uri = if request.uri().path() == "/v1/ws" {
+                let uri = if matches!(request.uri().path(), "/v1/ws" | "/v1/events") {
                     request.uri().path().to_owned()
+                } else if request.uri().path().starts_with("/s/") {
+                    "/s/{link}".to_owned()
                 } else {
                     request.uri().to_string()
                 };
@@ -0,0 +1,247 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{Extensions, HeaderMap, Method, header, request::Parts},
+};
+use serde_json::{Value, json};
+use tracing::error;
+
+use crate::{
+    db::record_audit_event,
+    models::{AuthenticatedUser, TokenCreatedResponse},
+    state::AppContext,
+};
+
+/// Failed authentications counted per client IP when
+/// `RATE_LIMIT_AUTH_FAILURES` is 0: nothing is refused, but only a burst of
+/// 10, then one per minute, is written down.
+pub const AUTH_FAILURE_BURST: u32 = 10;
+pub const AUTH_FAILURE_REFILL: Duration = Duration::from_secs(60);
+
+/// Where a request came from, for the audit log.
+#[derive(Debug, Clone, Default)]
+pub struct ClientInfo {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl ClientInfo {
+    /// With `trust_proxy_headers`, the IP is the last `X-Forwarded-For` entry,
+    /// i.e. the address the reverse proxy saw. Earlier entries are client
+    /// supplied and never trusted.
+    pub fn new(extensions: &Extensions, headers: &HeaderMap, trust_proxy_headers: bool) -> Self {
+        let forwarded_ip = trust_proxy_headers
+            .then(|| {
+                headers
+                    .get_all("x-forwarded-for")
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .next_back()
+                    .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+            })
+            .flatten();
+        let peer_ip = || {
+            extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        };
+
+        Self {
+            ip: forwarded_ip.or_else(peer_ip).map(|ip| ip.to_string()),
+            user_agent: headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        }
+    }
+}
+
+impl FromRequestParts<Arc<AppContext>> for ClientInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &Arc<AppContext>,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(Self::new(
+            &parts.extensions,
+            &parts.headers,
+            state.trust_proxy_headers,
+        ))
+    }
+}
+
+/// An event to append to `audit_events`. Actions are dotted names such as
+/// `user.created` or `token.revoked`.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub action: &'static str,
+    pub actor_user_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub target_type: Option<&'static str>,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+}
+
+impl AuditEntry {
+    /// An event with no actor, i.e. done by the server itself.
+    pub fn new(action: &'static str) -> Self {
+        Self {
+            action,
+            actor_user_id: None,
+            actor_name: None,
+            target_type: None,
+            target_id: None,
+            ip: None,
+            user_agent: None,
+            details: json!({}),
+        }
+    }
+
+    /// `token.created` for a newly issued token.
+    pub fn token_created(token: &TokenCreatedResponse) -> Self {
+        Self::new("token.created")
+            .target("token", token.id)
+            .details(json!({
+                "user_id": token.user_id,
+                "label": token.label,
+                "scopes": token.scopes,
+                "expires_at": token.expires_at,
+            }))
+    }
+
+    pub fn actor(mut self, user: &AuthenticatedUser) -> Self {
+        self.actor_user_id = Some(user.id);
+        self.actor_name = Some(user.name.clone());
+        self
+    }
+
+    pub fn target(mut self, target_type: &'static str, target_id: impl ToString) -> Self {
+        self.target_type = Some(target_type);
+        self.target_id = Some(target_id.to_string());
+        self
+    }
+
+    pub fn client(mut self, client: &ClientInfo) -> Self {
+        self.ip.clone_from(&client.ip);
+        self.user_agent.clone_from(&client.user_agent);
+        self
+    }
+
+    pub fn details(mut self, details: Value) -> Self {
+        self.details = details;
+        self
+    }
+}
+
+/// Records a `401` response as `auth.failed`. Called for the failures the
+/// per-IP limit still counts, so a flood of them is not all written down.
+/// There is no action to roll back, so a failed write is only logged.
+pub async fn record_auth_failure(
+    state: &AppContext,
+    client: &ClientInfo,
+    method: &Method,
+    path: &str,
+) {
+    // Path only: `/v1/ws` and `/v1/events` may carry a token in the query.
+    let entry = AuditEntry::new("auth.failed")
+        .client(client)
+        .details(json!({ "method": method.as_str(), "path": path }));
+    if let Err(err) = record_audit_event(&state.pool, &entry).await {
+        error!(action = entry.action, "failed to record audit event: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        extract::ConnectInfo,
+        http::{Extensions, HeaderMap, HeaderValue},
+    };
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::{AuditEntry, ClientInfo};
+    use crate::{
+        models::{AuthenticatedUser, TokenCreatedResponse},
+        scopes::Scopes,
+    };
+
+    fn request(forwarded_for: &str) -> (Extensions, HeaderMap) {
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 2], 4000))));
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_str(forwarded_for).unwrap(),
+        );
+        headers.insert("user-agent", HeaderValue::from_static("player/1.0"));
+        (extensions, headers)
+    }
+
+    #[test]
+    fn client_ip_comes_from_the_proxy_only_when_trusted() {
+        let (extensions, headers) = request("1.2.3.4, 203.0.113.9");
+
+        let direct = ClientInfo::new(&extensions, &headers, false);
+        assert_eq!(direct.ip.as_deref(), Some("10.0.0.2"));
+        assert_eq!(direct.user_agent.as_deref(), Some("player/1.0"));
+
+        // The last entry is the one the proxy added; earlier ones are spoofable.
+        let proxied = ClientInfo::new(&extensions, &headers, true);
+        assert_eq!(proxied.ip.as_deref(), Some("203.0.113.9"));
+
+        let (extensions, headers) = request("not an ip");
+        let proxied = ClientInfo::new(&extensions, &headers, true);
+        assert_eq!(proxied.ip.as_deref(), Some("10.0.0.2"));
+    }
+
+    #[test]
+    fn entry_records_actor_target_and_client() {
+        let user = AuthenticatedUser {
+            id: 7,
+            name: "ana".to_string(),
+            is_admin: true,
+            token_id: 3,
+            scopes: Scopes::all(),
+            expires_at: None,
+        };
+        let token = TokenCreatedResponse {
+            id: 42,
+            user_id: 9,
+            label: "tv".to_string(),
+            token_prefix: "ap_abcde".to_string(),
+            token: "ap_secret".to_string(),
+            scopes: vec!["*".to_string()],
+            created_at: Utc::now(),
+            expires_at: None,
+        };
+        let (extensions, headers) = request("203.0.113.9");
+        let client = ClientInfo::new(&extensions, &headers, false);
+
+        let entry = AuditEntry::token_created(&token)
+            .actor(&user)
+            .client(&client);
+
+        assert_eq!(entry.action, "token.created");
+        assert_eq!(entry.actor_user_id, Some(7));
+        assert_eq!(entry.actor_name.as_deref(), Some("ana"));
+        assert_eq!(entry.target_type, Some("token"));
+        assert_eq!(entry.target_id.as_deref(), Some("42"));
+        assert_eq!(entry.ip.as_deref(), Some("10.0.0.2"));
+        assert_eq!(entry.details["user_id"], json!(9));
+        // The secret itself is never part of the audit trail.
+        assert!(!entry.details.to_string().contains("ap_secret"));
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use rand::{Rng, distr::Alphanumeric};
+use tokio::sync::broadcast;
+
+use crate::{
+    db::{list_online_devices, load_command_result},
+    errors::ApiError,
+    models::{CommandAction, CommandEvent, CommandRequest, CommandResponse, UserEvent},
+    state::AppContext,
+};
+
+/// How long `POST /v1/commands` waits for the target device to answer.
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn validate(request: &CommandRequest) -> Result<(), ApiError> {
+    if request.target_client_id.trim().is_empty() {
+        return Err(ApiError::bad_request(
+            "target_client_id is required".to_string(),
+        ));
+    }
+    if !(request.params.is_null() || request.params.is_object()) {
+        return Err(ApiError::bad_request(
+            "params must be an object".to_string(),
+        ));
+    }
+    if request.action == CommandAction::Seek
+        && !request
+            .params
+            .get("position_ms")
+            .is_some_and(|v| v.is_u64())
+    {
+        return Err(ApiError::bad_request(
+            "seek requires a non-negative integer params.position_ms".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Sends a command to one of the user's devices and waits for its result.
+/// The command and the result both travel over the user's broadcast channel,
+/// so the target may be connected to any server instance.
+pub async fn send_command(
+    state: &AppContext,
+    user_id: i64,
+    request: CommandRequest,
+) -> Result<CommandResponse, ApiError> {
+    validate(&request)?;
+
+    let target_online = list_online_devices(&state.pool, user_id)
+        .await?
+        .iter()
+        .any(|device| device.client_id.as_deref() == Some(request.target_client_id.as_str()));
+    if !target_online {
+        return Err(ApiError::not_found(format!(
+            "device {} is not connected",
+            request.target_client_id
+        )));
+    }
+
+    let command_id: String = rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect();
+    let expires_at = Utc::now()
+        + chrono::Duration::from_std(COMMAND_TIMEOUT).unwrap_or(chrono::Duration::zero());
+
+    // Subscribe before sending so a fast answer cannot be missed.
+    let mut results = state.subscribe_user(user_id).await;
+    state
+        .send_user_event(
+            user_id,
+            CommandEvent {
+                event_type: "command".to_string(),
+                command_id: command_id.clone(),
+                action: request.action,
+                params: request.params,
+                target_client_id: request.target_client_id.clone(),
+                source_client_id: request.client_id,
+                expires_at,
+            },
+        )
+        .await;
+
+    let wait = async {
+        loop {
+            match results.recv().await {
+                Ok(UserEvent::CommandResult(event)) if event.response.command_id == command_id => {
+                    return Ok(Some(event.response));
+                }
+                Ok(_) => {}
+                // The result may have been among the dropped events; the
+                // target also stored it.
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    if let Some(response) =
+                        load_command_result(&state.pool, user_id, &command_id).await?
+                    {
+                        return Ok(Some(response));
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return Ok(None),
+            }
+        }
+    };
+
+    match tokio::time::timeout(COMMAND_TIMEOUT, wait).await {
+        Ok(Ok(Some(response))) => Ok(response),
+        Ok(Err(err)) => Err(err),
+        Ok(Ok(None)) | Err(_) => Err(ApiError::command_timeout(format!(
+            "device {} did not answer within {} seconds",
+            request.target_client_id,
+            COMMAND_TIMEOUT.as_secs()
+        ))),
+    }
+}
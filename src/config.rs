@@ -14,6 +14,93 @@ pub struct AppConfig {
     /// Optional bootstrap admin user name and token. When token is set, the user and token are ensured at startup.
     pub admin_bootstrap_name: String,
     pub admin_bootstrap_token: Option<String>,
+    /// Bounds on the per-user revision history kept for rollback.
+    pub history_retention: HistoryRetention,
+    /// Optional OpenID Connect login, enabled when `OIDC_ISSUER_URL` is set.
+    pub oidc: Option<OidcConfig>,
+    /// Built-in limits on failed authentications and writes.
+    pub rate_limits: RateLimitConfig,
+    /// Take the client IP from `X-Forwarded-For` (`TRUST_PROXY_HEADERS`, default: false). Only enable behind a reverse proxy that sets it.
+    pub trust_proxy_headers: bool,
+}
+
+/// Built-in request rate limits; 0 disables a limit.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitConfig {
+    /// Failed authentications allowed per client IP per minute (`RATE_LIMIT_AUTH_FAILURES`, default: 10).
+    pub auth_failures_per_minute: u32,
+    /// Write requests allowed per token per minute (`RATE_LIMIT_WRITES`, default: 120).
+    pub writes_per_minute: u32,
+}
+
+/// How much write history is kept per user. Revisions beyond either bound are
+/// pruned in the same transaction as each new write.
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryRetention {
+    /// Maximum number of versions kept (`HISTORY_MAX_REVISIONS`, default: 100).
+    pub max_revisions: i64,
+    /// Maximum age of kept revisions in days (`HISTORY_MAX_AGE_DAYS`, default: 30).
+    pub max_age_days: i32,
+}
+
+/// OpenID Connect provider used to log in to the admin console.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Issuer URL (`OIDC_ISSUER_URL`), e.g. `https://auth.example.com/realms/main`.
+    pub issuer_url: String,
+    /// Client credentials registered with the provider (`OIDC_CLIENT_ID`, `OIDC_CLIENT_SECRET`).
+    pub client_id: String,
+    pub client_secret: String,
+    /// Public URL of `/admin/oidc/callback` (`OIDC_REDIRECT_URL`).
+    pub redirect_url: String,
+    /// ID token claim listing the user's groups (`OIDC_GROUPS_CLAIM`, default: `groups`).
+    pub groups_claim: String,
+    /// Group whose members are admins (`OIDC_ADMIN_GROUP`). When unset, logging in never changes `is_admin`.
+    pub admin_group: Option<String>,
+}
+
+impl OidcConfig {
+    fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(issuer_url) = optional_env("OIDC_ISSUER_URL") else {
+            return Ok(None);
+        };
+        let required = |name: &str| {
+            optional_env(name)
+                .with_context(|| format!("{name} is required when OIDC_ISSUER_URL is set"))
+        };
+
+        Ok(Some(Self {
+            issuer_url: issuer_url.trim_end_matches('/').to_string(),
+            client_id: required("OIDC_CLIENT_ID")?,
+            client_secret: required("OIDC_CLIENT_SECRET")?,
+            redirect_url: required("OIDC_REDIRECT_URL")?,
+            groups_claim: optional_env("OIDC_GROUPS_CLAIM").unwrap_or_else(|| "groups".into()),
+            admin_group: optional_env("OIDC_ADMIN_GROUP"),
+        }))
+    }
+}
+
+/// A trimmed, non-empty environment variable.
+fn optional_env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// A number from the environment, or `default` when unset. A value that does
+/// not parse is an error rather than silently replaced by the default.
+fn parsed_env<T>(name: &str, default: T) -> anyhow::Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match optional_env(name) {
+        Some(value) => value
+            .parse()
+            .with_context(|| format!("{name} must be a non-negative integer, got '{value}'")),
+        None => Ok(default),
+    }
 }
 
 impl AppConfig {
@@ -56,6 +143,29 @@ impl AppConfig {
             .map(|value| value.trim().to_string())
             .filter(|value| !value.is_empty());
 
+        let history_retention = HistoryRetention {
+            max_revisions: std::env::var("HISTORY_MAX_REVISIONS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(100_i64)
+                .max(1),
+            max_age_days: std::env::var("HISTORY_MAX_AGE_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30_i32)
+                .max(1),
+        };
+
+        let oidc = OidcConfig::from_env()?;
+
+        let rate_limits = RateLimitConfig {
+            auth_failures_per_minute: parsed_env("RATE_LIMIT_AUTH_FAILURES", 10)?,
+            writes_per_minute: parsed_env("RATE_LIMIT_WRITES", 120)?,
+        };
+
+        let trust_proxy_headers = optional_env("TRUST_PROXY_HEADERS")
+            .is_some_and(|value| matches!(value.to_ascii_lowercase().as_str(), "1" | "true"));
+
         let bind_address = bind_address
             .parse()
             .with_context(|| format!("invalid BIND_ADDRESS '{bind_address}'"))?;
@@ -68,6 +178,10 @@ impl AppConfig {
             max_body_size,
             admin_bootstrap_name,
             admin_bootstrap_token,
+            history_retention,
+            oidc,
+            rate_limits,
+            trust_proxy_headers,
         })
     }
 }
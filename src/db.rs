@@ -1,16 +1,37 @@
+use std::collections::HashMap;
+
 use chrono::{DateTime, Utc};
 use rand::{Rng, distr::Alphanumeric};
-use serde_json::json;
+use serde_json::{Map, Value, json};
 use sha2::{Digest, Sha256};
-use sqlx::PgPool;
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool, Row, postgres::PgRow};
 use tracing::{error, info, warn};
 
 use crate::{
+    audit::AuditEntry,
+    config::HistoryRetention,
+    deletion,
     errors::ApiError,
+    handoff,
+    merge::{MergeReport, three_way_merge},
     models::{
-        AuthenticatedUser, Namespace, NamespacePayload, Snapshot, SnapshotPayload, TokenInfo,
-        UpdateEvent, UserCreatedResponse, UserSummary,
+        AuditEvent, AuditQuery, AuthenticatedUser, CommandResponse, CreatePlaylistRequest,
+        DevicePresence, HandoffClaimRequest, HandoffOffer, HandoffOfferRequest,
+        InsertPlaylistItemsRequest, LinkedPlaylist, MovePlaylistItemRequest, Namespace,
+        NamespacePayload, NamespaceVersions, PairApproveResponse, PairPollResponse,
+        PairStartResponse, PatchQuery, PlaylistItemInput, PlaylistRecord, PlaylistShare,
+        PlaylistSummary, PlaylistWriteQuery, QuarantinedPlaylists, Replay, RestorePayload,
+        Revision, RevisionChange, ShareAccess, ShareLink, ShareLinkCreated, SharedPlaylist,
+        SharedPlaylistsSince, Snapshot, SnapshotPayload, SnapshotSince, TokenCreatedResponse,
+        TokenInfo, TokenRotatedResponse, UpdateEvent, UpdatePlaylistRequest, UserCreatedResponse,
+        UserSummary, namespace_data, snapshot_delta,
     },
+    oidc::{self, OidcIdentity},
+    ordering::Placement,
+    pairing,
+    patch::{PatchFormat, apply_patch},
+    playlists::{self, Anchor, Playlist, PlaylistItem},
+    scopes::Scopes,
 };
 
 fn hash_token(token: &str) -> String {
@@ -23,6 +44,25 @@ fn token_prefix(token: &str) -> String {
     token.chars().take(8).collect()
 }
 
+/// Short random id for server-issued resources such as handoff offers.
+fn generate_id() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+/// Random token for a public playlist link. It is the whole secret, so it
+/// carries no prefix.
+fn generate_link() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
 fn generate_token() -> String {
     let random: String = rand::rng()
         .sample_iter(Alphanumeric)
@@ -47,6 +87,11 @@ pub async fn ensure_schema(pool: &PgPool) -> anyhow::Result<()> {
     .execute(pool)
     .await?;
 
+    // Set while a deleted user waits out the grace period.
+    sqlx::query("ALTER TABLE users ADD COLUMN IF NOT EXISTS delete_after TIMESTAMPTZ")
+        .execute(pool)
+        .await?;
+
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS auth_tokens (
@@ -73,6 +118,27 @@ pub async fn ensure_schema(pool: &PgPool) -> anyhow::Result<()> {
     .execute(pool)
     .await?;
 
+    // Tokens issued before scopes existed keep full access.
+    sqlx::query(
+        r#"
+        ALTER TABLE auth_tokens
+        ADD COLUMN IF NOT EXISTS scopes TEXT[] NOT NULL DEFAULT ARRAY['*']::TEXT[];
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        ALTER TABLE auth_tokens
+        ADD COLUMN IF NOT EXISTS expires_at TIMESTAMPTZ,
+        ADD COLUMN IF NOT EXISTS replaced_by_token_id BIGINT
+            REFERENCES auth_tokens(id) ON DELETE SET NULL;
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
     sqlx::query(
         r#"
         CREATE TABLE IF NOT EXISTS user_sync_document (
@@ -91,643 +157,4929 @@ pub async fn ensure_schema(pool: &PgPool) -> anyhow::Result<()> {
     .execute(pool)
     .await?;
 
-    // Migrate legacy sync_document data into user_sync_document if the old table
-    // exists. This preserves existing snapshot state for upgraded databases.
-    let (legacy_exists,): (bool,) = sqlx::query_as(
-        r#"
-        SELECT to_regclass('public.sync_document') IS NOT NULL AS exists
-        "#,
-    )
-    .fetch_one(pool)
-    .await?;
+    // Per-namespace versions let writes to different namespaces proceed
+    // independently. Existing documents start every namespace at the current
+    // global version so clients holding that version keep working.
+    for column in Namespace::DATA.map(Namespace::as_str) {
+        sqlx::query(&format!(
+            "ALTER TABLE user_sync_document ADD COLUMN IF NOT EXISTS {column}_version BIGINT"
+        ))
+        .execute(pool)
+        .await?;
 
-    if legacy_exists {
-        // Ensure there is a default user to own the migrated snapshots.
-        // Uses ON CONFLICT to be idempotent across runs.
-        let default_user_id: i64 = sqlx::query_scalar(
-            r#"
-            INSERT INTO users (name, is_admin)
-            VALUES ($1, FALSE)
-            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
-            RETURNING id
-            "#,
-        )
-        .bind("default")
-        .fetch_one(pool)
+        sqlx::query(&format!(
+            "UPDATE user_sync_document SET {column}_version = version WHERE {column}_version IS NULL"
+        ))
+        .execute(pool)
         .await?;
 
-        sqlx::query(
-            r#"
-            INSERT INTO user_sync_document (
-                user_id,
-                id,
-                version,
-                updated_at,
-                app_state,
-                playlists,
-                provider_configuration,
-                settings
-            )
-            SELECT
-                $1 AS user_id,
-                id,
-                version,
-                updated_at,
-                app_state,
-                playlists,
-                provider_configuration,
-                settings
-            FROM sync_document
-            ON CONFLICT (user_id, id) DO NOTHING
-            "#,
-        )
-        .bind(default_user_id)
+        sqlx::query(&format!(
+            "ALTER TABLE user_sync_document \
+             ALTER COLUMN {column}_version SET DEFAULT 0, \
+             ALTER COLUMN {column}_version SET NOT NULL"
+        ))
         .execute(pool)
         .await?;
     }
 
-    Ok(())
-}
-
-pub async fn ensure_bootstrap_admin(
-    pool: &PgPool,
-    admin_name: &str,
-    admin_token: Option<&str>,
-) -> anyhow::Result<()> {
-    let Some(admin_token) = admin_token else {
-        return Ok(());
-    };
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS sync_revisions (
+            user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            version BIGINT NOT NULL,
+            namespace TEXT NOT NULL,
+            scope TEXT NOT NULL,
+            previous_data JSONB NOT NULL,
+            new_data JSONB NOT NULL,
+            client_id TEXT,
+            created_at TIMESTAMPTZ NOT NULL,
+            PRIMARY KEY (user_id, version, namespace)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    let admin_name = admin_name.trim();
-    if admin_name.is_empty() {
-        anyhow::bail!("ADMIN_BOOTSTRAP_NAME cannot be empty when ADMIN_BOOTSTRAP_TOKEN is set");
-    }
+    sqlx::query(
+        r#"
+        ALTER TABLE sync_revisions
+        ADD COLUMN IF NOT EXISTS namespace_version BIGINT;
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    let mut transaction = pool.begin().await?;
+    // Edits of a single playlist record only that playlist: its value before
+    // and after (`NULL` when it did not exist) and its index before.
+    sqlx::query(
+        r#"
+        ALTER TABLE sync_revisions
+        ADD COLUMN IF NOT EXISTS playlist_id TEXT,
+        ADD COLUMN IF NOT EXISTS playlist_position INTEGER;
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    let user_id = sqlx::query_scalar::<_, i64>(
+    sqlx::query(
         r#"
-        INSERT INTO users (name, is_admin)
-        VALUES ($1, TRUE)
-        ON CONFLICT (name)
-        DO UPDATE SET is_admin = TRUE
-        RETURNING id
+        CREATE INDEX IF NOT EXISTS idx_sync_revisions_user_namespace_version
+        ON sync_revisions(user_id, namespace, version);
         "#,
     )
-    .bind(admin_name)
-    .fetch_one(&mut *transaction)
+    .execute(pool)
     .await?;
 
-    let token_hash = hash_token(admin_token);
-    let token_prefix = token_prefix(admin_token);
+    // Answers to remote playback commands, so a waiting `POST /v1/commands`
+    // that missed the relayed result can still find it.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS command_results (
+            command_id TEXT PRIMARY KEY,
+            user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            ok BOOLEAN NOT NULL,
+            result JSONB,
+            error TEXT,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
+    // Events too large for a `NOTIFY` payload, relayed to other instances by
+    // id (see `fanout`).
     sqlx::query(
         r#"
-        INSERT INTO auth_tokens (user_id, token_hash, token_prefix, label, revoked_at)
-        VALUES ($1, $2, $3, 'bootstrap', NULL)
-        ON CONFLICT (token_hash)
-        DO UPDATE SET user_id = $1, token_prefix = $3, label = 'bootstrap', revoked_at = NULL
+        CREATE TABLE IF NOT EXISTS relayed_events (
+            id BIGSERIAL PRIMARY KEY,
+            payload TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
         "#,
     )
-    .bind(user_id)
-    .bind(token_hash)
-    .bind(token_prefix)
-    .execute(&mut *transaction)
+    .execute(pool)
     .await?;
 
-    transaction.commit().await?;
-    info!(admin_name, "ensured bootstrap admin user/token");
-    Ok(())
-}
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS device_presence (
+            id BIGSERIAL PRIMARY KEY,
+            user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            client_id TEXT,
+            device_name TEXT,
+            platform TEXT,
+            connected_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            last_seen TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-async fn ensure_user_document(pool: &PgPool, user_id: i64) -> Result<(), ApiError> {
     sqlx::query(
         r#"
-        INSERT INTO user_sync_document (
-            user_id,
-            id,
-            version,
-            updated_at,
-            app_state,
-            playlists,
-            provider_configuration,
-            settings
-        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-        ON CONFLICT (user_id, id) DO NOTHING
+        CREATE INDEX IF NOT EXISTS idx_device_presence_user_id
+        ON device_presence(user_id);
         "#,
     )
-    .bind(user_id)
-    .bind(1_i16)
-    .bind(0_i64)
-    .bind(Utc::now())
-    .bind(json!({}))
-    .bind(json!([]))
-    .bind(json!({}))
-    .bind(json!({}))
     .execute(pool)
-    .await
-    .map_err(|err| {
-        error!(user_id, "failed to ensure user snapshot row: {err}");
-        ApiError::internal("failed to ensure user snapshot row".to_string())
-    })?;
+    .await?;
 
-    Ok(())
-}
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS playback_handoffs (
+            id TEXT PRIMARY KEY,
+            user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            source_client_id TEXT NOT NULL,
+            track JSONB NOT NULL,
+            position_ms BIGINT NOT NULL,
+            queue JSONB NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            expires_at TIMESTAMPTZ NOT NULL,
+            claimed_by TEXT,
+            claimed_at TIMESTAMPTZ
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-pub async fn authenticate_token(pool: &PgPool, token: &str) -> Result<AuthenticatedUser, ApiError> {
-    let trimmed = token.trim();
-    if trimmed.is_empty() {
-        return Err(ApiError::unauthorized("missing bearer token".to_string()));
-    }
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_playback_handoffs_user_id
+        ON playback_handoffs(user_id);
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    let token_hash = hash_token(trimmed);
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS device_pairings (
+            id BIGSERIAL PRIMARY KEY,
+            device_code_hash TEXT NOT NULL UNIQUE,
+            user_code TEXT NOT NULL UNIQUE,
+            device_name TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            expires_at TIMESTAMPTZ NOT NULL,
+            last_polled_at TIMESTAMPTZ,
+            approved_by BIGINT REFERENCES users(id) ON DELETE CASCADE,
+            approved_at TIMESTAMPTZ,
+            scopes TEXT[],
+            completed_at TIMESTAMPTZ
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    let row = sqlx::query_as::<_, (i64, String, bool, Option<DateTime<Utc>>, i64)>(
+    sqlx::query(
         r#"
-        SELECT u.id, u.name, u.is_admin, u.disabled_at, t.id
-        FROM auth_tokens t
-        JOIN users u ON u.id = t.user_id
-        WHERE t.token_hash = $1
-          AND t.revoked_at IS NULL
+        CREATE TABLE IF NOT EXISTS oidc_identities (
+            issuer TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            last_login_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            PRIMARY KEY (issuer, subject)
+        );
         "#,
     )
-    .bind(token_hash)
-    .fetch_optional(pool)
-    .await
-    .map_err(|err| {
-        error!("failed to validate token: {err}");
-        ApiError::internal("failed to validate token".to_string())
-    })?
-    .ok_or_else(|| ApiError::unauthorized("invalid bearer token".to_string()))?;
+    .execute(pool)
+    .await?;
 
-    if row.3.is_some() {
-        return Err(ApiError::forbidden("user account is disabled".to_string()));
-    }
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS oidc_login_states (
+            state_hash TEXT PRIMARY KEY,
+            nonce TEXT NOT NULL,
+            expires_at TIMESTAMPTZ NOT NULL
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    let _ = sqlx::query("UPDATE auth_tokens SET last_used_at = NOW() WHERE id = $1")
-        .bind(row.4)
-        .execute(pool)
-        .await;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS audit_events (
+            id BIGSERIAL PRIMARY KEY,
+            occurred_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            actor_user_id BIGINT,
+            actor_name TEXT,
+            action TEXT NOT NULL,
+            target_type TEXT,
+            target_id TEXT,
+            ip TEXT,
+            user_agent TEXT,
+            details JSONB NOT NULL DEFAULT '{}'::JSONB
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
 
-    Ok(AuthenticatedUser {
+    sqlx::query(
+        r#"
+        CREATE INDEX IF NOT EXISTS idx_audit_events_action
+        ON audit_events(action, id DESC);
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // The audit log is append-only. Actor and target are plain values rather
+    // than foreign keys so events outlive the users and tokens they mention.
+    // The only update allowed clears the actor's name, IP and user agent, which
+    // happens when that user is deleted.
+    sqlx::query(
+        r#"
+        CREATE OR REPLACE FUNCTION reject_audit_event_change() RETURNS trigger AS $$
+        BEGIN
+            RAISE EXCEPTION 'audit_events is append-only';
+        END;
+        $$ LANGUAGE plpgsql;
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE OR REPLACE FUNCTION check_audit_event_update() RETURNS trigger AS $$
+        BEGIN
+            IF NEW.actor_name IS NULL AND NEW.ip IS NULL AND NEW.user_agent IS NULL
+                AND (NEW.id, NEW.occurred_at, NEW.actor_user_id, NEW.action,
+                     NEW.target_type, NEW.target_id, NEW.details)
+                    IS NOT DISTINCT FROM
+                    (OLD.id, OLD.occurred_at, OLD.actor_user_id, OLD.action,
+                     OLD.target_type, OLD.target_id, OLD.details)
+            THEN
+                RETURN NEW;
+            END IF;
+            RAISE EXCEPTION 'audit_events is append-only';
+        END;
+        $$ LANGUAGE plpgsql;
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Replaces the earlier `audit_events_append_only` trigger, which rejected
+    // every update.
+    sqlx::query(
+        r#"
+        DO $$
+        BEGIN
+            IF NOT EXISTS (
+                SELECT 1 FROM pg_trigger WHERE tgname = 'audit_events_no_delete'
+            ) THEN
+                DROP TRIGGER IF EXISTS audit_events_append_only ON audit_events;
+                CREATE TRIGGER audit_events_no_delete
+                BEFORE DELETE OR TRUNCATE ON audit_events
+                FOR EACH STATEMENT EXECUTE FUNCTION reject_audit_event_change();
+                CREATE TRIGGER audit_events_anonymize_only
+                BEFORE UPDATE ON audit_events
+                FOR EACH ROW EXECUTE FUNCTION check_audit_event_update();
+            END IF;
+        END;
+        $$;
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS playlists (
+            user_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            id TEXT NOT NULL,
+            position INTEGER NOT NULL,
+            name TEXT,
+            attributes JSONB NOT NULL DEFAULT '{}'::JSONB,
+            version BIGINT NOT NULL DEFAULT 1,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            PRIMARY KEY (user_id, id)
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS playlist_items (
+            user_id BIGINT NOT NULL,
+            playlist_id TEXT NOT NULL,
+            id TEXT NOT NULL,
+            position TEXT COLLATE "C" NOT NULL,
+            client_id TEXT NOT NULL DEFAULT '',
+            stamp BIGINT NOT NULL DEFAULT 0,
+            data JSONB NOT NULL,
+            PRIMARY KEY (user_id, playlist_id, id),
+            FOREIGN KEY (user_id, playlist_id)
+                REFERENCES playlists(user_id, id) ON DELETE CASCADE
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Items used to be ordered by index; turn those into position keys that
+    // sort the same way.
+    sqlx::query(
+        r#"
+        DO $$
+        BEGIN
+            IF EXISTS (
+                SELECT 1 FROM information_schema.columns
+                WHERE table_name = 'playlist_items'
+                  AND column_name = 'position'
+                  AND data_type = 'integer'
+            ) THEN
+                ALTER TABLE playlist_items
+                    ALTER COLUMN position TYPE TEXT COLLATE "C"
+                    USING lpad(position::TEXT, 10, '0') || 'V';
+            END IF;
+        END;
+        $$;
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "ALTER TABLE playlist_items ADD COLUMN IF NOT EXISTS client_id TEXT NOT NULL DEFAULT ''",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "ALTER TABLE playlist_items ADD COLUMN IF NOT EXISTS stamp BIGINT NOT NULL DEFAULT 0",
+    )
+    .execute(pool)
+    .await?;
+
+    // Ids the server chose for playlists and items written without one, so
+    // later writes without ids keep them (see `playlists::keep_assigned_ids`).
+    for table in ["playlists", "playlist_items"] {
+        sqlx::query(&format!(
+            "ALTER TABLE {table} ADD COLUMN IF NOT EXISTS id_assigned BOOLEAN NOT NULL DEFAULT FALSE"
+        ))
+        .execute(pool)
+        .await?;
+    }
+
+    // Namespace values whose playlists could not all be moved into playlist
+    // rows, kept as they were.
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS quarantined_playlists (
+            user_id BIGINT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+            playlists JSONB NOT NULL,
+            error TEXT NOT NULL,
+            quarantined_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS playlist_shares (
+            id BIGSERIAL PRIMARY KEY,
+            owner_id BIGINT NOT NULL,
+            playlist_id TEXT NOT NULL,
+            recipient_id BIGINT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+            access TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            UNIQUE (owner_id, playlist_id, recipient_id),
+            FOREIGN KEY (owner_id, playlist_id)
+                REFERENCES playlists(user_id, id) ON DELETE CASCADE
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "CREATE INDEX IF NOT EXISTS playlist_shares_recipient_idx ON playlist_shares(recipient_id)",
+    )
+    .execute(pool)
+    .await?;
+
+    // A recipient's shared version is bumped whenever one of their shares
+    // changes, starts or ends; each share keeps the shared version of its
+    // last change, so snapshots can return only the shares changed since.
+    sqlx::query(
+        "ALTER TABLE users ADD COLUMN IF NOT EXISTS shared_version BIGINT NOT NULL DEFAULT 0",
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        "ALTER TABLE playlist_shares ADD COLUMN IF NOT EXISTS version BIGINT NOT NULL DEFAULT 0",
+    )
+    .execute(pool)
+    .await?;
+
+    // Shares made before shared versions were tracked count as changed at 1.
+    sqlx::query(
+        r#"
+        UPDATE users u
+        SET shared_version = 1
+        WHERE u.shared_version = 0
+          AND EXISTS (SELECT 1 FROM playlist_shares s WHERE s.recipient_id = u.id)
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS playlist_share_links (
+            id BIGSERIAL PRIMARY KEY,
+            owner_id BIGINT NOT NULL,
+            playlist_id TEXT NOT NULL,
+            link_hash TEXT NOT NULL UNIQUE,
+            label TEXT NOT NULL,
+            created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+            expires_at TIMESTAMPTZ,
+            FOREIGN KEY (owner_id, playlist_id)
+                REFERENCES playlists(user_id, id) ON DELETE CASCADE
+        );
+        "#,
+    )
+    .execute(pool)
+    .await?;
+
+    // Links used to keep the first characters of the link, which are part of
+    // the secret.
+    sqlx::query("ALTER TABLE playlist_share_links DROP COLUMN IF EXISTS link_prefix")
+        .execute(pool)
+        .await?;
+
+    // Migrate legacy sync_document data into user_sync_document if the old table
+    // exists. This preserves existing snapshot state for upgraded databases.
+    let (legacy_exists,): (bool,) = sqlx::query_as(
+        r#"
+        SELECT to_regclass('public.sync_document') IS NOT NULL AS exists
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if legacy_exists {
+        // Ensure there is a default user to own the migrated snapshots.
+        // Uses ON CONFLICT to be idempotent across runs.
+        let default_user_id: i64 = sqlx::query_scalar(
+            r#"
+            INSERT INTO users (name, is_admin)
+            VALUES ($1, FALSE)
+            ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+            RETURNING id
+            "#,
+        )
+        .bind("default")
+        .fetch_one(pool)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO user_sync_document (
+                user_id,
+                id,
+                version,
+                updated_at,
+                app_state,
+                playlists,
+                provider_configuration,
+                settings
+            )
+            SELECT
+                $1 AS user_id,
+                id,
+                version,
+                updated_at,
+                app_state,
+                playlists,
+                provider_configuration,
+                settings
+            FROM sync_document
+            ON CONFLICT (user_id, id) DO NOTHING
+            "#,
+        )
+        .bind(default_user_id)
+        .execute(pool)
+        .await?;
+    }
+
+    migrate_document_playlists(pool).await?;
+
+    Ok(())
+}
+
+/// Splits playlists written before they had rows of their own out of each
+/// document, once. The namespace keeps its version but is rewritten in its
+/// derived form. When some entries are not valid playlists, the original value
+/// is kept in `quarantined_playlists` and the valid ones are moved.
+async fn migrate_document_playlists(pool: &PgPool) -> anyhow::Result<()> {
+    let user_ids = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT d.user_id
+        FROM user_sync_document d
+        WHERE jsonb_typeof(d.playlists) = 'array'
+          AND jsonb_array_length(d.playlists) > 0
+          AND NOT EXISTS (SELECT 1 FROM playlists p WHERE p.user_id = d.user_id)
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    for user_id in user_ids {
+        let mut transaction = pool.begin().await?;
+        let value = sqlx::query_scalar::<_, Value>(
+            "SELECT playlists FROM user_sync_document WHERE user_id = $1 AND id = 1 FOR UPDATE",
+        )
+        .bind(user_id)
+        .fetch_one(&mut *transaction)
+        .await?;
+
+        let playlists = match playlists::parse_playlists(&value) {
+            Ok(playlists) => playlists,
+            Err(err) => {
+                let error = err.message().to_string();
+                sqlx::query(
+                    r#"
+                    INSERT INTO quarantined_playlists (user_id, playlists, error)
+                    VALUES ($1, $2, $3)
+                    ON CONFLICT (user_id) DO NOTHING
+                    "#,
+                )
+                .bind(user_id)
+                .bind(&value)
+                .bind(&error)
+                .execute(&mut *transaction)
+                .await?;
+                warn!(
+                    user_id,
+                    error,
+                    "kept the original playlists in quarantined_playlists and moved only the valid ones"
+                );
+                playlists::salvage_playlists(&value)
+            }
+        };
+
+        let (derived, _) = store_and_render_playlists(&mut transaction, user_id, playlists, None)
+            .await
+            .map_err(|err| anyhow::anyhow!("failed to move playlists: {}", err.message()))?;
+        sqlx::query("UPDATE user_sync_document SET playlists = $2 WHERE user_id = $1 AND id = 1")
+            .bind(user_id)
+            .bind(derived)
+            .execute(&mut *transaction)
+            .await?;
+        transaction.commit().await?;
+        info!(user_id, "moved playlists into playlist rows");
+    }
+
+    Ok(())
+}
+
+pub async fn ensure_bootstrap_admin(
+    pool: &PgPool,
+    admin_name: &str,
+    admin_token: Option<&str>,
+) -> anyhow::Result<()> {
+    let Some(admin_token) = admin_token else {
+        return Ok(());
+    };
+
+    let admin_name = admin_name.trim();
+    if admin_name.is_empty() {
+        anyhow::bail!("ADMIN_BOOTSTRAP_NAME cannot be empty when ADMIN_BOOTSTRAP_TOKEN is set");
+    }
+
+    let mut transaction = pool.begin().await?;
+
+    // Each upsert returns a row only when it inserted or changed one, so an
+    // unchanged restart leaves no audit event.
+    let changed_user_id = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO users (name, is_admin)
+        VALUES ($1, TRUE)
+        ON CONFLICT (name)
+        DO UPDATE SET is_admin = TRUE
+        WHERE NOT users.is_admin
+        RETURNING id
+        "#,
+    )
+    .bind(admin_name)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let user_id = match changed_user_id {
+        Some(user_id) => user_id,
+        None => {
+            sqlx::query_scalar::<_, i64>("SELECT id FROM users WHERE name = $1")
+                .bind(admin_name)
+                .fetch_one(&mut *transaction)
+                .await?
+        }
+    };
+
+    let token_hash = hash_token(admin_token);
+    let token_prefix = token_prefix(admin_token);
+
+    let token_changed = sqlx::query_scalar::<_, i64>(
+        r#"
+        INSERT INTO auth_tokens (user_id, token_hash, token_prefix, label, revoked_at)
+        VALUES ($1, $2, $3, 'bootstrap', NULL)
+        ON CONFLICT (token_hash)
+        DO UPDATE SET user_id = $1, token_prefix = $3, label = 'bootstrap', revoked_at = NULL,
+                      expires_at = NULL, replaced_by_token_id = NULL
+        WHERE auth_tokens.user_id <> $1
+           OR auth_tokens.label <> 'bootstrap'
+           OR auth_tokens.revoked_at IS NOT NULL
+           OR auth_tokens.expires_at IS NOT NULL
+           OR auth_tokens.replaced_by_token_id IS NOT NULL
+        RETURNING id
+        "#,
+    )
+    .bind(user_id)
+    .bind(token_hash)
+    .bind(token_prefix)
+    .fetch_optional(&mut *transaction)
+    .await?
+    .is_some();
+
+    if changed_user_id.is_some() || token_changed {
+        record_audit_event(
+            &mut *transaction,
+            &AuditEntry::new("admin.bootstrapped")
+                .target("user", user_id)
+                .details(json!({ "name": admin_name })),
+        )
+        .await?;
+    }
+
+    transaction.commit().await?;
+    info!(admin_name, "ensured bootstrap admin user/token");
+    Ok(())
+}
+
+async fn ensure_user_document(pool: &PgPool, user_id: i64) -> Result<(), ApiError> {
+    sqlx::query(
+        r#"
+        INSERT INTO user_sync_document (
+            user_id,
+            id,
+            version,
+            updated_at,
+            app_state,
+            playlists,
+            provider_configuration,
+            settings
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        ON CONFLICT (user_id, id) DO NOTHING
+        "#,
+    )
+    .bind(user_id)
+    .bind(1_i16)
+    .bind(0_i64)
+    .bind(Utc::now())
+    .bind(json!({}))
+    .bind(json!([]))
+    .bind(json!({}))
+    .bind(json!({}))
+    .execute(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to ensure user snapshot row: {err}");
+        ApiError::internal("failed to ensure user snapshot row".to_string())
+    })?;
+
+    Ok(())
+}
+
+pub async fn authenticate_token(pool: &PgPool, token: &str) -> Result<AuthenticatedUser, ApiError> {
+    let trimmed = token.trim();
+    if trimmed.is_empty() {
+        return Err(ApiError::unauthorized("missing bearer token".to_string()));
+    }
+
+    let token_hash = hash_token(trimmed);
+
+    let row = sqlx::query_as::<
+        _,
+        (
+            i64,
+            String,
+            bool,
+            Option<DateTime<Utc>>,
+            i64,
+            Vec<String>,
+            Option<DateTime<Utc>>,
+        ),
+    >(
+        r#"
+        SELECT u.id, u.name, u.is_admin, u.disabled_at, t.id, t.scopes, t.expires_at
+        FROM auth_tokens t
+        JOIN users u ON u.id = t.user_id
+        WHERE t.token_hash = $1
+          AND t.revoked_at IS NULL
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| {
+        error!("failed to validate token: {err}");
+        ApiError::internal("failed to validate token".to_string())
+    })?
+    .ok_or_else(|| ApiError::unauthorized("invalid bearer token".to_string()))?;
+
+    if row.6.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(ApiError::unauthorized(
+            "bearer token has expired".to_string(),
+        ));
+    }
+
+    if row.3.is_some() {
+        return Err(ApiError::forbidden("user account is disabled".to_string()));
+    }
+
+    let _ = sqlx::query("UPDATE auth_tokens SET last_used_at = NOW() WHERE id = $1")
+        .bind(row.4)
+        .execute(pool)
+        .await;
+
+    Ok(AuthenticatedUser {
+        id: row.0,
+        name: row.1,
+        is_admin: row.2,
+        token_id: row.4,
+        scopes: Scopes::from_stored(&row.5),
+        expires_at: row.6,
+    })
+}
+
+type SnapshotRow = (
+    i64,
+    DateTime<Utc>,
+    serde_json::Value,
+    serde_json::Value,
+    serde_json::Value,
+    serde_json::Value,
+    i64,
+    i64,
+    i64,
+    i64,
+);
+
+fn snapshot_from_row(row: SnapshotRow) -> Snapshot {
+    Snapshot {
+        version: row.0,
+        updated_at: row.1,
+        app_state: row.2,
+        playlists: row.3,
+        provider_configuration: row.4,
+        settings: row.5,
+        namespace_versions: NamespaceVersions {
+            app_state: row.6,
+            playlists: row.7,
+            provider_configuration: row.8,
+            settings: row.9,
+        },
+    }
+}
+
+/// Current global and per-namespace versions, without loading any data.
+pub async fn load_versions(
+    pool: &PgPool,
+    user_id: i64,
+) -> Result<(i64, NamespaceVersions), ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let row = sqlx::query_as::<_, (i64, i64, i64, i64, i64)>(
+        r#"
+        SELECT
+            version,
+            app_state_version,
+            playlists_version,
+            provider_configuration_version,
+            settings_version
+        FROM user_sync_document
+        WHERE user_id = $1
+          AND id = 1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read versions from database: {err}");
+        ApiError::internal("failed to read versions".to_string())
+    })?;
+
+    Ok((
+        row.0,
+        NamespaceVersions {
+            app_state: row.1,
+            playlists: row.2,
+            provider_configuration: row.3,
+            settings: row.4,
+        },
+    ))
+}
+
+pub async fn load_snapshot(pool: &PgPool, user_id: i64) -> Result<Snapshot, ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let row = sqlx::query_as::<_, SnapshotRow>(
+        r#"
+        SELECT
+            version,
+            updated_at,
+            app_state,
+            playlists,
+            provider_configuration,
+            settings,
+            app_state_version,
+            playlists_version,
+            provider_configuration_version,
+            settings_version
+        FROM user_sync_document
+        WHERE user_id = $1
+          AND id = 1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read snapshot from database: {err}");
+        ApiError::internal("failed to read snapshot".to_string())
+    })?;
+
+    Ok(snapshot_from_row(row))
+}
+
+/// Reads the user's document inside `conn`'s transaction and locks the row so
+/// concurrent writers serialize on the version check.
+async fn lock_snapshot(conn: &mut PgConnection, user_id: i64) -> Result<Snapshot, ApiError> {
+    let row = sqlx::query_as::<_, SnapshotRow>(
+        r#"
+        SELECT
+            version,
+            updated_at,
+            app_state,
+            playlists,
+            provider_configuration,
+            settings,
+            app_state_version,
+            playlists_version,
+            provider_configuration_version,
+            settings_version
+        FROM user_sync_document
+        WHERE user_id = $1
+          AND id = 1
+        FOR UPDATE
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read current version: {err}");
+        ApiError::internal("failed to read current version".to_string())
+    })?;
+
+    Ok(snapshot_from_row(row))
+}
+
+fn check_expected_version(expected: Option<i64>, current: i64) -> Result<(), ApiError> {
+    if let Some(expected) = expected
+        && expected != current
+    {
+        return Err(ApiError::conflict(format!(
+            "expected version {expected}, but current version is {current}"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Applies `changes` on top of `current`, bumps the global version and the
+/// version of every written namespace, records one revision row per written
+/// namespace and prunes history beyond `retention`.
+async fn write_changes(
+    conn: &mut PgConnection,
+    user_id: i64,
+    current: &Snapshot,
+    scope: Namespace,
+    mut changes: Vec<(Namespace, serde_json::Value)>,
+    client_id: Option<String>,
+    retention: &HistoryRetention,
+) -> Result<(Snapshot, UpdateEvent), ApiError> {
+    // Playlists live in their own rows; the namespace is derived from them.
+    let mut ended_shares = Vec::new();
+    for (namespace, data) in &mut changes {
+        if *namespace == Namespace::Playlists {
+            let stored = store_playlists_value(conn, user_id, data, client_id.as_deref()).await?;
+            (*data, ended_shares) = stored;
+        }
+    }
+
+    let snapshot = bump_versions(conn, user_id, current, &changes).await?;
+    for (namespace, _) in &changes {
+        let revision = NewRevision {
+            namespace: *namespace,
+            previous_data: namespace_data(current, *namespace),
+            new_data: namespace_data(&snapshot, *namespace),
+            playlist: None,
+        };
+        record_revision(
+            conn,
+            user_id,
+            &snapshot,
+            scope,
+            revision,
+            client_id.as_deref(),
+        )
+        .await?;
+    }
+    prune_history(conn, user_id, snapshot.version, retention).await?;
+
+    let mut event = update_event(&snapshot, scope, client_id);
+    event.ended_shares = ended_shares;
+    Ok((snapshot, event))
+}
+
+/// Stores `changes` in the user's document, bumping the global version and
+/// the version of every written namespace.
+async fn bump_versions(
+    conn: &mut PgConnection,
+    user_id: i64,
+    current: &Snapshot,
+    changes: &[(Namespace, serde_json::Value)],
+) -> Result<Snapshot, ApiError> {
+    let assignments: Vec<String> = changes
+        .iter()
+        .enumerate()
+        .map(|(index, (namespace, _))| {
+            let column = namespace.as_str();
+            format!(
+                "{column} = ${}, {column}_version = {column}_version + 1",
+                index + 4
+            )
+        })
+        .collect();
+    let query = format!(
+        "UPDATE user_sync_document SET version = $1, updated_at = $2, {} WHERE user_id = $3 AND id = 1 \
+         RETURNING version, updated_at, app_state, playlists, provider_configuration, settings, \
+         app_state_version, playlists_version, provider_configuration_version, settings_version",
+        assignments.join(", ")
+    );
+
+    let mut update = sqlx::query_as::<_, SnapshotRow>(&query)
+        .bind(current.version + 1)
+        .bind(Utc::now())
+        .bind(user_id);
+    for (_, data) in changes {
+        update = update.bind(data);
+    }
+    let row = update.fetch_one(&mut *conn).await.map_err(|err| {
+        error!(user_id, "failed to update snapshot: {err}");
+        ApiError::internal("failed to update snapshot".to_string())
+    })?;
+    Ok(snapshot_from_row(row))
+}
+
+/// One `sync_revisions` row. Whole-namespace writes record the namespace
+/// value before and after; edits of a single playlist record only that
+/// playlist, see [`PlaylistRevision`].
+struct NewRevision<'a> {
+    namespace: Namespace,
+    previous_data: serde_json::Value,
+    new_data: serde_json::Value,
+    playlist: Option<PlaylistRevision<'a>>,
+}
+
+/// The playlist a revision row is limited to. Its data is the playlist's
+/// namespace value, or `null` when it did not exist before or after.
+struct PlaylistRevision<'a> {
+    playlist_id: &'a str,
+    /// Index of the playlist in the namespace before the write.
+    position: Option<usize>,
+}
+
+async fn record_revision(
+    conn: &mut PgConnection,
+    user_id: i64,
+    snapshot: &Snapshot,
+    scope: Namespace,
+    revision: NewRevision<'_>,
+    client_id: Option<&str>,
+) -> Result<(), ApiError> {
+    let playlist = revision.playlist.as_ref();
+    sqlx::query(
+        r#"
+        INSERT INTO sync_revisions (
+            user_id,
+            version,
+            namespace,
+            scope,
+            namespace_version,
+            previous_data,
+            new_data,
+            client_id,
+            created_at,
+            playlist_id,
+            playlist_position
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+        "#,
+    )
+    .bind(user_id)
+    .bind(snapshot.version)
+    .bind(revision.namespace.as_str())
+    .bind(scope.as_str())
+    .bind(snapshot.namespace_version(revision.namespace))
+    .bind(&revision.previous_data)
+    .bind(&revision.new_data)
+    .bind(client_id)
+    .bind(snapshot.updated_at)
+    .bind(playlist.map(|playlist| playlist.playlist_id))
+    .bind(
+        playlist
+            .and_then(|playlist| playlist.position)
+            .map(|position| position as i32),
+    )
+    .execute(&mut *conn)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to record revision: {err}");
+        ApiError::internal("failed to record revision".to_string())
+    })?;
+    Ok(())
+}
+
+async fn prune_history(
+    conn: &mut PgConnection,
+    user_id: i64,
+    version: i64,
+    retention: &HistoryRetention,
+) -> Result<(), ApiError> {
+    sqlx::query(
+        r#"
+        DELETE FROM sync_revisions
+        WHERE user_id = $1
+          AND (version <= $2 OR created_at < NOW() - make_interval(days => $3))
+        "#,
+    )
+    .bind(user_id)
+    .bind(version - retention.max_revisions)
+    .bind(retention.max_age_days)
+    .execute(&mut *conn)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to prune revision history: {err}");
+        ApiError::internal("failed to prune revision history".to_string())
+    })?;
+    Ok(())
+}
+
+fn update_event(snapshot: &Snapshot, scope: Namespace, client_id: Option<String>) -> UpdateEvent {
+    UpdateEvent {
+        event_type: "state_updated".to_string(),
+        namespace: scope,
+        version: snapshot.version,
+        namespace_versions: snapshot.namespace_versions.clone(),
+        updated_at: snapshot.updated_at,
+        source_client_id: client_id,
+        playlist_id: None,
+        ended_shares: Vec::new(),
+    }
+}
+
+/// Writes a namespace. When the payload carries a `merge_strategy` and its
+/// `expected_version` is stale, the write is three-way merged against the
+/// namespace value at `expected_version` instead of being rejected.
+pub async fn update_namespace(
+    pool: &PgPool,
+    user_id: i64,
+    namespace: Namespace,
+    payload: NamespacePayload,
+    retention: &HistoryRetention,
+) -> Result<(Snapshot, UpdateEvent, Option<MergeReport>), ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let mut transaction = pool.begin().await.map_err(|err| {
+        error!("failed to start transaction: {err}");
+        ApiError::internal("failed to start transaction".to_string())
+    })?;
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    let current_version = current.namespace_version(namespace);
+
+    let (data, merge_report) = match (payload.expected_version, payload.merge_strategy) {
+        (Some(expected), Some(strategy)) if expected != current_version => {
+            let base =
+                namespace_value_at_version(&mut transaction, user_id, namespace, expected, &current)
+                    .await?
+                .ok_or_else(|| {
+                    ApiError::conflict(format!(
+                        "expected version {expected} is no longer in history, cannot merge against current version {current_version}"
+                    ))
+                })?;
+            let (merged, report) = three_way_merge(
+                strategy,
+                expected,
+                &base,
+                &namespace_data(&current, namespace),
+                &payload.data,
+            )?;
+            (merged, Some(report))
+        }
+        _ => {
+            check_expected_version(payload.expected_version, current_version)?;
+            (payload.data, None)
+        }
+    };
+
+    let (snapshot, event) = write_changes(
+        &mut transaction,
+        user_id,
+        &current,
+        namespace,
+        vec![(namespace, data)],
+        payload.client_id,
+        retention,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(|err| {
+        error!(user_id, "failed to commit update: {err}");
+        ApiError::internal("failed to commit update".to_string())
+    })?;
+
+    Ok((snapshot, event, merge_report))
+}
+
+/// Value a namespace had when its own version was `namespace_version`, if
+/// history still covers it.
+async fn namespace_value_at_version(
+    conn: &mut PgConnection,
+    user_id: i64,
+    namespace: Namespace,
+    namespace_version: i64,
+    current: &Snapshot,
+) -> Result<Option<serde_json::Value>, ApiError> {
+    // The write that produced `namespace_version`, or, when that one was
+    // pruned, the last version before the write that followed it.
+    let version = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT version
+        FROM (
+            SELECT version, 0 AS priority
+            FROM sync_revisions
+            WHERE user_id = $1
+              AND namespace = $2
+              AND namespace_version = $3
+            UNION ALL
+            SELECT version - 1, 1
+            FROM sync_revisions
+            WHERE user_id = $1
+              AND namespace = $2
+              AND namespace_version = $3 + 1
+        ) candidates
+        ORDER BY priority
+        LIMIT 1
+        "#,
+    )
+    .bind(user_id)
+    .bind(namespace.as_str())
+    .bind(namespace_version)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read merge base: {err}");
+        ApiError::internal("failed to read merge base".to_string())
+    })?;
+
+    match version {
+        Some(version) => Ok(Some(
+            namespace_value_at(conn, user_id, namespace, version, current).await?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Applies a merge patch or JSON Patch to the namespace's current value inside
+/// the same transaction as the version bump.
+pub async fn patch_namespace(
+    pool: &PgPool,
+    user_id: i64,
+    namespace: Namespace,
+    format: PatchFormat,
+    patch: serde_json::Value,
+    query: PatchQuery,
+    retention: &HistoryRetention,
+) -> Result<(Snapshot, UpdateEvent), ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let mut transaction = pool.begin().await.map_err(|err| {
+        error!("failed to start transaction: {err}");
+        ApiError::internal("failed to start transaction".to_string())
+    })?;
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    check_expected_version(query.expected_version, current.namespace_version(namespace))?;
+
+    let data = apply_patch(format, &namespace_data(&current, namespace), &patch)?;
+    let result = write_changes(
+        &mut transaction,
+        user_id,
+        &current,
+        namespace,
+        vec![(namespace, data)],
+        query.client_id,
+        retention,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(|err| {
+        error!(user_id, "failed to commit update: {err}");
+        ApiError::internal("failed to commit update".to_string())
+    })?;
+
+    Ok(result)
+}
+
+pub async fn replace_snapshot(
+    pool: &PgPool,
+    user_id: i64,
+    payload: SnapshotPayload,
+    retention: &HistoryRetention,
+) -> Result<(Snapshot, UpdateEvent), ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let mut transaction = pool.begin().await.map_err(|err| {
+        error!("failed to start transaction: {err}");
+        ApiError::internal("failed to start transaction".to_string())
+    })?;
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    check_expected_version(payload.expected_version, current.version)?;
+
+    let changes = vec![
+        (Namespace::AppState, payload.app_state),
+        (Namespace::Playlists, payload.playlists),
+        (
+            Namespace::ProviderConfiguration,
+            payload.provider_configuration,
+        ),
+        (Namespace::Settings, payload.settings),
+    ];
+    let result = write_changes(
+        &mut transaction,
+        user_id,
+        &current,
+        Namespace::Snapshot,
+        changes,
+        payload.client_id,
+        retention,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(|err| {
+        error!(user_id, "failed to commit update: {err}");
+        ApiError::internal("failed to commit update".to_string())
+    })?;
+
+    Ok(result)
+}
+
+/// Reads the snapshot for a client that last synced at `since_version`: not
+/// modified, only the changed namespaces, or the full snapshot when history
+/// no longer reaches back that far (or no version was given).
+///
+/// The playlists shared with the user are versioned apart from the snapshot:
+/// only those changed after `shared_since_version` are included, and the
+/// response is only not modified when none changed either. Without a
+/// `shared_since_version`, every shared playlist is included.
+pub async fn snapshot_since(
+    pool: &PgPool,
+    user_id: i64,
+    since_version: Option<i64>,
+    shared_since_version: Option<i64>,
+) -> Result<SnapshotSince, ApiError> {
+    let snapshot = load_snapshot(pool, user_id).await?;
+    let shared = shared_playlists_since(pool, user_id, shared_since_version).await?;
+
+    let Some(since_version) = since_version else {
+        return Ok(SnapshotSince::Full(shared.respond(snapshot)));
+    };
+
+    if snapshot.version <= since_version
+        && shared.version <= shared_since_version.unwrap_or_default()
+    {
+        return Ok(SnapshotSince::NotModified);
+    }
+
+    let changed = if snapshot.version <= since_version {
+        Some(Vec::new())
+    } else {
+        changed_namespaces_since(pool, user_id, since_version, snapshot.version).await?
+    };
+    match changed {
+        Some(changed) => Ok(SnapshotSince::Delta(shared.respond(snapshot_delta(
+            snapshot,
+            since_version,
+            changed,
+        )))),
+        None => Ok(SnapshotSince::Full(shared.respond(snapshot))),
+    }
+}
+
+/// Whether revision history still covers every write after `since_version`.
+/// Pruning always removes the oldest versions first, so it is enough to check
+/// that the oldest kept revision directly follows `since_version`.
+async fn history_reaches(
+    pool: &PgPool,
+    user_id: i64,
+    since_version: i64,
+) -> Result<bool, ApiError> {
+    let oldest = sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MIN(version) FROM sync_revisions WHERE user_id = $1",
+    )
+    .bind(user_id)
+    .fetch_one(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read revision history: {err}");
+        ApiError::internal("failed to read revision history".to_string())
+    })?;
+
+    Ok(oldest.is_some_and(|oldest| oldest <= since_version + 1))
+}
+
+/// Rebuilds the `state_updated` events for every write after `since_version`,
+/// oldest first, or asks the client to resync when history is too short.
+pub async fn update_events_since(
+    pool: &PgPool,
+    user_id: i64,
+    since_version: i64,
+) -> Result<Replay, ApiError> {
+    let (current_version, mut namespace_versions) = load_versions(pool, user_id).await?;
+    if since_version >= current_version {
+        return Ok(Replay::Events(Vec::new()));
+    }
+    if !history_reaches(pool, user_id, since_version).await? {
+        return Ok(Replay::ResyncRequired(current_version));
+    }
+
+    let rows = sqlx::query_as::<_, (i64, String, String, Option<String>, DateTime<Utc>)>(
+        r#"
+        SELECT version, namespace, scope, client_id, created_at
+        FROM sync_revisions
+        WHERE user_id = $1
+          AND version > $2
+          AND version <= $3
+        ORDER BY version DESC
+        "#,
+    )
+    .bind(user_id)
+    .bind(since_version)
+    .bind(current_version)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read revision history: {err}");
+        ApiError::internal("failed to read revision history".to_string())
+    })?;
+
+    // Walk backwards from the current namespace versions: every write bumped
+    // each namespace it touched by exactly one.
+    let mut events: Vec<UpdateEvent> = Vec::new();
+    for (version, namespace, scope, client_id, created_at) in rows {
+        if events.last().is_none_or(|event| event.version != version) {
+            let Some(scope) = Namespace::from_str_id(&scope) else {
+                warn!(user_id, version, "skipping revision with unknown scope");
+                continue;
+            };
+            events.push(UpdateEvent {
+                event_type: "state_updated".to_string(),
+                namespace: scope,
+                version,
+                namespace_versions: namespace_versions.clone(),
+                updated_at: created_at,
+                source_client_id: client_id,
+                playlist_id: None,
+                ended_shares: Vec::new(),
+            });
+        }
+        if let Some(namespace) = Namespace::from_str_id(&namespace) {
+            namespace_versions.decrement(namespace);
+        }
+    }
+    events.reverse();
+
+    Ok(Replay::Events(events))
+}
+
+/// Namespaces whose data changed in versions `(since_version, up_to_version]`,
+/// or `None` when pruned history no longer reaches back to `since_version`.
+pub async fn changed_namespaces_since(
+    pool: &PgPool,
+    user_id: i64,
+    since_version: i64,
+    up_to_version: i64,
+) -> Result<Option<Vec<Namespace>>, ApiError> {
+    if !history_reaches(pool, user_id, since_version).await? {
+        return Ok(None);
+    }
+
+    let namespaces = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT DISTINCT namespace
+        FROM sync_revisions
+        WHERE user_id = $1
+          AND version > $2
+          AND version <= $3
+          AND previous_data IS DISTINCT FROM new_data
+        ORDER BY namespace ASC
+        "#,
+    )
+    .bind(user_id)
+    .bind(since_version)
+    .bind(up_to_version)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read changed namespaces: {err}");
+        ApiError::internal("failed to read changed namespaces".to_string())
+    })?;
+
+    Ok(Some(
+        namespaces
+            .iter()
+            .filter_map(|namespace| Namespace::from_str_id(namespace))
+            .collect(),
+    ))
+}
+
+pub async fn list_revisions(
+    pool: &PgPool,
+    user_id: i64,
+    namespace: Option<Namespace>,
+    before_version: Option<i64>,
+    limit: i64,
+) -> Result<Vec<Revision>, ApiError> {
+    let rows = sqlx::query_as::<
+        _,
+        (
+            i64,
+            String,
+            String,
+            serde_json::Value,
+            serde_json::Value,
+            Option<String>,
+            DateTime<Utc>,
+            Option<i64>,
+            Option<String>,
+        ),
+    >(
+        r#"
+        SELECT version, namespace, scope, previous_data, new_data, client_id, created_at,
+               namespace_version, playlist_id
+        FROM sync_revisions
+        WHERE user_id = $1
+          AND ($2::TEXT IS NULL OR namespace = $2)
+          AND version IN (
+              SELECT DISTINCT version
+              FROM sync_revisions
+              WHERE user_id = $1
+                AND ($2::TEXT IS NULL OR namespace = $2)
+                AND ($3::BIGINT IS NULL OR version < $3)
+              ORDER BY version DESC
+              LIMIT $4
+          )
+        ORDER BY version DESC, namespace ASC
+        "#,
+    )
+    .bind(user_id)
+    .bind(namespace.map(Namespace::as_str))
+    .bind(before_version)
+    .bind(limit)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to list revisions: {err}");
+        ApiError::internal("failed to list revisions".to_string())
+    })?;
+
+    let mut revisions: Vec<Revision> = Vec::new();
+    for row in rows {
+        let (Some(change_namespace), Some(scope)) = (
+            Namespace::from_str_id(&row.1),
+            Namespace::from_str_id(&row.2),
+        ) else {
+            warn!(
+                user_id,
+                version = row.0,
+                "skipping revision with unknown namespace"
+            );
+            continue;
+        };
+        let change = RevisionChange {
+            namespace: change_namespace,
+            namespace_version: row.7,
+            playlist_id: row.8,
+            previous_data: row.3,
+            new_data: row.4,
+        };
+
+        match revisions.last_mut() {
+            Some(revision) if revision.version == row.0 => revision.changes.push(change),
+            _ => revisions.push(Revision {
+                version: row.0,
+                namespace: scope,
+                client_id: row.5,
+                created_at: row.6,
+                changes: vec![change],
+            }),
+        }
+    }
+
+    Ok(revisions)
+}
+
+/// Value of `namespace` as it was right after `version` was written: the
+/// current value with every later revision touching it undone, newest first.
+/// Whole-namespace revisions hold the complete value before them, so undoing
+/// stops at the oldest of those.
+async fn namespace_value_at(
+    conn: &mut PgConnection,
+    user_id: i64,
+    namespace: Namespace,
+    version: i64,
+    current: &Snapshot,
+) -> Result<serde_json::Value, ApiError> {
+    let revisions = sqlx::query_as::<_, (serde_json::Value, Option<String>, Option<i32>)>(
+        r#"
+        SELECT previous_data, playlist_id, playlist_position
+        FROM sync_revisions
+        WHERE user_id = $1
+          AND namespace = $2
+          AND version > $3
+          AND version <= COALESCE(
+              (
+                  SELECT MIN(version)
+                  FROM sync_revisions
+                  WHERE user_id = $1
+                    AND namespace = $2
+                    AND version > $3
+                    AND playlist_id IS NULL
+              ),
+              version
+          )
+        ORDER BY version DESC
+        "#,
+    )
+    .bind(user_id)
+    .bind(namespace.as_str())
+    .bind(version)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read revision history: {err}");
+        ApiError::internal("failed to read revision history".to_string())
+    })?;
+
+    let mut value = namespace_data(current, namespace);
+    for (previous, playlist_id, position) in revisions {
+        match playlist_id {
+            None => value = previous,
+            Some(playlist_id) => {
+                playlists::set_playlist_value(
+                    &mut value,
+                    &playlist_id,
+                    Some(previous).filter(|previous| !previous.is_null()),
+                    position.map(|position| position as usize),
+                );
+            }
+        }
+    }
+    Ok(value)
+}
+
+/// Rolls one namespace (or the whole snapshot when `namespace` is `None`) back
+/// to its state at `version`. The rollback is itself recorded as a new write.
+pub async fn restore_revision(
+    pool: &PgPool,
+    user_id: i64,
+    version: i64,
+    namespace: Option<Namespace>,
+    payload: RestorePayload,
+    retention: &HistoryRetention,
+) -> Result<(Snapshot, UpdateEvent), ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let mut transaction = pool.begin().await.map_err(|err| {
+        error!("failed to start transaction: {err}");
+        ApiError::internal("failed to start transaction".to_string())
+    })?;
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    check_expected_version(payload.expected_version, current.version)?;
+
+    let in_history = sqlx::query_scalar::<_, bool>(
+        "SELECT EXISTS (SELECT 1 FROM sync_revisions WHERE user_id = $1 AND version = $2)",
+    )
+    .bind(user_id)
+    .bind(version)
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to read revision history: {err}");
+        ApiError::internal("failed to read revision history".to_string())
+    })?;
+
+    if !in_history {
+        return Err(ApiError::not_found(format!(
+            "version {version} is not available in history"
+        )));
+    }
+
+    let (scope, namespaces) = match namespace {
+        Some(namespace) => (namespace, vec![namespace]),
+        None => (Namespace::Snapshot, Namespace::DATA.to_vec()),
+    };
+
+    let mut changes = Vec::with_capacity(namespaces.len());
+    for namespace in namespaces {
+        let data =
+            namespace_value_at(&mut transaction, user_id, namespace, version, &current).await?;
+        changes.push((namespace, data));
+    }
+
+    let result = write_changes(
+        &mut transaction,
+        user_id,
+        &current,
+        scope,
+        changes,
+        payload.client_id,
+        retention,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(|err| {
+        error!(user_id, "failed to commit restore: {err}");
+        ApiError::internal("failed to commit restore".to_string())
+    })?;
+
+    Ok(result)
+}
+
+/// Seconds without a heartbeat after which a presence entry is considered
+/// stale, e.g. because the instance holding the connection crashed.
+pub const PRESENCE_TTL_SECONDS: i32 = 90;
+
+type PlaylistRow = (String, bool, Option<String>, Value, i64, DateTime<Utc>);
+
+fn object_or_empty(value: Value) -> Map<String, Value> {
+    match value {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    }
+}
+
+/// The user's playlists in order, or only `playlist_id` when given.
+async fn load_playlist_records(
+    conn: &mut PgConnection,
+    user_id: i64,
+    playlist_id: Option<&str>,
+) -> Result<Vec<PlaylistRecord>, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to load playlists: {err}");
+        ApiError::internal("failed to load playlists".to_string())
+    };
+
+    let rows = sqlx::query_as::<_, PlaylistRow>(
+        r#"
+        SELECT id, id_assigned, name, attributes, version, updated_at
+        FROM playlists
+        WHERE user_id = $1
+          AND ($2::TEXT IS NULL OR id = $2)
+        ORDER BY position
+        "#,
+    )
+    .bind(user_id)
+    .bind(playlist_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(map_err)?;
+
+    let items = sqlx::query_as::<_, (String, String, bool, Value, String, String, i64)>(
+        r#"
+        SELECT playlist_id, id, id_assigned, data, position, client_id, stamp
+        FROM playlist_items
+        WHERE user_id = $1
+          AND ($2::TEXT IS NULL OR playlist_id = $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(playlist_id)
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(map_err)?;
+
+    let mut items_by_playlist: HashMap<String, Vec<PlaylistItem>> = HashMap::new();
+    for (playlist_id, id, id_assigned, data, position, client_id, stamp) in items {
+        items_by_playlist
+            .entry(playlist_id)
+            .or_default()
+            .push(PlaylistItem {
+                id,
+                id_assigned,
+                data: object_or_empty(data),
+                placement: Placement {
+                    position,
+                    client_id,
+                    stamp,
+                },
+            });
+    }
+
+    Ok(rows
+        .into_iter()
+        .map(|(id, id_assigned, name, attributes, version, updated_at)| {
+            let mut playlist = Playlist {
+                items: items_by_playlist.remove(&id).unwrap_or_default(),
+                id,
+                id_assigned,
+                name,
+                attributes: object_or_empty(attributes),
+            };
+            playlist.sort_items();
+            PlaylistRecord {
+                playlist,
+                version,
+                updated_at,
+            }
+        })
+        .collect())
+}
+
+/// Makes the user's playlist rows match `value`, a `playlists` namespace
+/// value, and returns the namespace value derived back from them with the
+/// shares that ended.
+async fn store_playlists_value(
+    conn: &mut PgConnection,
+    user_id: i64,
+    value: &Value,
+    client_id: Option<&str>,
+) -> Result<(Value, Vec<PlaylistShare>), ApiError> {
+    let playlists = playlists::parse_playlists(value)?;
+    store_and_render_playlists(conn, user_id, playlists, client_id).await
+}
+
+/// [`store_playlists`], then the namespace value derived back from the rows.
+async fn store_and_render_playlists(
+    conn: &mut PgConnection,
+    user_id: i64,
+    playlists: Vec<Playlist>,
+    client_id: Option<&str>,
+) -> Result<(Value, Vec<PlaylistShare>), ApiError> {
+    let ended_shares = store_playlists(conn, user_id, playlists, client_id).await?;
+
+    let records = load_playlist_records(conn, user_id, None).await?;
+    let value = playlists::render_playlists(records.iter().map(|record| &record.playlist));
+    Ok((value, ended_shares))
+}
+
+/// Makes the user's playlist rows match `playlists`. Playlists and items
+/// written without an id keep the id the server assigned them before. Items
+/// are placed in the given order by `client_id`, keeping their stored
+/// placement where it still fits. Playlists whose name, attributes or items
+/// changed get a new version. Returns the shares of the deleted playlists,
+/// which end with them.
+async fn store_playlists(
+    conn: &mut PgConnection,
+    user_id: i64,
+    mut playlists: Vec<Playlist>,
+    client_id: Option<&str>,
+) -> Result<Vec<PlaylistShare>, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to store playlists: {err}");
+        ApiError::internal("failed to store playlists".to_string())
+    };
+
+    let stored = load_playlist_records(conn, user_id, None).await?;
+    let stored_playlists: Vec<Playlist> = stored
+        .iter()
+        .map(|record| record.playlist.clone())
+        .collect();
+    playlists::keep_assigned_ids(&mut playlists, &stored_playlists);
+    let stored: HashMap<String, PlaylistRecord> = stored
+        .into_iter()
+        .map(|record| (record.playlist.id.clone(), record))
+        .collect();
+    let ids: Vec<String> = playlists
+        .iter()
+        .map(|playlist| playlist.id.clone())
+        .collect();
+    let removed_ids: Vec<String> = stored
+        .keys()
+        .filter(|id| !ids.contains(id))
+        .cloned()
+        .collect();
+
+    let ended_shares = end_shares(conn, user_id, &removed_ids)
+        .await
+        .map_err(map_err)?;
+    sqlx::query("DELETE FROM playlists WHERE user_id = $1 AND id = ANY($2)")
+        .bind(user_id)
+        .bind(&removed_ids)
+        .execute(&mut *conn)
+        .await
+        .map_err(map_err)?;
+
+    let mut changed_ids = Vec::new();
+    for (position, playlist) in playlists.iter_mut().enumerate() {
+        let stored = stored.get(&playlist.id);
+        playlist.place_items(
+            stored.map(|record| &record.playlist),
+            client_id.unwrap_or_default(),
+        );
+        if store_playlist(conn, user_id, position as i32, playlist, stored)
+            .await
+            .map_err(map_err)?
+        {
+            changed_ids.push(playlist.id.clone());
+        }
+    }
+    touch_shares(conn, user_id, Some(changed_ids.as_slice()))
+        .await
+        .map_err(map_err)?;
+
+    sqlx::query(
+        r#"
+        UPDATE playlists p
+        SET position = ordered.position - 1
+        FROM UNNEST($2::TEXT[]) WITH ORDINALITY AS ordered(id, position)
+        WHERE p.user_id = $1
+          AND p.id = ordered.id
+          AND p.position <> ordered.position - 1
+        "#,
+    )
+    .bind(user_id)
+    .bind(&ids)
+    .execute(&mut *conn)
+    .await
+    .map_err(map_err)?;
+
+    Ok(ended_shares)
+}
+
+/// Writes one placed playlist over its `stored` rows, or inserts it at
+/// `position` when there are none. Unchanged playlists are left alone;
+/// returns whether it changed.
+async fn store_playlist(
+    conn: &mut PgConnection,
+    user_id: i64,
+    position: i32,
+    playlist: &Playlist,
+    stored: Option<&PlaylistRecord>,
+) -> Result<bool, sqlx::Error> {
+    let stored_items = match stored {
+        Some(record) if record.playlist == *playlist => return Ok(false),
+        Some(record) => {
+            sqlx::query(
+                r#"
+                UPDATE playlists
+                SET name = $3, attributes = $4, version = version + 1, updated_at = NOW()
+                WHERE user_id = $1
+                  AND id = $2
+                "#,
+            )
+            .bind(user_id)
+            .bind(&playlist.id)
+            .bind(&playlist.name)
+            .bind(Value::Object(playlist.attributes.clone()))
+            .execute(&mut *conn)
+            .await?;
+            Some(&record.playlist.items)
+        }
+        None => {
+            sqlx::query(
+                r#"
+                INSERT INTO playlists (user_id, id, id_assigned, position, name, attributes)
+                VALUES ($1, $2, $3, $4, $5, $6)
+                "#,
+            )
+            .bind(user_id)
+            .bind(&playlist.id)
+            .bind(playlist.id_assigned)
+            .bind(position)
+            .bind(&playlist.name)
+            .bind(Value::Object(playlist.attributes.clone()))
+            .execute(&mut *conn)
+            .await?;
+            None
+        }
+    };
+
+    if stored_items != Some(&playlist.items) {
+        replace_playlist_items(conn, user_id, playlist).await?;
+    }
+    Ok(true)
+}
+
+async fn replace_playlist_items(
+    conn: &mut PgConnection,
+    user_id: i64,
+    playlist: &Playlist,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM playlist_items WHERE user_id = $1 AND playlist_id = $2")
+        .bind(user_id)
+        .bind(&playlist.id)
+        .execute(&mut *conn)
+        .await?;
+
+    let ids: Vec<&str> = playlist.items.iter().map(|item| item.id.as_str()).collect();
+    let ids_assigned: Vec<bool> = playlist.items.iter().map(|item| item.id_assigned).collect();
+    let data: Vec<Value> = playlist
+        .items
+        .iter()
+        .map(|item| Value::Object(item.data.clone()))
+        .collect();
+    let placements = playlist.items.iter().map(|item| &item.placement);
+    let positions: Vec<&str> = placements
+        .clone()
+        .map(|placement| placement.position.as_str())
+        .collect();
+    let client_ids: Vec<&str> = placements
+        .clone()
+        .map(|placement| placement.client_id.as_str())
+        .collect();
+    let stamps: Vec<i64> = placements.map(|placement| placement.stamp).collect();
+    sqlx::query(
+        r#"
+        INSERT INTO playlist_items (
+            user_id, playlist_id, id, id_assigned, data, position, client_id, stamp
+        )
+        SELECT $1, $2, item.*
+        FROM UNNEST($3::TEXT[], $4::BOOLEAN[], $5::JSONB[], $6::TEXT[], $7::TEXT[], $8::BIGINT[])
+            AS item(id, id_assigned, data, position, client_id, stamp)
+        "#,
+    )
+    .bind(user_id)
+    .bind(&playlist.id)
+    .bind(&ids)
+    .bind(&ids_assigned)
+    .bind(&data)
+    .bind(&positions)
+    .bind(&client_ids)
+    .bind(&stamps)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn list_playlists(pool: &PgPool, user_id: i64) -> Result<Vec<PlaylistSummary>, ApiError> {
+    let rows = sqlx::query_as::<_, (String, Option<String>, Value, i64, i64, DateTime<Utc>)>(
+        r#"
+        SELECT p.id, p.name, p.attributes,
+               (SELECT COUNT(*) FROM playlist_items i
+                WHERE i.user_id = p.user_id AND i.playlist_id = p.id),
+               p.version, p.updated_at
+        FROM playlists p
+        WHERE p.user_id = $1
+        ORDER BY p.position
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to list playlists: {err}");
+        ApiError::internal("failed to list playlists".to_string())
+    })?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| PlaylistSummary {
+            id: row.0,
+            name: row.1,
+            attributes: object_or_empty(row.2),
+            item_count: row.3,
+            version: row.4,
+            updated_at: row.5,
+        })
+        .collect())
+}
+
+pub async fn load_playlist(
+    pool: &PgPool,
+    user_id: i64,
+    playlist_id: &str,
+) -> Result<PlaylistRecord, ApiError> {
+    let mut conn = pool.acquire().await.map_err(|err| {
+        error!("failed to acquire connection: {err}");
+        ApiError::internal("failed to load playlists".to_string())
+    })?;
+    load_playlist_records(&mut conn, user_id, Some(playlist_id))
+        .await?
+        .pop()
+        .ok_or_else(|| ApiError::not_found(format!("playlist '{playlist_id}' not found")))
+}
+
+/// The stored playlist `playlist_id`, checked against `expected_version`, to
+/// edit.
+fn found_playlist(
+    stored: Option<&PlaylistRecord>,
+    playlist_id: &str,
+    expected_version: Option<i64>,
+) -> Result<Playlist, ApiError> {
+    let record =
+        stored.ok_or_else(|| ApiError::not_found(format!("playlist '{playlist_id}' not found")))?;
+    check_expected_version(expected_version, record.version)?;
+    Ok(record.playlist.clone())
+}
+
+fn playlist_items(items: Vec<PlaylistItemInput>) -> Result<Vec<PlaylistItem>, ApiError> {
+    items
+        .into_iter()
+        .map(|item| PlaylistItem::new(item.id, item.data))
+        .collect()
+}
+
+fn check_playlist_name(name: &str) -> Result<(), ApiError> {
+    if name.trim().is_empty() {
+        return Err(ApiError::bad_request(
+            "playlist name must not be empty".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Applies `edit` to the playlist `playlist_id` (`None` when there is no such
+/// playlist) and stores what it returns, or deletes the playlist on `None`.
+/// This is a `playlists` namespace write limited to that playlist: only its
+/// rows are written, the namespace value is patched in place and the revision
+/// holds just that playlist, so history, merges and events stay in step with
+/// the rows. Returns the playlist as stored, unless the edit deleted it.
+///
+/// A collaborator edits through their `share_id`, which is checked again
+/// once the playlist is locked, so a share that ends or turns read-only
+/// meanwhile stops the edit.
+async fn edit_playlists(
+    pool: &PgPool,
+    user_id: i64,
+    share_id: Option<i64>,
+    playlist_id: &str,
+    client_id: Option<String>,
+    retention: &HistoryRetention,
+    edit: impl FnOnce(Option<&PlaylistRecord>) -> Result<Option<Playlist>, ApiError>,
+) -> Result<(Option<PlaylistRecord>, UpdateEvent), ApiError> {
+    ensure_user_document(pool, user_id).await?;
+
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to store playlist: {err}");
+        ApiError::internal("failed to store playlist".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(|err| {
+        error!("failed to start transaction: {err}");
+        ApiError::internal("failed to start transaction".to_string())
+    })?;
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    if let Some(share_id) = share_id {
+        check_collaboration(&mut transaction, user_id, playlist_id, share_id).await?;
+    }
+    let stored = load_playlist_records(&mut transaction, user_id, Some(playlist_id))
+        .await?
+        .pop();
+
+    let mut ended_shares = Vec::new();
+    match (edit(stored.as_ref())?, &stored) {
+        (Some(mut playlist), _) => {
+            playlist.place_items(
+                stored.as_ref().map(|record| &record.playlist),
+                client_id.as_deref().unwrap_or_default(),
+            );
+            let position = sqlx::query_scalar::<_, i32>(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM playlists WHERE user_id = $1",
+            )
+            .bind(user_id)
+            .fetch_one(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+            if store_playlist(
+                &mut transaction,
+                user_id,
+                position,
+                &playlist,
+                stored.as_ref(),
+            )
+            .await
+            .map_err(map_err)?
+            {
+                touch_shares(
+                    &mut transaction,
+                    user_id,
+                    Some(std::slice::from_ref(&playlist.id)),
+                )
+                .await
+                .map_err(map_err)?;
+            }
+        }
+        (None, Some(_)) => {
+            ended_shares = end_shares(&mut transaction, user_id, &[playlist_id.to_string()])
+                .await
+                .map_err(map_err)?;
+            sqlx::query("DELETE FROM playlists WHERE user_id = $1 AND id = $2")
+                .bind(user_id)
+                .bind(playlist_id)
+                .execute(&mut *transaction)
+                .await
+                .map_err(map_err)?;
+        }
+        (None, None) => {}
+    }
+
+    let record = load_playlist_records(&mut transaction, user_id, Some(playlist_id))
+        .await?
+        .pop();
+    let previous_data = stored.map(|record| record.playlist.to_value());
+    let new_data = record.as_ref().map(|record| record.playlist.to_value());
+    let mut data = namespace_data(&current, Namespace::Playlists);
+    let position = playlists::set_playlist_value(&mut data, playlist_id, new_data.clone(), None);
+
+    let snapshot = bump_versions(
+        &mut transaction,
+        user_id,
+        &current,
+        &[(Namespace::Playlists, data)],
+    )
+    .await?;
+    let revision = NewRevision {
+        namespace: Namespace::Playlists,
+        previous_data: previous_data.unwrap_or_default(),
+        new_data: new_data.unwrap_or_default(),
+        playlist: Some(PlaylistRevision {
+            playlist_id,
+            position,
+        }),
+    };
+    record_revision(
+        &mut transaction,
+        user_id,
+        &snapshot,
+        Namespace::Playlists,
+        revision,
+        client_id.as_deref(),
+    )
+    .await?;
+    prune_history(&mut transaction, user_id, snapshot.version, retention).await?;
+
+    transaction.commit().await.map_err(|err| {
+        error!(user_id, "failed to commit update: {err}");
+        ApiError::internal("failed to commit update".to_string())
+    })?;
+
+    let mut event = update_event(&snapshot, Namespace::Playlists, client_id);
+    event.playlist_id = Some(playlist_id.to_string());
+    event.ended_shares = ended_shares;
+    Ok((record, event))
+}
+
+/// [`edit_playlists`] for edits that keep the playlist.
+async fn edit_playlist(
+    pool: &PgPool,
+    user_id: i64,
+    share_id: Option<i64>,
+    playlist_id: &str,
+    client_id: Option<String>,
+    retention: &HistoryRetention,
+    edit: impl FnOnce(Option<&PlaylistRecord>) -> Result<Playlist, ApiError>,
+) -> Result<(PlaylistRecord, UpdateEvent), ApiError> {
+    let (record, event) = edit_playlists(
+        pool,
+        user_id,
+        share_id,
+        playlist_id,
+        client_id,
+        retention,
+        |stored| edit(stored).map(Some),
+    )
+    .await?;
+    let record =
+        record.ok_or_else(|| ApiError::internal("failed to reload playlist".to_string()))?;
+    Ok((record, event))
+}
+
+pub async fn create_playlist(
+    pool: &PgPool,
+    user_id: i64,
+    payload: CreatePlaylistRequest,
+    retention: &HistoryRetention,
+) -> Result<(PlaylistRecord, UpdateEvent), ApiError> {
+    check_playlist_name(&payload.name)?;
+    let playlist = Playlist::new(
+        payload.id,
+        Some(payload.name),
+        payload.attributes,
+        playlist_items(payload.items)?,
+    )?;
+    let playlist_id = playlist.id.clone();
+
+    edit_playlist(
+        pool,
+        user_id,
+        None,
+        &playlist_id,
+        payload.client_id,
+        retention,
+        |stored| match stored {
+            Some(_) => Err(ApiError::already_exists(format!(
+                "playlist '{playlist_id}' already exists"
+            ))),
+            None => Ok(playlist),
+        },
+    )
+    .await
+}
+
+pub async fn update_playlist(
+    pool: &PgPool,
+    user_id: i64,
+    share_id: Option<i64>,
+    playlist_id: &str,
+    payload: UpdatePlaylistRequest,
+    retention: &HistoryRetention,
+) -> Result<(PlaylistRecord, UpdateEvent), ApiError> {
+    if let Some(name) = &payload.name {
+        check_playlist_name(name)?;
+    }
+
+    edit_playlist(
+        pool,
+        user_id,
+        share_id,
+        playlist_id,
+        payload.client_id,
+        retention,
+        |stored| {
+            let mut playlist = found_playlist(stored, playlist_id, payload.expected_version)?;
+            if let Some(name) = payload.name {
+                playlist.name = Some(name);
+            }
+            if let Some(attributes) = payload.attributes {
+                playlist.set_attributes(attributes)?;
+            }
+            Ok(playlist)
+        },
+    )
+    .await
+}
+
+pub async fn remove_playlist(
+    pool: &PgPool,
+    user_id: i64,
+    playlist_id: &str,
+    query: PlaylistWriteQuery,
+    retention: &HistoryRetention,
+) -> Result<UpdateEvent, ApiError> {
+    let (_, event) = edit_playlists(
+        pool,
+        user_id,
+        None,
+        playlist_id,
+        query.client_id,
+        retention,
+        |stored| {
+            found_playlist(stored, playlist_id, query.expected_version)?;
+            Ok(None)
+        },
+    )
+    .await?;
+    Ok(event)
+}
+
+pub async fn insert_playlist_items(
+    pool: &PgPool,
+    user_id: i64,
+    share_id: Option<i64>,
+    playlist_id: &str,
+    payload: InsertPlaylistItemsRequest,
+    retention: &HistoryRetention,
+) -> Result<(PlaylistRecord, UpdateEvent), ApiError> {
+    let items = playlist_items(payload.items)?;
+    let anchor = Anchor::from_fields(
+        payload.index,
+        payload.after,
+        payload.before,
+        payload.position,
+    )?;
+    let placed_by = payload.client_id.clone().unwrap_or_default();
+
+    edit_playlist(
+        pool,
+        user_id,
+        share_id,
+        playlist_id,
+        payload.client_id,
+        retention,
+        |stored| {
+            let mut playlist = found_playlist(stored, playlist_id, payload.expected_version)?;
+            playlist.insert_items(anchor.as_ref(), items, &placed_by)?;
+            Ok(playlist)
+        },
+    )
+    .await
+}
+
+pub async fn move_playlist_item(
+    pool: &PgPool,
+    user_id: i64,
+    share_id: Option<i64>,
+    playlist_id: &str,
+    item_id: &str,
+    payload: MovePlaylistItemRequest,
+    retention: &HistoryRetention,
+) -> Result<(PlaylistRecord, UpdateEvent), ApiError> {
+    let anchor = Anchor::from_fields(
+        payload.index,
+        payload.after,
+        payload.before,
+        payload.position,
+    )?
+    .ok_or_else(|| {
+        ApiError::bad_request("one of index, after, before or position is required".to_string())
+    })?;
+    let placed_by = payload.client_id.clone().unwrap_or_default();
+
+    edit_playlist(
+        pool,
+        user_id,
+        share_id,
+        playlist_id,
+        payload.client_id,
+        retention,
+        |stored| {
+            let mut playlist = found_playlist(stored, playlist_id, payload.expected_version)?;
+            playlist.move_item(item_id, &anchor, &placed_by)?;
+            Ok(playlist)
+        },
+    )
+    .await
+}
+
+pub async fn remove_playlist_item(
+    pool: &PgPool,
+    user_id: i64,
+    share_id: Option<i64>,
+    playlist_id: &str,
+    item_id: &str,
+    query: PlaylistWriteQuery,
+    retention: &HistoryRetention,
+) -> Result<(PlaylistRecord, UpdateEvent), ApiError> {
+    edit_playlist(
+        pool,
+        user_id,
+        share_id,
+        playlist_id,
+        query.client_id,
+        retention,
+        |stored| {
+            let mut playlist = found_playlist(stored, playlist_id, query.expected_version)?;
+            playlist.remove_item(item_id)?;
+            Ok(playlist)
+        },
+    )
+    .await
+}
+
+type PlaylistShareRow = (i64, String, i64, String, String, DateTime<Utc>);
+
+fn playlist_share_from_row(row: PlaylistShareRow) -> PlaylistShare {
+    PlaylistShare {
+        id: row.0,
+        playlist_id: row.1,
+        user_id: row.2,
+        user_name: row.3,
+        access: ShareAccess::parse(&row.4).unwrap_or(ShareAccess::ReadOnly),
+        created_at: row.5,
+    }
+}
+
+/// Bumps the shared version of `recipient_ids`, locking their rows in id
+/// order so that writes to shares with the same recipients cannot deadlock.
+async fn bump_shared_versions(
+    conn: &mut PgConnection,
+    recipient_ids: &[i64],
+) -> Result<(), sqlx::Error> {
+    if recipient_ids.is_empty() {
+        return Ok(());
+    }
+    sqlx::query("SELECT id FROM users WHERE id = ANY($1) ORDER BY id FOR NO KEY UPDATE")
+        .bind(recipient_ids)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("UPDATE users SET shared_version = shared_version + 1 WHERE id = ANY($1)")
+        .bind(recipient_ids)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Marks the owner's shares of `playlist_ids` (all their shares when `None`)
+/// as changed, so the recipients' next snapshot includes them.
+async fn touch_shares(
+    conn: &mut PgConnection,
+    owner_id: i64,
+    playlist_ids: Option<&[String]>,
+) -> Result<(), sqlx::Error> {
+    let recipient_ids = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT DISTINCT recipient_id
+        FROM playlist_shares
+        WHERE owner_id = $1
+          AND ($2::TEXT[] IS NULL OR playlist_id = ANY($2))
+        ORDER BY recipient_id
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_ids)
+    .fetch_all(&mut *conn)
+    .await?;
+    if recipient_ids.is_empty() {
+        return Ok(());
+    }
+
+    bump_shared_versions(conn, &recipient_ids).await?;
+    sqlx::query(
+        r#"
+        UPDATE playlist_shares s
+        SET version = u.shared_version
+        FROM users u
+        WHERE u.id = s.recipient_id
+          AND s.owner_id = $1
+          AND ($2::TEXT[] IS NULL OR s.playlist_id = ANY($2))
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_ids)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Ends the owner's shares of `playlist_ids`, which are about to be deleted,
+/// and returns them so their recipients can be told.
+async fn end_shares(
+    conn: &mut PgConnection,
+    owner_id: i64,
+    playlist_ids: &[String],
+) -> Result<Vec<PlaylistShare>, sqlx::Error> {
+    if playlist_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rows = sqlx::query_as::<_, PlaylistShareRow>(
+        r#"
+        DELETE FROM playlist_shares s
+        USING users u
+        WHERE u.id = s.recipient_id
+          AND s.owner_id = $1
+          AND s.playlist_id = ANY($2)
+        RETURNING s.id, s.playlist_id, s.recipient_id, u.name, s.access, s.created_at
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_ids)
+    .fetch_all(&mut *conn)
+    .await?;
+    let shares: Vec<PlaylistShare> = rows.into_iter().map(playlist_share_from_row).collect();
+
+    let mut recipient_ids: Vec<i64> = shares.iter().map(|share| share.user_id).collect();
+    recipient_ids.sort_unstable();
+    recipient_ids.dedup();
+    bump_shared_versions(conn, &recipient_ids).await?;
+    Ok(shares)
+}
+
+/// Checks, inside an edit's transaction, that the share `share_id` of the
+/// owner's playlist still allows editing, and keeps it from changing until
+/// the edit commits.
+async fn check_collaboration(
+    conn: &mut PgConnection,
+    owner_id: i64,
+    playlist_id: &str,
+    share_id: i64,
+) -> Result<(), ApiError> {
+    let access = sqlx::query_scalar::<_, String>(
+        r#"
+        SELECT s.access
+        FROM playlist_shares s
+        JOIN users u ON u.id = s.owner_id
+        WHERE s.id = $1
+          AND s.owner_id = $2
+          AND s.playlist_id = $3
+          AND u.disabled_at IS NULL
+        FOR SHARE OF s
+        "#,
+    )
+    .bind(share_id)
+    .bind(owner_id)
+    .bind(playlist_id)
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|err| {
+        error!(owner_id, "failed to check playlist share: {err}");
+        ApiError::internal("failed to check playlist share".to_string())
+    })?
+    .ok_or_else(|| ApiError::not_found(format!("shared playlist {share_id} not found")))?;
+
+    if ShareAccess::parse(&access) != Some(ShareAccess::Collaborative) {
+        return Err(ApiError::forbidden(
+            "this playlist is shared read-only".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Shares of the owner's playlist `playlist_id`, or of all their playlists.
+pub async fn list_playlist_shares(
+    pool: &PgPool,
+    owner_id: i64,
+    playlist_id: Option<&str>,
+) -> Result<Vec<PlaylistShare>, ApiError> {
+    let rows = sqlx::query_as::<_, PlaylistShareRow>(
+        r#"
+        SELECT s.id, s.playlist_id, s.recipient_id, u.name, s.access, s.created_at
+        FROM playlist_shares s
+        JOIN users u ON u.id = s.recipient_id
+        WHERE s.owner_id = $1
+          AND ($2::TEXT IS NULL OR s.playlist_id = $2)
+        ORDER BY s.id
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(owner_id, "failed to list playlist shares: {err}");
+        ApiError::internal("failed to list playlist shares".to_string())
+    })?;
+
+    Ok(rows.into_iter().map(playlist_share_from_row).collect())
+}
+
+/// Shares the owner's playlist with the user named `recipient`, or changes
+/// the access of an existing share.
+pub async fn share_playlist(
+    pool: &PgPool,
+    owner_id: i64,
+    playlist_id: &str,
+    recipient: &str,
+    access: ShareAccess,
+    audit: impl FnOnce(&PlaylistShare) -> AuditEntry,
+) -> Result<PlaylistShare, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(owner_id, "failed to share playlist: {err}");
+        ApiError::internal("failed to share playlist".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let recipient_id = sqlx::query_scalar::<_, i64>("SELECT id FROM users WHERE name = $1")
+        .bind(recipient)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(map_err)?
+        .ok_or_else(|| ApiError::not_found(format!("user '{recipient}' not found")))?;
+    if recipient_id == owner_id {
+        return Err(ApiError::bad_request(
+            "you cannot share a playlist with yourself".to_string(),
+        ));
+    }
+
+    let row = sqlx::query_as::<_, PlaylistShareRow>(
+        r#"
+        INSERT INTO playlist_shares (owner_id, playlist_id, recipient_id, access)
+        SELECT $1, p.id, $3, $4
+        FROM playlists p
+        WHERE p.user_id = $1
+          AND p.id = $2
+        ON CONFLICT (owner_id, playlist_id, recipient_id)
+            DO UPDATE SET access = EXCLUDED.access
+        RETURNING id, playlist_id, recipient_id, $5::TEXT, access, created_at
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_id)
+    .bind(recipient_id)
+    .bind(access.as_str())
+    .bind(recipient)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found(format!("playlist '{playlist_id}' not found")))?;
+    let share = playlist_share_from_row(row);
+
+    bump_shared_versions(&mut transaction, &[recipient_id])
+        .await
+        .map_err(map_err)?;
+    sqlx::query(
+        r#"
+        UPDATE playlist_shares s
+        SET version = u.shared_version
+        FROM users u
+        WHERE u.id = s.recipient_id
+          AND s.id = $1
+        "#,
+    )
+    .bind(share.id)
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+
+    record_audit_event(&mut *transaction, &audit(&share))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(share)
+}
+
+/// Ends the share of the owner's playlist with the user named `recipient`.
+pub async fn unshare_playlist(
+    pool: &PgPool,
+    owner_id: i64,
+    playlist_id: &str,
+    recipient: &str,
+    audit: impl FnOnce(&PlaylistShare) -> AuditEntry,
+) -> Result<PlaylistShare, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(owner_id, "failed to unshare playlist: {err}");
+        ApiError::internal("failed to unshare playlist".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let row = sqlx::query_as::<_, PlaylistShareRow>(
+        r#"
+        DELETE FROM playlist_shares s
+        USING users u
+        WHERE u.id = s.recipient_id
+          AND s.owner_id = $1
+          AND s.playlist_id = $2
+          AND u.name = $3
+        RETURNING s.id, s.playlist_id, s.recipient_id, u.name, s.access, s.created_at
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_id)
+    .bind(recipient)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| {
+        ApiError::not_found(format!(
+            "playlist '{playlist_id}' is not shared with '{recipient}'"
+        ))
+    })?;
+    let share = playlist_share_from_row(row);
+    bump_shared_versions(&mut transaction, &[share.user_id])
+        .await
+        .map_err(map_err)?;
+
+    record_audit_event(&mut *transaction, &audit(&share))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(share)
+}
+
+type SharedPlaylistRow = (i64, i64, String, String, String);
+
+/// Playlists shared with `recipient_id`, or only the share `share_id`, or
+/// only the shares changed after the shared version `since_version`. Shares
+/// of disabled owners are left out.
+async fn load_shared_playlists(
+    pool: &PgPool,
+    recipient_id: i64,
+    share_id: Option<i64>,
+    since_version: Option<i64>,
+) -> Result<Vec<SharedPlaylist>, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(recipient_id, "failed to load shared playlists: {err}");
+        ApiError::internal("failed to load shared playlists".to_string())
+    };
+
+    let rows = sqlx::query_as::<_, SharedPlaylistRow>(
+        r#"
+        SELECT s.id, s.owner_id, u.name, s.playlist_id, s.access
+        FROM playlist_shares s
+        JOIN users u ON u.id = s.owner_id
+        WHERE s.recipient_id = $1
+          AND ($2::BIGINT IS NULL OR s.id = $2)
+          AND ($3::BIGINT IS NULL OR s.version > $3)
+          AND u.disabled_at IS NULL
+        ORDER BY s.id
+        "#,
+    )
+    .bind(recipient_id)
+    .bind(share_id)
+    .bind(since_version)
+    .fetch_all(pool)
+    .await
+    .map_err(map_err)?;
+
+    let mut conn = pool.acquire().await.map_err(map_err)?;
+    let mut shared = Vec::with_capacity(rows.len());
+    for (share_id, owner_id, owner_name, playlist_id, access) in rows {
+        let Some(playlist) = load_playlist_records(&mut conn, owner_id, Some(&playlist_id))
+            .await?
+            .pop()
+        else {
+            continue;
+        };
+        shared.push(SharedPlaylist {
+            share_id,
+            owner_id,
+            owner_name,
+            access: ShareAccess::parse(&access).unwrap_or(ShareAccess::ReadOnly),
+            playlist,
+        });
+    }
+    Ok(shared)
+}
+
+pub async fn list_shared_playlists(
+    pool: &PgPool,
+    recipient_id: i64,
+) -> Result<Vec<SharedPlaylist>, ApiError> {
+    load_shared_playlists(pool, recipient_id, None, None).await
+}
+
+/// The recipient's shared version and the shared playlists changed after
+/// `since_version`, or all of them when `None`.
+pub async fn shared_playlists_since(
+    pool: &PgPool,
+    recipient_id: i64,
+    since_version: Option<i64>,
+) -> Result<SharedPlaylistsSince, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(recipient_id, "failed to load shared playlists: {err}");
+        ApiError::internal("failed to load shared playlists".to_string())
+    };
+
+    // Read the version first: a change committed after this read is then
+    // sent again next time rather than missed.
+    let version = sqlx::query_scalar::<_, i64>("SELECT shared_version FROM users WHERE id = $1")
+        .bind(recipient_id)
+        .fetch_one(pool)
+        .await
+        .map_err(map_err)?;
+    let playlists = load_shared_playlists(pool, recipient_id, None, since_version).await?;
+    let share_ids = sqlx::query_scalar::<_, i64>(
+        r#"
+        SELECT s.id
+        FROM playlist_shares s
+        JOIN users u ON u.id = s.owner_id
+        WHERE s.recipient_id = $1
+          AND u.disabled_at IS NULL
+        ORDER BY s.id
+        "#,
+    )
+    .bind(recipient_id)
+    .fetch_all(pool)
+    .await
+    .map_err(map_err)?;
+
+    Ok(SharedPlaylistsSince {
+        version,
+        playlists,
+        share_ids,
+    })
+}
+
+pub async fn load_shared_playlist(
+    pool: &PgPool,
+    recipient_id: i64,
+    share_id: i64,
+) -> Result<SharedPlaylist, ApiError> {
+    load_shared_playlists(pool, recipient_id, Some(share_id), None)
+        .await?
+        .pop()
+        .ok_or_else(|| ApiError::not_found(format!("shared playlist {share_id} not found")))
+}
+
+/// Removes the recipient's share `share_id`, e.g. when they no longer want
+/// the playlist. Returns the owner's id and the share.
+pub async fn leave_shared_playlist(
+    pool: &PgPool,
+    recipient_id: i64,
+    share_id: i64,
+    audit: impl FnOnce(i64, &PlaylistShare) -> AuditEntry,
+) -> Result<(i64, PlaylistShare), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(recipient_id, "failed to leave shared playlist: {err}");
+        ApiError::internal("failed to leave shared playlist".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let row = sqlx::query_as::<_, (i64, i64, String, i64, String, String, DateTime<Utc>)>(
+        r#"
+        DELETE FROM playlist_shares s
+        USING users u
+        WHERE u.id = s.recipient_id
+          AND s.id = $1
+          AND s.recipient_id = $2
+        RETURNING s.owner_id, s.id, s.playlist_id, s.recipient_id, u.name, s.access, s.created_at
+        "#,
+    )
+    .bind(share_id)
+    .bind(recipient_id)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found(format!("shared playlist {share_id} not found")))?;
+    let owner_id = row.0;
+    let share = playlist_share_from_row((row.1, row.2, row.3, row.4, row.5, row.6));
+    bump_shared_versions(&mut transaction, &[recipient_id])
+        .await
+        .map_err(map_err)?;
+
+    record_audit_event(&mut *transaction, &audit(owner_id, &share))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok((owner_id, share))
+}
+
+type ShareLinkRow = (i64, String, String, DateTime<Utc>, Option<DateTime<Utc>>);
+
+fn share_link_from_row(row: ShareLinkRow) -> ShareLink {
+    ShareLink {
+        id: row.0,
+        playlist_id: row.1,
+        label: row.2,
+        created_at: row.3,
+        expires_at: row.4,
+    }
+}
+
+/// Links to the owner's playlist `playlist_id`, expired ones included.
+pub async fn list_share_links(
+    pool: &PgPool,
+    owner_id: i64,
+    playlist_id: &str,
+) -> Result<Vec<ShareLink>, ApiError> {
+    let rows = sqlx::query_as::<_, ShareLinkRow>(
+        r#"
+        SELECT id, playlist_id, label, created_at, expires_at
+        FROM playlist_share_links
+        WHERE owner_id = $1
+          AND playlist_id = $2
+        ORDER BY id
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_id)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(owner_id, "failed to list share links: {err}");
+        ApiError::internal("failed to list share links".to_string())
+    })?;
+
+    Ok(rows.into_iter().map(share_link_from_row).collect())
+}
+
+/// Mints a public link to the owner's playlist `playlist_id`. The link is
+/// only stored hashed, so this is the one time it can be read.
+pub async fn create_share_link(
+    pool: &PgPool,
+    owner_id: i64,
+    playlist_id: &str,
+    label: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    audit: impl FnOnce(&ShareLink) -> AuditEntry,
+) -> Result<ShareLinkCreated, ApiError> {
+    if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(ApiError::bad_request(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+
+    let map_err = |err: sqlx::Error| {
+        error!(owner_id, "failed to create share link: {err}");
+        ApiError::internal("failed to create share link".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let link = generate_link();
+    let row = sqlx::query_as::<_, ShareLinkRow>(
+        r#"
+        INSERT INTO playlist_share_links
+            (owner_id, playlist_id, link_hash, label, expires_at)
+        SELECT $1, p.id, $3, $4, $5
+        FROM playlists p
+        WHERE p.user_id = $1
+          AND p.id = $2
+        RETURNING id, playlist_id, label, created_at, expires_at
+        "#,
+    )
+    .bind(owner_id)
+    .bind(playlist_id)
+    .bind(hash_token(&link))
+    .bind(label.unwrap_or_else(|| "link".to_string()))
+    .bind(expires_at)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found(format!("playlist '{playlist_id}' not found")))?;
+    let info = share_link_from_row(row);
+
+    record_audit_event(&mut *transaction, &audit(&info))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(ShareLinkCreated {
+        path: format!("/s/{link}"),
+        link,
+        info,
+    })
+}
+
+/// Revokes the link `link_id` to the owner's playlist `playlist_id`.
+pub async fn revoke_share_link(
+    pool: &PgPool,
+    owner_id: i64,
+    playlist_id: &str,
+    link_id: i64,
+    audit: impl FnOnce(&ShareLink) -> AuditEntry,
+) -> Result<ShareLink, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(owner_id, "failed to revoke share link: {err}");
+        ApiError::internal("failed to revoke share link".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let row = sqlx::query_as::<_, ShareLinkRow>(
+        r#"
+        DELETE FROM playlist_share_links
+        WHERE id = $1
+          AND owner_id = $2
+          AND playlist_id = $3
+        RETURNING id, playlist_id, label, created_at, expires_at
+        "#,
+    )
+    .bind(link_id)
+    .bind(owner_id)
+    .bind(playlist_id)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found(format!("share link {link_id} not found")))?;
+    let link = share_link_from_row(row);
+
+    record_audit_event(&mut *transaction, &audit(&link))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(link)
+}
+
+/// The playlist behind the public link `link`. Unknown, revoked and expired
+/// links, and links of disabled owners, are all reported as not found.
+pub async fn resolve_share_link(pool: &PgPool, link: &str) -> Result<LinkedPlaylist, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!("failed to resolve share link: {err}");
+        ApiError::internal("failed to load playlist".to_string())
+    };
+    let not_found = || ApiError::not_found("link not found".to_string());
+
+    let (owner_id, playlist_id, expires_at) =
+        sqlx::query_as::<_, (i64, String, Option<DateTime<Utc>>)>(
+            r#"
+            SELECT l.owner_id, l.playlist_id, l.expires_at
+            FROM playlist_share_links l
+            JOIN users u ON u.id = l.owner_id
+            WHERE l.link_hash = $1
+              AND (l.expires_at IS NULL OR l.expires_at > NOW())
+              AND u.disabled_at IS NULL
+            "#,
+        )
+        .bind(hash_token(link))
+        .fetch_optional(pool)
+        .await
+        .map_err(map_err)?
+        .ok_or_else(not_found)?;
+
+    let mut conn = pool.acquire().await.map_err(map_err)?;
+    let record = load_playlist_records(&mut conn, owner_id, Some(&playlist_id))
+        .await?
+        .pop()
+        .ok_or_else(not_found)?;
+
+    Ok(LinkedPlaylist {
+        playlist: record.playlist.to_value(),
+        version: record.version,
+        updated_at: record.updated_at,
+        expires_at,
+    })
+}
+
+type PresenceRow = (
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    DateTime<Utc>,
+    DateTime<Utc>,
+);
+
+fn presence_from_row(row: PresenceRow) -> DevicePresence {
+    DevicePresence {
+        client_id: row.0,
+        device_name: row.1,
+        platform: row.2,
+        connected_at: row.3,
+        last_seen: row.4,
+    }
+}
+
+/// Whether the user has another live connection for `client_id`, in which
+/// case that device is already (or still) online.
+async fn client_connected_elsewhere(
+    pool: &PgPool,
+    user_id: i64,
+    presence_id: i64,
+    client_id: Option<&str>,
+) -> Result<bool, sqlx::Error> {
+    let Some(client_id) = client_id else {
+        return Ok(false);
+    };
+
+    sqlx::query_scalar::<_, bool>(
+        r#"
+        SELECT EXISTS (
+            SELECT 1
+            FROM device_presence
+            WHERE user_id = $1
+              AND id <> $2
+              AND client_id = $3
+              AND last_seen > NOW() - make_interval(secs => $4)
+        )
+        "#,
+    )
+    .bind(user_id)
+    .bind(presence_id)
+    .bind(client_id)
+    .bind(PRESENCE_TTL_SECONDS)
+    .fetch_one(pool)
+    .await
+}
+
+/// Registers a connection. Returns the entry id and, when this is the
+/// device's first live connection, the presence to announce as online.
+pub async fn register_presence(
+    pool: &PgPool,
+    user_id: i64,
+    client_id: Option<String>,
+    device_name: Option<String>,
+    platform: Option<String>,
+) -> Result<(i64, Option<DevicePresence>), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to register device presence: {err}");
+        ApiError::internal("failed to register device presence".to_string())
+    };
+
+    sqlx::query(
+        r#"
+        DELETE FROM device_presence
+        WHERE user_id = $1
+          AND last_seen <= NOW() - make_interval(secs => $2)
+        "#,
+    )
+    .bind(user_id)
+    .bind(PRESENCE_TTL_SECONDS)
+    .execute(pool)
+    .await
+    .map_err(map_err)?;
+
+    let (id, client_id, device_name, platform, connected_at, last_seen) = sqlx::query_as::<
+        _,
+        (
+            i64,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            DateTime<Utc>,
+            DateTime<Utc>,
+        ),
+    >(
+        r#"
+            INSERT INTO device_presence (user_id, client_id, device_name, platform)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, client_id, device_name, platform, connected_at, last_seen
+            "#,
+    )
+    .bind(user_id)
+    .bind(client_id)
+    .bind(device_name)
+    .bind(platform)
+    .fetch_one(pool)
+    .await
+    .map_err(map_err)?;
+
+    let already_online = client_connected_elsewhere(pool, user_id, id, client_id.as_deref())
+        .await
+        .map_err(map_err)?;
+    let presence = presence_from_row((client_id, device_name, platform, connected_at, last_seen));
+
+    Ok((id, (!already_online).then_some(presence)))
+}
+
+pub async fn touch_presence(pool: &PgPool, presence_id: i64) -> Result<(), ApiError> {
+    sqlx::query("UPDATE device_presence SET last_seen = NOW() WHERE id = $1")
+        .bind(presence_id)
+        .execute(pool)
+        .await
+        .map_err(|err| {
+            error!(presence_id, "failed to update device presence: {err}");
+            ApiError::internal("failed to update device presence".to_string())
+        })?;
+
+    Ok(())
+}
+
+/// Removes a connection's entry. Returns the presence to announce as offline
+/// when it was the device's last live connection.
+pub async fn unregister_presence(
+    pool: &PgPool,
+    user_id: i64,
+    presence_id: i64,
+) -> Result<Option<DevicePresence>, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to unregister device presence: {err}");
+        ApiError::internal("failed to unregister device presence".to_string())
+    };
+
+    let Some(row) = sqlx::query_as::<_, PresenceRow>(
+        r#"
+        DELETE FROM device_presence
+        WHERE id = $1
+        RETURNING client_id, device_name, platform, connected_at, NOW()
+        "#,
+    )
+    .bind(presence_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(map_err)?
+    else {
+        return Ok(None);
+    };
+
+    let still_online = client_connected_elsewhere(pool, user_id, presence_id, row.0.as_deref())
+        .await
+        .map_err(map_err)?;
+
+    Ok((!still_online).then(|| presence_from_row(row)))
+}
+
+/// Live devices for a user, one entry per `client_id` (connections without
+/// one are listed individually), most recently seen first.
+pub async fn list_online_devices(
+    pool: &PgPool,
+    user_id: i64,
+) -> Result<Vec<DevicePresence>, ApiError> {
+    let rows = sqlx::query_as::<_, PresenceRow>(
+        r#"
+        SELECT client_id, device_name, platform, connected_at, last_seen
+        FROM (
+            SELECT DISTINCT ON (COALESCE(client_id, id::TEXT))
+                client_id, device_name, platform, connected_at, last_seen
+            FROM device_presence
+            WHERE user_id = $1
+              AND last_seen > NOW() - make_interval(secs => $2)
+            ORDER BY COALESCE(client_id, id::TEXT), last_seen DESC
+        ) devices
+        ORDER BY last_seen DESC
+        "#,
+    )
+    .bind(user_id)
+    .bind(PRESENCE_TTL_SECONDS)
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to list online devices: {err}");
+        ApiError::internal("failed to list online devices".to_string())
+    })?;
+
+    Ok(rows.into_iter().map(presence_from_row).collect())
+}
+
+/// How long command results are kept; well past the command timeout.
+const COMMAND_RESULT_RETENTION_SECONDS: i32 = 300;
+
+/// Stores a device's answer to a command, pruning expired answers on the way.
+/// A command is answered at most once.
+pub async fn record_command_result(
+    pool: &PgPool,
+    user_id: i64,
+    response: &CommandResponse,
+) -> Result<(), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to record command result: {err}");
+        ApiError::internal("failed to record command result".to_string())
+    };
+
+    sqlx::query("DELETE FROM command_results WHERE created_at < NOW() - make_interval(secs => $1)")
+        .bind(COMMAND_RESULT_RETENTION_SECONDS)
+        .execute(pool)
+        .await
+        .map_err(map_err)?;
+    let inserted = sqlx::query(
+        r#"
+        INSERT INTO command_results (command_id, user_id, ok, result, error)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (command_id) DO NOTHING
+        "#,
+    )
+    .bind(&response.command_id)
+    .bind(user_id)
+    .bind(response.ok)
+    .bind(&response.result)
+    .bind(&response.error)
+    .execute(pool)
+    .await
+    .map_err(map_err)?
+    .rows_affected();
+
+    if inserted == 0 {
+        return Err(ApiError::conflict(format!(
+            "command {} was already answered",
+            response.command_id
+        )));
+    }
+    Ok(())
+}
+
+/// The stored answer to a command, if the target has answered it.
+pub async fn load_command_result(
+    pool: &PgPool,
+    user_id: i64,
+    command_id: &str,
+) -> Result<Option<CommandResponse>, ApiError> {
+    let row = sqlx::query_as::<_, (bool, Option<serde_json::Value>, Option<String>)>(
+        "SELECT ok, result, error FROM command_results WHERE command_id = $1 AND user_id = $2",
+    )
+    .bind(command_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to load command result: {err}");
+        ApiError::internal("failed to load command result".to_string())
+    })?;
+
+    Ok(row.map(|(ok, result, error)| CommandResponse {
+        command_id: command_id.to_string(),
+        ok,
+        result,
+        error,
+    }))
+}
+
+type HandoffRow = (
+    String,
+    String,
+    serde_json::Value,
+    i64,
+    serde_json::Value,
+    DateTime<Utc>,
+    DateTime<Utc>,
+    Option<String>,
+    Option<DateTime<Utc>>,
+);
+
+fn handoff_from_row(row: HandoffRow) -> HandoffOffer {
+    HandoffOffer {
+        id: row.0,
+        source_client_id: row.1,
+        track: row.2,
+        position_ms: row.3,
+        queue: row.4,
+        created_at: row.5,
+        expires_at: row.6,
+        claimed_by: row.7,
+        claimed_at: row.8,
+    }
+}
+
+/// Stores a new handoff offer, replacing the user's previous offers.
+pub async fn create_handoff(
+    pool: &PgPool,
+    user_id: i64,
+    request: HandoffOfferRequest,
+) -> Result<HandoffOffer, ApiError> {
+    if request.client_id.trim().is_empty() {
+        return Err(ApiError::bad_request("client_id is required".to_string()));
+    }
+    if request.position_ms < 0 {
+        return Err(ApiError::bad_request(
+            "position_ms must not be negative".to_string(),
+        ));
+    }
+    let ttl_seconds = handoff::ttl_seconds(request.ttl_seconds)?;
+
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to create handoff offer: {err}");
+        ApiError::internal("failed to create handoff offer".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    sqlx::query("DELETE FROM playback_handoffs WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_err)?;
+
+    let row = sqlx::query_as::<_, HandoffRow>(
+        r#"
+        INSERT INTO playback_handoffs
+            (id, user_id, source_client_id, track, position_ms, queue, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, NOW() + make_interval(secs => $7))
+        RETURNING id, source_client_id, track, position_ms, queue, created_at, expires_at,
+                  claimed_by, claimed_at
+        "#,
+    )
+    .bind(generate_id())
+    .bind(user_id)
+    .bind(request.client_id)
+    .bind(request.track)
+    .bind(request.position_ms)
+    .bind(request.queue)
+    .bind(ttl_seconds)
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+
+    transaction.commit().await.map_err(map_err)?;
+
+    Ok(handoff_from_row(row))
+}
+
+/// The user's unclaimed, unexpired offer, if any.
+pub async fn active_handoff(pool: &PgPool, user_id: i64) -> Result<HandoffOffer, ApiError> {
+    sqlx::query_as::<_, HandoffRow>(
+        r#"
+        SELECT id, source_client_id, track, position_ms, queue, created_at, expires_at,
+               claimed_by, claimed_at
+        FROM playback_handoffs
+        WHERE user_id = $1
+          AND claimed_at IS NULL
+          AND expires_at > NOW()
+        ORDER BY created_at DESC
+        LIMIT 1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| {
+        error!(user_id, "failed to load handoff offer: {err}");
+        ApiError::internal("failed to load handoff offer".to_string())
+    })?
+    .map(handoff_from_row)
+    .ok_or_else(|| ApiError::not_found("no active handoff offer".to_string()))
+}
+
+/// Claims an offer for `client_id` and records the handed-off playback in
+/// `app_state`, in one transaction. Only one device can win a claim.
+pub async fn claim_handoff(
+    pool: &PgPool,
+    user_id: i64,
+    offer_id: &str,
+    request: HandoffClaimRequest,
+    retention: &HistoryRetention,
+) -> Result<(HandoffOffer, Snapshot, UpdateEvent), ApiError> {
+    if request.client_id.trim().is_empty() {
+        return Err(ApiError::bad_request("client_id is required".to_string()));
+    }
+    ensure_user_document(pool, user_id).await?;
+
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to claim handoff offer: {err}");
+        ApiError::internal("failed to claim handoff offer".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let claimed = sqlx::query_as::<_, HandoffRow>(
+        r#"
+        UPDATE playback_handoffs
+        SET claimed_by = $3, claimed_at = NOW()
+        WHERE id = $1
+          AND user_id = $2
+          AND claimed_at IS NULL
+          AND expires_at > NOW()
+        RETURNING id, source_client_id, track, position_ms, queue, created_at, expires_at,
+                  claimed_by, claimed_at
+        "#,
+    )
+    .bind(offer_id)
+    .bind(user_id)
+    .bind(&request.client_id)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+
+    let Some(row) = claimed else {
+        let claimed_by = sqlx::query_scalar::<_, Option<String>>(
+            "SELECT claimed_by FROM playback_handoffs WHERE id = $1 AND user_id = $2",
+        )
+        .bind(offer_id)
+        .bind(user_id)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(map_err)?;
+
+        return Err(match claimed_by {
+            None => ApiError::not_found("handoff offer not found".to_string()),
+            Some(Some(claimed_by)) => ApiError::handoff_claimed(format!(
+                "handoff offer was already claimed by {claimed_by}"
+            )),
+            Some(None) => ApiError::handoff_expired("handoff offer has expired".to_string()),
+        });
+    };
+    let offer = handoff_from_row(row);
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    let app_state = handoff::claimed_app_state(current.app_state.clone(), &offer);
+    let (snapshot, event) = write_changes(
+        &mut transaction,
+        user_id,
+        &current,
+        Namespace::AppState,
+        vec![(Namespace::AppState, app_state)],
+        Some(request.client_id),
+        retention,
+    )
+    .await?;
+
+    transaction.commit().await.map_err(map_err)?;
+
+    Ok((offer, snapshot, event))
+}
+
+pub async fn list_users(pool: &PgPool) -> Result<Vec<UserSummary>, ApiError> {
+    let users = sqlx::query_as::<
+        _,
+        (
+            i64,
+            String,
+            bool,
+            DateTime<Utc>,
+            Option<DateTime<Utc>>,
+            Option<DateTime<Utc>>,
+        ),
+    >(
+        r#"
+        SELECT id, name, is_admin, created_at, disabled_at, delete_after
+        FROM users
+        ORDER BY name ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!("failed to list users: {err}");
+        ApiError::internal("failed to list users".to_string())
+    })?;
+
+    // The leading columns are a `TokenRow`; the owner comes last.
+    let tokens = sqlx::query(
+        r#"
+        SELECT id, label, token_prefix, scopes, created_at, expires_at, last_used_at, revoked_at,
+               user_id
+        FROM auth_tokens
+        ORDER BY created_at DESC
+        "#,
+    )
+    .try_map(|row: PgRow| Ok((row.try_get::<i64, _>("user_id")?, TokenRow::from_row(&row)?)))
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!("failed to list tokens: {err}");
+        ApiError::internal("failed to list tokens".to_string())
+    })?;
+
+    let mut by_user: std::collections::HashMap<i64, Vec<TokenInfo>> =
+        std::collections::HashMap::new();
+    for (user_id, token) in tokens {
+        by_user
+            .entry(user_id)
+            .or_default()
+            .push(token_info_from_row(token));
+    }
+
+    Ok(users
+        .into_iter()
+        .map(|user| UserSummary {
+            id: user.0,
+            name: user.1,
+            is_admin: user.2,
+            created_at: user.3,
+            disabled_at: user.4,
+            delete_after: user.5,
+            tokens: by_user.remove(&user.0).unwrap_or_default(),
+        })
+        .collect())
+}
+
+pub async fn list_quarantined_playlists(
+    pool: &PgPool,
+) -> Result<Vec<QuarantinedPlaylists>, ApiError> {
+    let rows = sqlx::query_as::<_, (i64, String, Value, String, DateTime<Utc>)>(
+        r#"
+        SELECT q.user_id, u.name, q.playlists, q.error, q.quarantined_at
+        FROM quarantined_playlists q
+        JOIN users u ON u.id = q.user_id
+        ORDER BY q.quarantined_at ASC, q.user_id ASC
+        "#,
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(|err| {
+        error!("failed to list quarantined playlists: {err}");
+        ApiError::internal("failed to list quarantined playlists".to_string())
+    })?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| QuarantinedPlaylists {
+            user_id: row.0,
+            user_name: row.1,
+            playlists: row.2,
+            error: row.3,
+            quarantined_at: row.4,
+        })
+        .collect())
+}
+
+/// Replaces a user's playlists with their quarantined value, or with
+/// `playlists` when given, and drops the quarantined copy. The value is
+/// checked like any other write, so one that still has invalid entries is
+/// rejected with `400` and stays quarantined.
+pub async fn restore_quarantined_playlists(
+    pool: &PgPool,
+    user_id: i64,
+    playlists: Option<Value>,
+    retention: &HistoryRetention,
+    audit: &AuditEntry,
+) -> Result<(Snapshot, UpdateEvent), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to restore quarantined playlists: {err}");
+        ApiError::internal("failed to restore quarantined playlists".to_string())
+    };
+
+    // Quarantined values come from an existing document, so there is no
+    // document to create first.
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let quarantined = sqlx::query_scalar::<_, Value>(
+        "SELECT playlists FROM quarantined_playlists WHERE user_id = $1 FOR UPDATE",
+    )
+    .bind(user_id)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found("no quarantined playlists for this user".to_string()))?;
+
+    let current = lock_snapshot(&mut transaction, user_id).await?;
+    let (snapshot, event) = write_changes(
+        &mut transaction,
+        user_id,
+        &current,
+        Namespace::Playlists,
+        vec![(Namespace::Playlists, playlists.unwrap_or(quarantined))],
+        None,
+        retention,
+    )
+    .await?;
+
+    sqlx::query("DELETE FROM quarantined_playlists WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_err)?;
+    record_audit_event(&mut *transaction, audit)
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok((snapshot, event))
+}
+
+/// Drops a user's quarantined playlists, keeping the playlists they have now.
+pub async fn dismiss_quarantined_playlists(
+    pool: &PgPool,
+    user_id: i64,
+    audit: &AuditEntry,
+) -> Result<(), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to dismiss quarantined playlists: {err}");
+        ApiError::internal("failed to dismiss quarantined playlists".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let deleted = sqlx::query("DELETE FROM quarantined_playlists WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_err)?;
+    if deleted.rows_affected() == 0 {
+        return Err(ApiError::not_found(
+            "no quarantined playlists for this user".to_string(),
+        ));
+    }
+
+    record_audit_event(&mut *transaction, audit)
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(())
+}
+
+pub async fn create_user(
+    pool: &PgPool,
+    name: &str,
+    is_admin: bool,
+    audit: impl FnOnce(&UserCreatedResponse) -> AuditEntry,
+) -> Result<UserCreatedResponse, ApiError> {
+    let normalized_name = name.trim();
+    if normalized_name.is_empty() {
+        return Err(ApiError::bad_request("name is required".to_string()));
+    }
+
+    let map_err = |err: sqlx::Error| {
+        error!("failed to create user: {err}");
+        ApiError::internal("failed to create user".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let row = sqlx::query_as::<_, (i64, String, bool, DateTime<Utc>)>(
+        r#"
+        INSERT INTO users (name, is_admin)
+        VALUES ($1, $2)
+        RETURNING id, name, is_admin, created_at
+        "#,
+    )
+    .bind(normalized_name)
+    .bind(is_admin)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(|err| {
+        if let sqlx::Error::Database(db_err) = &err
+            && db_err.code().as_deref() == Some("23505")
+        {
+            return ApiError::conflict("a user with that name already exists".to_string());
+        }
+        map_err(err)
+    })?
+    .ok_or_else(|| ApiError::internal("failed to create user".to_string()))?;
+    let created = UserCreatedResponse {
         id: row.0,
         name: row.1,
         is_admin: row.2,
-    })
+        created_at: row.3,
+    };
+
+    record_audit_event(&mut *transaction, &audit(&created))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(created)
+}
+
+pub async fn set_user_disabled(
+    pool: &PgPool,
+    user_id: i64,
+    disabled: bool,
+    audit: &AuditEntry,
+) -> Result<(), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to update user disabled state: {err}");
+        ApiError::internal("failed to update user".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let updated = sqlx::query(
+        r#"
+        UPDATE users
+        SET disabled_at = CASE WHEN $2 THEN NOW() ELSE NULL END,
+            delete_after = CASE WHEN $2 THEN delete_after ELSE NULL END
+        WHERE id = $1
+        "#,
+    )
+    .bind(user_id)
+    .bind(disabled)
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+
+    if updated.rows_affected() == 0 {
+        return Err(ApiError::not_found("user not found".to_string()));
+    }
+    // Their shared playlists are hidden or shown again.
+    touch_shares(&mut transaction, user_id, None)
+        .await
+        .map_err(map_err)?;
+
+    record_audit_event(&mut *transaction, audit)
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(())
+}
+
+/// Checks that `confirm` is the name of the user about to be deleted, and
+/// locks the user's row until the deletion commits.
+async fn confirm_user_deletion(
+    conn: &mut PgConnection,
+    user_id: i64,
+    confirm: &str,
+) -> Result<String, ApiError> {
+    let name = sqlx::query_scalar::<_, String>("SELECT name FROM users WHERE id = $1 FOR UPDATE")
+        .bind(user_id)
+        .fetch_optional(conn)
+        .await
+        .map_err(|err| {
+            error!(user_id, "failed to load user: {err}");
+            ApiError::internal("failed to delete user".to_string())
+        })?
+        .ok_or_else(|| ApiError::not_found("user not found".to_string()))?;
+
+    deletion::check_confirmation(&name, confirm)?;
+    Ok(name)
+}
+
+/// Removes what identifies a deleted user from the audit events they
+/// performed. The events themselves and the user id are kept.
+async fn anonymize_audit_events(conn: &mut PgConnection, user_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE audit_events
+        SET actor_name = NULL, ip = NULL, user_agent = NULL
+        WHERE actor_user_id = $1
+          AND (actor_name IS NOT NULL OR ip IS NOT NULL OR user_agent IS NOT NULL)
+        "#,
+    )
+    .bind(user_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Deletes a user. Every table referencing `users` cascades, so this also
+/// removes their tokens, sync document, history, devices, handoffs, pairings
+/// and single sign-on identities. Audit events the user performed are
+/// anonymized. Returns the user's name.
+pub async fn delete_user(
+    pool: &PgPool,
+    user_id: i64,
+    confirm: &str,
+    audit: impl FnOnce(&str) -> AuditEntry,
+) -> Result<String, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to delete user: {err}");
+        ApiError::internal("failed to delete user".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+    let name = confirm_user_deletion(&mut transaction, user_id, confirm).await?;
+
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(user_id)
+        .execute(&mut *transaction)
+        .await
+        .map_err(map_err)?;
+    anonymize_audit_events(&mut transaction, user_id)
+        .await
+        .map_err(map_err)?;
+
+    record_audit_event(&mut *transaction, &audit(&name))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(name)
+}
+
+/// Disables a user now and schedules [`purge_deleted_users`] to delete them
+/// after `grace_days`. Re-enabling the user cancels the deletion. Returns the
+/// user's name and when they will be purged.
+pub async fn schedule_user_deletion(
+    pool: &PgPool,
+    user_id: i64,
+    confirm: &str,
+    grace_days: i32,
+    audit: impl FnOnce(&str, DateTime<Utc>) -> AuditEntry,
+) -> Result<(String, DateTime<Utc>), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to schedule user deletion: {err}");
+        ApiError::internal("failed to delete user".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+    let name = confirm_user_deletion(&mut transaction, user_id, confirm).await?;
+
+    let delete_after = sqlx::query_scalar::<_, DateTime<Utc>>(
+        r#"
+        UPDATE users
+        SET disabled_at = COALESCE(disabled_at, NOW()),
+            delete_after = NOW() + make_interval(days => $2)
+        WHERE id = $1
+        RETURNING delete_after
+        "#,
+    )
+    .bind(user_id)
+    .bind(grace_days)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found("user not found".to_string()))?;
+
+    record_audit_event(&mut *transaction, &audit(&name, delete_after))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok((name, delete_after))
 }
 
-pub async fn load_snapshot(pool: &PgPool, user_id: i64) -> Result<Snapshot, ApiError> {
-    ensure_user_document(pool, user_id).await?;
+/// Deletes users whose deletion grace period has ended, the same way as
+/// [`delete_user`], recording `user.deleted` for each. Returns their ids and
+/// names.
+pub async fn purge_deleted_users(pool: &PgPool) -> Result<Vec<(i64, String)>, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!("failed to purge deleted users: {err}");
+        ApiError::internal("failed to purge deleted users".to_string())
+    };
 
-    let row = sqlx::query_as::<
-        _,
-        (
-            i64,
-            DateTime<Utc>,
-            serde_json::Value,
-            serde_json::Value,
-            serde_json::Value,
-            serde_json::Value,
-        ),
-    >(
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+    let users = sqlx::query_as::<_, (i64, String)>(
+        "DELETE FROM users WHERE delete_after <= NOW() RETURNING id, name",
+    )
+    .fetch_all(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+
+    for (user_id, name) in &users {
+        anonymize_audit_events(&mut transaction, *user_id)
+            .await
+            .map_err(map_err)?;
+        let entry = AuditEntry::new("user.deleted")
+            .target("user", user_id)
+            .details(json!({ "name": name, "scheduled": true }));
+        record_audit_event(&mut *transaction, &entry)
+            .await
+            .map_err(map_err)?;
+    }
+    transaction.commit().await.map_err(map_err)?;
+    Ok(users)
+}
+
+pub async fn create_token(
+    pool: &PgPool,
+    user_id: i64,
+    label: Option<String>,
+    scopes: Scopes,
+    expires_at: Option<DateTime<Utc>>,
+    audit: impl FnOnce(&TokenCreatedResponse) -> AuditEntry,
+) -> Result<TokenCreatedResponse, ApiError> {
+    if expires_at.is_some_and(|expires_at| expires_at <= Utc::now()) {
+        return Err(ApiError::bad_request(
+            "expires_at must be in the future".to_string(),
+        ));
+    }
+
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to create token: {err}");
+        ApiError::internal("failed to create token".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let user_exists = sqlx::query_scalar::<_, i64>("SELECT id FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(map_err)?
+        .is_some();
+
+    if !user_exists {
+        return Err(ApiError::not_found("user not found".to_string()));
+    }
+
+    let created = insert_token(
+        &mut transaction,
+        user_id,
+        label.unwrap_or_else(|| "manual".to_string()),
+        scopes.to_strings(),
+        expires_at,
+    )
+    .await?;
+
+    record_audit_event(&mut *transaction, &audit(&created))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(created)
+}
+
+async fn insert_token(
+    conn: &mut PgConnection,
+    user_id: i64,
+    label: String,
+    scopes: Vec<String>,
+    expires_at: Option<DateTime<Utc>>,
+) -> Result<TokenCreatedResponse, ApiError> {
+    let token = generate_token();
+    let hash = hash_token(&token);
+    let prefix = token_prefix(&token);
+
+    let (id, created_at) = sqlx::query_as::<_, (i64, DateTime<Utc>)>(
         r#"
-        SELECT
-            version,
-            updated_at,
-            app_state,
-            playlists,
-            provider_configuration,
-            settings
-        FROM user_sync_document
-        WHERE user_id = $1
-          AND id = 1
+        INSERT INTO auth_tokens (user_id, token_hash, token_prefix, label, scopes, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, created_at
         "#,
     )
     .bind(user_id)
-    .fetch_one(pool)
+    .bind(hash)
+    .bind(prefix.clone())
+    .bind(label.clone())
+    .bind(scopes.clone())
+    .bind(expires_at)
+    .fetch_one(conn)
     .await
     .map_err(|err| {
-        error!(user_id, "failed to read snapshot from database: {err}");
-        ApiError::internal("failed to read snapshot".to_string())
+        error!(user_id, "failed to create token: {err}");
+        ApiError::internal("failed to create token".to_string())
     })?;
 
-    Ok(Snapshot {
-        version: row.0,
-        updated_at: row.1,
-        app_state: row.2,
-        playlists: row.3,
-        provider_configuration: row.4,
-        settings: row.5,
+    Ok(TokenCreatedResponse {
+        id,
+        user_id,
+        label,
+        token_prefix: prefix,
+        token,
+        scopes,
+        created_at,
+        expires_at,
     })
 }
 
-pub async fn update_namespace(
+/// Default and maximum time a rotated token keeps working.
+const DEFAULT_ROTATION_GRACE_SECONDS: i64 = 300;
+const MAX_ROTATION_GRACE_SECONDS: i64 = 86_400;
+
+/// Issues a replacement for `token_id` with the same label, scopes and
+/// lifetime, and lets the old token expire after a grace period. A token can
+/// only be rotated once.
+pub async fn rotate_token(
     pool: &PgPool,
     user_id: i64,
-    namespace: Namespace,
-    payload: NamespacePayload,
-) -> Result<(Snapshot, UpdateEvent), ApiError> {
-    ensure_user_document(pool, user_id).await?;
+    token_id: i64,
+    grace_period_seconds: Option<i64>,
+    audit: impl FnOnce(&TokenRotatedResponse) -> AuditEntry,
+) -> Result<TokenRotatedResponse, ApiError> {
+    let grace_seconds = grace_period_seconds.unwrap_or(DEFAULT_ROTATION_GRACE_SECONDS);
+    if !(0..=MAX_ROTATION_GRACE_SECONDS).contains(&grace_seconds) {
+        return Err(ApiError::bad_request(format!(
+            "grace_period_seconds must be between 0 and {MAX_ROTATION_GRACE_SECONDS}"
+        )));
+    }
 
-    let mut transaction = pool.begin().await.map_err(|err| {
-        error!("failed to start transaction: {err}");
-        ApiError::internal("failed to start transaction".to_string())
-    })?;
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, token_id, "failed to rotate token: {err}");
+        ApiError::internal("failed to rotate token".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
 
-    let current = sqlx::query_as::<_, (i64,)>(
-        "SELECT version FROM user_sync_document WHERE user_id = $1 AND id = 1",
+    let (label, scopes, created_at, expires_at, replaced_by) = sqlx::query_as::<
+        _,
+        (
+            String,
+            Vec<String>,
+            DateTime<Utc>,
+            Option<DateTime<Utc>>,
+            Option<i64>,
+        ),
+    >(
+        r#"
+        SELECT label, scopes, created_at, expires_at, replaced_by_token_id
+        FROM auth_tokens
+        WHERE id = $1
+          AND user_id = $2
+          AND revoked_at IS NULL
+        FOR UPDATE
+        "#,
     )
+    .bind(token_id)
     .bind(user_id)
-    .fetch_one(&mut *transaction)
+    .fetch_optional(&mut *transaction)
     .await
-    .map_err(|err| {
-        error!(user_id, "failed to read current version: {err}");
-        ApiError::internal("failed to read current version".to_string())
-    })?
-    .0;
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::unauthorized("invalid bearer token".to_string()))?;
 
-    if let Some(expected) = payload.expected_version
-        && expected != current
-    {
-        return Err(ApiError::conflict(format!(
-            "expected version {expected}, but current version is {current}"
-        )));
+    if replaced_by.is_some() {
+        return Err(ApiError::token_rotated(
+            "token has already been rotated; use its replacement".to_string(),
+        ));
     }
 
-    let source_client_id = payload.client_id.clone();
+    let now = Utc::now();
+    let new_expires_at = expires_at.map(|expires_at| now + (expires_at - created_at));
+    let replacement =
+        insert_token(&mut transaction, user_id, label, scopes, new_expires_at).await?;
 
-    let new_version = current + 1;
-    let updated_at = Utc::now();
-    let data = payload.data;
-
-    let query = match namespace {
-        Namespace::AppState => {
-            "UPDATE user_sync_document SET app_state = $1, version = $2, updated_at = $3 WHERE user_id = $4 AND id = 1 \
-             RETURNING version, updated_at, app_state, playlists, provider_configuration, settings"
-        }
-        Namespace::Playlists => {
-            "UPDATE user_sync_document SET playlists = $1, version = $2, updated_at = $3 WHERE user_id = $4 AND id = 1 \
-             RETURNING version, updated_at, app_state, playlists, provider_configuration, settings"
-        }
-        Namespace::ProviderConfiguration => {
-            "UPDATE user_sync_document SET provider_configuration = $1, version = $2, updated_at = $3 WHERE user_id = $4 AND id = 1 \
-             RETURNING version, updated_at, app_state, playlists, provider_configuration, settings"
-        }
-        Namespace::Settings => {
-            "UPDATE user_sync_document SET settings = $1, version = $2, updated_at = $3 WHERE user_id = $4 AND id = 1 \
-             RETURNING version, updated_at, app_state, playlists, provider_configuration, settings"
-        }
-        Namespace::Snapshot => unreachable!(),
+    let grace_expires_at = now + chrono::Duration::seconds(grace_seconds);
+    let previous_token_expires_at = expires_at.map_or(grace_expires_at, |expires_at| {
+        expires_at.min(grace_expires_at)
+    });
+    sqlx::query(
+        r#"
+        UPDATE auth_tokens
+        SET expires_at = $2, replaced_by_token_id = $3
+        WHERE id = $1
+        "#,
+    )
+    .bind(token_id)
+    .bind(previous_token_expires_at)
+    .bind(replacement.id)
+    .execute(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+    let rotated = TokenRotatedResponse {
+        token: replacement,
+        previous_token_expires_at,
     };
 
-    let row = sqlx::query_as::<
+    record_audit_event(&mut *transaction, &audit(&rotated))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(rotated)
+}
+
+/// Appends an event to the audit log. Audited actions pass their transaction,
+/// so the action and its audit row are committed (or rolled back) together.
+pub async fn record_audit_event<'e>(
+    executor: impl PgExecutor<'e>,
+    entry: &AuditEntry,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO audit_events
+            (actor_user_id, actor_name, action, target_type, target_id, ip, user_agent, details)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+        "#,
+    )
+    .bind(entry.actor_user_id)
+    .bind(&entry.actor_name)
+    .bind(entry.action)
+    .bind(entry.target_type)
+    .bind(&entry.target_id)
+    .bind(&entry.ip)
+    .bind(&entry.user_agent)
+    .bind(&entry.details)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Newest first. `query.before_id` pages backwards from an earlier result.
+pub async fn list_audit_events(
+    pool: &PgPool,
+    query: &AuditQuery,
+    limit: i64,
+) -> Result<Vec<AuditEvent>, ApiError> {
+    let rows = sqlx::query_as::<
         _,
         (
             i64,
             DateTime<Utc>,
-            serde_json::Value,
-            serde_json::Value,
-            serde_json::Value,
-            serde_json::Value,
+            Option<i64>,
+            Option<String>,
+            String,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            Option<String>,
+            Value,
         ),
-    >(query)
-    .bind(data)
-    .bind(new_version)
-    .bind(updated_at)
-    .bind(user_id)
-    .fetch_one(&mut *transaction)
+    >(
+        r#"
+        SELECT id, occurred_at, actor_user_id, actor_name, action, target_type, target_id, ip,
+               user_agent, details
+        FROM audit_events
+        WHERE ($1::TEXT IS NULL OR action = $1)
+          AND ($2::BIGINT IS NULL OR actor_user_id = $2)
+          AND ($3::TEXT IS NULL OR target_type = $3)
+          AND ($4::TEXT IS NULL OR target_id = $4)
+          AND ($5::TIMESTAMPTZ IS NULL OR occurred_at >= $5)
+          AND ($6::TIMESTAMPTZ IS NULL OR occurred_at < $6)
+          AND ($7::BIGINT IS NULL OR id < $7)
+        ORDER BY id DESC
+        LIMIT $8
+        "#,
+    )
+    .bind(&query.action)
+    .bind(query.actor_user_id)
+    .bind(&query.target_type)
+    .bind(&query.target_id)
+    .bind(query.since)
+    .bind(query.until)
+    .bind(query.before_id)
+    .bind(limit)
+    .fetch_all(pool)
     .await
     .map_err(|err| {
-        error!(user_id, "failed to update snapshot: {err}");
-        ApiError::internal("failed to update snapshot".to_string())
+        error!("failed to list audit events: {err}");
+        ApiError::internal("failed to list audit events".to_string())
     })?;
 
-    transaction.commit().await.map_err(|err| {
-        error!(user_id, "failed to commit update: {err}");
-        ApiError::internal("failed to commit update".to_string())
-    })?;
+    Ok(rows
+        .into_iter()
+        .map(
+            |(
+                id,
+                occurred_at,
+                actor_user_id,
+                actor_name,
+                action,
+                target_type,
+                target_id,
+                ip,
+                user_agent,
+                details,
+            )| AuditEvent {
+                id,
+                occurred_at,
+                actor_user_id,
+                actor_name,
+                action,
+                target_type,
+                target_id,
+                ip,
+                user_agent,
+                details,
+            },
+        )
+        .collect())
+}
 
-    let snapshot = Snapshot {
-        version: row.0,
-        updated_at: row.1,
-        app_state: row.2,
-        playlists: row.3,
-        provider_configuration: row.4,
-        settings: row.5,
-    };
-    let event = UpdateEvent {
-        event_type: "state_updated".to_string(),
-        namespace,
-        version: new_version,
-        updated_at,
-        source_client_id,
+/// Records a login attempt so the callback can check its `state` and recover
+/// the `nonce` sent to the provider.
+pub async fn create_oidc_login(pool: &PgPool, state: &str, nonce: &str) -> Result<(), ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!("failed to start OIDC login: {err}");
+        ApiError::internal("failed to start login".to_string())
     };
 
-    Ok((snapshot, event))
+    sqlx::query("DELETE FROM oidc_login_states WHERE expires_at < NOW()")
+        .execute(pool)
+        .await
+        .map_err(map_err)?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO oidc_login_states (state_hash, nonce, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(secs => $3))
+        "#,
+    )
+    .bind(hash_token(state))
+    .bind(nonce)
+    .bind(oidc::LOGIN_TTL_SECONDS)
+    .execute(pool)
+    .await
+    .map_err(map_err)?;
+
+    Ok(())
 }
 
-pub async fn replace_snapshot(
-    pool: &PgPool,
-    user_id: i64,
-    payload: SnapshotPayload,
-) -> Result<(Snapshot, UpdateEvent), ApiError> {
-    ensure_user_document(pool, user_id).await?;
+/// Consumes a login attempt, returning its nonce. Each `state` works once.
+pub async fn take_oidc_login(pool: &PgPool, state: &str) -> Result<String, ApiError> {
+    sqlx::query_scalar::<_, String>(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE state_hash = $1
+          AND expires_at > NOW()
+        RETURNING nonce
+        "#,
+    )
+    .bind(hash_token(state))
+    .fetch_optional(pool)
+    .await
+    .map_err(|err| {
+        error!("failed to complete OIDC login: {err}");
+        ApiError::internal("failed to complete login".to_string())
+    })?
+    .ok_or_else(|| ApiError::unauthorized("login attempt is unknown or expired".to_string()))
+}
 
-    let mut transaction = pool.begin().await.map_err(|err| {
-        error!("failed to start transaction: {err}");
-        ApiError::internal("failed to start transaction".to_string())
-    })?;
+/// Finds or creates the user for an OIDC identity. New users are named after
+/// the identity, with a suffix if that name is taken; existing users are never
+/// matched by name. Admin status follows the admin group when one is set.
+pub async fn upsert_oidc_user(pool: &PgPool, identity: &OidcIdentity) -> Result<i64, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(subject = %identity.subject, "failed to map OIDC identity: {err}");
+        ApiError::internal("failed to complete login".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let existing = sqlx::query_scalar::<_, i64>(
+        r#"
+        UPDATE oidc_identities
+        SET last_login_at = NOW()
+        WHERE issuer = $1
+          AND subject = $2
+        RETURNING user_id
+        "#,
+    )
+    .bind(&identity.issuer)
+    .bind(&identity.subject)
+    .fetch_optional(&mut *transaction)
+    .await
+    .map_err(map_err)?;
+
+    let user_id = match existing {
+        Some(user_id) => user_id,
+        None => {
+            let mut name = identity.display_name.clone();
+            let user_id = loop {
+                let inserted = sqlx::query_scalar::<_, i64>(
+                    r#"
+                    INSERT INTO users (name, is_admin)
+                    VALUES ($1, $2)
+                    ON CONFLICT (name) DO NOTHING
+                    RETURNING id
+                    "#,
+                )
+                .bind(&name)
+                .bind(identity.is_admin.unwrap_or(false))
+                .fetch_optional(&mut *transaction)
+                .await
+                .map_err(map_err)?;
+                if let Some(user_id) = inserted {
+                    break user_id;
+                }
+                name = format!(
+                    "{}-{}",
+                    identity.display_name,
+                    &generate_id()[..4].to_lowercase()
+                );
+            };
+
+            sqlx::query(
+                "INSERT INTO oidc_identities (issuer, subject, user_id) VALUES ($1, $2, $3)",
+            )
+            .bind(&identity.issuer)
+            .bind(&identity.subject)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
 
-    let current = sqlx::query_as::<_, (i64,)>(
-        "SELECT version FROM user_sync_document WHERE user_id = $1 AND id = 1",
+            info!(user_id, name = %name, "created user for OIDC identity");
+            user_id
+        }
+    };
+
+    // A disabled user's login changes nothing, not even their admin flag.
+    let disabled = sqlx::query_scalar::<_, bool>(
+        "SELECT disabled_at IS NOT NULL FROM users WHERE id = $1 FOR UPDATE",
     )
     .bind(user_id)
     .fetch_one(&mut *transaction)
     .await
-    .map_err(|err| {
-        error!(user_id, "failed to read current version: {err}");
-        ApiError::internal("failed to read current version".to_string())
-    })?
-    .0;
+    .map_err(map_err)?;
+    if disabled {
+        return Err(ApiError::forbidden("user account is disabled".to_string()));
+    }
+
+    if let Some(is_admin) = identity.is_admin {
+        sqlx::query("UPDATE users SET is_admin = $2 WHERE id = $1")
+            .bind(user_id)
+            .bind(is_admin)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+    }
+
+    transaction.commit().await.map_err(map_err)?;
+    Ok(user_id)
+}
+
+/// Longest accepted device name for pairing.
+const MAX_DEVICE_NAME_LENGTH: usize = 100;
+
+/// Starts pairing a new device. Returns the secret device code it polls with
+/// and the short user code to approve on a signed-in device.
+pub async fn start_pairing(
+    pool: &PgPool,
+    device_name: &str,
+) -> Result<PairStartResponse, ApiError> {
+    let device_name = device_name.trim();
+    if device_name.is_empty() || device_name.chars().count() > MAX_DEVICE_NAME_LENGTH {
+        return Err(ApiError::bad_request(format!(
+            "device_name must be 1 to {MAX_DEVICE_NAME_LENGTH} characters"
+        )));
+    }
+
+    let map_err = |err: sqlx::Error| {
+        error!("failed to start device pairing: {err}");
+        ApiError::internal("failed to start device pairing".to_string())
+    };
+
+    // Expired pairings are kept for a while so late polls get a clear 410.
+    sqlx::query("DELETE FROM device_pairings WHERE expires_at < NOW() - INTERVAL '1 hour'")
+        .execute(pool)
+        .await
+        .map_err(map_err)?;
+
+    let device_code = generate_token();
+    let mut attempts = 0;
+    loop {
+        let user_code = pairing::generate_user_code();
+        let result = sqlx::query_scalar::<_, DateTime<Utc>>(
+            r#"
+            INSERT INTO device_pairings (device_code_hash, user_code, device_name, expires_at)
+            VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))
+            RETURNING expires_at
+            "#,
+        )
+        .bind(hash_token(&device_code))
+        .bind(&user_code)
+        .bind(device_name)
+        .bind(pairing::PAIRING_TTL_SECONDS)
+        .fetch_one(pool)
+        .await;
 
-    if let Some(expected) = payload.expected_version
-        && expected != current
-    {
-        return Err(ApiError::conflict(format!(
-            "expected version {expected}, but current version is {current}"
-        )));
+        match result {
+            Ok(expires_at) => {
+                return Ok(PairStartResponse {
+                    device_code,
+                    user_code,
+                    expires_at,
+                    interval: pairing::POLL_INTERVAL_SECONDS,
+                });
+            }
+            // User codes are short, so retry the rare collision with a
+            // pending one.
+            Err(sqlx::Error::Database(err)) if err.is_unique_violation() && attempts < 3 => {
+                attempts += 1;
+            }
+            Err(err) => return Err(map_err(err)),
+        }
     }
+}
 
-    let source_client_id = payload.client_id.clone();
-    let new_version = current + 1;
-    let updated_at = Utc::now();
+/// Approves a pending pairing on behalf of `user_id`. The device receives a
+/// token with `scopes` on its next poll.
+pub async fn approve_pairing(
+    pool: &PgPool,
+    user_id: i64,
+    user_code: &str,
+    scopes: &Scopes,
+    audit: impl FnOnce(&PairApproveResponse) -> AuditEntry,
+) -> Result<PairApproveResponse, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, "failed to approve device pairing: {err}");
+        ApiError::internal("failed to approve device pairing".to_string())
+    };
 
-    let row = sqlx::query_as::<
-        _,
-        (
-            i64,
-            DateTime<Utc>,
-            serde_json::Value,
-            serde_json::Value,
-            serde_json::Value,
-            serde_json::Value,
-        ),
-    >(
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let scopes = scopes.to_strings();
+    let device_name = sqlx::query_scalar::<_, String>(
         r#"
-        UPDATE user_sync_document
-        SET
-            app_state = $1,
-            playlists = $2,
-            provider_configuration = $3,
-            settings = $4,
-            version = $5,
-            updated_at = $6
-        WHERE user_id = $7
-          AND id = 1
-        RETURNING version, updated_at, app_state, playlists, provider_configuration, settings
+        UPDATE device_pairings
+        SET approved_by = $2, approved_at = NOW(), scopes = $3
+        WHERE user_code = $1
+          AND approved_at IS NULL
+          AND expires_at > NOW()
+        RETURNING device_name
         "#,
     )
-    .bind(payload.app_state)
-    .bind(payload.playlists)
-    .bind(payload.provider_configuration)
-    .bind(payload.settings)
-    .bind(new_version)
-    .bind(updated_at)
+    .bind(user_code)
     .bind(user_id)
-    .fetch_one(&mut *transaction)
+    .bind(&scopes)
+    .fetch_optional(&mut *transaction)
     .await
-    .map_err(|err| {
-        error!(user_id, "failed to write snapshot: {err}");
-        ApiError::internal("failed to write snapshot".to_string())
-    })?;
+    .map_err(map_err)?
+    .ok_or_else(|| ApiError::not_found("unknown or expired pairing code".to_string()))?;
+    let approved = PairApproveResponse {
+        device_name,
+        scopes,
+    };
 
-    transaction.commit().await.map_err(|err| {
-        error!(user_id, "failed to commit update: {err}");
-        ApiError::internal("failed to commit update".to_string())
-    })?;
+    record_audit_event(&mut *transaction, &audit(&approved))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(approved)
+}
 
-    let snapshot = Snapshot {
-        version: row.0,
-        updated_at: row.1,
-        app_state: row.2,
-        playlists: row.3,
-        provider_configuration: row.4,
-        settings: row.5,
-    };
-    let event = UpdateEvent {
-        event_type: "state_updated".to_string(),
-        namespace: Namespace::Snapshot,
-        version: new_version,
-        updated_at,
-        source_client_id,
+/// Checks a pairing from the new device's side. Once approved, the token is
+/// issued exactly once, labelled with the device name, and audited with
+/// `audit`.
+pub async fn poll_pairing(
+    pool: &PgPool,
+    device_code: &str,
+    audit: impl FnOnce(&TokenCreatedResponse) -> AuditEntry,
+) -> Result<PairPollResponse, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!("failed to poll device pairing: {err}");
+        ApiError::internal("failed to poll device pairing".to_string())
     };
 
-    Ok((snapshot, event))
-}
+    let mut transaction = pool.begin().await.map_err(map_err)?;
 
-pub async fn list_users(pool: &PgPool) -> Result<Vec<UserSummary>, ApiError> {
-    let users = sqlx::query_as::<_, (i64, String, bool, DateTime<Utc>, Option<DateTime<Utc>>)>(
-        r#"
-        SELECT id, name, is_admin, created_at, disabled_at
-        FROM users
-        ORDER BY name ASC
-        "#,
+    let (id, device_name, expired, polled_too_soon, approved_by, scopes, completed) =
+        sqlx::query_as::<
+            _,
+            (
+                i64,
+                String,
+                bool,
+                bool,
+                Option<i64>,
+                Option<Vec<String>>,
+                bool,
+            ),
+        >(
+            r#"
+            SELECT id, device_name, expires_at <= NOW(),
+                   COALESCE(last_polled_at > NOW() - make_interval(secs => $2), FALSE),
+                   approved_by, scopes, completed_at IS NOT NULL
+            FROM device_pairings
+            WHERE device_code_hash = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(hash_token(device_code))
+        .bind(pairing::POLL_INTERVAL_SECONDS)
+        .fetch_optional(&mut *transaction)
+        .await
+        .map_err(map_err)?
+        .ok_or_else(|| ApiError::not_found("unknown device code".to_string()))?;
+
+    if completed {
+        return Err(ApiError::not_found(
+            "pairing has already been completed".to_string(),
+        ));
+    }
+    if expired {
+        return Err(ApiError::pairing_expired(
+            "pairing code has expired; start again".to_string(),
+        ));
+    }
+    if polled_too_soon {
+        let interval = pairing::POLL_INTERVAL_SECONDS;
+        return Err(ApiError::rate_limited(
+            format!("poll at most every {interval} seconds"),
+            std::time::Duration::from_secs(interval as u64),
+        ));
+    }
+
+    let Some(user_id) = approved_by else {
+        sqlx::query("UPDATE device_pairings SET last_polled_at = NOW() WHERE id = $1")
+            .bind(id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(map_err)?;
+        transaction.commit().await.map_err(map_err)?;
+        return Ok(PairPollResponse::Pending);
+    };
+
+    let token = insert_token(
+        &mut transaction,
+        user_id,
+        device_name,
+        scopes.unwrap_or_default(),
+        None,
     )
-    .fetch_all(pool)
-    .await
-    .map_err(|err| {
-        error!("failed to list users: {err}");
-        ApiError::internal("failed to list users".to_string())
-    })?;
+    .await?;
 
-    let tokens = sqlx::query_as::<
-        _,
-        (
-            i64,
-            i64,
-            String,
-            String,
-            DateTime<Utc>,
-            Option<DateTime<Utc>>,
-            Option<DateTime<Utc>>,
-        ),
-    >(
-        r#"
-        SELECT id, user_id, label, token_prefix, created_at, last_used_at, revoked_at
-        FROM auth_tokens
-        ORDER BY created_at DESC
-        "#,
+    sqlx::query(
+        "UPDATE device_pairings SET last_polled_at = NOW(), completed_at = NOW() WHERE id = $1",
     )
-    .fetch_all(pool)
+    .bind(id)
+    .execute(&mut *transaction)
     .await
-    .map_err(|err| {
-        error!("failed to list tokens: {err}");
-        ApiError::internal("failed to list tokens".to_string())
-    })?;
-
-    let mut by_user: std::collections::HashMap<i64, Vec<TokenInfo>> =
-        std::collections::HashMap::new();
-    for token in tokens {
-        by_user.entry(token.1).or_default().push(TokenInfo {
-            id: token.0,
-            label: token.2,
-            token_prefix: token.3,
-            created_at: token.4,
-            last_used_at: token.5,
-            revoked_at: token.6,
-        });
-    }
+    .map_err(map_err)?;
 
-    Ok(users
-        .into_iter()
-        .map(|user| UserSummary {
-            id: user.0,
-            name: user.1,
-            is_admin: user.2,
-            created_at: user.3,
-            disabled_at: user.4,
-            tokens: by_user.remove(&user.0).unwrap_or_default(),
-        })
-        .collect())
+    record_audit_event(&mut *transaction, &audit(&token))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(PairPollResponse::Approved { token })
 }
 
-pub async fn create_user(
-    pool: &PgPool,
-    name: &str,
-    is_admin: bool,
-) -> Result<UserCreatedResponse, ApiError> {
-    let normalized_name = name.trim();
-    if normalized_name.is_empty() {
-        return Err(ApiError::bad_request("name is required".to_string()));
+/// Columns of `auth_tokens` read into a [`TokenInfo`].
+type TokenRow = (
+    i64,
+    String,
+    String,
+    Vec<String>,
+    DateTime<Utc>,
+    Option<DateTime<Utc>>,
+    Option<DateTime<Utc>>,
+    Option<DateTime<Utc>>,
+);
+
+fn token_info_from_row(row: TokenRow) -> TokenInfo {
+    let (id, label, token_prefix, scopes, created_at, expires_at, last_used_at, revoked_at) = row;
+    TokenInfo {
+        id,
+        label,
+        token_prefix,
+        scopes,
+        created_at,
+        expires_at,
+        last_used_at,
+        revoked_at,
     }
+}
 
-    let row = sqlx::query_as::<_, (i64, String, bool, DateTime<Utc>)>(
-        r#"
-        INSERT INTO users (name, is_admin)
-        VALUES ($1, $2)
-        RETURNING id, name, is_admin, created_at
-        "#,
+pub async fn load_account(pool: &PgPool, user_id: i64) -> Result<UserCreatedResponse, ApiError> {
+    sqlx::query_as::<_, (i64, String, bool, DateTime<Utc>)>(
+        "SELECT id, name, is_admin, created_at FROM users WHERE id = $1",
     )
-    .bind(normalized_name)
-    .bind(is_admin)
+    .bind(user_id)
     .fetch_optional(pool)
     .await
     .map_err(|err| {
-        if let sqlx::Error::Database(db_err) = &err
-            && db_err.code().as_deref() == Some("23505")
-        {
-            return ApiError::conflict("a user with that name already exists".to_string());
-        }
-        error!("failed to create user: {err}");
-        ApiError::internal("failed to create user".to_string())
+        error!(user_id, "failed to load account: {err}");
+        ApiError::internal("failed to load account".to_string())
     })?
-    .ok_or_else(|| ApiError::internal("failed to create user".to_string()))?;
-
-    Ok(UserCreatedResponse {
-        id: row.0,
-        name: row.1,
-        is_admin: row.2,
-        created_at: row.3,
+    .map(|(id, name, is_admin, created_at)| UserCreatedResponse {
+        id,
+        name,
+        is_admin,
+        created_at,
     })
+    .ok_or_else(|| ApiError::not_found("user not found".to_string()))
 }
 
-pub async fn set_user_disabled(
-    pool: &PgPool,
-    user_id: i64,
-    disabled: bool,
-) -> Result<(), ApiError> {
-    let updated = sqlx::query(
+pub async fn list_user_tokens(pool: &PgPool, user_id: i64) -> Result<Vec<TokenInfo>, ApiError> {
+    let rows = sqlx::query_as::<_, TokenRow>(
         r#"
-        UPDATE users
-        SET disabled_at = CASE WHEN $2 THEN NOW() ELSE NULL END
-        WHERE id = $1
+        SELECT id, label, token_prefix, scopes, created_at, expires_at, last_used_at, revoked_at
+        FROM auth_tokens
+        WHERE user_id = $1
+        ORDER BY created_at DESC
         "#,
     )
     .bind(user_id)
-    .bind(disabled)
-    .execute(pool)
+    .fetch_all(pool)
     .await
     .map_err(|err| {
-        error!(user_id, "failed to update user disabled state: {err}");
-        ApiError::internal("failed to update user".to_string())
+        error!(user_id, "failed to list tokens: {err}");
+        ApiError::internal("failed to list tokens".to_string())
     })?;
 
-    if updated.rows_affected() == 0 {
-        return Err(ApiError::not_found("user not found".to_string()));
-    }
-
-    Ok(())
+    Ok(rows.into_iter().map(token_info_from_row).collect())
 }
 
-pub async fn create_token(
+pub async fn rename_token(
     pool: &PgPool,
     user_id: i64,
-    label: Option<String>,
-) -> Result<(i64, String, String, String, DateTime<Utc>), ApiError> {
-    let user_exists = sqlx::query_scalar::<_, i64>("SELECT id FROM users WHERE id = $1")
-        .bind(user_id)
-        .fetch_optional(pool)
-        .await
-        .map_err(|err| {
-            error!(user_id, "failed to check user for token create: {err}");
-            ApiError::internal("failed to create token".to_string())
-        })?
-        .is_some();
-
-    if !user_exists {
-        return Err(ApiError::not_found("user not found".to_string()));
+    token_id: i64,
+    label: &str,
+    audit: impl FnOnce(&TokenInfo) -> AuditEntry,
+) -> Result<TokenInfo, ApiError> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(ApiError::bad_request("label must not be empty".to_string()));
     }
 
-    let token = generate_token();
-    let hash = hash_token(&token);
-    let prefix = token_prefix(&token);
-    let normalized_label = label.unwrap_or_else(|| "manual".to_string());
+    let map_err = |err: sqlx::Error| {
+        error!(user_id, token_id, "failed to rename token: {err}");
+        ApiError::internal("failed to rename token".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
 
-    let row = sqlx::query_as::<_, (i64, DateTime<Utc>)>(
+    let token = sqlx::query_as::<_, TokenRow>(
         r#"
-        INSERT INTO auth_tokens (user_id, token_hash, token_prefix, label)
-        VALUES ($1, $2, $3, $4)
-        RETURNING id, created_at
+        UPDATE auth_tokens
+        SET label = $3
+        WHERE id = $1
+          AND user_id = $2
+        RETURNING id, label, token_prefix, scopes, created_at, expires_at, last_used_at,
+                  revoked_at
         "#,
     )
+    .bind(token_id)
     .bind(user_id)
-    .bind(hash)
-    .bind(prefix.clone())
-    .bind(normalized_label.clone())
-    .fetch_one(pool)
+    .bind(label)
+    .fetch_optional(&mut *transaction)
     .await
-    .map_err(|err| {
-        error!(user_id, "failed to create token: {err}");
-        ApiError::internal("failed to create token".to_string())
-    })?;
+    .map_err(map_err)?
+    .map(token_info_from_row)
+    .ok_or_else(|| ApiError::not_found("token not found".to_string()))?;
 
-    Ok((row.0, normalized_label, prefix, token, row.1))
+    record_audit_event(&mut *transaction, &audit(&token))
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(token)
 }
 
-pub async fn revoke_token(pool: &PgPool, token_id: i64) -> Result<(), ApiError> {
-    let result = sqlx::query(
+/// Revokes a token and returns the id of the user it belonged to. With
+/// `owner_id`, only that user's tokens match, so users cannot revoke each
+/// other's tokens.
+pub async fn revoke_token(
+    pool: &PgPool,
+    token_id: i64,
+    owner_id: Option<i64>,
+    audit: &AuditEntry,
+) -> Result<i64, ApiError> {
+    let map_err = |err: sqlx::Error| {
+        error!(token_id, "failed to revoke token: {err}");
+        ApiError::internal("failed to revoke token".to_string())
+    };
+
+    let mut transaction = pool.begin().await.map_err(map_err)?;
+
+    let user_id = sqlx::query_scalar::<_, i64>(
         r#"
         UPDATE auth_tokens
         SET revoked_at = NOW()
         WHERE id = $1
+          AND ($2::BIGINT IS NULL OR user_id = $2)
           AND revoked_at IS NULL
+        RETURNING user_id
         "#,
     )
     .bind(token_id)
-    .execute(pool)
+    .bind(owner_id)
+    .fetch_optional(&mut *transaction)
     .await
-    .map_err(|err| {
-        error!(token_id, "failed to revoke token: {err}");
-        ApiError::internal("failed to revoke token".to_string())
-    })?;
+    .map_err(map_err)?;
 
-    if result.rows_affected() == 0 {
+    let Some(user_id) = user_id else {
         warn!(
             token_id,
             "token revoke requested for missing/already-revoked token"
         );
         return Err(ApiError::not_found("token not found".to_string()));
+    };
+
+    record_audit_event(&mut *transaction, audit)
+        .await
+        .map_err(map_err)?;
+    transaction.commit().await.map_err(map_err)?;
+    Ok(user_id)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use axum::http::StatusCode;
+    use chrono::{Duration, Utc};
+    use sqlx::PgPool;
+    use tokio::sync::OnceCell;
+
+    use serde_json::json;
+
+    use super::{
+        create_playlist, create_share_link, dismiss_quarantined_playlists, ensure_schema,
+        list_quarantined_playlists, resolve_share_link, restore_quarantined_playlists,
+        revoke_share_link,
+    };
+    use crate::{
+        audit::AuditEntry,
+        config::{AppConfig, HistoryRetention},
+        models::{CreatePlaylistRequest, ShareLinkCreated},
+    };
+
+    static SCHEMA: OnceCell<()> = OnceCell::const_new();
+
+    /// A pool on the database configured through `DB_*`, as in CI, with the
+    /// schema in place.
+    pub async fn test_pool() -> PgPool {
+        let config = AppConfig::from_env().expect("config");
+        let pool = PgPool::connect(&config.database_url)
+            .await
+            .expect("test database");
+        SCHEMA
+            .get_or_init(|| async { ensure_schema(&pool).await.expect("schema") })
+            .await;
+        pool
     }
 
-    Ok(())
+    /// A new user with a playlist `party`, linked with `expires_at`.
+    async fn linked_playlist(
+        pool: &PgPool,
+        expires_at: Option<chrono::DateTime<Utc>>,
+    ) -> (i64, ShareLinkCreated) {
+        let name = format!("links-{}", super::generate_id());
+        let owner_id =
+            sqlx::query_scalar::<_, i64>("INSERT INTO users (name) VALUES ($1) RETURNING id")
+                .bind(&name)
+                .fetch_one(pool)
+                .await
+                .expect("user");
+        let retention = HistoryRetention {
+            max_revisions: 10,
+            max_age_days: 1,
+        };
+        let request = CreatePlaylistRequest {
+            id: Some("party".to_string()),
+            name: "Party".to_string(),
+            attributes: Default::default(),
+            items: Vec::new(),
+            client_id: None,
+        };
+        create_playlist(pool, owner_id, request, &retention)
+            .await
+            .expect("playlist");
+        let created = create_share_link(pool, owner_id, "party", None, expires_at, |_| {
+            AuditEntry::new("playlist.link_created")
+        })
+        .await
+        .expect("link");
+        (owner_id, created)
+    }
+
+    pub async fn remove_user(pool: &PgPool, user_id: i64) {
+        sqlx::query("DELETE FROM users WHERE id = $1")
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .expect("delete user");
+    }
+
+    #[tokio::test]
+    async fn resolves_a_link_to_its_playlist_but_not_a_guessed_one() {
+        let pool = test_pool().await;
+        let (owner_id, created) = linked_playlist(&pool, None).await;
+
+        let linked = resolve_share_link(&pool, &created.link)
+            .await
+            .expect("resolves");
+        assert_eq!(linked.playlist["id"], "party");
+        assert_eq!(linked.expires_at, None);
+        assert_eq!(created.path, format!("/s/{}", created.link));
+
+        let guessed = format!("{}x", &created.link[..created.link.len() - 1]);
+        let err = resolve_share_link(&pool, &guessed).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        remove_user(&pool, owner_id).await;
+    }
+
+    #[tokio::test]
+    async fn a_revoked_link_no_longer_resolves() {
+        let pool = test_pool().await;
+        let (owner_id, created) = linked_playlist(&pool, None).await;
+
+        revoke_share_link(&pool, owner_id, "party", created.info.id, |_| {
+            AuditEntry::new("playlist.link_revoked")
+        })
+        .await
+        .expect("revokes");
+
+        let err = resolve_share_link(&pool, &created.link).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        let err = revoke_share_link(&pool, owner_id, "party", created.info.id, |_| {
+            AuditEntry::new("playlist.link_revoked")
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        remove_user(&pool, owner_id).await;
+    }
+
+    #[tokio::test]
+    async fn a_link_resolves_until_it_expires() {
+        let pool = test_pool().await;
+        let expires_at = Utc::now() + Duration::hours(1);
+        let (owner_id, created) = linked_playlist(&pool, Some(expires_at)).await;
+
+        let linked = resolve_share_link(&pool, &created.link)
+            .await
+            .expect("resolves before expiry");
+        assert!(linked.expires_at.is_some());
+
+        sqlx::query("UPDATE playlist_share_links SET expires_at = NOW() WHERE id = $1")
+            .bind(created.info.id)
+            .execute(&pool)
+            .await
+            .expect("expire link");
+        let err = resolve_share_link(&pool, &created.link).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        let err = create_share_link(
+            &pool,
+            owner_id,
+            "party",
+            None,
+            Some(Utc::now() - Duration::minutes(1)),
+            |_| AuditEntry::new("playlist.link_created"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        remove_user(&pool, owner_id).await;
+    }
+
+    #[tokio::test]
+    async fn restores_quarantined_playlists_once_they_are_valid() {
+        let pool = test_pool().await;
+        let user_id =
+            sqlx::query_scalar::<_, i64>("INSERT INTO users (name) VALUES ($1) RETURNING id")
+                .bind(format!("quarantine-{}", super::generate_id()))
+                .fetch_one(&pool)
+                .await
+                .expect("user");
+        super::ensure_user_document(&pool, user_id)
+            .await
+            .expect("document");
+        let quarantined = json!(["loose", { "id": "p1", "name": "Party" }]);
+        sqlx::query(
+            "INSERT INTO quarantined_playlists (user_id, playlists, error) VALUES ($1, $2, 'bad')",
+        )
+        .bind(user_id)
+        .bind(&quarantined)
+        .execute(&pool)
+        .await
+        .expect("quarantine");
+        let retention = HistoryRetention {
+            max_revisions: 10,
+            max_age_days: 1,
+        };
+        let audit = AuditEntry::new("playlist.quarantine_restored");
+        let restore = |playlists| {
+            restore_quarantined_playlists(&pool, user_id, playlists, &retention, &audit)
+        };
+
+        let listed = list_quarantined_playlists(&pool).await.expect("list");
+        assert!(
+            listed
+                .iter()
+                .any(|entry| entry.user_id == user_id && entry.playlists == quarantined)
+        );
+
+        // The original value is still invalid, so it stays quarantined.
+        let err = restore(None).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+
+        let corrected = json!([{ "name": "loose" }, { "id": "p1", "name": "Party" }]);
+        let (snapshot, _) = restore(Some(corrected)).await.expect("restores");
+        let names = snapshot
+            .playlists
+            .as_array()
+            .expect("array")
+            .iter()
+            .map(|playlist| playlist["name"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, [json!("loose"), json!("Party")]);
+
+        let err = restore(None).await.unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        let err = dismiss_quarantined_playlists(
+            &pool,
+            user_id,
+            &AuditEntry::new("playlist.quarantine_dismissed"),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.status(), StatusCode::NOT_FOUND);
+
+        remove_user(&pool, user_id).await;
+    }
 }
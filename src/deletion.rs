@@ -0,0 +1,85 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::info;
+
+use crate::{db::purge_deleted_users, errors::ApiError, models::AccountEvent, state::AppContext};
+
+/// How often users past their deletion grace period are purged.
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Longest grace period for a scheduled user deletion.
+pub const MAX_GRACE_DAYS: i32 = 365;
+
+/// Validates the `grace_days` of a deletion request. Returns the grace period
+/// in days, or `None` when the user is to be deleted right away.
+pub fn grace_period(grace_days: Option<i32>) -> Result<Option<i32>, ApiError> {
+    match grace_days.unwrap_or(0) {
+        0 => Ok(None),
+        days @ 1..=MAX_GRACE_DAYS => Ok(Some(days)),
+        _ => Err(ApiError::bad_request(format!(
+            "grace_days must be between 0 and {MAX_GRACE_DAYS}"
+        ))),
+    }
+}
+
+/// Checks that `confirm` is exactly the name of the user about to be deleted.
+pub fn check_confirmation(name: &str, confirm: &str) -> Result<(), ApiError> {
+    if name != confirm {
+        return Err(ApiError::bad_request(
+            "confirm must be the name of the user to delete".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Purges users whose scheduled deletion is due, at startup and then every
+/// [`PURGE_INTERVAL`]. Runs for the lifetime of the server.
+pub async fn purge_scheduled_deletions(state: Arc<AppContext>) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        // Failures are logged by `purge_deleted_users`; the next tick retries.
+        let Ok(users) = purge_deleted_users(&state.pool).await else {
+            continue;
+        };
+
+        for (user_id, _) in users {
+            info!(user_id, "purged user after deletion grace period");
+            state
+                .send_user_event(user_id, AccountEvent::deleted())
+                .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_GRACE_DAYS, check_confirmation, grace_period};
+
+    #[test]
+    fn no_or_zero_grace_days_delete_right_away() {
+        assert_eq!(grace_period(None).expect("valid"), None);
+        assert_eq!(grace_period(Some(0)).expect("valid"), None);
+        assert_eq!(grace_period(Some(1)).expect("valid"), Some(1));
+        assert_eq!(
+            grace_period(Some(MAX_GRACE_DAYS)).expect("valid"),
+            Some(MAX_GRACE_DAYS)
+        );
+    }
+
+    #[test]
+    fn rejects_grace_days_out_of_range() {
+        for days in [-1, MAX_GRACE_DAYS + 1, i32::MIN, i32::MAX] {
+            let error = grace_period(Some(days)).expect_err("out of range");
+            assert!(format!("{error:?}").contains("grace_days must be between"));
+        }
+    }
+
+    #[test]
+    fn confirmation_must_match_the_name_exactly() {
+        assert!(check_confirmation("ana", "ana").is_ok());
+        for confirm in ["", "Ana", "ana ", "bob"] {
+            assert!(check_confirmation("ana", confirm).is_err());
+        }
+    }
+}
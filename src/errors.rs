@@ -1,6 +1,8 @@
+use std::time::Duration;
+
 use axum::{
     Json,
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde_json::json;
@@ -10,6 +12,8 @@ pub struct ApiError {
     status: StatusCode,
     code: &'static str,
     message: String,
+    /// Sent as `Retry-After` (whole seconds).
+    retry_after: Option<Duration>,
 }
 
 impl ApiError {
@@ -18,6 +22,7 @@ impl ApiError {
             status: StatusCode::UNAUTHORIZED,
             code: "unauthorized",
             message,
+            retry_after: None,
         }
     }
 
@@ -26,6 +31,16 @@ impl ApiError {
             status: StatusCode::FORBIDDEN,
             code: "forbidden",
             message,
+            retry_after: None,
+        }
+    }
+
+    pub fn insufficient_scope(message: String) -> Self {
+        Self {
+            status: StatusCode::FORBIDDEN,
+            code: "insufficient_scope",
+            message,
+            retry_after: None,
         }
     }
 
@@ -34,6 +49,7 @@ impl ApiError {
             status: StatusCode::NOT_FOUND,
             code: "not_found",
             message,
+            retry_after: None,
         }
     }
 
@@ -42,6 +58,7 @@ impl ApiError {
             status: StatusCode::BAD_REQUEST,
             code: "bad_request",
             message,
+            retry_after: None,
         }
     }
 
@@ -50,6 +67,97 @@ impl ApiError {
             status: StatusCode::CONFLICT,
             code: "version_conflict",
             message,
+            retry_after: None,
+        }
+    }
+
+    pub fn token_rotated(message: String) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            code: "token_rotated",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn patch_test_failed(message: String) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            code: "patch_test_failed",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn unsupported_media_type(message: String) -> Self {
+        Self {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            code: "unsupported_media_type",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn command_timeout(message: String) -> Self {
+        Self {
+            status: StatusCode::GATEWAY_TIMEOUT,
+            code: "command_timeout",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn handoff_claimed(message: String) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            code: "handoff_claimed",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn already_exists(message: String) -> Self {
+        Self {
+            status: StatusCode::CONFLICT,
+            code: "already_exists",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn handoff_expired(message: String) -> Self {
+        Self {
+            status: StatusCode::GONE,
+            code: "handoff_expired",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn pairing_expired(message: String) -> Self {
+        Self {
+            status: StatusCode::GONE,
+            code: "pairing_expired",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn payload_too_large(message: String) -> Self {
+        Self {
+            status: StatusCode::PAYLOAD_TOO_LARGE,
+            code: "payload_too_large",
+            message,
+            retry_after: None,
+        }
+    }
+
+    pub fn rate_limited(message: String, retry_after: Duration) -> Self {
+        Self {
+            status: StatusCode::TOO_MANY_REQUESTS,
+            code: "rate_limited",
+            message,
+            retry_after: Some(retry_after),
         }
     }
 
@@ -58,19 +166,41 @@ impl ApiError {
             status: StatusCode::INTERNAL_SERVER_ERROR,
             code: "internal_error",
             message,
+            retry_after: None,
         }
     }
 }
 
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The `{ "code", "message" }` object used in error payloads.
+    pub fn body(&self) -> serde_json::Value {
+        json!({
+            "code": self.code,
+            "message": self.message,
+        })
+    }
+}
+
 impl IntoResponse for ApiError {
     fn into_response(self) -> Response {
-        let payload = json!({
-            "error": {
-                "code": self.code,
-                "message": self.message,
-            }
-        });
-
-        (self.status, Json(payload)).into_response()
+        let payload = json!({ "error": self.body() });
+
+        let mut response = (self.status, Json(payload)).into_response();
+        if let Some(retry_after) = self.retry_after {
+            // Round up so clients never retry before the limit has reset.
+            let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, seconds.max(1).into());
+        }
+        response
     }
 }
@@ -0,0 +1,208 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, postgres::PgListener};
+use tracing::{error, info, warn};
+
+use crate::{models::UserEvent, state::AppContext};
+
+/// Postgres channel used to relay user events between server instances.
+const EVENTS_CHANNEL: &str = "any_player_sync_events";
+
+/// Delay before re-establishing a dropped `LISTEN` connection.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Postgres rejects `NOTIFY` payloads of 8000 bytes or more. Larger events
+/// are stored in `relayed_events` and only their id is sent.
+const MAX_NOTIFY_PAYLOAD: usize = 7900;
+
+/// How long stored events are kept for other instances to fetch.
+const STORED_EVENT_RETENTION_SECONDS: i32 = 300;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Notification {
+    /// Instance that published the event. Instances ignore their own
+    /// notifications because they already delivered the event locally.
+    origin: String,
+    user_id: i64,
+    #[serde(flatten)]
+    body: NotificationBody,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum NotificationBody {
+    Event(UserEvent),
+    /// Row of `relayed_events` holding the whole notification.
+    StoredEventId(i64),
+}
+
+/// The `NOTIFY` payload for `notification`, or `None` when it is too large
+/// to be sent inline.
+fn inline_payload(notification: &Notification) -> Result<Option<String>, serde_json::Error> {
+    let payload = serde_json::to_string(notification)?;
+    Ok((payload.len() <= MAX_NOTIFY_PAYLOAD).then_some(payload))
+}
+
+/// Publishes an event to the other server instances via `NOTIFY`.
+pub async fn publish(pool: &PgPool, origin: &str, user_id: i64, event: &UserEvent) {
+    let notification = Notification {
+        origin: origin.to_string(),
+        user_id,
+        body: NotificationBody::Event(event.clone()),
+    };
+    if let Err(err) = notify(pool, &notification).await {
+        error!(user_id, "failed to publish event notification: {err}");
+    }
+}
+
+async fn notify(pool: &PgPool, notification: &Notification) -> anyhow::Result<()> {
+    let payload = match inline_payload(notification)? {
+        Some(payload) => payload,
+        None => store_event(pool, notification).await?,
+    };
+    sqlx::query("SELECT pg_notify($1, $2)")
+        .bind(EVENTS_CHANNEL)
+        .bind(payload)
+        .execute(pool)
+        .await?;
+    Ok(())
+}
+
+/// Stores an oversized notification and returns the payload referring to it.
+/// Stored events older than the retention are pruned on the way.
+async fn store_event(pool: &PgPool, notification: &Notification) -> anyhow::Result<String> {
+    sqlx::query("DELETE FROM relayed_events WHERE created_at < NOW() - make_interval(secs => $1)")
+        .bind(STORED_EVENT_RETENTION_SECONDS)
+        .execute(pool)
+        .await?;
+    let id = sqlx::query_scalar::<_, i64>(
+        "INSERT INTO relayed_events (payload) VALUES ($1) RETURNING id",
+    )
+    .bind(serde_json::to_string(notification)?)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(serde_json::to_string(&Notification {
+        origin: notification.origin.clone(),
+        user_id: notification.user_id,
+        body: NotificationBody::StoredEventId(id),
+    })?)
+}
+
+/// Listens for events published by other instances and delivers them to this
+/// instance's local subscribers. Runs for the lifetime of the server.
+pub async fn relay_notifications(state: Arc<AppContext>) {
+    loop {
+        if let Err(err) = listen(&state).await {
+            warn!("event notification listener failed: {err}");
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn listen(state: &AppContext) -> Result<(), sqlx::Error> {
+    let mut listener = PgListener::connect_with(&state.pool).await?;
+    listener.listen(EVENTS_CHANNEL).await?;
+    info!(
+        channel = EVENTS_CHANNEL,
+        "listening for cross-instance events"
+    );
+
+    loop {
+        // `None` means the connection dropped; the next call reconnects.
+        // Notifications sent in between are lost, so clients that fall behind
+        // recover through `since_version` on their next reconnect.
+        let Some(notification) = listener.try_recv().await? else {
+            warn!("event notification connection lost, reconnecting");
+            continue;
+        };
+
+        let mut notification = match serde_json::from_str::<Notification>(notification.payload()) {
+            Ok(notification) => notification,
+            Err(err) => {
+                warn!("ignoring malformed event notification: {err}");
+                continue;
+            }
+        };
+
+        if notification.origin == state.instance_id {
+            continue;
+        }
+        if let NotificationBody::StoredEventId(id) = notification.body {
+            match load_stored_event(&state.pool, id).await {
+                Ok(stored) => notification = stored,
+                Err(err) => {
+                    error!(id, "failed to load relayed event: {err}");
+                    continue;
+                }
+            }
+        }
+        let NotificationBody::Event(event) = notification.body else {
+            warn!("ignoring relayed event that refers to another one");
+            continue;
+        };
+        state.send_local_event(notification.user_id, event).await;
+    }
+}
+
+async fn load_stored_event(pool: &PgPool, id: i64) -> anyhow::Result<Notification> {
+    let payload =
+        sqlx::query_scalar::<_, String>("SELECT payload FROM relayed_events WHERE id = $1")
+            .bind(id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("event was pruned before it was read"))?;
+    Ok(serde_json::from_str(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::{MAX_NOTIFY_PAYLOAD, Notification, NotificationBody, inline_payload};
+    use crate::models::{CommandAction, CommandEvent, UserEvent};
+
+    fn command_notification(params: serde_json::Value) -> Notification {
+        Notification {
+            origin: "instance-a".to_string(),
+            user_id: 7,
+            body: NotificationBody::Event(UserEvent::Command(CommandEvent {
+                event_type: "command".to_string(),
+                command_id: "c1".to_string(),
+                action: CommandAction::Play,
+                params,
+                target_client_id: "phone".to_string(),
+                source_client_id: None,
+                expires_at: Utc::now(),
+            })),
+        }
+    }
+
+    #[test]
+    fn small_events_are_sent_inline() {
+        let payload = inline_payload(&command_notification(json!({ "track": "t1" })))
+            .expect("serializes")
+            .expect("fits");
+        let value: serde_json::Value = serde_json::from_str(&payload).expect("json");
+
+        assert_eq!(value["user_id"], 7);
+        assert_eq!(value["event"]["action"], "play");
+    }
+
+    #[test]
+    fn large_events_are_stored_instead_of_dropped() {
+        let notification = command_notification(json!({ "queue": "x".repeat(MAX_NOTIFY_PAYLOAD) }));
+        assert_eq!(inline_payload(&notification).expect("serializes"), None);
+
+        let reference = serde_json::to_string(&Notification {
+            body: NotificationBody::StoredEventId(42),
+            ..notification
+        })
+        .expect("serializes");
+        assert!(reference.len() < MAX_NOTIFY_PAYLOAD);
+        let parsed: Notification = serde_json::from_str(&reference).expect("parses");
+        assert!(matches!(parsed.body, NotificationBody::StoredEventId(42)));
+    }
+}
@@ -1,29 +1,69 @@
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
 use axum::{
-    Json,
-    extract::{Path, Query, State, WebSocketUpgrade},
+    Json, RequestExt,
+    body::Bytes,
+    extract::{Path, Query, Request, State, WebSocketUpgrade},
     http::{HeaderMap, StatusCode, header},
-    response::{Html, IntoResponse, Response},
+    response::{
+        AppendHeaders, Html, IntoResponse, Redirect, Response,
+        sse::{KeepAlive, Sse},
+    },
 };
-use chrono::Utc;
+use chrono::{Duration, Utc};
+use futures_util::TryStreamExt;
+use serde_json::json;
+use tokio_util::io::StreamReader;
 
 use crate::{
+    audit::{AuditEntry, ClientInfo},
+    commands::send_command,
     db::{
-        authenticate_token, create_token, create_user, list_users, load_snapshot, replace_snapshot,
-        revoke_token, set_user_disabled, update_namespace,
+        active_handoff, approve_pairing, authenticate_token, claim_handoff, create_handoff,
+        create_oidc_login, create_playlist, create_share_link, create_token, create_user,
+        delete_user, dismiss_quarantined_playlists, insert_playlist_items, leave_shared_playlist,
+        list_audit_events, list_online_devices, list_playlist_shares, list_playlists,
+        list_quarantined_playlists, list_revisions, list_share_links, list_shared_playlists,
+        list_user_tokens, list_users, load_account, load_playlist, load_shared_playlist,
+        load_snapshot, move_playlist_item, patch_namespace, poll_pairing, remove_playlist,
+        remove_playlist_item, rename_token, replace_snapshot, resolve_share_link,
+        restore_quarantined_playlists, restore_revision, revoke_share_link, revoke_token,
+        rotate_token, schedule_user_deletion, set_user_disabled, share_playlist, snapshot_since,
+        start_pairing, take_oidc_login, unshare_playlist, update_namespace, update_playlist,
+        upsert_oidc_user,
     },
+    deletion,
     errors::ApiError,
     models::{
-        AuthenticatedUser, CreateTokenRequest, CreateUserRequest, HealthResponse, Namespace,
-        NamespacePayload, OperationResponse, SetUserDisabledRequest, SnapshotPayload,
-        SnapshotQuery, TokenCreatedResponse, UpdateResponse, WsQuery, namespace_data,
+        AccountEvent, AccountResponse, AuditEvent, AuditQuery, AuthenticatedUser, CommandRequest,
+        CommandResponse, CreatePlaylistRequest, CreateShareLinkRequest, CreateTokenRequest,
+        CreateUserRequest, DeleteUserQuery, DeleteUserResponse, DevicePresence, EventsQuery,
+        ExportPlaylistQuery, HandoffClaimRequest, HandoffClaimResponse, HandoffEvent, HandoffOffer,
+        HandoffOfferRequest, HealthResponse, HistoryQuery, ImportPlaylistQuery,
+        ImportPlaylistResponse, InsertPlaylistItemsRequest, MovePlaylistItemRequest, Namespace,
+        NamespacePayload, OidcCallbackQuery, OidcStatusResponse, OperationResponse,
+        PairApproveRequest, PairApproveResponse, PairPollRequest, PairPollResponse,
+        PairStartRequest, PairStartResponse, PatchQuery, PlaylistRecord, PlaylistShare,
+        PlaylistSummary, PlaylistWriteQuery, QuarantinedPlaylists, RenameTokenRequest,
+        RestorePayload, RestoreQuarantinedPlaylistsRequest, Revision, RotateTokenRequest,
+        SetUserDisabledRequest, ShareAccess, ShareLink, ShareLinkCreated, ShareLinkQuery,
+        SharePlaylistRequest, SharedPlaylist, SharedPlaylistEvent, SnapshotPayload, SnapshotQuery,
+        SnapshotSince, TokenCreatedResponse, TokenEvent, TokenInfo, TokenRotatedResponse,
+        UpdateEvent, UpdatePlaylistRequest, UpdateResponse, WsQuery,
     },
+    oidc::{self, OidcClient, random_login_value},
+    pairing::normalize_user_code,
+    patch::PatchFormat,
+    playlist_files::{PlaylistFormat, export_playlist, import_playlist},
+    playlists::PlaylistItem,
+    rate_limit::credential_key,
+    scopes::{Scope, Scopes},
+    sse::event_stream,
     state::AppContext,
     ws::handle_ws_connection,
 };
 
-fn bearer_token_from_headers(headers: &HeaderMap) -> Option<String> {
+pub fn bearer_token_from_headers(headers: &HeaderMap) -> Option<String> {
     let value = headers.get(header::AUTHORIZATION)?;
     let as_str = value.to_str().ok()?.trim();
     let token = as_str
@@ -37,6 +77,18 @@ fn bearer_token_from_headers(headers: &HeaderMap) -> Option<String> {
     }
 }
 
+pub fn cookie_from_headers(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
 async fn authenticate_with_headers(
     state: &Arc<AppContext>,
     headers: &HeaderMap,
@@ -46,6 +98,20 @@ async fn authenticate_with_headers(
     authenticate_token(&state.pool, &token).await
 }
 
+/// Authenticates with the bearer token, or with the admin console session
+/// cookie when there is none. Only the routes the admin console calls accept
+/// the cookie. It is `SameSite=Strict`, so other sites cannot make requests
+/// with it.
+async fn authenticate_admin_console(
+    state: &Arc<AppContext>,
+    headers: &HeaderMap,
+) -> Result<AuthenticatedUser, ApiError> {
+    let token = bearer_token_from_headers(headers)
+        .or_else(|| cookie_from_headers(headers, oidc::SESSION_COOKIE))
+        .ok_or_else(|| ApiError::unauthorized("missing Authorization: Bearer token".to_string()))?;
+    authenticate_token(&state.pool, &token).await
+}
+
 async fn authenticate_with_headers_or_query_token(
     state: &Arc<AppContext>,
     headers: &HeaderMap,
@@ -65,13 +131,12 @@ async fn authenticate_with_headers_or_query_token(
 }
 
 fn require_admin(user: &AuthenticatedUser) -> Result<(), ApiError> {
-    if user.is_admin {
-        Ok(())
-    } else {
-        Err(ApiError::forbidden(
+    if !user.is_admin {
+        return Err(ApiError::forbidden(
             "admin privileges are required".to_string(),
-        ))
+        ));
     }
+    user.scopes.require(Scope::All)
 }
 
 pub async fn health() -> Json<HealthResponse> {
@@ -88,15 +153,19 @@ pub async fn get_snapshot(
     Query(query): Query<SnapshotQuery>,
 ) -> Result<Response, ApiError> {
     let user = authenticate_with_headers(&state, &headers).await?;
-    let snapshot = load_snapshot(&state.pool, user.id).await?;
-
-    if let Some(since_version) = query.since_version
-        && snapshot.version <= since_version
+    user.scopes.require_read(Namespace::Snapshot)?;
+    match snapshot_since(
+        &state.pool,
+        user.id,
+        query.since_version,
+        query.shared_since_version,
+    )
+    .await?
     {
-        return Ok(StatusCode::NOT_MODIFIED.into_response());
+        SnapshotSince::NotModified => Ok(StatusCode::NOT_MODIFIED.into_response()),
+        SnapshotSince::Delta(response) => Ok(Json(response).into_response()),
+        SnapshotSince::Full(response) => Ok(Json(response).into_response()),
     }
-
-    Ok(Json(snapshot).into_response())
 }
 
 pub async fn put_snapshot(
@@ -105,7 +174,9 @@ pub async fn put_snapshot(
     Json(payload): Json<SnapshotPayload>,
 ) -> Result<Json<crate::models::Snapshot>, ApiError> {
     let user = authenticate_with_headers(&state, &headers).await?;
-    let (snapshot, event) = replace_snapshot(&state.pool, user.id, payload).await?;
+    user.scopes.require_write(Namespace::Snapshot)?;
+    let (snapshot, event) =
+        replace_snapshot(&state.pool, user.id, payload, &state.history_retention).await?;
     state.send_user_event(user.id, event).await;
     Ok(Json(snapshot))
 }
@@ -116,22 +187,11 @@ pub async fn get_namespace(
     Path(namespace): Path<String>,
 ) -> Result<Json<UpdateResponse>, ApiError> {
     let user = authenticate_with_headers(&state, &headers).await?;
-    let namespace = Namespace::parse(&namespace)?;
-    if matches!(namespace, Namespace::Snapshot) {
-        return Err(ApiError::bad_request(
-            "snapshot is only available via /v1/snapshot".into(),
-        ));
-    }
+    let namespace = Namespace::parse_data(&namespace)?;
+    user.scopes.require_read(namespace)?;
 
     let snapshot = load_snapshot(&state.pool, user.id).await?;
-    let data = namespace_data(&snapshot, namespace);
-
-    Ok(Json(UpdateResponse {
-        version: snapshot.version,
-        updated_at: snapshot.updated_at,
-        namespace,
-        data,
-    }))
+    Ok(Json(UpdateResponse::new(&snapshot, namespace, None)))
 }
 
 pub async fn put_namespace(
@@ -141,33 +201,1200 @@ pub async fn put_namespace(
     Json(payload): Json<NamespacePayload>,
 ) -> Result<Json<UpdateResponse>, ApiError> {
     let user = authenticate_with_headers(&state, &headers).await?;
-    let namespace = Namespace::parse(&namespace)?;
-    if matches!(namespace, Namespace::Snapshot) {
-        return Err(ApiError::bad_request(
-            "snapshot is only available via /v1/snapshot".into(),
+    let namespace = Namespace::parse_data(&namespace)?;
+    user.scopes.require_write(namespace)?;
+
+    let (snapshot, event, merge) = update_namespace(
+        &state.pool,
+        user.id,
+        namespace,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+
+    Ok(Json(UpdateResponse::new(&snapshot, namespace, merge)))
+}
+
+pub async fn get_playlists(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<PlaylistSummary>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    Ok(Json(list_playlists(&state.pool, user.id).await?))
+}
+
+pub async fn post_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Json(payload): Json<CreatePlaylistRequest>,
+) -> Result<Json<PlaylistRecord>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let (playlist, event) =
+        create_playlist(&state.pool, user.id, payload, &state.history_retention).await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(playlist))
+}
+
+pub async fn export_playlist_file(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+    Query(query): Query<ExportPlaylistQuery>,
+) -> Result<Response, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+    let format = PlaylistFormat::parse(query.format.as_deref().unwrap_or("json"))?;
+
+    let record = load_playlist(&state.pool, user.id, &playlist_id).await?;
+    // Keep the file name to characters that need no quoting.
+    let file_name: String = record
+        .playlist
+        .name
+        .as_deref()
+        .unwrap_or(&record.playlist.id)
+        .chars()
+        .filter(|ch| ch.is_ascii_alphanumeric() || matches!(ch, ' ' | '-' | '_' | '.'))
+        .collect();
+    let file_name = match file_name.trim() {
+        "" => "playlist",
+        name => name,
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{file_name}.{}\"",
+                    format.extension()
+                ),
+            ),
+        ],
+        export_playlist(&record.playlist, format),
+    )
+        .into_response())
+}
+
+/// Creates a playlist from an uploaded M3U8, XSPF or JSON file. The body is
+/// read as it arrives, within the server's body size limit.
+pub async fn import_playlist_file(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Query(query): Query<ImportPlaylistQuery>,
+    request: Request,
+) -> Result<Json<ImportPlaylistResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let format = match query.format.as_deref() {
+        Some(format) => PlaylistFormat::parse(format)?,
+        None => headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(PlaylistFormat::from_content_type)
+            .ok_or_else(|| {
+                ApiError::unsupported_media_type(
+                    "pass ?format=m3u8, xspf or json, or a matching Content-Type".to_string(),
+                )
+            })?,
+    };
+    let body = request
+        .into_limited_body()
+        .into_data_stream()
+        .map_err(io::Error::other);
+    let imported = import_playlist(StreamReader::new(body), format).await?;
+
+    let name = query.name.or(imported.name).ok_or_else(|| {
+        ApiError::bad_request("the file has no playlist name, pass one with ?name=".to_string())
+    })?;
+    let imported_count = imported.items.len();
+    let (playlist, event) = create_playlist(
+        &state.pool,
+        user.id,
+        CreatePlaylistRequest {
+            id: query.id.or(imported.id),
+            name,
+            attributes: imported.attributes,
+            items: imported.items,
+            client_id: query.client_id,
+        },
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(ImportPlaylistResponse {
+        playlist,
+        imported: imported_count,
+        skipped: imported.skipped,
+        errors: imported.errors,
+    }))
+}
+
+pub async fn get_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+) -> Result<Json<PlaylistRecord>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    Ok(Json(
+        load_playlist(&state.pool, user.id, &playlist_id).await?,
+    ))
+}
+
+pub async fn patch_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+    Json(payload): Json<UpdatePlaylistRequest>,
+) -> Result<Json<PlaylistRecord>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let (playlist, event) = update_playlist(
+        &state.pool,
+        user.id,
+        None,
+        &playlist_id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(playlist))
+}
+
+pub async fn delete_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+    Query(query): Query<PlaylistWriteQuery>,
+) -> Result<Json<OperationResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let event = remove_playlist(
+        &state.pool,
+        user.id,
+        &playlist_id,
+        query,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(OperationResponse { ok: true }))
+}
+
+pub async fn get_playlist_items(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+) -> Result<Json<Vec<PlaylistItem>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    let record = load_playlist(&state.pool, user.id, &playlist_id).await?;
+    Ok(Json(record.playlist.items))
+}
+
+pub async fn post_playlist_items(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+    Json(payload): Json<InsertPlaylistItemsRequest>,
+) -> Result<Json<PlaylistRecord>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let (playlist, event) = insert_playlist_items(
+        &state.pool,
+        user.id,
+        None,
+        &playlist_id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(playlist))
+}
+
+pub async fn patch_playlist_item(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path((playlist_id, item_id)): Path<(String, String)>,
+    Json(payload): Json<MovePlaylistItemRequest>,
+) -> Result<Json<PlaylistRecord>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let (playlist, event) = move_playlist_item(
+        &state.pool,
+        user.id,
+        None,
+        &playlist_id,
+        &item_id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(playlist))
+}
+
+pub async fn delete_playlist_item(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path((playlist_id, item_id)): Path<(String, String)>,
+    Query(query): Query<PlaylistWriteQuery>,
+) -> Result<Json<PlaylistRecord>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let (playlist, event) = remove_playlist_item(
+        &state.pool,
+        user.id,
+        None,
+        &playlist_id,
+        &item_id,
+        query,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    Ok(Json(playlist))
+}
+
+pub async fn get_playlist_shares(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+) -> Result<Json<Vec<PlaylistShare>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    load_playlist(&state.pool, user.id, &playlist_id).await?;
+    Ok(Json(
+        list_playlist_shares(&state.pool, user.id, Some(&playlist_id)).await?,
+    ))
+}
+
+pub async fn put_playlist_share(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path((playlist_id, user_name)): Path<(String, String)>,
+    Json(payload): Json<SharePlaylistRequest>,
+) -> Result<Json<PlaylistShare>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let share = share_playlist(
+        &state.pool,
+        user.id,
+        &playlist_id,
+        &user_name,
+        payload.access,
+        |share| {
+            AuditEntry::new("playlist.shared")
+                .actor(&user)
+                .target("user", share.user_id)
+                .client(&client)
+                .details(json!({
+                    "playlist_id": share.playlist_id,
+                    "access": share.access,
+                }))
+        },
+    )
+    .await?;
+    state
+        .send_user_event(share.user_id, SharedPlaylistEvent::updated(&share, None))
+        .await;
+    Ok(Json(share))
+}
+
+pub async fn delete_playlist_share(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path((playlist_id, user_name)): Path<(String, String)>,
+) -> Result<Json<OperationResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let share = unshare_playlist(&state.pool, user.id, &playlist_id, &user_name, |share| {
+        AuditEntry::new("playlist.unshared")
+            .actor(&user)
+            .target("user", share.user_id)
+            .client(&client)
+            .details(json!({ "playlist_id": share.playlist_id }))
+    })
+    .await?;
+    state
+        .send_user_event(share.user_id, SharedPlaylistEvent::removed(&share))
+        .await;
+    Ok(Json(OperationResponse { ok: true }))
+}
+
+pub async fn get_share_links(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(playlist_id): Path<String>,
+) -> Result<Json<Vec<ShareLink>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    load_playlist(&state.pool, user.id, &playlist_id).await?;
+    Ok(Json(
+        list_share_links(&state.pool, user.id, &playlist_id).await?,
+    ))
+}
+
+pub async fn post_share_link(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(playlist_id): Path<String>,
+    Json(payload): Json<CreateShareLinkRequest>,
+) -> Result<(StatusCode, Json<ShareLinkCreated>), ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let created = create_share_link(
+        &state.pool,
+        user.id,
+        &playlist_id,
+        payload.label,
+        payload.expires_at,
+        |link| {
+            AuditEntry::new("playlist.link_created")
+                .actor(&user)
+                .target("share_link", link.id)
+                .client(&client)
+                .details(json!({
+                    "playlist_id": link.playlist_id,
+                    "expires_at": link.expires_at,
+                }))
+        },
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(created)))
+}
+
+pub async fn delete_share_link(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path((playlist_id, link_id)): Path<(String, i64)>,
+) -> Result<Json<OperationResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    revoke_share_link(&state.pool, user.id, &playlist_id, link_id, |link| {
+        AuditEntry::new("playlist.link_revoked")
+            .actor(&user)
+            .target("share_link", link.id)
+            .client(&client)
+            .details(json!({ "playlist_id": link.playlist_id }))
+    })
+    .await?;
+    Ok(Json(OperationResponse { ok: true }))
+}
+
+/// Public view of a playlist through a share link: the playlist as JSON when
+/// asked for with `?format=json` or `Accept: application/json`, otherwise a
+/// page that shows it.
+pub async fn get_linked_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(link): Path<String>,
+    Query(query): Query<ShareLinkQuery>,
+) -> Result<Response, ApiError> {
+    let wants_json = match query.format.as_deref() {
+        Some("json") => true,
+        Some("html") => false,
+        Some(other) => {
+            return Err(ApiError::bad_request(format!(
+                "unknown format '{other}', expected json or html"
+            )));
+        }
+        None => headers
+            .get(header::ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|accept| accept.contains("application/json")),
+    };
+    // The link is the credential: keep it out of Referer headers and caches
+    // so that revoking it takes effect everywhere.
+    let private = AppendHeaders([
+        (header::REFERRER_POLICY, "no-referrer"),
+        (header::CACHE_CONTROL, "no-store"),
+    ]);
+
+    let playlist = resolve_share_link(&state.pool, &link).await;
+    Ok(match (wants_json, playlist) {
+        (true, playlist) => (private, Json(playlist?)).into_response(),
+        (false, Ok(_)) => (private, Html(SHARE_HTML)).into_response(),
+        (false, Err(err)) => (err.status(), private, Html(SHARE_HTML)).into_response(),
+    })
+}
+
+pub async fn get_shared_playlists(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<SharedPlaylist>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    Ok(Json(list_shared_playlists(&state.pool, user.id).await?))
+}
+
+pub async fn get_shared_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(share_id): Path<i64>,
+) -> Result<Json<SharedPlaylist>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_read(Namespace::Playlists)?;
+
+    Ok(Json(
+        load_shared_playlist(&state.pool, user.id, share_id).await?,
+    ))
+}
+
+/// Ends a share from the recipient's side.
+pub async fn delete_shared_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(share_id): Path<i64>,
+) -> Result<Json<OperationResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require_write(Namespace::Playlists)?;
+
+    let (_, share) = leave_shared_playlist(&state.pool, user.id, share_id, |owner_id, share| {
+        AuditEntry::new("playlist.unshared")
+            .actor(&user)
+            .target("user", owner_id)
+            .client(&client)
+            .details(json!({ "playlist_id": share.playlist_id, "left": true }))
+    })
+    .await?;
+    state
+        .send_user_event(user.id, SharedPlaylistEvent::removed(&share))
+        .await;
+    Ok(Json(OperationResponse { ok: true }))
+}
+
+/// The share `share_id` of `user`, which must allow editing. The edit checks
+/// the share again in its own transaction.
+async fn collaborative_share(
+    state: &AppContext,
+    user: &AuthenticatedUser,
+    share_id: i64,
+) -> Result<SharedPlaylist, ApiError> {
+    user.scopes.require_write(Namespace::Playlists)?;
+    let share = load_shared_playlist(&state.pool, user.id, share_id).await?;
+    if share.access != ShareAccess::Collaborative {
+        return Err(ApiError::forbidden(
+            "this playlist is shared read-only".to_string(),
         ));
     }
+    Ok(share)
+}
+
+/// Sends the owner's update event for a collaborator's edit and returns the
+/// share with the edited playlist.
+async fn finish_shared_edit(
+    state: &AppContext,
+    share: SharedPlaylist,
+    (playlist, event): (PlaylistRecord, UpdateEvent),
+) -> Json<SharedPlaylist> {
+    state.send_user_event(share.owner_id, event).await;
+    Json(SharedPlaylist { playlist, ..share })
+}
+
+pub async fn patch_shared_playlist(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(share_id): Path<i64>,
+    Json(payload): Json<UpdatePlaylistRequest>,
+) -> Result<Json<SharedPlaylist>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let share = collaborative_share(&state, &user, share_id).await?;
+
+    let edit = update_playlist(
+        &state.pool,
+        share.owner_id,
+        Some(share.share_id),
+        &share.playlist.playlist.id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    Ok(finish_shared_edit(&state, share, edit).await)
+}
+
+pub async fn post_shared_playlist_items(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(share_id): Path<i64>,
+    Json(payload): Json<InsertPlaylistItemsRequest>,
+) -> Result<Json<SharedPlaylist>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let share = collaborative_share(&state, &user, share_id).await?;
+
+    let edit = insert_playlist_items(
+        &state.pool,
+        share.owner_id,
+        Some(share.share_id),
+        &share.playlist.playlist.id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    Ok(finish_shared_edit(&state, share, edit).await)
+}
+
+pub async fn patch_shared_playlist_item(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path((share_id, item_id)): Path<(i64, String)>,
+    Json(payload): Json<MovePlaylistItemRequest>,
+) -> Result<Json<SharedPlaylist>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let share = collaborative_share(&state, &user, share_id).await?;
+
+    let edit = move_playlist_item(
+        &state.pool,
+        share.owner_id,
+        Some(share.share_id),
+        &share.playlist.playlist.id,
+        &item_id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    Ok(finish_shared_edit(&state, share, edit).await)
+}
 
-    let (snapshot, event) = update_namespace(&state.pool, user.id, namespace, payload).await?;
+pub async fn delete_shared_playlist_item(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path((share_id, item_id)): Path<(i64, String)>,
+    Query(query): Query<PlaylistWriteQuery>,
+) -> Result<Json<SharedPlaylist>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let share = collaborative_share(&state, &user, share_id).await?;
+
+    let edit = remove_playlist_item(
+        &state.pool,
+        share.owner_id,
+        Some(share.share_id),
+        &share.playlist.playlist.id,
+        &item_id,
+        query,
+        &state.history_retention,
+    )
+    .await?;
+    Ok(finish_shared_edit(&state, share, edit).await)
+}
+
+/// Default and maximum number of versions returned by `GET /v1/history`.
+const HISTORY_DEFAULT_LIMIT: i64 = 20;
+const HISTORY_MAX_LIMIT: i64 = 100;
+
+pub async fn get_history(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<Revision>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let namespace = query
+        .namespace
+        .as_deref()
+        .map(Namespace::parse_data)
+        .transpose()?;
+    user.scopes
+        .require_read(namespace.unwrap_or(Namespace::Snapshot))?;
+    let limit = query
+        .limit
+        .unwrap_or(HISTORY_DEFAULT_LIMIT)
+        .clamp(1, HISTORY_MAX_LIMIT);
+
+    let revisions =
+        list_revisions(&state.pool, user.id, namespace, query.before_version, limit).await?;
+    Ok(Json(revisions))
+}
+
+pub async fn restore_history(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(version): Path<i64>,
+    Json(payload): Json<RestorePayload>,
+) -> Result<Json<crate::models::Snapshot>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let namespace = payload
+        .namespace
+        .as_deref()
+        .map(Namespace::parse_data)
+        .transpose()?;
+    user.scopes
+        .require_write(namespace.unwrap_or(Namespace::Snapshot))?;
+
+    let (snapshot, event) = restore_revision(
+        &state.pool,
+        user.id,
+        version,
+        namespace,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
     state.send_user_event(user.id, event).await;
+    Ok(Json(snapshot))
+}
 
-    Ok(Json(UpdateResponse {
-        version: snapshot.version,
-        updated_at: snapshot.updated_at,
+pub async fn patch_namespace_state(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(namespace): Path<String>,
+    Query(query): Query<PatchQuery>,
+    body: Bytes,
+) -> Result<Json<UpdateResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let namespace = Namespace::parse_data(&namespace)?;
+    user.scopes.require_write(namespace)?;
+
+    let format = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(PatchFormat::from_content_type)
+        .ok_or_else(|| {
+            ApiError::unsupported_media_type(
+                "PATCH requires Content-Type application/merge-patch+json or application/json-patch+json"
+                    .to_string(),
+            )
+        })?;
+    let patch = serde_json::from_slice(&body)
+        .map_err(|err| ApiError::bad_request(format!("invalid patch document: {err}")))?;
+
+    let (snapshot, event) = patch_namespace(
+        &state.pool,
+        user.id,
         namespace,
-        data: namespace_data(&snapshot, namespace),
+        format,
+        patch,
+        query,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+
+    Ok(Json(UpdateResponse::new(&snapshot, namespace, None)))
+}
+
+pub async fn post_command(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Json(payload): Json<CommandRequest>,
+) -> Result<Json<CommandResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require(Scope::PlaybackControl)?;
+    let response = send_command(&state, user.id, payload).await?;
+    Ok(Json(response))
+}
+
+pub async fn offer_handoff(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Json(payload): Json<HandoffOfferRequest>,
+) -> Result<Json<HandoffOffer>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require(Scope::PlaybackControl)?;
+    let offer = create_handoff(&state.pool, user.id, payload).await?;
+    state
+        .send_user_event(user.id, HandoffEvent::offered(offer.clone()))
+        .await;
+    Ok(Json(offer))
+}
+
+pub async fn get_handoff(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<HandoffOffer>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require(Scope::PlaybackControl)?;
+    let offer = active_handoff(&state.pool, user.id).await?;
+    Ok(Json(offer))
+}
+
+pub async fn claim_handoff_offer(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Path(offer_id): Path<String>,
+    Json(payload): Json<HandoffClaimRequest>,
+) -> Result<Json<HandoffClaimResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require(Scope::PlaybackControl)?;
+    // Claiming writes the offered state into `app_state`.
+    user.scopes.require_write(Namespace::AppState)?;
+    let (offer, snapshot, event) = claim_handoff(
+        &state.pool,
+        user.id,
+        &offer_id,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+    state
+        .send_user_event(user.id, HandoffEvent::claimed(offer.clone()))
+        .await;
+
+    Ok(Json(HandoffClaimResponse {
+        offer,
+        state: UpdateResponse::new(&snapshot, Namespace::AppState, None),
     }))
 }
 
+pub async fn list_devices_online(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<DevicePresence>>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    user.scopes.require(Scope::WsSubscribe)?;
+    let devices = list_online_devices(&state.pool, user.id).await?;
+    Ok(Json(devices))
+}
+
 pub async fn ws_updates(
     ws: WebSocketUpgrade,
     State(state): State<Arc<AppContext>>,
     headers: HeaderMap,
-    Query(query): Query<WsQuery>,
+    Query(mut query): Query<WsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Writes over the socket count against this credential's REST write limit.
+    let write_key = bearer_token_from_headers(&headers)
+        .or_else(|| query.token.clone())
+        .map(|token| credential_key(&token));
+    let user =
+        authenticate_with_headers_or_query_token(&state, &headers, query.token.take()).await?;
+    user.scopes.require(Scope::WsSubscribe)?;
+    let updates_rx = state.subscribe_user(user.id).await;
+    Ok(ws.on_upgrade(move |socket| {
+        handle_ws_connection(socket, state, user, updates_rx, query, write_key)
+    }))
+}
+
+pub async fn sse_updates(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Query(query): Query<EventsQuery>,
 ) -> Result<impl IntoResponse, ApiError> {
     let user = authenticate_with_headers_or_query_token(&state, &headers, query.token).await?;
+    user.scopes.require(Scope::WsSubscribe)?;
+    let last_event_id = headers
+        .get("last-event-id")
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.trim().parse::<i64>().ok())
+                .ok_or_else(|| {
+                    ApiError::bad_request("Last-Event-ID must be a version number".to_string())
+                })
+        })
+        .transpose()?;
+
     let updates_rx = state.subscribe_user(user.id).await;
-    Ok(ws.on_upgrade(move |socket| handle_ws_connection(socket, updates_rx)))
+    let events = event_stream(
+        state,
+        user,
+        updates_rx,
+        last_event_id.or(query.since_version),
+    )
+    .await;
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+pub async fn rotate_own_token(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    payload: Option<Json<RotateTokenRequest>>,
+) -> Result<Json<TokenRotatedResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    let Json(payload) = payload.unwrap_or_default();
+
+    let rotated = rotate_token(
+        &state.pool,
+        user.id,
+        user.token_id,
+        payload.grace_period_seconds,
+        |rotated| {
+            AuditEntry::new("token.rotated")
+                .actor(&user)
+                .target("token", user.token_id)
+                .client(&client)
+                .details(json!({
+                    "replacement_token_id": rotated.token.id,
+                    "previous_token_expires_at": rotated.previous_token_expires_at,
+                }))
+        },
+    )
+    .await?;
+    state
+        .send_user_event(
+            user.id,
+            TokenEvent::rotated(user.token_id, rotated.previous_token_expires_at),
+        )
+        .await;
+    Ok(Json(rotated))
+}
+
+pub async fn get_me(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<AccountResponse>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+
+    let account = load_account(&state.pool, user.id).await?;
+    Ok(Json(AccountResponse {
+        user: account,
+        token_id: user.token_id,
+        scopes: user.scopes.to_strings(),
+    }))
+}
+
+pub async fn list_my_tokens(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<TokenInfo>>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+
+    let tokens = list_user_tokens(&state.pool, user.id).await?;
+    Ok(Json(tokens))
+}
+
+/// Creates a token for the caller. Scopes default to the calling token's own
+/// and may not exceed them.
+pub async fn create_my_token(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Json(payload): Json<CreateTokenRequest>,
+) -> Result<Json<TokenCreatedResponse>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+
+    let scopes = match payload.scopes {
+        Some(scopes) => Scopes::parse(&scopes)?,
+        None => user.scopes.clone(),
+    };
+    user.scopes.require_all(&scopes)?;
+
+    let created = create_token(
+        &state.pool,
+        user.id,
+        payload.label,
+        scopes,
+        payload.expires_at,
+        |created| {
+            AuditEntry::token_created(created)
+                .actor(&user)
+                .client(&client)
+        },
+    )
+    .await?;
+    Ok(Json(created))
+}
+
+/// Managing other tokens needs full access; any token may rename or revoke
+/// itself.
+fn require_token_management(user: &AuthenticatedUser, token_id: i64) -> Result<(), ApiError> {
+    if token_id == user.token_id {
+        return Ok(());
+    }
+    user.scopes.require(Scope::All)
+}
+
+pub async fn rename_my_token(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(token_id): Path<i64>,
+    Json(payload): Json<RenameTokenRequest>,
+) -> Result<Json<TokenInfo>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_token_management(&user, token_id)?;
+
+    let token = rename_token(&state.pool, user.id, token_id, &payload.label, |token| {
+        AuditEntry::new("token.renamed")
+            .actor(&user)
+            .target("token", token_id)
+            .client(&client)
+            .details(json!({ "label": token.label }))
+    })
+    .await?;
+    Ok(Json(token))
+}
+
+pub async fn revoke_my_token(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(token_id): Path<i64>,
+) -> Result<Json<OperationResponse>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_token_management(&user, token_id)?;
+
+    revoke_token(
+        &state.pool,
+        token_id,
+        Some(user.id),
+        &AuditEntry::new("token.revoked")
+            .actor(&user)
+            .target("token", token_id)
+            .client(&client),
+    )
+    .await?;
+    state
+        .send_user_event(user.id, TokenEvent::revoked(token_id))
+        .await;
+    Ok(Json(OperationResponse { ok: true }))
+}
+
+pub async fn pair_start(
+    State(state): State<Arc<AppContext>>,
+    client: ClientInfo,
+    Json(payload): Json<PairStartRequest>,
+) -> Result<Json<PairStartResponse>, ApiError> {
+    if let Err(retry_after) = state
+        .pairing_start_limiter
+        .check(client.ip.as_deref().unwrap_or_default())
+        .await
+    {
+        return Err(ApiError::rate_limited(
+            "too many pairing requests".to_string(),
+            retry_after,
+        ));
+    }
+
+    let started = start_pairing(&state.pool, &payload.device_name).await?;
+    Ok(Json(started))
+}
+
+pub async fn pair_approve(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Json(payload): Json<PairApproveRequest>,
+) -> Result<Json<PairApproveResponse>, ApiError> {
+    let user = authenticate_with_headers(&state, &headers).await?;
+    if let Err(retry_after) = state
+        .pairing_approve_limiter
+        .check(&user.id.to_string())
+        .await
+    {
+        return Err(ApiError::rate_limited(
+            "too many pairing approvals".to_string(),
+            retry_after,
+        ));
+    }
+
+    let scopes = match payload.scopes {
+        Some(scopes) => Scopes::parse(&scopes)?,
+        None => user.scopes.clone(),
+    };
+    user.scopes.require_all(&scopes)?;
+
+    let user_code = normalize_user_code(&payload.user_code)
+        .ok_or_else(|| ApiError::not_found("unknown or expired pairing code".to_string()))?;
+    let approved = approve_pairing(&state.pool, user.id, &user_code, &scopes, |approved| {
+        AuditEntry::new("pairing.approved")
+            .actor(&user)
+            .client(&client)
+            .details(json!({
+                "device_name": approved.device_name,
+                "scopes": approved.scopes,
+            }))
+    })
+    .await?;
+    Ok(Json(approved))
+}
+
+pub async fn pair_poll(
+    State(state): State<Arc<AppContext>>,
+    client: ClientInfo,
+    Json(payload): Json<PairPollRequest>,
+) -> Result<Json<PairPollResponse>, ApiError> {
+    let result = poll_pairing(&state.pool, &payload.device_code, |token| {
+        AuditEntry::token_created(token).client(&client)
+    })
+    .await?;
+    Ok(Json(result))
+}
+
+fn require_oidc(state: &AppContext) -> Result<&OidcClient, ApiError> {
+    state
+        .oidc
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found("OIDC login is not configured".to_string()))
+}
+
+fn cookie(
+    name: &str,
+    value: &str,
+    path: &str,
+    max_age: i64,
+    same_site: &str,
+    secure: bool,
+) -> String {
+    let secure = if secure { "; Secure" } else { "" };
+    format!(
+        "{name}={value}; Path={path}; Max-Age={max_age}; HttpOnly; SameSite={same_site}{secure}"
+    )
+}
+
+pub async fn oidc_status(State(state): State<Arc<AppContext>>) -> Json<OidcStatusResponse> {
+    Json(OidcStatusResponse {
+        enabled: state.oidc.is_some(),
+    })
+}
+
+/// Starts an OIDC login by redirecting to the identity provider.
+pub async fn oidc_login(State(state): State<Arc<AppContext>>) -> Result<Response, ApiError> {
+    let oidc = require_oidc(&state)?;
+
+    let login_state = random_login_value();
+    let nonce = random_login_value();
+    create_oidc_login(&state.pool, &login_state, &nonce).await?;
+    let url = oidc.authorization_url(&login_state, &nonce).await?;
+
+    // `Lax`, because the provider's redirect back is a cross-site navigation.
+    let state_cookie = cookie(
+        oidc::STATE_COOKIE,
+        &login_state,
+        "/admin/oidc",
+        i64::from(oidc::LOGIN_TTL_SECONDS),
+        "Lax",
+        oidc.secure_cookies(),
+    );
+    Ok((
+        AppendHeaders([(header::SET_COOKIE, state_cookie)]),
+        Redirect::to(&url),
+    )
+        .into_response())
+}
+
+/// Completes an OIDC login: maps the identity to a user and starts an admin
+/// console session, which is a full-access token that expires with it.
+pub async fn oidc_callback(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Query(query): Query<OidcCallbackQuery>,
+) -> Result<Response, ApiError> {
+    let oidc = require_oidc(&state)?;
+    if let Some(error) = query.error {
+        return Err(ApiError::unauthorized(format!(
+            "identity provider returned {error}"
+        )));
+    }
+    let (Some(code), Some(login_state)) = (query.code, query.state) else {
+        return Err(ApiError::bad_request(
+            "code and state are required".to_string(),
+        ));
+    };
+    oidc::check_login_state(
+        cookie_from_headers(&headers, oidc::STATE_COOKIE).as_deref(),
+        &login_state,
+    )?;
+
+    let nonce = take_oidc_login(&state.pool, &login_state).await?;
+    let identity = oidc.exchange_code(&code, &nonce).await?;
+    let user_id = upsert_oidc_user(&state.pool, &identity).await?;
+    let session = create_token(
+        &state.pool,
+        user_id,
+        Some("admin console session".to_string()),
+        Scopes::all(),
+        Some(Utc::now() + Duration::seconds(oidc::SESSION_TTL_SECONDS)),
+        |session| {
+            AuditEntry::new("oidc.login")
+                .target("user", user_id)
+                .client(&client)
+                .details(json!({
+                    "issuer": identity.issuer,
+                    "subject": identity.subject,
+                    "session_token_id": session.id,
+                }))
+        },
+    )
+    .await?;
+
+    let secure = oidc.secure_cookies();
+    Ok((
+        AppendHeaders([
+            (
+                header::SET_COOKIE,
+                cookie(
+                    oidc::SESSION_COOKIE,
+                    &session.token,
+                    "/",
+                    oidc::SESSION_TTL_SECONDS,
+                    "Strict",
+                    secure,
+                ),
+            ),
+            (
+                header::SET_COOKIE,
+                cookie(oidc::STATE_COOKIE, "", "/admin/oidc", 0, "Lax", secure),
+            ),
+        ]),
+        Redirect::to("/admin"),
+    )
+        .into_response())
+}
+
+/// Ends the admin console session by revoking its token.
+pub async fn oidc_logout(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+) -> Result<Response, ApiError> {
+    let token = cookie_from_headers(&headers, oidc::SESSION_COOKIE)
+        .ok_or_else(|| ApiError::unauthorized("no admin console session".to_string()))?;
+    let user = authenticate_token(&state.pool, &token).await?;
+    revoke_token(
+        &state.pool,
+        user.token_id,
+        Some(user.id),
+        &AuditEntry::new("oidc.logout")
+            .actor(&user)
+            .target("token", user.token_id)
+            .client(&client),
+    )
+    .await?;
+    state
+        .send_user_event(user.id, TokenEvent::revoked(user.token_id))
+        .await;
+
+    let secure = state.oidc.as_ref().is_some_and(OidcClient::secure_cookies);
+    Ok((
+        AppendHeaders([(
+            header::SET_COOKIE,
+            cookie(oidc::SESSION_COOKIE, "", "/", 0, "Strict", secure),
+        )]),
+        Json(OperationResponse { ok: true }),
+    )
+        .into_response())
 }
 
 pub async fn admin_index() -> Html<&'static str> {
@@ -182,71 +1409,262 @@ pub async fn admin_list_users(
     State(state): State<Arc<AppContext>>,
     headers: HeaderMap,
 ) -> Result<Json<Vec<crate::models::UserSummary>>, ApiError> {
-    let user = authenticate_with_headers(&state, &headers).await?;
+    let user = authenticate_admin_console(&state, &headers).await?;
     require_admin(&user)?;
 
     let users = list_users(&state.pool).await?;
     Ok(Json(users))
 }
 
+pub async fn admin_list_quarantined_playlists(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<QuarantinedPlaylists>>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_admin(&user)?;
+
+    let quarantined = list_quarantined_playlists(&state.pool).await?;
+    Ok(Json(quarantined))
+}
+
+pub async fn admin_restore_quarantined_playlists(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(user_id): Path<i64>,
+    payload: Option<Json<RestoreQuarantinedPlaylistsRequest>>,
+) -> Result<Json<UpdateResponse>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_admin(&user)?;
+    let Json(payload) = payload.unwrap_or_default();
+
+    let corrected = payload.playlists.is_some();
+    let (snapshot, event) = restore_quarantined_playlists(
+        &state.pool,
+        user_id,
+        payload.playlists,
+        &state.history_retention,
+        &AuditEntry::new("playlist.quarantine_restored")
+            .actor(&user)
+            .target("user", user_id)
+            .client(&client)
+            .details(json!({ "corrected": corrected })),
+    )
+    .await?;
+    state.send_user_event(user_id, event).await;
+
+    Ok(Json(UpdateResponse::new(
+        &snapshot,
+        Namespace::Playlists,
+        None,
+    )))
+}
+
+pub async fn admin_dismiss_quarantined_playlists(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(user_id): Path<i64>,
+) -> Result<Json<OperationResponse>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_admin(&user)?;
+
+    dismiss_quarantined_playlists(
+        &state.pool,
+        user_id,
+        &AuditEntry::new("playlist.quarantine_dismissed")
+            .actor(&user)
+            .target("user", user_id)
+            .client(&client),
+    )
+    .await?;
+    Ok(Json(OperationResponse { ok: true }))
+}
+
 pub async fn admin_create_user(
     State(state): State<Arc<AppContext>>,
     headers: HeaderMap,
+    client: ClientInfo,
     Json(payload): Json<CreateUserRequest>,
 ) -> Result<Json<crate::models::UserCreatedResponse>, ApiError> {
-    let user = authenticate_with_headers(&state, &headers).await?;
+    let user = authenticate_admin_console(&state, &headers).await?;
     require_admin(&user)?;
 
-    let created = create_user(&state.pool, &payload.name, payload.is_admin).await?;
+    let created = create_user(&state.pool, &payload.name, payload.is_admin, |created| {
+        AuditEntry::new("user.created")
+            .actor(&user)
+            .target("user", created.id)
+            .client(&client)
+            .details(json!({ "name": created.name, "is_admin": created.is_admin }))
+    })
+    .await?;
     Ok(Json(created))
 }
 
 pub async fn admin_create_token(
     State(state): State<Arc<AppContext>>,
     headers: HeaderMap,
+    client: ClientInfo,
     Path(user_id): Path<i64>,
     Json(payload): Json<CreateTokenRequest>,
 ) -> Result<Json<TokenCreatedResponse>, ApiError> {
-    let user = authenticate_with_headers(&state, &headers).await?;
+    let user = authenticate_admin_console(&state, &headers).await?;
     require_admin(&user)?;
 
-    let (id, label, token_prefix, token, created_at) =
-        create_token(&state.pool, user_id, payload.label).await?;
-
-    Ok(Json(TokenCreatedResponse {
-        id,
+    let scopes = payload
+        .scopes
+        .as_deref()
+        .map(Scopes::parse)
+        .transpose()?
+        .unwrap_or_else(Scopes::all);
+    let created = create_token(
+        &state.pool,
         user_id,
-        label,
-        token_prefix,
-        token,
-        created_at,
-    }))
+        payload.label,
+        scopes,
+        payload.expires_at,
+        |created| {
+            AuditEntry::token_created(created)
+                .actor(&user)
+                .client(&client)
+        },
+    )
+    .await?;
+
+    Ok(Json(created))
 }
 
 pub async fn admin_revoke_token(
     State(state): State<Arc<AppContext>>,
     headers: HeaderMap,
+    client: ClientInfo,
     Path(token_id): Path<i64>,
 ) -> Result<Json<OperationResponse>, ApiError> {
-    let user = authenticate_with_headers(&state, &headers).await?;
+    let user = authenticate_admin_console(&state, &headers).await?;
     require_admin(&user)?;
 
-    revoke_token(&state.pool, token_id).await?;
+    let owner_id = revoke_token(
+        &state.pool,
+        token_id,
+        None,
+        &AuditEntry::new("token.revoked")
+            .actor(&user)
+            .target("token", token_id)
+            .client(&client),
+    )
+    .await?;
+    state
+        .send_user_event(owner_id, TokenEvent::revoked(token_id))
+        .await;
     Ok(Json(OperationResponse { ok: true }))
 }
 
 pub async fn admin_set_user_disabled(
     State(state): State<Arc<AppContext>>,
     headers: HeaderMap,
+    client: ClientInfo,
     Path(user_id): Path<i64>,
     Json(payload): Json<SetUserDisabledRequest>,
 ) -> Result<Json<OperationResponse>, ApiError> {
-    let user = authenticate_with_headers(&state, &headers).await?;
+    let user = authenticate_admin_console(&state, &headers).await?;
     require_admin(&user)?;
 
-    set_user_disabled(&state.pool, user_id, payload.disabled).await?;
+    let action = if payload.disabled {
+        "user.disabled"
+    } else {
+        "user.enabled"
+    };
+    set_user_disabled(
+        &state.pool,
+        user_id,
+        payload.disabled,
+        &AuditEntry::new(action)
+            .actor(&user)
+            .target("user", user_id)
+            .client(&client),
+    )
+    .await?;
+    if payload.disabled {
+        state
+            .send_user_event(user_id, AccountEvent::disabled())
+            .await;
+    }
     Ok(Json(OperationResponse { ok: true }))
 }
 
+pub async fn admin_delete_user(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    client: ClientInfo,
+    Path(user_id): Path<i64>,
+    Query(query): Query<DeleteUserQuery>,
+) -> Result<Json<DeleteUserResponse>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_admin(&user)?;
+    if user_id == user.id {
+        return Err(ApiError::bad_request(
+            "you cannot delete your own account".to_string(),
+        ));
+    }
+    let grace_days = deletion::grace_period(query.grace_days)?;
+    let confirm = query.confirm.as_deref().unwrap_or_default();
+
+    let delete_after = if let Some(grace_days) = grace_days {
+        let (_, delete_after) = schedule_user_deletion(
+            &state.pool,
+            user_id,
+            confirm,
+            grace_days,
+            |name, delete_after| {
+                AuditEntry::new("user.deletion_scheduled")
+                    .actor(&user)
+                    .target("user", user_id)
+                    .client(&client)
+                    .details(json!({ "name": name, "delete_after": delete_after }))
+            },
+        )
+        .await?;
+        Some(delete_after)
+    } else {
+        delete_user(&state.pool, user_id, confirm, |name| {
+            AuditEntry::new("user.deleted")
+                .actor(&user)
+                .target("user", user_id)
+                .client(&client)
+                .details(json!({ "name": name }))
+        })
+        .await?;
+        None
+    };
+
+    state
+        .send_user_event(user_id, AccountEvent::deleted())
+        .await;
+    Ok(Json(DeleteUserResponse {
+        ok: true,
+        delete_after,
+    }))
+}
+
+const AUDIT_DEFAULT_LIMIT: i64 = 50;
+const AUDIT_MAX_LIMIT: i64 = 200;
+
+pub async fn admin_list_audit_events(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Vec<AuditEvent>>, ApiError> {
+    let user = authenticate_admin_console(&state, &headers).await?;
+    require_admin(&user)?;
+    let limit = query
+        .limit
+        .unwrap_or(AUDIT_DEFAULT_LIMIT)
+        .clamp(1, AUDIT_MAX_LIMIT);
+
+    let events = list_audit_events(&state.pool, &query, limit).await?;
+    Ok(Json(events))
+}
+
 const ADMIN_HTML: &str = include_str!("../static/admin/index.html");
 const ADMIN_LOGIN_HTML: &str = include_str!("../static/admin/login.html");
+const SHARE_HTML: &str = include_str!("../static/share/index.html");
@@ -0,0 +1,75 @@
+use serde_json::{Map, Value, json};
+
+use crate::{errors::ApiError, models::HandoffOffer};
+
+/// Lifetime of a handoff offer when the client does not ask for one.
+const DEFAULT_TTL_SECONDS: i64 = 60;
+const MAX_TTL_SECONDS: i64 = 300;
+
+/// `app_state` key holding the playback written when an offer is claimed.
+const PLAYBACK_KEY: &str = "playback";
+
+pub fn ttl_seconds(requested: Option<i64>) -> Result<i64, ApiError> {
+    match requested {
+        None => Ok(DEFAULT_TTL_SECONDS),
+        Some(ttl) if (1..=MAX_TTL_SECONDS).contains(&ttl) => Ok(ttl),
+        Some(_) => Err(ApiError::bad_request(format!(
+            "ttl_seconds must be between 1 and {MAX_TTL_SECONDS}"
+        ))),
+    }
+}
+
+/// Sets `app_state.playback` to the claimed offer, keeping the other keys.
+/// A non-object `app_state` is replaced.
+pub fn claimed_app_state(app_state: Value, offer: &HandoffOffer) -> Value {
+    let mut state = match app_state {
+        Value::Object(map) => map,
+        _ => Map::new(),
+    };
+    state.insert(
+        PLAYBACK_KEY.to_string(),
+        json!({
+            "track": offer.track,
+            "position_ms": offer.position_ms,
+            "queue": offer.queue,
+            "active_client_id": offer.claimed_by,
+            "handoff_id": offer.id,
+            "updated_at": offer.claimed_at,
+        }),
+    );
+    Value::Object(state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{claimed_app_state, ttl_seconds};
+    use crate::models::HandoffOffer;
+    use chrono::Utc;
+    use serde_json::json;
+
+    #[test]
+    fn claim_replaces_only_playback() {
+        let now = Utc::now();
+        let offer = HandoffOffer {
+            id: "h1".to_string(),
+            source_client_id: "desktop-main".to_string(),
+            track: json!({ "id": "t1", "title": "Song" }),
+            position_ms: 93_000,
+            queue: json!(["t2", "t3"]),
+            created_at: now,
+            expires_at: now,
+            claimed_by: Some("android-xyz".to_string()),
+            claimed_at: Some(now),
+        };
+        let app_state = json!({ "volume": 0.4, "playback": { "track": null } });
+
+        let state = claimed_app_state(app_state, &offer);
+
+        assert_eq!(state["volume"], 0.4);
+        assert_eq!(state["playback"]["track"]["id"], "t1");
+        assert_eq!(state["playback"]["position_ms"], 93_000);
+        assert_eq!(state["playback"]["active_client_id"], "android-xyz");
+        assert_eq!(ttl_seconds(None).expect("default"), 60);
+        assert!(ttl_seconds(Some(0)).is_err());
+    }
+}
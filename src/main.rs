@@ -1,14 +1,30 @@
 mod app;
+mod audit;
+mod commands;
 mod config;
 mod db;
+mod deletion;
 mod errors;
+mod fanout;
 mod handlers;
+mod handoff;
+mod merge;
 mod models;
+mod oidc;
+mod ordering;
+mod pairing;
+mod patch;
+mod playlist_files;
+mod playlists;
+mod rate_limit;
+mod replay;
+mod scopes;
 mod shutdown;
+mod sse;
 mod state;
 mod ws;
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc};
 
 use sqlx::PgPool;
 use tracing::info;
@@ -17,6 +33,7 @@ use crate::{
     app::build_router,
     config::AppConfig,
     db::{ensure_bootstrap_admin, ensure_schema},
+    oidc::OidcClient,
     shutdown::shutdown_signal,
     state::AppContext,
 };
@@ -46,15 +63,27 @@ async fn main() -> anyhow::Result<()> {
     )
     .await?;
 
-    let state = Arc::new(AppContext::new(pool));
+    let oidc = config.oidc.map(OidcClient::new).transpose()?;
+    let state = Arc::new(AppContext::new(
+        pool,
+        config.history_retention,
+        oidc,
+        config.rate_limits,
+        config.trust_proxy_headers,
+    ));
+    tokio::spawn(fanout::relay_notifications(state.clone()));
+    tokio::spawn(deletion::purge_scheduled_deletions(state.clone()));
 
     let app = build_router(state, config.cors_allowed_origins, config.max_body_size);
 
     info!(address = %config.bind_address, "sync server listening");
     let listener = tokio::net::TcpListener::bind(config.bind_address).await?;
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await?;
 
     Ok(())
 }
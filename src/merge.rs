@@ -0,0 +1,376 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::errors::ApiError;
+
+/// How the server resolves a write whose `expected_version` is behind the
+/// namespace's current version, instead of rejecting it with `409`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MergeStrategy {
+    /// Keep the server's value; the client's changes are discarded.
+    ServerWins,
+    /// Replace the server's value with the client's.
+    ClientWins,
+    /// Merge objects key by key (recursively); the server wins where both sides
+    /// changed the same key.
+    KeyMerge,
+    /// Like `key_merge`, but also merges arrays of objects by their `id` field.
+    MergeById,
+}
+
+/// Outcome of a server-side merge. Entries are JSON Pointers into the
+/// namespace value; array items are addressed by their `id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MergeReport {
+    pub strategy: MergeStrategy,
+    /// Namespace version used as the merge base (the client's `expected_version`).
+    pub base_version: i64,
+    /// Client changes applied on top of newer server data.
+    pub merged_keys: Vec<String>,
+    /// Server changes replaced by the client's value.
+    pub overwritten_keys: Vec<String>,
+    /// Client changes dropped in favor of the server's value.
+    pub discarded_keys: Vec<String>,
+}
+
+/// Three-way merges `client` into `server` using `base` as the common ancestor.
+pub fn three_way_merge(
+    strategy: MergeStrategy,
+    base_version: i64,
+    base: &Value,
+    server: &Value,
+    client: &Value,
+) -> Result<(Value, MergeReport), ApiError> {
+    let mut report = MergeReport {
+        strategy,
+        base_version,
+        merged_keys: Vec::new(),
+        overwritten_keys: Vec::new(),
+        discarded_keys: Vec::new(),
+    };
+
+    let merged = match strategy {
+        MergeStrategy::ServerWins => {
+            for (key, base, server, client) in top_level_units(base, server, client) {
+                if client != server && client != base {
+                    report.discarded_keys.push(key);
+                }
+            }
+            server.clone()
+        }
+        MergeStrategy::ClientWins => {
+            for (key, base, server, client) in top_level_units(base, server, client) {
+                if client == server {
+                    continue;
+                }
+                if server != base {
+                    report.overwritten_keys.push(key);
+                } else {
+                    report.merged_keys.push(key);
+                }
+            }
+            client.clone()
+        }
+        MergeStrategy::KeyMerge | MergeStrategy::MergeById => {
+            let by_id = strategy == MergeStrategy::MergeById;
+            if !(server.is_object() || by_id && server.is_array()) {
+                return Err(ApiError::bad_request(format!(
+                    "merge strategy {} is not applicable to this namespace value",
+                    strategy.as_str()
+                )));
+            }
+            merge_node(
+                Some(base),
+                Some(server),
+                Some(client),
+                "",
+                by_id,
+                &mut report,
+            )
+            .unwrap_or(Value::Null)
+        }
+    };
+
+    Ok((merged, report))
+}
+
+impl MergeStrategy {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ServerWins => "server_wins",
+            Self::ClientWins => "client_wins",
+            Self::KeyMerge => "key_merge",
+            Self::MergeById => "merge_by_id",
+        }
+    }
+}
+
+fn pointer(path: &str, key: &str) -> String {
+    format!("{path}/{}", key.replace('~', "~0").replace('/', "~1"))
+}
+
+fn item_id(item: &Value) -> Option<String> {
+    match item.get("id")? {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Keyed view of an object (by key) or of an array whose items all carry an `id`.
+fn units(value: &Value) -> Option<Vec<(String, &Value)>> {
+    match value {
+        Value::Object(map) => Some(
+            map.iter()
+                .map(|(key, value)| (key.clone(), value))
+                .collect(),
+        ),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| item_id(item).map(|id| (id, item)))
+            .collect(),
+        _ => None,
+    }
+}
+
+fn find<'a>(units: &[(String, &'a Value)], key: &str) -> Option<&'a Value> {
+    units
+        .iter()
+        .find(|(candidate, _)| candidate == key)
+        .map(|(_, value)| *value)
+}
+
+type Unit<'a> = (
+    String,
+    Option<&'a Value>,
+    Option<&'a Value>,
+    Option<&'a Value>,
+);
+
+/// Splits the three values into comparable top-level units, or one root unit
+/// when they are not all objects / all id-keyed arrays.
+fn top_level_units<'a>(base: &'a Value, server: &'a Value, client: &'a Value) -> Vec<Unit<'a>> {
+    let same_kind =
+        base.is_object() == server.is_object() && server.is_object() == client.is_object();
+    match (units(base), units(server), units(client)) {
+        (Some(base_units), Some(server_units), Some(client_units)) if same_kind => {
+            let mut keys: Vec<String> = Vec::new();
+            for (key, _) in server_units.iter().chain(&client_units).chain(&base_units) {
+                if !keys.contains(key) {
+                    keys.push(key.clone());
+                }
+            }
+            keys.into_iter()
+                .map(|key| {
+                    let base = find(&base_units, &key);
+                    let server = find(&server_units, &key);
+                    let client = find(&client_units, &key);
+                    (pointer("", &key), base, server, client)
+                })
+                .collect()
+        }
+        _ => vec![(String::new(), Some(base), Some(server), Some(client))],
+    }
+}
+
+fn merge_node(
+    base: Option<&Value>,
+    server: Option<&Value>,
+    client: Option<&Value>,
+    path: &str,
+    by_id: bool,
+    report: &mut MergeReport,
+) -> Option<Value> {
+    if client == server || client == base {
+        return server.cloned();
+    }
+    if server == base {
+        report.merged_keys.push(path.to_string());
+        return client.cloned();
+    }
+
+    // Both sides changed this node differently; descend if it is a container.
+    match (base, server, client) {
+        (base, Some(Value::Object(server)), Some(Value::Object(client)))
+            if base.is_none_or(Value::is_object) =>
+        {
+            let empty = Map::new();
+            let base = base.and_then(Value::as_object).unwrap_or(&empty);
+            Some(Value::Object(merge_objects(
+                base, server, client, path, by_id, report,
+            )))
+        }
+        (base, Some(server @ Value::Array(_)), Some(client @ Value::Array(_))) if by_id => {
+            let empty = Value::Array(Vec::new());
+            let base = base.unwrap_or(&empty);
+            match (units(base), units(server), units(client)) {
+                (Some(base), Some(server), Some(client)) => Some(Value::Array(merge_items(
+                    &base, &server, &client, path, report,
+                ))),
+                _ => {
+                    report.discarded_keys.push(path.to_string());
+                    Some(server.clone())
+                }
+            }
+        }
+        _ => {
+            report.discarded_keys.push(path.to_string());
+            server.cloned()
+        }
+    }
+}
+
+fn merge_objects(
+    base: &Map<String, Value>,
+    server: &Map<String, Value>,
+    client: &Map<String, Value>,
+    path: &str,
+    by_id: bool,
+    report: &mut MergeReport,
+) -> Map<String, Value> {
+    let mut merged = Map::new();
+    let keys = server
+        .keys()
+        .chain(client.keys().filter(|key| !server.contains_key(*key)))
+        .chain(
+            base.keys()
+                .filter(|key| !server.contains_key(*key) && !client.contains_key(*key)),
+        );
+
+    for key in keys {
+        let value = merge_node(
+            base.get(key),
+            server.get(key),
+            client.get(key),
+            &pointer(path, key),
+            by_id,
+            report,
+        );
+        if let Some(value) = value {
+            merged.insert(key.clone(), value);
+        }
+    }
+
+    merged
+}
+
+/// Merges id-keyed items. Server order is kept; items only the client added
+/// are appended in client order.
+fn merge_items(
+    base: &[(String, &Value)],
+    server: &[(String, &Value)],
+    client: &[(String, &Value)],
+    path: &str,
+    report: &mut MergeReport,
+) -> Vec<Value> {
+    let server_ids = server.iter().map(|(id, _)| id);
+    let client_only = client
+        .iter()
+        .map(|(id, _)| id)
+        .filter(|id| find(server, id).is_none());
+
+    server_ids
+        .chain(client_only)
+        .filter_map(|id| {
+            merge_node(
+                find(base, id),
+                find(server, id),
+                find(client, id),
+                &pointer(path, id),
+                true,
+                report,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MergeStrategy, three_way_merge};
+    use serde_json::json;
+
+    #[test]
+    fn key_merge_combines_independent_changes() {
+        let base = json!({ "volume": 0.5, "theme": { "dark": false, "accent": "blue" } });
+        let server = json!({ "volume": 0.8, "theme": { "dark": false, "accent": "red" } });
+        let client =
+            json!({ "volume": 0.5, "theme": { "dark": true, "accent": "green" }, "eq": "rock" });
+
+        let (merged, report) =
+            three_way_merge(MergeStrategy::KeyMerge, 4, &base, &server, &client).expect("merges");
+
+        assert_eq!(
+            merged,
+            json!({ "volume": 0.8, "theme": { "dark": true, "accent": "red" }, "eq": "rock" })
+        );
+        assert_eq!(report.merged_keys, vec!["/theme/dark", "/eq"]);
+        assert_eq!(report.discarded_keys, vec!["/theme/accent"]);
+        assert!(report.overwritten_keys.is_empty());
+    }
+
+    #[test]
+    fn merge_by_id_merges_playlists() {
+        let base = json!([
+            { "id": "p1", "name": "Road trip" },
+            { "id": "p2", "name": "Focus" },
+            { "id": "p3", "name": "Old" }
+        ]);
+        let server = json!([
+            { "id": "p1", "name": "Road Trip 2026" },
+            { "id": "p2", "name": "Focus" },
+            { "id": "p3", "name": "Old" },
+            { "id": "p4", "name": "Server new" }
+        ]);
+        let client = json!([
+            { "id": "p1", "name": "Road trip" },
+            { "id": "p2", "name": "Deep Focus" },
+            { "id": "p5", "name": "Client new" }
+        ]);
+
+        let (merged, report) =
+            three_way_merge(MergeStrategy::MergeById, 2, &base, &server, &client).expect("merges");
+
+        assert_eq!(
+            merged,
+            json!([
+                { "id": "p1", "name": "Road Trip 2026" },
+                { "id": "p2", "name": "Deep Focus" },
+                { "id": "p4", "name": "Server new" },
+                { "id": "p5", "name": "Client new" }
+            ])
+        );
+        assert_eq!(report.merged_keys, vec!["/p2", "/p3", "/p5"]);
+    }
+
+    #[test]
+    fn whole_value_strategies_report_conflicting_keys() {
+        let base = json!({ "a": 1, "b": 1 });
+        let server = json!({ "a": 2, "b": 1 });
+        let client = json!({ "a": 3, "b": 2 });
+
+        let (merged, report) =
+            three_way_merge(MergeStrategy::ServerWins, 1, &base, &server, &client).expect("merges");
+        assert_eq!(merged, server);
+        assert_eq!(report.discarded_keys, vec!["/a", "/b"]);
+
+        let (merged, report) =
+            three_way_merge(MergeStrategy::ClientWins, 1, &base, &server, &client).expect("merges");
+        assert_eq!(merged, client);
+        assert_eq!(report.overwritten_keys, vec!["/a"]);
+        assert_eq!(report.merged_keys, vec!["/b"]);
+    }
+
+    #[test]
+    fn key_merge_rejects_non_object_values() {
+        let error = three_way_merge(
+            MergeStrategy::KeyMerge,
+            1,
+            &json!([]),
+            &json!([1]),
+            &json!([2]),
+        )
+        .expect_err("not applicable");
+        assert!(format!("{error:?}").contains("not applicable"));
+    }
+}
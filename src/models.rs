@@ -1,8 +1,14 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use serde_json::{Value, json};
+use serde_json::{Map, Value, json};
 
-use crate::errors::ApiError;
+use crate::{
+    errors::ApiError,
+    merge::{MergeReport, MergeStrategy},
+    playlist_files::ImportEntryError,
+    playlists::Playlist,
+    scopes::Scopes,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Snapshot {
@@ -12,21 +18,115 @@ pub struct Snapshot {
     pub playlists: Value,
     pub provider_configuration: Value,
     pub settings: Value,
+    pub namespace_versions: NamespaceVersions,
+}
+
+/// Version of each namespace. A namespace's version only advances when that
+/// namespace is written, independently of the global snapshot `version`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct NamespaceVersions {
+    pub app_state: i64,
+    pub playlists: i64,
+    pub provider_configuration: i64,
+    pub settings: i64,
+}
+
+impl NamespaceVersions {
+    /// The version of `namespace`, or `None` for `snapshot`, which has no
+    /// version of its own.
+    pub fn get(&self, namespace: Namespace) -> Option<i64> {
+        match namespace {
+            Namespace::AppState => Some(self.app_state),
+            Namespace::Playlists => Some(self.playlists),
+            Namespace::ProviderConfiguration => Some(self.provider_configuration),
+            Namespace::Settings => Some(self.settings),
+            Namespace::Snapshot => None,
+        }
+    }
+
+    /// Steps `namespace` back one version; `snapshot` is left alone.
+    pub fn decrement(&mut self, namespace: Namespace) {
+        let version = match namespace {
+            Namespace::AppState => &mut self.app_state,
+            Namespace::Playlists => &mut self.playlists,
+            Namespace::ProviderConfiguration => &mut self.provider_configuration,
+            Namespace::Settings => &mut self.settings,
+            Namespace::Snapshot => return,
+        };
+        *version -= 1;
+    }
+}
+
+impl Snapshot {
+    /// The version of `namespace`; for `snapshot` that is the global version.
+    pub fn namespace_version(&self, namespace: Namespace) -> i64 {
+        self.namespace_versions
+            .get(namespace)
+            .unwrap_or(self.version)
+    }
+}
+
+/// Response to `GET /v1/snapshot?since_version=` when history still covers
+/// `since_version`: only the namespaces listed in `changed` are included.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotDelta {
+    pub version: i64,
+    pub updated_at: DateTime<Utc>,
+    pub since_version: i64,
+    pub namespace_versions: NamespaceVersions,
+    pub changed: Vec<Namespace>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_state: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlists: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider_configuration: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub settings: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct UpdateResponse {
     pub version: i64,
+    pub namespace_version: i64,
     pub updated_at: DateTime<Utc>,
     pub namespace: Namespace,
     pub data: Value,
+    /// Present when the write was three-way merged with newer server data.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge: Option<MergeReport>,
+}
+
+impl UpdateResponse {
+    pub fn new(snapshot: &Snapshot, namespace: Namespace, merge: Option<MergeReport>) -> Self {
+        Self {
+            version: snapshot.version,
+            namespace_version: snapshot.namespace_version(namespace),
+            updated_at: snapshot.updated_at,
+            namespace,
+            data: namespace_data(snapshot, namespace),
+            merge,
+        }
+    }
+}
+
+/// Result of reading a snapshot, with the playlists shared with the user,
+/// relative to a client's last known versions.
+#[derive(Debug, Clone)]
+pub enum SnapshotSince {
+    NotModified,
+    Delta(SyncResponse<SnapshotDelta>),
+    Full(SyncResponse<Snapshot>),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NamespacePayload {
+    /// Checked against the namespace's own version, not the global version.
     pub expected_version: Option<i64>,
     pub client_id: Option<String>,
     pub data: Value,
+    /// Opt-in conflict resolution used when `expected_version` is stale.
+    pub merge_strategy: Option<MergeStrategy>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -46,14 +146,370 @@ pub struct HealthResponse {
     pub timestamp: DateTime<Utc>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct UpdateEvent {
     pub event_type: String,
     pub namespace: Namespace,
     pub version: i64,
+    pub namespace_versions: NamespaceVersions,
     pub updated_at: DateTime<Utc>,
     pub source_client_id: Option<String>,
+    /// Set when the write went through `/v1/playlists` and changed this playlist.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub playlist_id: Option<String>,
+    /// Shares that ended because the write removed their playlist, announced
+    /// to their recipients by `AppContext::send_user_event`.
+    #[serde(skip)]
+    pub ended_shares: Vec<PlaylistShare>,
+}
+
+/// A `/v1/ws` connection registered as online.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DevicePresence {
+    pub client_id: Option<String>,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+}
+
+/// `device_online` / `device_offline`, pushed when a device's first
+/// connection opens or its last one closes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceEvent {
+    pub event_type: String,
+    pub device: DevicePresence,
+}
+
+impl DeviceEvent {
+    pub fn online(device: DevicePresence) -> Self {
+        Self {
+            event_type: "device_online".to_string(),
+            device,
+        }
+    }
+
+    pub fn offline(device: DevicePresence) -> Self {
+        Self {
+            event_type: "device_offline".to_string(),
+            device,
+        }
+    }
+}
+
+/// Remote playback actions one device can ask another to perform.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandAction {
+    Play,
+    Pause,
+    /// Requires `params.position_ms`.
+    Seek,
+    Next,
+    /// Hands playback over to the target device.
+    TransferPlayback,
+}
+
+/// Body of `POST /v1/commands`.
+#[derive(Debug, Deserialize)]
+pub struct CommandRequest {
+    pub target_client_id: String,
+    /// The sending device, passed on to the target.
+    pub client_id: Option<String>,
+    pub action: CommandAction,
+    #[serde(default)]
+    pub params: Value,
+}
+
+/// `command`, pushed to the target device's `/v1/ws` connections.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandEvent {
+    pub event_type: String,
+    pub command_id: String,
+    pub action: CommandAction,
+    pub params: Value,
+    pub target_client_id: String,
+    pub source_client_id: Option<String>,
+    /// The sender stops waiting for a result after this time.
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Result reported by the target device, returned from `POST /v1/commands`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResponse {
+    pub command_id: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Routes a [`CommandResponse`] back to the instance waiting for it. Not
+/// forwarded to clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CommandResultEvent {
+    pub event_type: String,
+    #[serde(flatten)]
+    pub response: CommandResponse,
+}
+
+/// Body of `POST /v1/handoff`: the playback a device offers to hand over.
+#[derive(Debug, Deserialize)]
+pub struct HandoffOfferRequest {
+    pub client_id: String,
+    pub track: Value,
+    pub position_ms: i64,
+    #[serde(default = "empty_queue")]
+    pub queue: Value,
+    /// Seconds the offer stays claimable; defaults to 60, capped at 300.
+    pub ttl_seconds: Option<i64>,
+}
+
+fn empty_queue() -> Value {
+    Value::Array(Vec::new())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HandoffClaimRequest {
+    pub client_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandoffOffer {
+    pub id: String,
+    pub source_client_id: String,
+    pub track: Value,
+    pub position_ms: i64,
+    pub queue: Value,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub claimed_by: Option<String>,
+    pub claimed_at: Option<DateTime<Utc>>,
+}
+
+/// Response of `POST /v1/handoff/{offer_id}/claim`: the claimed offer and the
+/// `app_state` write that records the new active device.
+#[derive(Debug, Clone, Serialize)]
+pub struct HandoffClaimResponse {
+    pub offer: HandoffOffer,
+    pub state: UpdateResponse,
+}
+
+/// `handoff_offered` / `handoff_claimed`, pushed to all of the user's devices.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HandoffEvent {
+    pub event_type: String,
+    pub offer: HandoffOffer,
+}
+
+impl HandoffEvent {
+    pub fn offered(offer: HandoffOffer) -> Self {
+        Self {
+            event_type: "handoff_offered".to_string(),
+            offer,
+        }
+    }
+
+    pub fn claimed(offer: HandoffOffer) -> Self {
+        Self {
+            event_type: "handoff_claimed".to_string(),
+            offer,
+        }
+    }
+}
+
+/// Sent to a user's connections right before they are closed because the
+/// account is being deleted or disabled. Only `account_deleted` and
+/// `account_disabled` deserialize, so other events with just an `event_type`
+/// never turn into one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(try_from = "AccountEventFields")]
+pub struct AccountEvent {
+    pub event_type: String,
+}
+
+#[derive(Deserialize)]
+struct AccountEventFields {
+    event_type: String,
+}
+
+impl TryFrom<AccountEventFields> for AccountEvent {
+    type Error = String;
+
+    fn try_from(fields: AccountEventFields) -> Result<Self, Self::Error> {
+        match fields.event_type.as_str() {
+            "account_deleted" => Ok(Self::deleted()),
+            "account_disabled" => Ok(Self::disabled()),
+            other => Err(format!("unknown account event '{other}'")),
+        }
+    }
+}
+
+impl AccountEvent {
+    pub fn deleted() -> Self {
+        Self {
+            event_type: "account_deleted".to_string(),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self {
+            event_type: "account_disabled".to_string(),
+        }
+    }
+}
+
+/// `token_revoked` / `token_rotated`, sent on a user's channel when one of
+/// their tokens stops working at `expires_at`: right away when revoked, after
+/// the grace period when rotated. Only connections opened with `token_id`
+/// receive it, and they close once it expires.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEvent {
+    pub event_type: String,
+    pub token_id: i64,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl TokenEvent {
+    pub fn revoked(token_id: i64) -> Self {
+        Self {
+            event_type: "token_revoked".to_string(),
+            token_id,
+            expires_at: Utc::now(),
+        }
+    }
+
+    pub fn rotated(token_id: i64, expires_at: DateTime<Utc>) -> Self {
+        Self {
+            event_type: "token_rotated".to_string(),
+            token_id,
+            expires_at,
+        }
+    }
+}
+
+/// `shared_playlist_updated` / `shared_playlist_removed`, pushed to a user a
+/// playlist is shared with when the owner or a collaborator changes it, or
+/// when the share ends.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SharedPlaylistEvent {
+    pub event_type: String,
+    pub share_id: i64,
+    pub playlist_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_client_id: Option<String>,
+}
+
+impl SharedPlaylistEvent {
+    pub fn updated(share: &PlaylistShare, source_client_id: Option<String>) -> Self {
+        Self {
+            event_type: "shared_playlist_updated".to_string(),
+            share_id: share.id,
+            playlist_id: share.playlist_id.clone(),
+            source_client_id,
+        }
+    }
+
+    pub fn removed(share: &PlaylistShare) -> Self {
+        Self {
+            event_type: "shared_playlist_removed".to_string(),
+            share_id: share.id,
+            playlist_id: share.playlist_id.clone(),
+            source_client_id: None,
+        }
+    }
+}
+
+/// Payload of a user's broadcast channel. Every variant carries its own
+/// `event_type`, so they serialize without an extra tag. Variants are tried
+/// in order when deserializing and are told apart by their required fields.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UserEvent {
+    State(UpdateEvent),
+    Device(DeviceEvent),
+    Command(CommandEvent),
+    CommandResult(CommandResultEvent),
+    Handoff(HandoffEvent),
+    SharedPlaylist(SharedPlaylistEvent),
+    Token(TokenEvent),
+    /// Last, since its only field is shared by every other event.
+    Account(AccountEvent),
+}
+
+impl From<UpdateEvent> for UserEvent {
+    fn from(event: UpdateEvent) -> Self {
+        Self::State(event)
+    }
+}
+
+impl From<DeviceEvent> for UserEvent {
+    fn from(event: DeviceEvent) -> Self {
+        Self::Device(event)
+    }
+}
+
+impl From<CommandEvent> for UserEvent {
+    fn from(event: CommandEvent) -> Self {
+        Self::Command(event)
+    }
+}
+
+impl From<CommandResultEvent> for UserEvent {
+    fn from(event: CommandResultEvent) -> Self {
+        Self::CommandResult(event)
+    }
+}
+
+impl From<HandoffEvent> for UserEvent {
+    fn from(event: HandoffEvent) -> Self {
+        Self::Handoff(event)
+    }
+}
+
+impl From<SharedPlaylistEvent> for UserEvent {
+    fn from(event: SharedPlaylistEvent) -> Self {
+        Self::SharedPlaylist(event)
+    }
+}
+
+impl From<TokenEvent> for UserEvent {
+    fn from(event: TokenEvent) -> Self {
+        Self::Token(event)
+    }
+}
+
+impl From<AccountEvent> for UserEvent {
+    fn from(event: AccountEvent) -> Self {
+        Self::Account(event)
+    }
+}
+
+/// Events a subscriber missed, rebuilt from revision history.
+#[derive(Debug, Clone)]
+pub enum Replay {
+    Events(Vec<UpdateEvent>),
+    /// History no longer covers the gap; the client must refetch the snapshot
+    /// at (or after) this version.
+    ResyncRequired(i64),
+}
+
+/// Pushed instead of replayed events when the gap cannot be filled.
+#[derive(Debug, Clone, Serialize)]
+pub struct ResyncRequiredEvent {
+    pub event_type: &'static str,
+    pub version: i64,
+}
+
+impl ResyncRequiredEvent {
+    pub fn new(version: i64) -> Self {
+        Self {
+            event_type: "resync_required",
+            version,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -61,9 +517,13 @@ pub struct AuthenticatedUser {
     pub id: i64,
     pub name: String,
     pub is_admin: bool,
+    /// The token used for this request, its scopes and when it expires.
+    pub token_id: i64,
+    pub scopes: Scopes,
+    pub expires_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Copy)]
+#[derive(Debug, Clone, Serialize, Deserialize, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum Namespace {
     AppState,
@@ -74,6 +534,47 @@ pub enum Namespace {
 }
 
 impl Namespace {
+    /// The four data namespaces stored in a user's sync document, in column order.
+    pub const DATA: [Namespace; 4] = [
+        Namespace::AppState,
+        Namespace::Playlists,
+        Namespace::ProviderConfiguration,
+        Namespace::Settings,
+    ];
+
+    /// Stable identifier used for database columns and history rows.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AppState => "app_state",
+            Self::Playlists => "playlists",
+            Self::ProviderConfiguration => "provider_configuration",
+            Self::Settings => "settings",
+            Self::Snapshot => "snapshot",
+        }
+    }
+
+    pub fn from_str_id(value: &str) -> Option<Self> {
+        match value {
+            "app_state" => Some(Self::AppState),
+            "playlists" => Some(Self::Playlists),
+            "provider_configuration" => Some(Self::ProviderConfiguration),
+            "settings" => Some(Self::Settings),
+            "snapshot" => Some(Self::Snapshot),
+            _ => None,
+        }
+    }
+
+    /// Parses a namespace that holds data of its own, rejecting `snapshot`.
+    pub fn parse_data(value: &str) -> Result<Self, ApiError> {
+        let namespace = Self::parse(value)?;
+        if namespace == Self::Snapshot {
+            return Err(ApiError::bad_request(
+                "snapshot is only available via /v1/snapshot".into(),
+            ));
+        }
+        Ok(namespace)
+    }
+
     pub fn parse(value: &str) -> Result<Self, ApiError> {
         match value {
             "app-state" => Ok(Self::AppState),
@@ -90,11 +591,170 @@ impl Namespace {
 #[derive(Debug, Deserialize)]
 pub struct SnapshotQuery {
     pub since_version: Option<i64>,
+    /// `shared_version` of the client's last snapshot response.
+    pub shared_since_version: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PatchQuery {
+    pub expected_version: Option<i64>,
+    pub client_id: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct WsQuery {
     pub token: Option<String>,
+    /// Replay events after this version before streaming live ones.
+    pub since_version: Option<i64>,
+    /// Presence details shown to the user's other devices.
+    pub client_id: Option<String>,
+    pub device_name: Option<String>,
+    pub platform: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    pub token: Option<String>,
+    /// Replay events after this version before streaming live ones. The
+    /// `Last-Event-ID` header takes precedence when a client reconnects.
+    pub since_version: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    pub namespace: Option<String>,
+    pub before_version: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditQuery {
+    pub action: Option<String>,
+    pub actor_user_id: Option<i64>,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    /// Returns events older than this id, for paging backwards.
+    pub before_id: Option<i64>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    /// Absent for events the server recorded on its own behalf.
+    pub actor_user_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub action: String,
+    pub target_type: Option<String>,
+    pub target_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub details: Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RevisionChange {
+    pub namespace: Namespace,
+    /// Namespace version after this write. Absent for revisions recorded
+    /// before per-namespace versions existed.
+    pub namespace_version: Option<i64>,
+    /// Set when the write edited only this playlist. The data is then that
+    /// playlist alone, `null` before it was created or after it was deleted.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playlist_id: Option<String>,
+    pub previous_data: Value,
+    pub new_data: Value,
+}
+
+/// One accepted write. `namespace` is the scope of the write (`snapshot` for
+/// whole-snapshot writes); `changes` holds the before/after data per namespace.
+#[derive(Debug, Clone, Serialize)]
+pub struct Revision {
+    pub version: i64,
+    pub namespace: Namespace,
+    pub client_id: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub changes: Vec<RevisionChange>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestorePayload {
+    /// Namespace to roll back (e.g. `playlists`). Restores the whole snapshot when omitted.
+    pub namespace: Option<String>,
+    pub expected_version: Option<i64>,
+    pub client_id: Option<String>,
+}
+
+/// Requests a client can send over `/v1/ws`. Each carries a `request_id`
+/// that is echoed back in the matching [`WsResponse`].
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsClientMessage {
+    PutNamespace {
+        request_id: String,
+        namespace: String,
+        #[serde(flatten)]
+        payload: NamespacePayload,
+    },
+    GetSnapshot {
+        request_id: String,
+        since_version: Option<i64>,
+        shared_since_version: Option<i64>,
+    },
+    /// Limits pushed `state_updated` events to the given namespaces; `None`
+    /// restores the default of all namespaces.
+    Subscribe {
+        request_id: String,
+        namespaces: Option<Vec<String>>,
+    },
+    /// Keep-alive carrying the version the client has applied, which the
+    /// server echoes back without recording it.
+    Ack { request_id: String, version: i64 },
+    /// Reports the outcome of a `command` event addressed to this client.
+    CommandResult {
+        request_id: String,
+        command_id: String,
+        ok: bool,
+        result: Option<Value>,
+        error: Option<String>,
+    },
+}
+
+#[derive(Debug, Serialize)]
+pub struct WsResponse {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub request_id: Option<String>,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<Value>,
+}
+
+impl WsResponse {
+    pub fn ok(request_id: String, result: Value) -> Self {
+        Self {
+            kind: "response",
+            request_id: Some(request_id),
+            ok: true,
+            result: Some(result),
+            error: None,
+        }
+    }
+
+    pub fn error(request_id: Option<String>, error: &ApiError) -> Self {
+        Self {
+            kind: "response",
+            request_id,
+            ok: false,
+            result: None,
+            error: Some(error.body()),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -102,7 +762,9 @@ pub struct TokenInfo {
     pub id: i64,
     pub label: String,
     pub token_prefix: String,
+    pub scopes: Vec<String>,
     pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
     pub last_used_at: Option<DateTime<Utc>>,
     pub revoked_at: Option<DateTime<Utc>>,
 }
@@ -114,9 +776,30 @@ pub struct UserSummary {
     pub is_admin: bool,
     pub created_at: DateTime<Utc>,
     pub disabled_at: Option<DateTime<Utc>>,
+    /// When a scheduled deletion purges the user.
+    pub delete_after: Option<DateTime<Utc>>,
     pub tokens: Vec<TokenInfo>,
 }
 
+/// A `playlists` value kept aside on upgrade because some of its entries
+/// were not valid playlists.
+#[derive(Debug, Clone, Serialize)]
+pub struct QuarantinedPlaylists {
+    pub user_id: i64,
+    pub user_name: String,
+    pub playlists: Value,
+    /// Why the value was not accepted as a whole.
+    pub error: String,
+    pub quarantined_at: DateTime<Utc>,
+}
+
+/// Body of `POST /v1/admin/quarantined-playlists/{user_id}/restore`.
+#[derive(Debug, Default, Deserialize)]
+pub struct RestoreQuarantinedPlaylistsRequest {
+    /// A corrected value to write instead of the quarantined one.
+    pub playlists: Option<Value>,
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct UserCreatedResponse {
     pub id: i64,
@@ -132,7 +815,66 @@ pub struct TokenCreatedResponse {
     pub label: String,
     pub token_prefix: String,
     pub token: String,
+    pub scopes: Vec<String>,
     pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Response of `POST /v1/tokens/rotate`.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenRotatedResponse {
+    #[serde(flatten)]
+    pub token: TokenCreatedResponse,
+    /// When the token used for the request stops working.
+    pub previous_token_expires_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct RotateTokenRequest {
+    /// Seconds the old token keeps working; defaults to 300, at most 86400.
+    pub grace_period_seconds: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PairStartRequest {
+    /// Label for the token the device receives once approved.
+    pub device_name: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairStartResponse {
+    /// Secret the new device polls with; never shown to the user.
+    pub device_code: String,
+    /// Short code the user enters on an already signed-in device.
+    pub user_code: String,
+    pub expires_at: DateTime<Utc>,
+    /// Minimum seconds between polls.
+    pub interval: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PairApproveRequest {
+    pub user_code: String,
+    /// Defaults to the approving token's scopes; may not exceed them.
+    pub scopes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PairApproveResponse {
+    pub device_name: String,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PairPollRequest {
+    pub device_code: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum PairPollResponse {
+    Pending,
+    Approved { token: TokenCreatedResponse },
 }
 
 #[derive(Debug, Deserialize)]
@@ -145,6 +887,37 @@ pub struct CreateUserRequest {
 #[derive(Debug, Deserialize)]
 pub struct CreateTokenRequest {
     pub label: Option<String>,
+    /// Defaults to full access (`["*"]`).
+    pub scopes: Option<Vec<String>>,
+    /// Defaults to never expiring.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Query parameters the identity provider sends to `/admin/oidc/callback`.
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackQuery {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcStatusResponse {
+    pub enabled: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameTokenRequest {
+    pub label: String,
+}
+
+/// The caller's account and the token used for the request.
+#[derive(Debug, Serialize)]
+pub struct AccountResponse {
+    #[serde(flatten)]
+    pub user: UserCreatedResponse,
+    pub token_id: i64,
+    pub scopes: Vec<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -152,6 +925,274 @@ pub struct SetUserDisabledRequest {
     pub disabled: bool,
 }
 
+/// A playlist with its version, as served by `/v1/playlists/{playlist_id}`.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaylistRecord {
+    #[serde(flatten)]
+    pub playlist: Playlist,
+    /// Bumped whenever the playlist's name, attributes or items change.
+    pub version: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Entry of `GET /v1/playlists`, without the items.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaylistSummary {
+    pub id: String,
+    pub name: Option<String>,
+    pub attributes: Map<String, Value>,
+    pub item_count: i64,
+    pub version: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Item in a `/v1/playlists` request body; `id` is generated when absent.
+#[derive(Debug, Clone, Deserialize)]
+pub struct PlaylistItemInput {
+    pub id: Option<String>,
+    #[serde(default)]
+    pub data: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreatePlaylistRequest {
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+    #[serde(default)]
+    pub items: Vec<PlaylistItemInput>,
+    pub client_id: Option<String>,
+}
+
+/// Query of `GET /v1/playlists/{playlist_id}/export`.
+#[derive(Debug, Deserialize)]
+pub struct ExportPlaylistQuery {
+    /// `m3u8`, `xspf` or `json` (the default).
+    pub format: Option<String>,
+}
+
+/// Query of `POST /v1/playlists/import`. The body is the file itself.
+#[derive(Debug, Deserialize)]
+pub struct ImportPlaylistQuery {
+    /// `m3u8`, `xspf` or `json`; taken from `Content-Type` when absent.
+    pub format: Option<String>,
+    pub id: Option<String>,
+    /// Overrides the name given in the file.
+    pub name: Option<String>,
+    pub client_id: Option<String>,
+}
+
+/// Response of `POST /v1/playlists/import`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportPlaylistResponse {
+    pub playlist: PlaylistRecord,
+    pub imported: usize,
+    /// Entries left out because of an error.
+    pub skipped: usize,
+    /// The first 100 entry errors.
+    pub errors: Vec<ImportEntryError>,
+}
+
+/// Body of `PATCH /v1/playlists/{playlist_id}`. Omitted fields are kept.
+#[derive(Debug, Deserialize)]
+pub struct UpdatePlaylistRequest {
+    pub name: Option<String>,
+    /// Replaces all attributes.
+    pub attributes: Option<Map<String, Value>>,
+    /// Checked against the playlist's version.
+    pub expected_version: Option<i64>,
+    pub client_id: Option<String>,
+}
+
+/// Body of `POST /v1/playlists/{playlist_id}/items`. At most one of `index`,
+/// `after`, `before` and `position` says where the items go; they are
+/// appended when none is given.
+#[derive(Debug, Deserialize)]
+pub struct InsertPlaylistItemsRequest {
+    pub items: Vec<PlaylistItemInput>,
+    pub index: Option<usize>,
+    /// Id of the item the new items follow.
+    pub after: Option<String>,
+    /// Id of the item the new items precede.
+    pub before: Option<String>,
+    /// Position key of the first new item.
+    pub position: Option<String>,
+    /// Optional: item edits merge with concurrent ones instead of conflicting.
+    pub expected_version: Option<i64>,
+    pub client_id: Option<String>,
+}
+
+/// Body of `PATCH /v1/playlists/{playlist_id}/items/{item_id}`. Exactly one
+/// of `index`, `after`, `before` and `position` is required.
+#[derive(Debug, Deserialize)]
+pub struct MovePlaylistItemRequest {
+    pub index: Option<usize>,
+    pub after: Option<String>,
+    pub before: Option<String>,
+    pub position: Option<String>,
+    pub expected_version: Option<i64>,
+    pub client_id: Option<String>,
+}
+
+/// Query of the `DELETE` playlist endpoints.
+#[derive(Debug, Deserialize)]
+pub struct PlaylistWriteQuery {
+    pub expected_version: Option<i64>,
+    pub client_id: Option<String>,
+}
+
+/// What a playlist share lets its recipient do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareAccess {
+    ReadOnly,
+    /// Rename the playlist and add, move and remove items.
+    Collaborative,
+}
+
+impl ShareAccess {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read_only",
+            Self::Collaborative => "collaborative",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read_only" => Some(Self::ReadOnly),
+            "collaborative" => Some(Self::Collaborative),
+            _ => None,
+        }
+    }
+}
+
+/// A grant on one of the caller's playlists.
+#[derive(Debug, Clone, Serialize)]
+pub struct PlaylistShare {
+    pub id: i64,
+    pub playlist_id: String,
+    /// Recipient.
+    pub user_id: i64,
+    pub user_name: String,
+    pub access: ShareAccess,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Body of `PUT /v1/playlists/{playlist_id}/shares/{user_name}`.
+#[derive(Debug, Deserialize)]
+pub struct SharePlaylistRequest {
+    pub access: ShareAccess,
+}
+
+/// Body of `POST /v1/playlists/{playlist_id}/share-links`.
+#[derive(Debug, Deserialize)]
+pub struct CreateShareLinkRequest {
+    pub label: Option<String>,
+    /// Defaults to never expiring.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// A public read-only link to one of the caller's playlists.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareLink {
+    pub id: i64,
+    pub playlist_id: String,
+    pub label: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Response of `POST /v1/playlists/{playlist_id}/share-links`, the only one
+/// that includes the link itself.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShareLinkCreated {
+    #[serde(flatten)]
+    pub info: ShareLink,
+    pub link: String,
+    /// `/s/{link}`, to be appended to the server's public URL.
+    pub path: String,
+}
+
+/// A playlist as shown through a public link, in its `playlists` namespace
+/// form.
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkedPlaylist {
+    pub playlist: Value,
+    pub version: i64,
+    pub updated_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Query parameters of `GET /s/{link}`.
+#[derive(Debug, Deserialize)]
+pub struct ShareLinkQuery {
+    /// `json` for the playlist itself instead of the page showing it.
+    pub format: Option<String>,
+}
+
+/// A playlist another user shares with the caller.
+#[derive(Debug, Clone, Serialize)]
+pub struct SharedPlaylist {
+    pub share_id: i64,
+    pub owner_id: i64,
+    pub owner_name: String,
+    pub access: ShareAccess,
+    pub playlist: PlaylistRecord,
+}
+
+/// Snapshot (or delta) response, with the playlists shared with the caller.
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncResponse<T> {
+    #[serde(flatten)]
+    pub data: T,
+    /// Bumped whenever a playlist shared with the caller changes or a share
+    /// starts or ends; send it back as `shared_since_version`.
+    pub shared_version: i64,
+    /// The shared playlists changed after `shared_since_version`, or all of
+    /// them without one.
+    pub shared_playlists: Vec<SharedPlaylist>,
+    /// Every share the caller has, so a client can drop the ones that ended.
+    pub share_ids: Vec<i64>,
+}
+
+/// The playlists shared with a user that changed after a shared version.
+#[derive(Debug, Clone)]
+pub struct SharedPlaylistsSince {
+    pub version: i64,
+    pub playlists: Vec<SharedPlaylist>,
+    pub share_ids: Vec<i64>,
+}
+
+impl SharedPlaylistsSince {
+    pub fn respond<T>(self, data: T) -> SyncResponse<T> {
+        SyncResponse {
+            data,
+            shared_version: self.version,
+            shared_playlists: self.playlists,
+            share_ids: self.share_ids,
+        }
+    }
+}
+
+/// Query of `DELETE /v1/admin/users/{user_id}`.
+#[derive(Debug, Deserialize)]
+pub struct DeleteUserQuery {
+    /// Must be the user's name.
+    pub confirm: Option<String>,
+    /// Days to keep the user disabled before purging; purged right away when
+    /// absent or 0.
+    pub grace_days: Option<i32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteUserResponse {
+    pub ok: bool,
+    /// When the user will be purged, or `null` when they already were.
+    pub delete_after: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct OperationResponse {
     pub ok: bool,
@@ -172,9 +1213,33 @@ pub fn namespace_data(snapshot: &Snapshot, namespace: Namespace) -> Value {
     }
 }
 
+pub fn snapshot_delta(
+    snapshot: Snapshot,
+    since_version: i64,
+    changed: Vec<Namespace>,
+) -> SnapshotDelta {
+    let has = |namespace: Namespace| changed.contains(&namespace);
+
+    SnapshotDelta {
+        version: snapshot.version,
+        updated_at: snapshot.updated_at,
+        since_version,
+        namespace_versions: snapshot.namespace_versions,
+        app_state: has(Namespace::AppState).then_some(snapshot.app_state),
+        playlists: has(Namespace::Playlists).then_some(snapshot.playlists),
+        provider_configuration: has(Namespace::ProviderConfiguration)
+            .then_some(snapshot.provider_configuration),
+        settings: has(Namespace::Settings).then_some(snapshot.settings),
+        changed,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::{Namespace, Snapshot, namespace_data};
+    use super::{
+        DeviceEvent, DevicePresence, Namespace, NamespaceVersions, Snapshot, TokenEvent, UserEvent,
+        namespace_data, snapshot_delta,
+    };
     use chrono::Utc;
     use serde_json::json;
 
@@ -186,6 +1251,12 @@ mod tests {
             playlists: json!([{ "id": "p1" }]),
             provider_configuration: json!({ "jellyfin": { "base_url": "http://localhost" } }),
             settings: json!({ "audio_normalization_enabled": true }),
+            namespace_versions: NamespaceVersions {
+                app_state: 3,
+                playlists: 2,
+                provider_configuration: 1,
+                settings: 1,
+            },
         }
     }
 
@@ -216,6 +1287,42 @@ mod tests {
         assert!(message.contains("unsupported namespace"));
     }
 
+    #[test]
+    fn round_trips_namespace_ids() {
+        for namespace in Namespace::DATA.into_iter().chain([Namespace::Snapshot]) {
+            assert_eq!(Namespace::from_str_id(namespace.as_str()), Some(namespace));
+        }
+        assert!(Namespace::from_str_id("app-state").is_none());
+    }
+
+    #[test]
+    fn snapshot_has_no_namespace_version_of_its_own() {
+        let mut snapshot = sample_snapshot();
+        assert_eq!(snapshot.namespace_versions.get(Namespace::Snapshot), None);
+        assert_eq!(snapshot.namespace_version(Namespace::Snapshot), 7);
+        assert_eq!(snapshot.namespace_version(Namespace::AppState), 3);
+
+        snapshot.namespace_versions.decrement(Namespace::Snapshot);
+        snapshot.namespace_versions.decrement(Namespace::AppState);
+        assert_eq!(
+            snapshot.namespace_versions.get(Namespace::AppState),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn delta_only_includes_changed_namespaces() {
+        let delta = snapshot_delta(sample_snapshot(), 5, vec![Namespace::Playlists]);
+        let value = serde_json::to_value(&delta).expect("serializes");
+
+        assert_eq!(value["since_version"], 5);
+        assert_eq!(value["namespace_versions"]["playlists"], 2);
+        assert_eq!(value["changed"], json!(["playlists"]));
+        assert_eq!(value["playlists"][0]["id"], "p1");
+        assert!(value.get("app_state").is_none());
+        assert!(value.get("settings").is_none());
+    }
+
     #[test]
     fn builds_snapshot_namespace_payload() {
         let snapshot = sample_snapshot();
@@ -229,4 +1336,81 @@ mod tests {
         );
         assert_eq!(value["settings"]["audio_normalization_enabled"], true);
     }
+
+    #[test]
+    fn user_events_round_trip_without_tag() {
+        let device = DeviceEvent::online(DevicePresence {
+            client_id: Some("android-phone".to_string()),
+            device_name: Some("Android phone".to_string()),
+            platform: Some("android".to_string()),
+            connected_at: Utc::now(),
+            last_seen: Utc::now(),
+        });
+        let value = serde_json::to_value(UserEvent::from(device)).expect("serializes");
+        assert_eq!(value["event_type"], "device_online");
+
+        match serde_json::from_value::<UserEvent>(value).expect("deserializes") {
+            UserEvent::Device(event) => {
+                assert_eq!(event.device.client_id.as_deref(), Some("android-phone"))
+            }
+            other => panic!("device event parsed as {other:?}"),
+        }
+
+        let state = json!({
+            "event_type": "state_updated",
+            "namespace": "settings",
+            "version": 3,
+            "namespace_versions": NamespaceVersions::default(),
+            "updated_at": Utc::now(),
+            "source_client_id": null
+        });
+        assert!(matches!(
+            serde_json::from_value::<UserEvent>(state).expect("deserializes"),
+            UserEvent::State(event) if event.version == 3
+        ));
+
+        let result = json!({
+            "event_type": "command_result",
+            "command_id": "abc",
+            "ok": false,
+            "error": "nothing is playing"
+        });
+        assert!(matches!(
+            serde_json::from_value::<UserEvent>(result).expect("deserializes"),
+            UserEvent::CommandResult(event) if event.response.command_id == "abc"
+        ));
+
+        let shared = json!({
+            "event_type": "shared_playlist_updated",
+            "share_id": 7,
+            "playlist_id": "party"
+        });
+        assert!(matches!(
+            serde_json::from_value::<UserEvent>(shared).expect("deserializes"),
+            UserEvent::SharedPlaylist(event) if event.share_id == 7
+        ));
+
+        let revoked =
+            serde_json::to_value(UserEvent::from(TokenEvent::revoked(5))).expect("serializes");
+        assert!(matches!(
+            serde_json::from_value::<UserEvent>(revoked).expect("deserializes"),
+            UserEvent::Token(event) if event.token_id == 5
+        ));
+    }
+
+    #[test]
+    fn only_known_account_events_deserialize_as_account_events() {
+        for event_type in ["account_deleted", "account_disabled"] {
+            assert!(matches!(
+                serde_json::from_value::<UserEvent>(json!({ "event_type": event_type }))
+                    .expect("deserializes"),
+                UserEvent::Account(event) if event.event_type == event_type
+            ));
+        }
+
+        // An event this instance does not know, e.g. relayed from a newer one,
+        // must not disconnect the user as if the account was deleted.
+        let unknown = json!({ "event_type": "queue_cleared", "client_id": "tv" });
+        assert!(serde_json::from_value::<UserEvent>(unknown).is_err());
+    }
 }
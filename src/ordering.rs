@@ -0,0 +1,350 @@
+use serde::Serialize;
+
+/// Digits of a position key, in byte order. Keys compare as plain byte
+/// strings, so they sort the same in Rust and under Postgres' `"C"`
+/// collation.
+const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// Longest position key a client may choose.
+pub const MAX_POSITION_LENGTH: usize = 256;
+
+/// Where an item sits in a playlist. Items sort by `position`, then
+/// `client_id`, then item id, so items placed at the same position by
+/// different clients still end up in the same order everywhere.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Placement {
+    /// Fractional index: a key that sorts between the keys of the item's
+    /// neighbours when it was placed. Empty while the item is not placed yet.
+    pub position: String,
+    /// Client that last placed the item; empty when unknown.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub client_id: String,
+    /// Lamport clock of the placement. Of two placements of the same item the
+    /// one with the higher stamp wins, ties going to the higher `client_id`.
+    #[serde(skip)]
+    pub stamp: i64,
+}
+
+impl Placement {
+    pub fn new(position: String, client_id: &str, stamp: i64) -> Self {
+        Self {
+            position,
+            client_id: client_id.to_string(),
+            stamp,
+        }
+    }
+
+    pub fn is_placed(&self) -> bool {
+        !self.position.is_empty()
+    }
+
+    /// Whether this placement replaces `other` when both place the same item.
+    pub fn wins_over(&self, other: &Placement) -> bool {
+        (self.stamp, &self.client_id) > (other.stamp, &other.client_id)
+    }
+}
+
+/// Sort key of the item `item_id` placed at `placement`.
+pub fn order_key<'a>(placement: &'a Placement, item_id: &'a str) -> (&'a str, &'a str, &'a str) {
+    (&placement.position, &placement.client_id, item_id)
+}
+
+/// Whether `position` is a key [`key_between`] can place items around:
+/// non-empty digits, not ending in the lowest digit.
+pub fn is_valid_position(position: &str) -> bool {
+    !position.is_empty()
+        && position.len() <= MAX_POSITION_LENGTH
+        && position.bytes().all(|byte| DIGITS.contains(&byte))
+        && !position.ends_with(char::from(DIGITS[0]))
+}
+
+fn digit(byte: u8) -> usize {
+    DIGITS
+        .iter()
+        .position(|&digit| digit == byte)
+        .unwrap_or_default()
+}
+
+/// A key that sorts strictly between `before` and `after`, where `None`
+/// stands for the start and the end of the sequence. Both must be valid
+/// positions with `before < after`.
+///
+/// Keys are fractions in base 62. Appending steps the last digit up instead
+/// of halving the gap, so a playlist built by appending keeps short keys.
+pub fn key_between(before: Option<&str>, after: Option<&str>) -> String {
+    debug_assert!(
+        before
+            .zip(after)
+            .is_none_or(|(before, after)| before < after)
+    );
+    if before.is_none() && after.is_none() {
+        return char::from(DIGITS[DIGITS.len() / 2]).to_string();
+    }
+    midpoint(
+        before.unwrap_or_default().as_bytes(),
+        after.map(str::as_bytes),
+    )
+    .into_iter()
+    .map(char::from)
+    .collect()
+}
+
+fn midpoint(before: &[u8], after: Option<&[u8]>) -> Vec<u8> {
+    if let Some(after) = after {
+        // Digits missing from `before` count as zeros.
+        let common = after
+            .iter()
+            .enumerate()
+            .take_while(|(index, digit)| before.get(*index).unwrap_or(&DIGITS[0]) == *digit)
+            .count();
+        if common > 0 {
+            let mut key = after[..common].to_vec();
+            key.extend(midpoint(
+                before.get(common..).unwrap_or_default(),
+                Some(&after[common..]),
+            ));
+            return key;
+        }
+    }
+
+    let low = before.first().map_or(0, |&byte| digit(byte));
+    let high = after.map_or(DIGITS.len(), |after| digit(after[0]));
+    if high > low + 1 {
+        let digit = if after.is_none() {
+            low + 1
+        } else {
+            (low + high) / 2
+        };
+        return vec![DIGITS[digit]];
+    }
+
+    match after {
+        // `after`'s first digit alone sorts before `after` and after `before`.
+        Some(after) if after.len() > 1 => vec![after[0]],
+        _ => {
+            let mut key = vec![DIGITS[low]];
+            key.extend(midpoint(before.get(1..).unwrap_or_default(), None));
+            key
+        }
+    }
+}
+
+/// `count` ascending keys strictly between `before` and `after`.
+pub fn keys_between(before: Option<&str>, after: Option<&str>, count: usize) -> Vec<String> {
+    match count {
+        0 => Vec::new(),
+        _ if after.is_none() => {
+            let mut keys: Vec<String> = Vec::with_capacity(count);
+            for _ in 0..count {
+                let key = key_between(keys.last().map(String::as_str).or(before), None);
+                keys.push(key);
+            }
+            keys
+        }
+        _ => {
+            // Split the gap in the middle first so keys grow logarithmically.
+            let middle = count / 2;
+            let key = key_between(before, after);
+            let mut keys = keys_between(before, Some(&key), middle);
+            let rest = keys_between(Some(&key), after, count - middle - 1);
+            keys.push(key);
+            keys.extend(rest);
+            keys
+        }
+    }
+}
+
+/// Keys for `count` items inserted at `index` of `positions`, the positions
+/// of a sequence in order. Nothing sorts between items sharing a position,
+/// so the new items go after every item at the position of the one before
+/// `index`.
+pub fn keys_at(positions: &[&str], index: usize, count: usize) -> Vec<String> {
+    let index = index.min(positions.len());
+    let before = index.checked_sub(1).map(|index| positions[index]);
+    let after = positions[index..]
+        .iter()
+        .copied()
+        .find(|position| Some(*position) != before);
+    keys_between(before, after, count)
+}
+
+/// Placements for a sequence given in its intended order, where `items`
+/// holds each item's id and its current placement, if any. The longest run of
+/// current placements that already sorts in this order is kept; every other
+/// item is placed between its neighbours by `client_id` at `stamp`.
+pub fn place_in_order(
+    items: &[(&str, Option<&Placement>)],
+    client_id: &str,
+    stamp: i64,
+) -> Vec<Placement> {
+    let mut kept = longest_ordered_run(items);
+
+    // An item can only be placed between two kept ones when their positions
+    // differ; otherwise the later one is placed again as well.
+    let mut previous: Option<usize> = None;
+    let mut gap = false;
+    for index in 0..items.len() {
+        if !kept[index] {
+            gap = true;
+            continue;
+        }
+        if gap
+            && let Some(previous) = previous
+            && items[previous].1.map(|placement| &placement.position)
+                == items[index].1.map(|placement| &placement.position)
+        {
+            kept[index] = false;
+            continue;
+        }
+        previous = Some(index);
+        gap = false;
+    }
+
+    let position = |index: usize| items[index].1.map(|placement| placement.position.as_str());
+    let mut placements = Vec::with_capacity(items.len());
+    let mut start = 0;
+    while start < items.len() {
+        if kept[start] {
+            placements.push(items[start].1.cloned().unwrap_or_default());
+            start += 1;
+            continue;
+        }
+        let end = (start..items.len())
+            .find(|&index| kept[index])
+            .unwrap_or(items.len());
+        let before = start.checked_sub(1).and_then(position);
+        let after = (end < items.len()).then(|| position(end)).flatten();
+        placements.extend(
+            keys_between(before, after, end - start)
+                .into_iter()
+                .map(|key| Placement::new(key, client_id, stamp)),
+        );
+        start = end;
+    }
+    placements
+}
+
+/// Marks the longest subsequence of placed items that is already in sort
+/// order.
+fn longest_ordered_run(items: &[(&str, Option<&Placement>)]) -> Vec<bool> {
+    let key = |index: usize| {
+        let (id, placement) = items[index];
+        placement
+            .filter(|placement| placement.is_placed())
+            .map(|placement| order_key(placement, id))
+    };
+
+    // Patience sorting: `tails[n]` ends the best run of length `n + 1`.
+    let mut tails: Vec<usize> = Vec::new();
+    let mut previous: Vec<Option<usize>> = Vec::with_capacity(items.len());
+    for index in 0..items.len() {
+        let Some(current) = key(index) else {
+            previous.push(None);
+            continue;
+        };
+        let length = tails.partition_point(|&tail| key(tail) < Some(current));
+        previous.push(length.checked_sub(1).map(|length| tails[length]));
+        if length == tails.len() {
+            tails.push(index);
+        } else {
+            tails[length] = index;
+        }
+    }
+
+    let mut kept = vec![false; items.len()];
+    let mut next = tails.last().copied();
+    while let Some(index) = next {
+        kept[index] = true;
+        next = previous[index];
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{
+        Placement, is_valid_position, key_between, keys_between, order_key, place_in_order,
+    };
+
+    fn position() -> impl Strategy<Value = String> {
+        "[0-9A-Za-z]{0,5}[1-9A-Za-z]"
+    }
+
+    proptest! {
+        #[test]
+        fn key_between_sorts_between_its_bounds(
+            a in position(),
+            b in position(),
+            open_start in any::<bool>(),
+            open_end in any::<bool>(),
+        ) {
+            prop_assume!(a != b);
+            let (low, high) = if a < b { (a, b) } else { (b, a) };
+            let low = (!open_start).then_some(low.as_str());
+            let high = (!open_end).then_some(high.as_str());
+
+            let key = key_between(low, high);
+            prop_assert!(is_valid_position(&key));
+            prop_assert!(low.is_none_or(|low| low < key.as_str()));
+            prop_assert!(high.is_none_or(|high| key.as_str() < high));
+        }
+
+        #[test]
+        fn keys_between_ascend_within_bounds(
+            a in position(),
+            b in position(),
+            count in 0usize..40,
+        ) {
+            prop_assume!(a < b);
+            let keys = keys_between(Some(&a), Some(&b), count);
+            prop_assert_eq!(keys.len(), count);
+            prop_assert!(keys.iter().all(|key| is_valid_position(key)));
+            let mut bounds = vec![a.clone()];
+            bounds.extend(keys);
+            bounds.push(b);
+            prop_assert!(bounds.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+
+        #[test]
+        fn place_in_order_keeps_placements_already_in_order(
+            placed in prop::collection::vec((position(), 0u8..3), 0..12),
+            order in Just((0..12).collect::<Vec<usize>>()).prop_shuffle(),
+        ) {
+            let ids: Vec<String> = (0..order.len()).map(|n| format!("item-{n}")).collect();
+            let current: Vec<Option<Placement>> = (0..order.len())
+                .map(|n| placed.get(n).map(|(key, client)| {
+                    Placement::new(key.clone(), &client.to_string(), 1)
+                }))
+                .collect();
+            let items: Vec<(&str, Option<&Placement>)> = order
+                .iter()
+                .map(|&n| (ids[n].as_str(), current[n].as_ref()))
+                .collect();
+
+            let placements = place_in_order(&items, "writer", 2);
+            let keys: Vec<_> = items
+                .iter()
+                .zip(&placements)
+                .map(|((id, _), placement)| order_key(placement, id))
+                .collect();
+            prop_assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+            // Items that were already in order keep their placement.
+            if items.iter().all(|(_, placement)| placement.is_some()) {
+                let mut sorted = keys.clone();
+                sorted.sort();
+                if sorted == keys {
+                    prop_assert!(placements.iter().all(|placement| placement.stamp == 1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn appending_keeps_keys_short() {
+        let keys = keys_between(None, None, 1000);
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(keys.iter().all(|key| key.len() <= 20));
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+/// How long a pairing code stays valid.
+pub const PAIRING_TTL_SECONDS: i32 = 600;
+/// Minimum seconds between polls from the new device.
+pub const POLL_INTERVAL_SECONDS: i32 = 5;
+
+/// `POST /v1/pair/start` calls allowed per client IP: a burst of 5, then one
+/// every 12 seconds.
+pub const START_BURST: u32 = 5;
+pub const START_REFILL: Duration = Duration::from_secs(12);
+/// `POST /v1/pair/approve` calls allowed per user: a burst of 5, then one per
+/// minute. Keeps a signed-in token from guessing other pending codes.
+pub const APPROVE_BURST: u32 = 5;
+pub const APPROVE_REFILL: Duration = Duration::from_secs(60);
+
+/// Unambiguous characters for codes typed by hand (no 0/O, 1/I/L).
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 8;
+
+/// A fresh user code, formatted for display as `XXXX-XXXX`.
+pub fn generate_user_code() -> String {
+    let mut rng = rand::rng();
+    let code: String = (0..CODE_LENGTH)
+        .map(|_| char::from(CODE_ALPHABET[rng.random_range(0..CODE_ALPHABET.len())]))
+        .collect();
+    format!("{}-{}", &code[..4], &code[4..])
+}
+
+/// Normalizes user input (case, separators, whitespace) to the stored
+/// `XXXX-XXXX` form, or `None` if it cannot be a valid code.
+pub fn normalize_user_code(input: &str) -> Option<String> {
+    let code: String = input
+        .chars()
+        .filter(|c| !matches!(c, '-' | ' '))
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let valid = code.len() == CODE_LENGTH && code.bytes().all(|b| CODE_ALPHABET.contains(&b));
+    valid.then(|| format!("{}-{}", &code[..4], &code[4..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate_user_code, normalize_user_code};
+
+    #[test]
+    fn generated_codes_round_trip_through_normalization() {
+        let code = generate_user_code();
+        assert_eq!(code.len(), 9);
+        assert_eq!(normalize_user_code(&code).as_deref(), Some(code.as_str()));
+        assert_eq!(
+            normalize_user_code(&code.replace('-', "").to_lowercase()).as_deref(),
+            Some(code.as_str())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert_eq!(normalize_user_code("ABCD-EFG"), None);
+        assert_eq!(normalize_user_code("ABCD-EFG0"), None);
+        assert_eq!(
+            normalize_user_code(" abcd efgh ").as_deref(),
+            Some("ABCD-EFGH")
+        );
+    }
+}
@@ -0,0 +1,327 @@
+use serde_json::{Map, Value};
+
+use crate::errors::ApiError;
+
+/// Partial-update formats accepted by `PATCH /v1/state/{namespace}`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// RFC 7396 JSON Merge Patch (`application/merge-patch+json`).
+    MergePatch,
+    /// RFC 6902 JSON Patch (`application/json-patch+json`).
+    JsonPatch,
+}
+
+impl PatchFormat {
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        if media_type.eq_ignore_ascii_case("application/merge-patch+json") {
+            Some(Self::MergePatch)
+        } else if media_type.eq_ignore_ascii_case("application/json-patch+json") {
+            Some(Self::JsonPatch)
+        } else {
+            None
+        }
+    }
+}
+
+/// Applies `patch` to a copy of `target`. The original is left untouched when
+/// any operation fails, so a patch is all-or-nothing.
+pub fn apply_patch(format: PatchFormat, target: &Value, patch: &Value) -> Result<Value, ApiError> {
+    let mut document = target.clone();
+    match format {
+        PatchFormat::MergePatch => merge_patch(&mut document, patch),
+        PatchFormat::JsonPatch => json_patch(&mut document, patch)?,
+    }
+    Ok(document)
+}
+
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let Value::Object(target) = target else {
+        unreachable!();
+    };
+
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+fn json_patch(document: &mut Value, operations: &Value) -> Result<(), ApiError> {
+    let operations = operations
+        .as_array()
+        .ok_or_else(|| ApiError::bad_request("JSON Patch body must be an array".to_string()))?;
+
+    for (index, operation) in operations.iter().enumerate() {
+        apply_operation(document, operation).map_err(|err| err.at_operation(index))?;
+    }
+
+    Ok(())
+}
+
+/// Error from a single JSON Patch operation, before it is tagged with the
+/// operation's index.
+enum OperationError {
+    Invalid(String),
+    TestFailed(String),
+}
+
+impl OperationError {
+    fn at_operation(self, index: usize) -> ApiError {
+        match self {
+            Self::Invalid(message) => {
+                ApiError::bad_request(format!("patch operation {index}: {message}"))
+            }
+            Self::TestFailed(message) => {
+                ApiError::patch_test_failed(format!("patch operation {index}: {message}"))
+            }
+        }
+    }
+}
+
+fn apply_operation(document: &mut Value, operation: &Value) -> Result<(), OperationError> {
+    let op = string_member(operation, "op")?;
+    let path = parse_pointer(string_member(operation, "path")?)?;
+
+    match op {
+        "add" => add(document, &path, value_member(operation)?.clone()),
+        "remove" => remove(document, &path).map(|_| ()),
+        "replace" => {
+            let target = get_mut(document, &path)?;
+            *target = value_member(operation)?.clone();
+            Ok(())
+        }
+        "move" => {
+            let from = parse_pointer(string_member(operation, "from")?)?;
+            if path.len() > from.len() && path[..from.len()] == from[..] {
+                return Err(OperationError::Invalid(
+                    "cannot move a value into one of its children".to_string(),
+                ));
+            }
+            let value = remove(document, &from)?;
+            add(document, &path, value)
+        }
+        "copy" => {
+            let from = parse_pointer(string_member(operation, "from")?)?;
+            let value = get_mut(document, &from)?.clone();
+            add(document, &path, value)
+        }
+        "test" => {
+            let expected = value_member(operation)?;
+            let actual = get_mut(document, &path).map_err(|_| {
+                OperationError::TestFailed(format!("path '{}' does not exist", join(&path)))
+            })?;
+            if actual == expected {
+                Ok(())
+            } else {
+                Err(OperationError::TestFailed(format!(
+                    "value at '{}' does not match",
+                    join(&path)
+                )))
+            }
+        }
+        other => Err(OperationError::Invalid(format!("unsupported op '{other}'"))),
+    }
+}
+
+fn string_member<'a>(operation: &'a Value, name: &str) -> Result<&'a str, OperationError> {
+    operation
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| OperationError::Invalid(format!("missing string member '{name}'")))
+}
+
+fn value_member(operation: &Value) -> Result<&Value, OperationError> {
+    operation
+        .get("value")
+        .ok_or_else(|| OperationError::Invalid("missing member 'value'".to_string()))
+}
+
+/// Splits an RFC 6901 JSON Pointer into unescaped reference tokens.
+fn parse_pointer(pointer: &str) -> Result<Vec<String>, OperationError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let Some(rest) = pointer.strip_prefix('/') else {
+        return Err(OperationError::Invalid(format!(
+            "invalid JSON pointer '{pointer}'"
+        )));
+    };
+
+    Ok(rest
+        .split('/')
+        .map(|token| token.replace("~1", "/").replace("~0", "~"))
+        .collect())
+}
+
+fn join(path: &[String]) -> String {
+    path.iter()
+        .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+        .collect()
+}
+
+fn array_index(token: &str, len: usize, allow_end: bool) -> Result<usize, OperationError> {
+    if allow_end && token == "-" {
+        return Ok(len);
+    }
+    let valid = !token.is_empty()
+        && token.bytes().all(|byte| byte.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    let index = token
+        .parse::<usize>()
+        .ok()
+        .filter(|_| valid)
+        .ok_or_else(|| OperationError::Invalid(format!("invalid array index '{token}'")))?;
+
+    let in_bounds = if allow_end { index <= len } else { index < len };
+    if !in_bounds {
+        return Err(OperationError::Invalid(format!(
+            "array index {index} is out of bounds"
+        )));
+    }
+    Ok(index)
+}
+
+fn get_mut<'a>(document: &'a mut Value, path: &[String]) -> Result<&'a mut Value, OperationError> {
+    let mut current = document;
+    for token in path {
+        current = match current {
+            Value::Object(map) => map.get_mut(token),
+            Value::Array(items) => {
+                let index = array_index(token, items.len(), false)?;
+                items.get_mut(index)
+            }
+            _ => None,
+        }
+        .ok_or_else(|| OperationError::Invalid(format!("path '{}' does not exist", join(path))))?;
+    }
+    Ok(current)
+}
+
+fn add(document: &mut Value, path: &[String], value: Value) -> Result<(), OperationError> {
+    let Some((last, parent_path)) = path.split_last() else {
+        *document = value;
+        return Ok(());
+    };
+
+    match get_mut(document, parent_path)? {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let index = array_index(last, items.len(), true)?;
+            items.insert(index, value);
+            Ok(())
+        }
+        _ => Err(OperationError::Invalid(format!(
+            "parent of '{}' is not a container",
+            join(path)
+        ))),
+    }
+}
+
+fn remove(document: &mut Value, path: &[String]) -> Result<Value, OperationError> {
+    let Some((last, parent_path)) = path.split_last() else {
+        return Err(OperationError::Invalid(
+            "cannot remove the whole document".to_string(),
+        ));
+    };
+
+    let missing = || OperationError::Invalid(format!("path '{}' does not exist", join(path)));
+    match get_mut(document, parent_path)? {
+        Value::Object(map) => map.remove(last).ok_or_else(missing),
+        Value::Array(items) => {
+            let index = array_index(last, items.len(), false)?;
+            Ok(items.remove(index))
+        }
+        _ => Err(missing()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PatchFormat, apply_patch};
+    use serde_json::json;
+
+    #[test]
+    fn detects_patch_content_types() {
+        assert_eq!(
+            PatchFormat::from_content_type("application/merge-patch+json; charset=utf-8"),
+            Some(PatchFormat::MergePatch)
+        );
+        assert_eq!(
+            PatchFormat::from_content_type("application/json-patch+json"),
+            Some(PatchFormat::JsonPatch)
+        );
+        assert_eq!(PatchFormat::from_content_type("application/json"), None);
+    }
+
+    #[test]
+    fn applies_merge_patch() {
+        let target = json!({ "volume": 0.5, "theme": { "dark": true, "accent": "blue" } });
+        let patch = json!({ "volume": 0.8, "theme": { "accent": null }, "crossfade": 3 });
+
+        let patched = apply_patch(PatchFormat::MergePatch, &target, &patch).expect("applies");
+
+        assert_eq!(
+            patched,
+            json!({ "volume": 0.8, "theme": { "dark": true }, "crossfade": 3 })
+        );
+    }
+
+    #[test]
+    fn applies_json_patch_operations() {
+        let target = json!([{ "id": "p1", "tracks": ["a", "b"] }, { "id": "p2", "tracks": [] }]);
+        let patch = json!([
+            { "op": "test", "path": "/0/id", "value": "p1" },
+            { "op": "add", "path": "/0/tracks/-", "value": "c" },
+            { "op": "move", "from": "/0/tracks/0", "path": "/1/tracks/0" },
+            { "op": "replace", "path": "/1/id", "value": "p3" },
+            { "op": "copy", "from": "/1/id", "path": "/0/source" },
+            { "op": "remove", "path": "/0/tracks/0" }
+        ]);
+
+        let patched = apply_patch(PatchFormat::JsonPatch, &target, &patch).expect("applies");
+
+        assert_eq!(
+            patched,
+            json!([
+                { "id": "p1", "tracks": ["c"], "source": "p3" },
+                { "id": "p3", "tracks": ["a"] }
+            ])
+        );
+    }
+
+    #[test]
+    fn failed_test_operation_is_reported_separately() {
+        let target = json!({ "volume": 0.5 });
+        let patch = json!([
+            { "op": "replace", "path": "/volume", "value": 1.0 },
+            { "op": "test", "path": "/volume", "value": 0.5 }
+        ]);
+
+        let error = apply_patch(PatchFormat::JsonPatch, &target, &patch).expect_err("test fails");
+        let message = format!("{error:?}");
+        assert!(message.contains("patch_test_failed"));
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_index() {
+        let target = json!([1, 2]);
+        let patch = json!([{ "op": "add", "path": "/3", "value": 0 }]);
+
+        let error = apply_patch(PatchFormat::JsonPatch, &target, &patch).expect_err("fails");
+        assert!(format!("{error:?}").contains("out of bounds"));
+    }
+}
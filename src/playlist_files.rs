@@ -0,0 +1,884 @@
+use std::{collections::HashSet, io};
+
+use http_body_util::LengthLimitError;
+use quick_xml::{
+    Reader,
+    escape::{escape, resolve_predefined_entity},
+    events::Event,
+};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::{
+    errors::ApiError,
+    models::PlaylistItemInput,
+    playlists::{Playlist, PlaylistItem},
+};
+
+/// Item `data` fields that M3U8 and XSPF entries map to.
+const URI: &str = "uri";
+const TITLE: &str = "title";
+const ARTIST: &str = "artist";
+const ALBUM: &str = "album";
+const DURATION_MS: &str = "duration_ms";
+
+/// Longest entry location accepted on import.
+const MAX_LOCATION_LENGTH: usize = 4096;
+
+/// Entry errors listed in an import report; later ones are only counted.
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// File formats playlists can be exported to and imported from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistFormat {
+    /// Extended M3U in UTF-8.
+    M3u8,
+    /// XML Shareable Playlist Format, version 1.
+    Xspf,
+    /// The playlist in its `playlists` namespace form.
+    Json,
+}
+
+impl PlaylistFormat {
+    pub fn parse(value: &str) -> Result<Self, ApiError> {
+        match value {
+            "m3u8" | "m3u" => Ok(Self::M3u8),
+            "xspf" => Ok(Self::Xspf),
+            "json" => Ok(Self::Json),
+            _ => Err(ApiError::bad_request(format!(
+                "unknown format '{value}', expected m3u8, xspf or json"
+            ))),
+        }
+    }
+
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        [
+            ("application/vnd.apple.mpegurl", Self::M3u8),
+            ("application/x-mpegurl", Self::M3u8),
+            ("audio/mpegurl", Self::M3u8),
+            ("audio/x-mpegurl", Self::M3u8),
+            ("application/xspf+xml", Self::Xspf),
+            ("application/json", Self::Json),
+        ]
+        .into_iter()
+        .find(|(name, _)| media_type.eq_ignore_ascii_case(name))
+        .map(|(_, format)| format)
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::M3u8 => "application/vnd.apple.mpegurl",
+            Self::Xspf => "application/xspf+xml",
+            Self::Json => "application/json",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::M3u8 => "m3u8",
+            Self::Xspf => "xspf",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// A problem with one entry of an imported file. The entry is left out.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ImportEntryError {
+    /// 1-based number of the entry in the file.
+    pub entry: usize,
+    /// Line the problem is on, for line-based formats.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// What an imported file holds, ready to be created as a playlist.
+#[derive(Debug, Default)]
+pub struct ImportedPlaylist {
+    pub id: Option<String>,
+    pub name: Option<String>,
+    pub attributes: Map<String, Value>,
+    pub items: Vec<PlaylistItemInput>,
+    /// Number of entries left out because of an error.
+    pub skipped: usize,
+    /// The first errors, in file order.
+    pub errors: Vec<ImportEntryError>,
+}
+
+impl ImportedPlaylist {
+    fn add_entry(
+        &mut self,
+        entry: usize,
+        line: Option<usize>,
+        item: Result<PlaylistItemInput, String>,
+    ) {
+        match item {
+            Ok(item) => self.items.push(item),
+            Err(message) => {
+                self.skipped += 1;
+                if self.errors.len() < MAX_REPORTED_ERRORS {
+                    self.errors.push(ImportEntryError {
+                        entry,
+                        line,
+                        message,
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// `playlist` as a file in `format`. Items without a `uri` cannot be written
+/// to M3U8 and are left out.
+pub fn export_playlist(playlist: &Playlist, format: PlaylistFormat) -> String {
+    match format {
+        PlaylistFormat::M3u8 => export_m3u8(playlist),
+        PlaylistFormat::Xspf => export_xspf(playlist),
+        PlaylistFormat::Json => {
+            serde_json::to_string_pretty(&playlist.to_value()).unwrap_or_default()
+        }
+    }
+}
+
+fn text_field<'a>(data: &'a Map<String, Value>, key: &str) -> Option<&'a str> {
+    data.get(key)
+        .and_then(Value::as_str)
+        .filter(|value| !value.is_empty())
+}
+
+/// `value` on a single line, for line-based formats.
+fn one_line(value: &str) -> String {
+    value
+        .chars()
+        .map(|ch| if ch.is_control() { ' ' } else { ch })
+        .collect()
+}
+
+/// `value` as XML character data: escaped, and without the characters XML 1.0
+/// does not allow at all (C0 control characters other than tab and line
+/// breaks, U+FFFE and U+FFFF).
+fn xml_text(value: &str) -> String {
+    let allowed: String = value
+        .chars()
+        .filter(|&ch| match ch {
+            '\t' | '\n' | '\r' => true,
+            '\u{fffe}' | '\u{ffff}' => false,
+            ch => ch >= ' ',
+        })
+        .collect();
+    escape(allowed.as_str()).into_owned()
+}
+
+fn export_m3u8(playlist: &Playlist) -> String {
+    let mut file = String::from("#EXTM3U\n");
+    if let Some(name) = &playlist.name {
+        file.push_str(&format!("#PLAYLIST:{}\n", one_line(name)));
+    }
+    for item in &playlist.items {
+        let Some(uri) = text_field(&item.data, URI) else {
+            continue;
+        };
+        let title = text_field(&item.data, TITLE);
+        let artist = text_field(&item.data, ARTIST);
+        let duration = item.data.get(DURATION_MS).and_then(Value::as_u64);
+        if title.is_some() || duration.is_some() {
+            let seconds = duration.map_or(-1, |ms| ms.div_ceil(1000) as i64);
+            let title = match (artist, title) {
+                (Some(artist), Some(title)) => format!("{artist} - {title}"),
+                (_, title) => title.unwrap_or_default().to_string(),
+            };
+            file.push_str(&format!("#EXTINF:{seconds},{}\n", one_line(&title)));
+        }
+        if let Some(artist) = artist {
+            file.push_str(&format!("#EXTART:{}\n", one_line(artist)));
+        }
+        if let Some(album) = text_field(&item.data, ALBUM) {
+            file.push_str(&format!("#EXTALB:{}\n", one_line(album)));
+        }
+        file.push_str(&one_line(uri));
+        file.push('\n');
+    }
+    file
+}
+
+fn export_xspf(playlist: &Playlist) -> String {
+    let mut file = String::from(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n",
+    );
+    if let Some(name) = &playlist.name {
+        file.push_str(&format!("  <title>{}</title>\n", xml_text(name)));
+    }
+    file.push_str("  <trackList>\n");
+    for item in &playlist.items {
+        file.push_str("    <track>\n");
+        for (key, element) in [
+            (URI, "location"),
+            (TITLE, "title"),
+            (ARTIST, "creator"),
+            (ALBUM, "album"),
+        ] {
+            if let Some(value) = text_field(&item.data, key) {
+                file.push_str(&format!(
+                    "      <{element}>{}</{element}>\n",
+                    xml_text(value)
+                ));
+            }
+        }
+        if let Some(duration) = item.data.get(DURATION_MS).and_then(Value::as_u64) {
+            file.push_str(&format!("      <duration>{duration}</duration>\n"));
+        }
+        file.push_str("    </track>\n");
+    }
+    file.push_str("  </trackList>\n</playlist>\n");
+    file
+}
+
+/// Reads a playlist file in `format` from `reader`. M3U8 and XSPF files are
+/// parsed as they arrive; JSON files are read whole.
+pub async fn import_playlist<R: AsyncBufRead + Unpin>(
+    mut reader: R,
+    format: PlaylistFormat,
+) -> Result<ImportedPlaylist, ApiError> {
+    match format {
+        PlaylistFormat::M3u8 => {
+            let mut parser = M3u8Parser::default();
+            let mut lines = reader.lines();
+            while let Some(line) = lines.next_line().await.map_err(|err| read_error(&err))? {
+                parser.push_line(&line);
+            }
+            Ok(parser.finish())
+        }
+        PlaylistFormat::Xspf => {
+            let mut reader = Reader::from_reader(reader);
+            let mut parser = XspfParser::default();
+            let mut buf = Vec::new();
+            loop {
+                let event = match reader.read_event_into_async(&mut buf).await {
+                    Ok(Event::Eof) => break,
+                    Ok(event) => event,
+                    Err(quick_xml::Error::Io(err)) => return Err(read_error(&err)),
+                    Err(err) => {
+                        return Err(ApiError::bad_request(format!(
+                            "invalid XSPF at byte {}: {err}",
+                            reader.error_position()
+                        )));
+                    }
+                };
+                parser.handle(event)?;
+                buf.clear();
+            }
+            parser.finish()
+        }
+        PlaylistFormat::Json => {
+            let mut body = Vec::new();
+            reader
+                .read_to_end(&mut body)
+                .await
+                .map_err(|err| read_error(&err))?;
+            let value = serde_json::from_slice(&body)
+                .map_err(|err| ApiError::bad_request(format!("invalid JSON: {err}")))?;
+            parse_json(&value)
+        }
+    }
+}
+
+/// The error for a failed read of an uploaded file.
+fn read_error(err: &io::Error) -> ApiError {
+    let mut source = err
+        .get_ref()
+        .map(|err| err as &(dyn std::error::Error + 'static));
+    while let Some(err) = source {
+        if err.is::<LengthLimitError>() {
+            return ApiError::payload_too_large(
+                "playlist file is larger than the maximum body size".to_string(),
+            );
+        }
+        source = err.source();
+    }
+    if err.kind() == io::ErrorKind::InvalidData {
+        return ApiError::bad_request("playlist file is not valid UTF-8".to_string());
+    }
+    ApiError::bad_request(format!("failed to read playlist file: {err}"))
+}
+
+fn check_location(location: &str) -> Result<(), String> {
+    if location.len() > MAX_LOCATION_LENGTH {
+        return Err(format!(
+            "location is longer than {MAX_LOCATION_LENGTH} characters"
+        ));
+    }
+    if location.chars().any(char::is_control) {
+        return Err("location contains control characters".to_string());
+    }
+    Ok(())
+}
+
+/// Item data with the string fields that are present and not empty.
+fn item_data(fields: [(&str, Option<String>); 4], duration_ms: Option<u64>) -> PlaylistItemInput {
+    let mut data: Map<String, Value> = fields
+        .into_iter()
+        .filter_map(|(key, value)| Some((key.to_string(), Value::String(value?))))
+        .filter(|(_, value)| value.as_str().is_some_and(|value| !value.is_empty()))
+        .collect();
+    if let Some(duration_ms) = duration_ms {
+        data.insert(DURATION_MS.to_string(), duration_ms.into());
+    }
+    PlaylistItemInput { id: None, data }
+}
+
+/// The `#EXTINF`, `#EXTART` and `#EXTALB` lines before an entry's location.
+#[derive(Debug)]
+struct EntryInfo {
+    /// Line of the first of them.
+    line: usize,
+    has_extinf: bool,
+    duration: Result<Option<u64>, String>,
+    title: Option<String>,
+    artist: Option<String>,
+    album: Option<String>,
+}
+
+impl EntryInfo {
+    fn new(line: usize) -> Self {
+        Self {
+            line,
+            has_extinf: false,
+            duration: Ok(None),
+            title: None,
+            artist: None,
+            album: None,
+        }
+    }
+
+    /// The `#EXTINF` title without the `Artist - ` it starts with when it
+    /// was written from an artist and title. That is only assumed when an
+    /// `#EXTART` line names the artist, as titles may contain " - " too.
+    fn title(&self) -> Option<String> {
+        let title = self.title.as_deref()?;
+        let title = self
+            .artist
+            .as_deref()
+            .and_then(|artist| title.strip_prefix(artist)?.strip_prefix(" - "))
+            .unwrap_or(title);
+        Some(title.trim().to_string())
+    }
+}
+
+/// Line-by-line parser of extended M3U files.
+#[derive(Debug, Default)]
+pub struct M3u8Parser {
+    playlist: ImportedPlaylist,
+    lines: usize,
+    entries: usize,
+    info: Option<EntryInfo>,
+}
+
+impl M3u8Parser {
+    pub fn push_line(&mut self, line: &str) {
+        self.lines += 1;
+        let line = line.strip_prefix('\u{feff}').unwrap_or(line).trim();
+        if line.is_empty() {
+            return;
+        }
+
+        if let Some(name) = line.strip_prefix("#PLAYLIST:") {
+            self.playlist.name = Some(name.trim().to_string()).filter(|name| !name.is_empty());
+        } else if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            if self.info.as_ref().is_some_and(|info| info.has_extinf) {
+                self.start_entry();
+            }
+            let info = self.info_mut();
+            info.has_extinf = true;
+            let (head, title) = extinf.split_once(',').unwrap_or((extinf, ""));
+            info.duration = parse_extinf_duration(head.split_whitespace().next().unwrap_or(""));
+            info.title = Some(title.trim().to_string());
+        } else if let Some(artist) = line.strip_prefix("#EXTART:") {
+            self.info_mut().artist = Some(artist.trim().to_string());
+        } else if let Some(album) = line.strip_prefix("#EXTALB:") {
+            self.info_mut().album = Some(album.trim().to_string());
+        } else if !line.starts_with('#') {
+            self.add_entry(line);
+        }
+    }
+
+    pub fn finish(mut self) -> ImportedPlaylist {
+        if let Some(info) = self.info.take() {
+            self.orphan_info(info);
+        }
+        self.playlist
+    }
+
+    /// Starts the info of a new entry at the current line. Info still
+    /// waiting for a location is reported as an entry without one.
+    fn start_entry(&mut self) {
+        if let Some(info) = self.info.take() {
+            self.orphan_info(info);
+        }
+        self.info = Some(EntryInfo::new(self.lines));
+    }
+
+    fn info_mut(&mut self) -> &mut EntryInfo {
+        if self.info.is_none() {
+            self.start_entry();
+        }
+        self.info.as_mut().expect("entry started")
+    }
+
+    fn orphan_info(&mut self, info: EntryInfo) {
+        self.entries += 1;
+        self.playlist.add_entry(
+            self.entries,
+            Some(info.line),
+            Err("entry has no location".to_string()),
+        );
+    }
+
+    fn add_entry(&mut self, location: &str) {
+        self.entries += 1;
+        let info = self
+            .info
+            .take()
+            .unwrap_or_else(|| EntryInfo::new(self.lines));
+        let title = info.title();
+        let (line, item) = match (check_location(location), info.duration) {
+            (Err(message), _) => (self.lines, Err(message)),
+            (Ok(()), Err(message)) => (info.line, Err(message)),
+            (Ok(()), Ok(duration_ms)) => (
+                self.lines,
+                Ok(item_data(
+                    [
+                        (URI, Some(location.to_string())),
+                        (TITLE, title),
+                        (ARTIST, info.artist),
+                        (ALBUM, info.album),
+                    ],
+                    duration_ms,
+                )),
+            ),
+        };
+        self.playlist.add_entry(self.entries, Some(line), item);
+    }
+}
+
+/// `#EXTINF` duration in seconds, where `-1` (or `0`) means unknown.
+fn parse_extinf_duration(value: &str) -> Result<Option<u64>, String> {
+    match value.parse::<f64>() {
+        Ok(seconds) if seconds == -1.0 || seconds == 0.0 => Ok(None),
+        Ok(seconds) if seconds.is_finite() && seconds > 0.0 => {
+            Ok(Some((seconds * 1000.0).round() as u64))
+        }
+        _ => Err(format!(
+            "#EXTINF duration '{value}' is not a number of seconds"
+        )),
+    }
+}
+
+/// Fields of the `<track>` being read.
+#[derive(Debug, Default)]
+struct XspfTrack {
+    location: Option<String>,
+    title: Option<String>,
+    creator: Option<String>,
+    album: Option<String>,
+    duration: Option<String>,
+}
+
+/// Event-by-event parser of XSPF files.
+#[derive(Debug, Default)]
+pub struct XspfParser {
+    playlist: ImportedPlaylist,
+    /// Local names of the open elements.
+    path: Vec<String>,
+    /// Text of the innermost open element.
+    text: String,
+    track: Option<XspfTrack>,
+    entries: usize,
+}
+
+impl XspfParser {
+    pub fn handle(&mut self, event: Event<'_>) -> Result<(), ApiError> {
+        match event {
+            Event::Start(element) => {
+                self.start(String::from_utf8_lossy(element.local_name().as_ref()).into_owned())?;
+            }
+            Event::Empty(element) => {
+                self.start(String::from_utf8_lossy(element.local_name().as_ref()).into_owned())?;
+                self.end();
+            }
+            Event::End(_) => self.end(),
+            Event::Text(text) => self
+                .text
+                .push_str(&text.xml_content().map_err(invalid_xspf)?),
+            Event::CData(text) => self
+                .text
+                .push_str(&text.xml_content().map_err(invalid_xspf)?),
+            Event::GeneralRef(reference) => {
+                if let Some(ch) = reference.resolve_char_ref().map_err(invalid_xspf)? {
+                    self.text.push(ch);
+                } else {
+                    let name = reference.decode().map_err(invalid_xspf)?;
+                    let value = resolve_predefined_entity(&name).ok_or_else(|| {
+                        ApiError::bad_request(format!("invalid XSPF: unknown entity &{name};"))
+                    })?;
+                    self.text.push_str(value);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<ImportedPlaylist, ApiError> {
+        if !self.path.is_empty() {
+            return Err(ApiError::bad_request(
+                "invalid XSPF: unexpected end of file".to_string(),
+            ));
+        }
+        Ok(self.playlist)
+    }
+
+    fn start(&mut self, name: String) -> Result<(), ApiError> {
+        if self.path.is_empty() && name != "playlist" {
+            return Err(ApiError::bad_request(format!(
+                "not an XSPF playlist: the root element is <{name}>"
+            )));
+        }
+        self.path.push(name);
+        self.text.clear();
+        if self.path == ["playlist", "trackList", "track"] {
+            self.entries += 1;
+            self.track = Some(XspfTrack::default());
+        }
+        Ok(())
+    }
+
+    fn end(&mut self) {
+        let text = std::mem::take(&mut self.text).trim().to_string();
+        let path: Vec<&str> = self.path.iter().map(String::as_str).collect();
+        match path.as_slice() {
+            ["playlist", "title"] => {
+                self.playlist.name = Some(text).filter(|name| !name.is_empty());
+            }
+            ["playlist", "trackList", "track"] => {
+                if let Some(track) = self.track.take() {
+                    let item = xspf_item(track);
+                    self.playlist.add_entry(self.entries, None, item);
+                }
+            }
+            ["playlist", "trackList", "track", field] => {
+                if let Some(track) = self.track.as_mut() {
+                    let slot = match *field {
+                        "location" => Some(&mut track.location),
+                        "title" => Some(&mut track.title),
+                        "creator" => Some(&mut track.creator),
+                        "album" => Some(&mut track.album),
+                        "duration" => Some(&mut track.duration),
+                        _ => None,
+                    };
+                    // A track may list several locations; the first is used.
+                    if let Some(slot) = slot {
+                        slot.get_or_insert(text);
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.path.pop();
+    }
+}
+
+fn invalid_xspf(err: impl std::fmt::Display) -> ApiError {
+    ApiError::bad_request(format!("invalid XSPF: {err}"))
+}
+
+fn xspf_item(track: XspfTrack) -> Result<PlaylistItemInput, String> {
+    let location = track.location.filter(|location| !location.is_empty());
+    if location.is_none() && track.title.as_ref().is_none_or(String::is_empty) {
+        return Err("track has neither a location nor a title".to_string());
+    }
+    if let Some(location) = &location {
+        check_location(location)?;
+    }
+    let duration_ms = track
+        .duration
+        .map(|duration| {
+            duration
+                .parse::<u64>()
+                .map_err(|_| format!("duration '{duration}' is not a number of milliseconds"))
+        })
+        .transpose()?;
+    Ok(item_data(
+        [
+            (URI, location),
+            (TITLE, track.title),
+            (ARTIST, track.creator),
+            (ALBUM, track.album),
+        ],
+        duration_ms,
+    ))
+}
+
+/// A playlist in its `playlists` namespace form, as written by the `json`
+/// export.
+fn parse_json(value: &Value) -> Result<ImportedPlaylist, ApiError> {
+    let Value::Object(fields) = value else {
+        return Err(ApiError::bad_request(
+            "a JSON playlist must be an object".to_string(),
+        ));
+    };
+    let mut playlist = ImportedPlaylist::default();
+    let mut attributes = fields.clone();
+    for (key, slot) in [("id", &mut playlist.id), ("name", &mut playlist.name)] {
+        match attributes.remove(key) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(value)) => *slot = Some(value),
+            Some(_) => {
+                return Err(ApiError::bad_request(format!(
+                    "playlist {key} must be a string"
+                )));
+            }
+        }
+    }
+    let entries = match attributes.remove("items") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(entries)) => entries,
+        Some(_) => {
+            return Err(ApiError::bad_request(
+                "playlist items must be an array".to_string(),
+            ));
+        }
+    };
+    playlist.attributes = attributes;
+
+    let mut ids = HashSet::new();
+    for (index, entry) in entries.iter().enumerate() {
+        let item = PlaylistItem::from_value(entry)
+            .map_err(|err| err.message().to_string())
+            .and_then(|item| {
+                if !ids.insert(item.id.clone()) {
+                    return Err(format!("duplicate item id '{}'", item.id));
+                }
+                Ok(PlaylistItemInput {
+                    id: Some(item.id),
+                    data: item.data,
+                })
+            });
+        playlist.add_entry(index + 1, None, item);
+    }
+    Ok(playlist)
+}
+
+#[cfg(test)]
+mod tests {
+    use quick_xml::Reader;
+    use serde_json::json;
+
+    use super::{
+        ImportEntryError, ImportedPlaylist, M3u8Parser, PlaylistFormat, XspfParser,
+        export_playlist, parse_json,
+    };
+    use crate::playlists::{Playlist, PlaylistItem};
+
+    fn parse_m3u8(file: &str) -> ImportedPlaylist {
+        let mut parser = M3u8Parser::default();
+        file.lines().for_each(|line| parser.push_line(line));
+        parser.finish()
+    }
+
+    fn parse_xspf(file: &str) -> ImportedPlaylist {
+        let mut reader = Reader::from_str(file);
+        let mut parser = XspfParser::default();
+        loop {
+            match reader.read_event().expect("well-formed") {
+                quick_xml::events::Event::Eof => break,
+                event => parser.handle(event).expect("valid"),
+            }
+        }
+        parser.finish().expect("complete")
+    }
+
+    fn sample() -> Playlist {
+        let items = [
+            json!({ "id": "a", "uri": "https://example.com/a.mp3", "title": "Rock & Roll", "artist": "Band", "album": "Live", "duration_ms": 215000 }),
+            json!({ "id": "b", "uri": "music/b.flac" }),
+        ];
+        Playlist::new(
+            Some("p1".to_string()),
+            Some("Road <Trip>".to_string()),
+            serde_json::Map::new(),
+            items
+                .iter()
+                .map(|item| PlaylistItem::from_value(item).expect("item"))
+                .collect(),
+        )
+        .expect("playlist")
+    }
+
+    #[test]
+    fn m3u8_round_trips_item_fields() {
+        let file = export_playlist(&sample(), PlaylistFormat::M3u8);
+        assert!(
+            file.starts_with("#EXTM3U\n#PLAYLIST:Road <Trip>\n#EXTINF:215,Band - Rock & Roll\n")
+        );
+
+        let imported = parse_m3u8(&file);
+        assert_eq!(imported.name.as_deref(), Some("Road <Trip>"));
+        assert!(imported.errors.is_empty());
+        assert_eq!(
+            imported.items.iter().map(|item| &item.data).collect::<Vec<_>>(),
+            [
+                json!({ "uri": "https://example.com/a.mp3", "title": "Rock & Roll", "artist": "Band", "album": "Live", "duration_ms": 215000 }),
+                json!({ "uri": "music/b.flac" }),
+            ]
+            .iter()
+            .map(|value| value.as_object().expect("object"))
+            .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn m3u8_splits_extinf_titles_only_with_an_artist() {
+        let file = export_playlist(&sample(), PlaylistFormat::M3u8);
+        assert!(file.contains("#EXTINF:215,Band - Rock & Roll\n#EXTART:Band\n"));
+
+        let imported = parse_m3u8(
+            "#EXTM3U\n#EXTINF:60,Rock - Live - Encore\nencore.mp3\n#EXTINF:60,Band - Intro\n#EXTART:Band\nintro.mp3\n#EXTINF:60,Other - Outro\n#EXTART:Band\noutro.mp3\n",
+        );
+        let titles: Vec<_> = imported
+            .items
+            .iter()
+            .map(|item| (&item.data["title"], item.data.get("artist")))
+            .collect();
+        assert_eq!(
+            titles,
+            [
+                (&json!("Rock - Live - Encore"), None),
+                (&json!("Intro"), Some(&json!("Band"))),
+                (&json!("Other - Outro"), Some(&json!("Band"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn xspf_export_leaves_out_characters_xml_does_not_allow() {
+        let items = [json!({ "uri": "a.mp3", "title": "Tab\there\u{0}\u{1b}[1m\u{fffe}" })];
+        let playlist = Playlist::new(
+            None,
+            Some("Mix\u{7}".to_string()),
+            serde_json::Map::new(),
+            items
+                .iter()
+                .map(|item| PlaylistItem::from_value(item).expect("item"))
+                .collect(),
+        )
+        .expect("playlist");
+
+        let file = export_playlist(&playlist, PlaylistFormat::Xspf);
+        assert!(file.contains("<title>Mix</title>"));
+        assert!(file.contains("<title>Tab\there[1m</title>"));
+
+        let imported = parse_xspf(&file);
+        assert_eq!(imported.items[0].data["title"], json!("Tab\there[1m"));
+    }
+
+    #[test]
+    fn xspf_round_trips_item_fields() {
+        let file = export_playlist(&sample(), PlaylistFormat::Xspf);
+        assert!(file.contains("<title>Rock &amp; Roll</title>"));
+
+        let imported = parse_xspf(&file);
+        assert_eq!(imported.name.as_deref(), Some("Road <Trip>"));
+        assert!(imported.errors.is_empty());
+        assert_eq!(imported.items.len(), 2);
+        assert_eq!(imported.items[0].data["title"], json!("Rock & Roll"));
+        assert_eq!(imported.items[0].data["duration_ms"], json!(215000));
+        assert_eq!(imported.items[1].data["uri"], json!("music/b.flac"));
+    }
+
+    #[test]
+    fn reports_bad_m3u8_entries_and_keeps_the_rest() {
+        let imported = parse_m3u8(
+            "\u{feff}#EXTM3U\n#EXTINF:abc,Broken\nbroken.mp3\n#EXTINF:-1,Unknown length\nok.mp3\n#EXTINF:10,Dangling\n#EXTINF:12.5,Last\nlast.mp3\n",
+        );
+
+        assert_eq!(imported.items.len(), 2);
+        assert_eq!(imported.items[0].data.get("duration_ms"), None);
+        assert_eq!(imported.items[1].data["duration_ms"], json!(12500));
+        assert_eq!(imported.skipped, 2);
+        assert_eq!(
+            imported.errors,
+            [
+                ImportEntryError {
+                    entry: 1,
+                    line: Some(2),
+                    message: "#EXTINF duration 'abc' is not a number of seconds".to_string(),
+                },
+                ImportEntryError {
+                    entry: 3,
+                    line: Some(6),
+                    message: "entry has no location".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_bad_xspf_tracks_and_keeps_the_rest() {
+        let imported = parse_xspf(
+            r#"<?xml version="1.0"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/">
+              <trackList>
+                <track><location>a.mp3</location><duration>soon</duration></track>
+                <track><annotation>nothing</annotation></track>
+                <track/>
+                <track><title>Only &#x54;itle</title></track>
+              </trackList>
+            </playlist>"#,
+        );
+
+        assert_eq!(imported.items.len(), 1);
+        assert_eq!(imported.items[0].data["title"], json!("Only Title"));
+        assert_eq!(
+            imported
+                .errors
+                .iter()
+                .map(|error| (error.entry, error.message.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (1, "duration 'soon' is not a number of milliseconds"),
+                (2, "track has neither a location nor a title"),
+                (3, "track has neither a location nor a title"),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_import_reports_bad_items() {
+        let imported = parse_json(&json!({
+            "id": "p1",
+            "name": "Mix",
+            "color": "red",
+            "items": [{ "id": "a", "uri": "x" }, "nope", { "id": "a", "uri": "y" }, { "uri": "z" }],
+        }))
+        .expect("valid playlist");
+
+        assert_eq!(imported.id.as_deref(), Some("p1"));
+        assert_eq!(imported.attributes["color"], json!("red"));
+        assert_eq!(imported.items.len(), 2);
+        assert_eq!(
+            imported
+                .errors
+                .iter()
+                .map(|error| (error.entry, error.message.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                (2, "each playlist item must be an object"),
+                (3, "duplicate item id 'a'"),
+            ]
+        );
+    }
+}
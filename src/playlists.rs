@@ -0,0 +1,1046 @@
+use std::collections::{HashMap, HashSet};
+
+use rand::{Rng, distr::Alphanumeric};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    errors::ApiError,
+    ordering::{self, Placement},
+};
+
+/// Longest playlist or item id a client may choose.
+const MAX_ID_LENGTH: usize = 200;
+
+/// Playlist ids taken by static routes under `/v1/playlists`, which would
+/// hide a playlist with that id.
+const RESERVED_PLAYLIST_IDS: [&str; 1] = ["import"];
+
+/// Namespace fields with a column of their own; everything else is kept in
+/// `attributes`.
+const PLAYLIST_FIELDS: [&str; 3] = ["id", "name", "items"];
+
+/// A playlist as stored in the `playlists` and `playlist_items` tables. In the
+/// `playlists` namespace it is an object with `id`, optional `name`, `items`
+/// and any other fields the app keeps.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Playlist {
+    pub id: String,
+    /// Whether the server chose `id` because the playlist was written without
+    /// one. See [`keep_assigned_ids`].
+    #[serde(skip)]
+    pub id_assigned: bool,
+    pub name: Option<String>,
+    pub attributes: Map<String, Value>,
+    pub items: Vec<PlaylistItem>,
+}
+
+/// A playlist entry. In the `playlists` namespace it is the `data` object
+/// with `id` added; the placement is kept in the rows only.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlaylistItem {
+    pub id: String,
+    /// Whether the server chose `id`, as for [`Playlist::id_assigned`].
+    #[serde(skip)]
+    pub id_assigned: bool,
+    pub data: Map<String, Value>,
+    #[serde(flatten)]
+    pub placement: Placement,
+}
+
+/// Where inserted or moved items go.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Anchor {
+    /// At this index of the current order, clamped to the end.
+    Index(usize),
+    After(String),
+    Before(String),
+    /// At this position key, e.g. one a client computed while offline.
+    Position(String),
+}
+
+impl Anchor {
+    /// The anchor named by at most one of the request fields.
+    pub fn from_fields(
+        index: Option<usize>,
+        after: Option<String>,
+        before: Option<String>,
+        position: Option<String>,
+    ) -> Result<Option<Self>, ApiError> {
+        let anchors = [
+            index.map(Self::Index),
+            after.map(Self::After),
+            before.map(Self::Before),
+            position.map(Self::Position),
+        ];
+        let mut anchors = anchors.into_iter().flatten();
+        let anchor = anchors.next();
+        if anchors.next().is_some() {
+            return Err(ApiError::bad_request(
+                "only one of index, after, before and position may be given".to_string(),
+            ));
+        }
+        if let Some(Self::Position(position)) = &anchor
+            && !ordering::is_valid_position(position)
+        {
+            return Err(ApiError::bad_request(format!(
+                "position must be 1 to {} characters of 0-9, A-Z and a-z, not ending in 0",
+                ordering::MAX_POSITION_LENGTH
+            )));
+        }
+        Ok(anchor)
+    }
+}
+
+/// Random id for playlists and items created without one.
+pub fn generate_id() -> String {
+    rand::rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect()
+}
+
+/// The id to store, and whether it was generated because there was none.
+fn checked_id(kind: &str, id: Option<String>) -> Result<(String, bool), ApiError> {
+    match id {
+        None => Ok((generate_id(), true)),
+        Some(id) if !id.is_empty() && id.len() <= MAX_ID_LENGTH => Ok((id, false)),
+        Some(_) => Err(ApiError::bad_request(format!(
+            "{kind} id must be 1 to {MAX_ID_LENGTH} characters"
+        ))),
+    }
+}
+
+fn check_attributes(attributes: &Map<String, Value>) -> Result<(), ApiError> {
+    match PLAYLIST_FIELDS
+        .iter()
+        .find(|field| attributes.contains_key(**field))
+    {
+        Some(field) => Err(ApiError::bad_request(format!(
+            "attributes must not contain '{field}'"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// `fields[key]` as an optional string.
+fn take_string(
+    fields: &mut Map<String, Value>,
+    key: &str,
+    what: &str,
+) -> Result<Option<String>, ApiError> {
+    match fields.remove(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(ApiError::bad_request(format!("{what} must be a string"))),
+    }
+}
+
+/// `fields[key]` as an optional id. Integer ids are accepted and kept as
+/// their decimal string.
+fn take_id(
+    fields: &mut Map<String, Value>,
+    key: &str,
+    what: &str,
+) -> Result<Option<String>, ApiError> {
+    let Some(Value::Number(number)) = fields.get(key) else {
+        return take_string(fields, key, what);
+    };
+    if !(number.is_i64() || number.is_u64()) {
+        return Err(ApiError::bad_request(format!(
+            "{what} must be a string or an integer"
+        )));
+    }
+    let id = number.to_string();
+    fields.remove(key);
+    Ok(Some(id))
+}
+
+/// Parses a `playlists` namespace value. Playlists and items without an `id`
+/// are given one.
+pub fn parse_playlists(value: &Value) -> Result<Vec<Playlist>, ApiError> {
+    let Value::Array(entries) = value else {
+        return Err(ApiError::bad_request(
+            "playlists must be an array".to_string(),
+        ));
+    };
+
+    let mut ids = HashSet::new();
+    entries
+        .iter()
+        .map(|entry| {
+            let playlist = Playlist::from_value(entry)?;
+            if !ids.insert(playlist.id.clone()) {
+                return Err(ApiError::bad_request(format!(
+                    "duplicate playlist id '{}'",
+                    playlist.id
+                )));
+            }
+            Ok(playlist)
+        })
+        .collect()
+}
+
+/// Parses what can be kept of a `playlists` namespace value that
+/// [`parse_playlists`] rejects: entries that are not valid playlists and
+/// repeated ids are left out.
+pub fn salvage_playlists(value: &Value) -> Vec<Playlist> {
+    let Value::Array(entries) = value else {
+        return Vec::new();
+    };
+
+    let mut ids = HashSet::new();
+    entries
+        .iter()
+        .filter_map(|entry| Playlist::from_value(entry).ok())
+        .filter(|playlist| ids.insert(playlist.id.clone()))
+        .collect()
+}
+
+/// The `playlists` namespace value for `playlists`, in order.
+pub fn render_playlists<'a>(playlists: impl IntoIterator<Item = &'a Playlist>) -> Value {
+    Value::Array(playlists.into_iter().map(Playlist::to_value).collect())
+}
+
+/// Sets the playlist `playlist_id` in a `playlists` namespace value to
+/// `value`, or removes it when `value` is `None`. A playlist that is not there
+/// yet is inserted at `position`, or appended. Returns the index the playlist
+/// had before.
+pub fn set_playlist_value(
+    namespace: &mut Value,
+    playlist_id: &str,
+    value: Option<Value>,
+    position: Option<usize>,
+) -> Option<usize> {
+    if !namespace.is_array() {
+        *namespace = Value::Array(Vec::new());
+    }
+    let Value::Array(entries) = namespace else {
+        unreachable!("replaced above");
+    };
+
+    let index = entries
+        .iter()
+        .position(|entry| entry.get("id").and_then(Value::as_str) == Some(playlist_id));
+    match (index, value) {
+        (Some(index), Some(value)) => entries[index] = value,
+        (Some(index), None) => {
+            entries.remove(index);
+        }
+        (None, Some(value)) => {
+            let position = position.map_or(entries.len(), |position| position.min(entries.len()));
+            entries.insert(position, value);
+        }
+        (None, None) => {}
+    }
+    index
+}
+
+/// Playlists and items with an id of their own.
+trait Identified {
+    fn id(&self) -> &str;
+    fn id_assigned(&self) -> bool;
+    fn set_id(&mut self, id: String, assigned: bool);
+}
+
+impl Identified for Playlist {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn id_assigned(&self) -> bool {
+        self.id_assigned
+    }
+
+    fn set_id(&mut self, id: String, assigned: bool) {
+        self.id = id;
+        self.id_assigned = assigned;
+    }
+}
+
+impl Identified for PlaylistItem {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn id_assigned(&self) -> bool {
+        self.id_assigned
+    }
+
+    fn set_id(&mut self, id: String, assigned: bool) {
+        self.id = id;
+        self.id_assigned = assigned;
+    }
+}
+
+/// Gives each entry that was written without an id the id the server
+/// assigned to one of `stored`, so clients that never send ids do not get new
+/// ones, and new rows, on every write. Candidates are stored entries with an
+/// assigned id that no written entry names; they are matched first by `same`,
+/// then in order.
+fn reuse_assigned_ids<T: Identified>(
+    entries: &mut [T],
+    stored: &[T],
+    same: impl Fn(&T, &T) -> bool,
+) {
+    let stored_by_id: HashMap<&str, &T> = stored.iter().map(|entry| (entry.id(), entry)).collect();
+    let fresh: Vec<usize> = (0..entries.len())
+        .filter(|index| entries[*index].id_assigned())
+        .collect();
+
+    let mut named = HashSet::new();
+    for entry in entries.iter_mut().filter(|entry| !entry.id_assigned()) {
+        named.insert(entry.id().to_string());
+        // An id that was assigned stays assigned when clients send it back.
+        if let Some(stored) = stored_by_id.get(entry.id()) {
+            let id = entry.id().to_string();
+            entry.set_id(id, stored.id_assigned());
+        }
+    }
+
+    let mut candidates: Vec<Option<&T>> = stored
+        .iter()
+        .filter(|entry| entry.id_assigned() && !named.contains(entry.id()))
+        .map(Some)
+        .collect();
+    let mut unmatched = fresh;
+    for by_content in [true, false] {
+        unmatched.retain(|index| {
+            let entry = &mut entries[*index];
+            let Some(candidate) = candidates
+                .iter_mut()
+                .find(|candidate| {
+                    candidate.is_some_and(|stored| !by_content || same(entry, stored))
+                })
+                .and_then(Option::take)
+            else {
+                return true;
+            };
+            entry.set_id(candidate.id().to_string(), true);
+            false
+        });
+    }
+}
+
+/// Keeps the ids the server assigned earlier for playlists and items that are
+/// written without one again, matching playlists by name and items by data
+/// before falling back to their order.
+pub fn keep_assigned_ids(playlists: &mut [Playlist], stored: &[Playlist]) {
+    reuse_assigned_ids(playlists, stored, |playlist, stored| {
+        playlist.name == stored.name
+    });
+
+    let stored_by_id: HashMap<&str, &Playlist> = stored
+        .iter()
+        .map(|playlist| (playlist.id.as_str(), playlist))
+        .collect();
+    for playlist in playlists {
+        if let Some(stored) = stored_by_id.get(playlist.id.as_str()) {
+            reuse_assigned_ids(&mut playlist.items, &stored.items, |item, stored| {
+                item.data == stored.data
+            });
+        }
+    }
+}
+
+impl Playlist {
+    pub fn new(
+        id: Option<String>,
+        name: Option<String>,
+        attributes: Map<String, Value>,
+        items: Vec<PlaylistItem>,
+    ) -> Result<Self, ApiError> {
+        check_attributes(&attributes)?;
+        let (id, id_assigned) = checked_id("playlist", id)?;
+        if RESERVED_PLAYLIST_IDS.contains(&id.as_str()) {
+            return Err(ApiError::bad_request(format!(
+                "playlist id '{id}' is reserved"
+            )));
+        }
+        let mut playlist = Self {
+            id,
+            id_assigned,
+            name,
+            attributes,
+            items: Vec::new(),
+        };
+        playlist.check_new_items(&items)?;
+        playlist.items = items;
+        Ok(playlist)
+    }
+
+    pub fn set_attributes(&mut self, attributes: Map<String, Value>) -> Result<(), ApiError> {
+        check_attributes(&attributes)?;
+        self.attributes = attributes;
+        Ok(())
+    }
+
+    fn from_value(value: &Value) -> Result<Self, ApiError> {
+        let Value::Object(fields) = value else {
+            return Err(ApiError::bad_request(
+                "each playlist must be an object".to_string(),
+            ));
+        };
+        let mut attributes = fields.clone();
+        let id = take_id(&mut attributes, "id", "playlist id")?;
+        let name = take_string(&mut attributes, "name", "playlist name")?;
+        let items = match attributes.remove("items") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(PlaylistItem::from_value)
+                .collect::<Result<_, _>>()?,
+            Some(_) => {
+                return Err(ApiError::bad_request(
+                    "playlist items must be an array".to_string(),
+                ));
+            }
+        };
+        Self::new(id, name, attributes, items)
+    }
+
+    /// The playlist in its `playlists` namespace form.
+    pub fn to_value(&self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("id".to_string(), Value::String(self.id.clone()));
+        if let Some(name) = &self.name {
+            fields.insert("name".to_string(), Value::String(name.clone()));
+        }
+        fields.extend(self.attributes.clone());
+        fields.insert(
+            "items".to_string(),
+            Value::Array(self.items.iter().map(PlaylistItem::to_value).collect()),
+        );
+        Value::Object(fields)
+    }
+
+    fn item_index(&self, item_id: &str) -> Result<usize, ApiError> {
+        self.items
+            .iter()
+            .position(|item| item.id == item_id)
+            .ok_or_else(|| ApiError::not_found(format!("item '{item_id}' not found")))
+    }
+
+    fn check_new_items(&self, items: &[PlaylistItem]) -> Result<(), ApiError> {
+        let mut ids: HashSet<&str> = self.items.iter().map(|item| item.id.as_str()).collect();
+        for item in items {
+            if !ids.insert(&item.id) {
+                return Err(ApiError::bad_request(format!(
+                    "duplicate item id '{}'",
+                    item.id
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Stamp for the next placement: one past every stamp seen so far.
+    fn next_stamp(&self) -> i64 {
+        self.items
+            .iter()
+            .map(|item| item.placement.stamp)
+            .max()
+            .unwrap_or_default()
+            + 1
+    }
+
+    /// Sorts the items into their merged order.
+    pub fn sort_items(&mut self) {
+        self.items.sort_by(|a, b| {
+            ordering::order_key(&a.placement, &a.id).cmp(&ordering::order_key(&b.placement, &b.id))
+        });
+    }
+
+    /// Gives every item a placement that keeps the current item order. Items
+    /// without one take their placement in `stored` when it still fits, so a
+    /// rewritten playlist only re-places the items that actually moved.
+    pub fn place_items(&mut self, stored: Option<&Playlist>, client_id: &str) {
+        let stored: HashMap<&str, &Placement> = stored
+            .map(|stored| {
+                stored
+                    .items
+                    .iter()
+                    .map(|item| (item.id.as_str(), &item.placement))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let stamp = stored
+            .values()
+            .map(|placement| placement.stamp)
+            .max()
+            .unwrap_or_default()
+            .max(self.next_stamp() - 1)
+            + 1;
+
+        let current: Vec<(&str, Option<&Placement>)> = self
+            .items
+            .iter()
+            .map(|item| {
+                let placement = Some(&item.placement)
+                    .filter(|placement| placement.is_placed())
+                    .or_else(|| stored.get(item.id.as_str()).copied());
+                (item.id.as_str(), placement)
+            })
+            .collect();
+        let placements = ordering::place_in_order(&current, client_id, stamp);
+        for (item, placement) in self.items.iter_mut().zip(placements) {
+            item.placement = placement;
+        }
+    }
+
+    /// Keys for `count` items placed at `anchor`, skipping the item
+    /// `moving` (which is being moved).
+    fn keys_at(
+        &self,
+        anchor: Option<&Anchor>,
+        moving: Option<&str>,
+        count: usize,
+    ) -> Result<Vec<String>, ApiError> {
+        if let Some(Anchor::After(item_id) | Anchor::Before(item_id)) = anchor
+            && Some(item_id.as_str()) == moving
+        {
+            return Err(ApiError::bad_request(
+                "an item cannot be placed next to itself".to_string(),
+            ));
+        }
+        let others: Vec<&PlaylistItem> = self
+            .items
+            .iter()
+            .filter(|item| Some(item.id.as_str()) != moving)
+            .collect();
+        let index_of = |item_id: &str| {
+            others
+                .iter()
+                .position(|item| item.id == item_id)
+                .ok_or_else(|| ApiError::not_found(format!("item '{item_id}' not found")))
+        };
+
+        if let Some(Anchor::Position(position)) = anchor {
+            // The first item goes exactly at `position`, the rest after it.
+            let after = others
+                .iter()
+                .map(|item| item.placement.position.as_str())
+                .find(|other| *other > position.as_str());
+            let mut keys = vec![position.clone()];
+            keys.extend(ordering::keys_between(Some(position), after, count - 1));
+            return Ok(keys);
+        }
+
+        let index = match anchor {
+            None => others.len(),
+            Some(Anchor::Index(index)) => *index,
+            Some(Anchor::After(item_id)) => index_of(item_id)? + 1,
+            Some(Anchor::Before(item_id)) => index_of(item_id)?,
+            Some(Anchor::Position(_)) => unreachable!("handled above"),
+        };
+        let positions: Vec<&str> = others
+            .iter()
+            .map(|item| item.placement.position.as_str())
+            .collect();
+        Ok(ordering::keys_at(&positions, index, count))
+    }
+
+    /// Inserts `items` at `anchor`, or appends them when there is none. The
+    /// items are placed by `client_id`.
+    pub fn insert_items(
+        &mut self,
+        anchor: Option<&Anchor>,
+        items: Vec<PlaylistItem>,
+        client_id: &str,
+    ) -> Result<(), ApiError> {
+        self.check_new_items(&items)?;
+        if items.is_empty() {
+            return Ok(());
+        }
+        self.place_items(None, client_id);
+
+        let stamp = self.next_stamp();
+        let keys = self.keys_at(anchor, None, items.len())?;
+        self.items
+            .extend(items.into_iter().zip(keys).map(|(mut item, key)| {
+                item.placement = Placement::new(key, client_id, stamp);
+                item
+            }));
+        self.sort_items();
+        Ok(())
+    }
+
+    /// Places an item at `anchor` on behalf of `client_id`.
+    pub fn move_item(
+        &mut self,
+        item_id: &str,
+        anchor: &Anchor,
+        client_id: &str,
+    ) -> Result<(), ApiError> {
+        let index = self.item_index(item_id)?;
+        self.place_items(None, client_id);
+
+        let stamp = self.next_stamp();
+        let key = self.keys_at(Some(anchor), Some(item_id), 1)?.remove(0);
+        let placement = Placement::new(key, client_id, stamp);
+        let item = &mut self.items[index];
+        if placement.wins_over(&item.placement) {
+            item.placement = placement;
+        }
+        self.sort_items();
+        Ok(())
+    }
+
+    pub fn remove_item(&mut self, item_id: &str) -> Result<(), ApiError> {
+        let index = self.item_index(item_id)?;
+        self.items.remove(index);
+        Ok(())
+    }
+}
+
+impl PlaylistItem {
+    pub fn new(id: Option<String>, data: Map<String, Value>) -> Result<Self, ApiError> {
+        if data.contains_key("id") {
+            return Err(ApiError::bad_request(
+                "item data must not contain 'id'".to_string(),
+            ));
+        }
+        let (id, id_assigned) = checked_id("item", id)?;
+        Ok(Self {
+            id,
+            id_assigned,
+            data,
+            placement: Placement::default(),
+        })
+    }
+
+    /// An item in its `playlists` namespace form.
+    pub fn from_value(value: &Value) -> Result<Self, ApiError> {
+        let Value::Object(fields) = value else {
+            return Err(ApiError::bad_request(
+                "each playlist item must be an object".to_string(),
+            ));
+        };
+        let mut data = fields.clone();
+        let id = take_id(&mut data, "id", "item id")?;
+        Self::new(id, data)
+    }
+
+    fn to_value(&self) -> Value {
+        let mut fields = Map::new();
+        fields.insert("id".to_string(), Value::String(self.id.clone()));
+        fields.extend(self.data.clone());
+        Value::Object(fields)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    use super::{
+        Anchor, Playlist, PlaylistItem, keep_assigned_ids, parse_playlists, render_playlists,
+        salvage_playlists, set_playlist_value,
+    };
+
+    #[test]
+    fn parses_and_renders_namespace_value() {
+        let value = json!([
+            { "id": "p1", "name": "Party", "pinned": true, "items": [{ "id": "a", "uri": "x" }] },
+            { "items": [{ "uri": "y" }] },
+        ]);
+
+        let playlists = parse_playlists(&value).expect("valid");
+        assert_eq!(playlists[0].name.as_deref(), Some("Party"));
+        assert_eq!(playlists[0].attributes["pinned"], json!(true));
+        assert_eq!(playlists[0].items[0].data["uri"], json!("x"));
+        // Missing ids are generated.
+        assert_eq!(playlists[1].id.len(), 16);
+        assert_eq!(playlists[1].items[0].id.len(), 16);
+
+        let rendered = render_playlists(&playlists);
+        assert_eq!(rendered[0], value[0]);
+        let reparsed = parse_playlists(&rendered).expect("valid");
+        assert_eq!(render_playlists(&reparsed), rendered);
+        assert!(playlists[1].id_assigned && !reparsed[1].id_assigned);
+    }
+
+    #[test]
+    fn integer_ids_are_kept_as_strings() {
+        let playlists =
+            parse_playlists(&json!([{ "id": 7, "items": [{ "id": 42 }] }])).expect("valid");
+
+        assert_eq!(playlists[0].id, "7");
+        assert_eq!(playlists[0].items[0].id, "42");
+        assert!(!playlists[0].id_assigned);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        assert!(parse_playlists(&json!({})).is_err());
+        assert!(parse_playlists(&json!(["p1"])).is_err());
+        assert!(parse_playlists(&json!([{ "id": "p1" }, { "id": "p1" }])).is_err());
+        assert!(parse_playlists(&json!([{ "id": 1.5 }])).is_err());
+        assert!(parse_playlists(&json!([{ "id": true }])).is_err());
+        assert!(parse_playlists(&json!([{ "id": "import" }])).is_err());
+        assert!(parse_playlists(&json!([{ "items": [{ "id": "a" }, { "id": "a" }] }])).is_err());
+    }
+
+    #[test]
+    fn inserts_moves_and_removes_items() {
+        let mut playlist = parse_playlists(&json!([{ "id": "p", "items": [{ "id": "a" }] }]))
+            .expect("valid")
+            .remove(0);
+        let item = |id: &str| PlaylistItem::new(Some(id.to_string()), Default::default()).unwrap();
+        let ids = |playlist: &super::Playlist| {
+            playlist
+                .items
+                .iter()
+                .map(|item| item.id.clone())
+                .collect::<Vec<_>>()
+        };
+
+        playlist
+            .insert_items(None, vec![item("b"), item("c")], "phone")
+            .unwrap();
+        playlist
+            .insert_items(Some(&Anchor::Index(0)), vec![item("d")], "phone")
+            .unwrap();
+        assert_eq!(ids(&playlist), ["d", "a", "b", "c"]);
+        assert!(
+            playlist
+                .insert_items(None, vec![item("a")], "phone")
+                .is_err()
+        );
+
+        playlist
+            .move_item("d", &Anchor::Index(99), "phone")
+            .unwrap();
+        playlist
+            .move_item("c", &Anchor::Before("a".to_string()), "laptop")
+            .unwrap();
+        assert_eq!(ids(&playlist), ["c", "a", "b", "d"]);
+        assert_eq!(playlist.items[0].placement.client_id, "laptop");
+        assert!(
+            playlist
+                .move_item("c", &Anchor::After("c".to_string()), "laptop")
+                .is_err()
+        );
+
+        playlist.remove_item("a").unwrap();
+        assert_eq!(ids(&playlist), ["c", "b", "d"]);
+        assert!(playlist.remove_item("a").is_err());
+    }
+
+    #[test]
+    fn same_position_from_two_clients_orders_by_client() {
+        let mut playlist = parse_playlists(&json!([{ "id": "p", "items": [{ "id": "a" }] }]))
+            .expect("valid")
+            .remove(0);
+        let item = |id: &str| PlaylistItem::new(Some(id.to_string()), Default::default()).unwrap();
+        playlist.place_items(None, "");
+        let position = playlist.items[0].placement.position.clone();
+
+        // Both devices computed the same key offline; arrival order must not
+        // matter.
+        let anchor = Anchor::Position(format!("{position}V"));
+        playlist
+            .insert_items(Some(&anchor), vec![item("from-tv")], "tv")
+            .unwrap();
+        playlist
+            .insert_items(Some(&anchor), vec![item("from-car")], "car")
+            .unwrap();
+        let ids: Vec<_> = playlist.items.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, ["a", "from-car", "from-tv"]);
+    }
+
+    #[test]
+    fn rewrites_keep_placements_of_unmoved_items() {
+        let value = json!([{ "id": "p", "items": [{ "id": "a" }, { "id": "b" }, { "id": "c" }] }]);
+        let mut stored = parse_playlists(&value).expect("valid").remove(0);
+        stored.place_items(None, "phone");
+
+        let reordered =
+            json!([{ "id": "p", "items": [{ "id": "c" }, { "id": "a" }, { "id": "b" }] }]);
+        let mut playlist = parse_playlists(&reordered).expect("valid").remove(0);
+        playlist.place_items(Some(&stored), "laptop");
+
+        assert_eq!(playlist.items[1].placement, stored.items[0].placement);
+        assert_eq!(playlist.items[2].placement, stored.items[1].placement);
+        assert_eq!(playlist.items[0].placement.client_id, "laptop");
+        let mut sorted = playlist.clone();
+        sorted.sort_items();
+        assert_eq!(sorted, playlist);
+    }
+
+    #[test]
+    fn rejects_conflicting_or_invalid_anchors() {
+        assert!(Anchor::from_fields(Some(1), Some("a".to_string()), None, None).is_err());
+        assert!(Anchor::from_fields(None, None, None, Some("V0".to_string())).is_err());
+        assert!(Anchor::from_fields(None, None, None, Some("a-b".to_string())).is_err());
+        assert_eq!(
+            Anchor::from_fields(None, None, Some("a".to_string()), None).unwrap(),
+            Some(Anchor::Before("a".to_string()))
+        );
+        assert_eq!(Anchor::from_fields(None, None, None, None).unwrap(), None);
+    }
+
+    #[test]
+    fn rewrites_without_ids_keep_assigned_ids() {
+        let written = json!([
+            { "name": "Party", "items": [{ "uri": "a" }, { "uri": "b" }] },
+            { "name": "Focus" },
+        ]);
+        let stored = parse_playlists(&written).expect("valid");
+
+        // A client that never sends ids renames a playlist, reorders and adds
+        // items, and inserts a new playlist in front.
+        let mut rewritten = parse_playlists(&json!([
+            { "name": "New" },
+            { "name": "Party", "items": [{ "uri": "c" }, { "uri": "b" }, { "uri": "a" }] },
+            { "name": "Deep focus" },
+        ]))
+        .expect("valid");
+        keep_assigned_ids(&mut rewritten, &stored);
+
+        assert_eq!(rewritten[1].id, stored[0].id);
+        assert_eq!(rewritten[1].items[1].id, stored[0].items[1].id);
+        assert_eq!(rewritten[1].items[2].id, stored[0].items[0].id);
+        assert!(rewritten[1].items[0].id != stored[0].items[0].id);
+        // No other name matches, so the remaining ones pair up in order.
+        assert_eq!(rewritten[0].id, stored[1].id);
+        assert!(rewritten[2].id != stored[0].id && rewritten[2].id != stored[1].id);
+        assert!(rewritten.iter().all(|playlist| playlist.id_assigned));
+    }
+
+    #[test]
+    fn assigned_ids_named_by_a_write_are_not_reused() {
+        let stored = parse_playlists(&json!([{ "name": "Party" }])).expect("valid");
+        let mut rewritten = parse_playlists(&json!([
+            { "name": "Party" },
+            { "id": stored[0].id, "name": "Renamed" },
+            { "id": "mine", "name": "Mine" },
+        ]))
+        .expect("valid");
+        keep_assigned_ids(&mut rewritten, &stored);
+
+        assert!(rewritten[0].id != stored[0].id);
+        // Sending an assigned id back keeps it marked as assigned.
+        assert!(rewritten[1].id_assigned);
+        assert!(!rewritten[2].id_assigned);
+    }
+
+    #[test]
+    fn salvages_valid_playlists() {
+        let playlists = salvage_playlists(&json!([
+            { "id": "a" },
+            "not a playlist",
+            { "id": "a", "name": "duplicate" },
+            { "id": 2, "items": [{ "id": "x" }, { "id": "x" }] },
+            { "id": "b", "items": [{ "uri": "y" }] },
+        ]));
+
+        let ids: Vec<_> = playlists
+            .iter()
+            .map(|playlist| playlist.id.as_str())
+            .collect();
+        assert_eq!(ids, ["a", "b"]);
+        assert_eq!(playlists[0].name, None);
+        assert!(salvage_playlists(&json!({})).is_empty());
+    }
+
+    #[test]
+    fn sets_and_removes_playlists_in_a_namespace_value() {
+        let mut value = json!([{ "id": "a" }, { "id": "b" }, { "id": "c" }]);
+
+        assert_eq!(
+            set_playlist_value(&mut value, "b", Some(json!({ "id": "b", "n": 1 })), None),
+            Some(1)
+        );
+        assert_eq!(value[1], json!({ "id": "b", "n": 1 }));
+
+        assert_eq!(set_playlist_value(&mut value, "a", None, None), Some(0));
+        assert_eq!(value, json!([{ "id": "b", "n": 1 }, { "id": "c" }]));
+
+        // Undoing the removal puts it back where it was.
+        assert_eq!(
+            set_playlist_value(&mut value, "a", Some(json!({ "id": "a" })), Some(0)),
+            None
+        );
+        set_playlist_value(&mut value, "d", Some(json!({ "id": "d" })), None);
+        set_playlist_value(&mut value, "e", Some(json!({ "id": "e" })), Some(99));
+        let ids: Vec<_> = value
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry["id"].as_str().unwrap())
+            .collect();
+        assert_eq!(ids, ["a", "b", "c", "d", "e"]);
+
+        let mut value = json!({});
+        set_playlist_value(&mut value, "a", Some(json!({ "id": "a" })), None);
+        assert_eq!(value, json!([{ "id": "a" }]));
+    }
+
+    /// What a device does to its own copy, by index into what it sees.
+    #[derive(Debug, Clone)]
+    enum Action {
+        Insert(usize),
+        Move(usize, usize),
+        Remove(usize),
+    }
+
+    /// An item edit as a device sends it to the item endpoints after making it
+    /// offline: the `position` the item got on the device, or its removal.
+    #[derive(Debug, Clone)]
+    enum Edit {
+        Insert(String, String),
+        Move(String, String),
+        Remove(String),
+    }
+
+    fn item(id: &str) -> PlaylistItem {
+        PlaylistItem::new(Some(id.to_string()), Default::default()).unwrap()
+    }
+
+    fn item_ids(playlist: &Playlist) -> Vec<String> {
+        playlist.items.iter().map(|item| item.id.clone()).collect()
+    }
+
+    fn position_of(playlist: &Playlist, item_id: &str) -> String {
+        let item = playlist.items.iter().find(|item| item.id == item_id);
+        item.unwrap().placement.position.clone()
+    }
+
+    /// Runs `actions` on `copy` as device `device` of `devices`, through the
+    /// same methods the item endpoints use, and returns the edits to send.
+    /// A device only moves its own items and the base items assigned to it,
+    /// since concurrent moves of one item are resolved by arrival.
+    fn act(copy: &mut Playlist, device: usize, devices: usize, actions: &[Action]) -> Vec<Edit> {
+        let client_id = format!("device{device}");
+        let owns = |item_id: &str| match item_id.strip_prefix("base-") {
+            Some(number) => number.parse::<usize>().unwrap() % devices == device,
+            None => item_id.starts_with(&client_id),
+        };
+
+        let mut edits = Vec::new();
+        for (number, action) in actions.iter().enumerate() {
+            let order = item_ids(copy);
+            let owned: Vec<&String> = order.iter().filter(|id| owns(id)).collect();
+            let edit = match *action {
+                Action::Insert(index) => {
+                    let item_id = format!("{client_id}-{number}");
+                    let anchor = Anchor::Index(index % (order.len() + 1));
+                    copy.insert_items(Some(&anchor), vec![item(&item_id)], &client_id)
+                        .unwrap();
+                    Edit::Insert(position_of(copy, &item_id), item_id)
+                }
+                Action::Move(from, to) if !owned.is_empty() => {
+                    let item_id = owned[from % owned.len()].clone();
+                    let anchor = Anchor::Index(to % order.len());
+                    copy.move_item(&item_id, &anchor, &client_id).unwrap();
+                    Edit::Move(position_of(copy, &item_id), item_id)
+                }
+                Action::Remove(index) if !order.is_empty() => {
+                    let item_id = order[index % order.len()].clone();
+                    copy.remove_item(&item_id).unwrap();
+                    Edit::Remove(item_id)
+                }
+                _ => continue,
+            };
+            edits.push(edit);
+        }
+        edits
+    }
+
+    /// Applies `edit` from `client_id` to the stored playlist the way the item
+    /// endpoints do: through `Playlist`, placing the result against what was
+    /// stored and reading it back in stored order. Edits of items another
+    /// device removed meanwhile get `404` and change nothing.
+    fn deliver(stored: &mut Playlist, client_id: &str, edit: &Edit) {
+        let mut playlist = stored.clone();
+        let result = match edit {
+            Edit::Insert(position, item_id) => playlist.insert_items(
+                Some(&Anchor::Position(position.clone())),
+                vec![item(item_id)],
+                client_id,
+            ),
+            Edit::Move(position, item_id) => {
+                playlist.move_item(item_id, &Anchor::Position(position.clone()), client_id)
+            }
+            Edit::Remove(item_id) => playlist.remove_item(item_id),
+        };
+        if result.is_err() {
+            return;
+        }
+        playlist.place_items(Some(stored), client_id);
+        playlist.sort_items();
+        *stored = playlist;
+    }
+
+    fn action() -> impl Strategy<Value = Action> {
+        prop_oneof![
+            3 => any::<usize>().prop_map(Action::Insert),
+            2 => (any::<usize>(), any::<usize>()).prop_map(|(from, to)| Action::Move(from, to)),
+            1 => any::<usize>().prop_map(Action::Remove),
+        ]
+    }
+
+    /// Delivers each device's edits in its own order, interleaved with the
+    /// other devices' as picked by `seed`.
+    fn interleave(mut stored: Playlist, edits: &[(String, Vec<Edit>)], mut seed: u64) -> Playlist {
+        let mut next = vec![0; edits.len()];
+        loop {
+            let pending: Vec<usize> = (0..edits.len())
+                .filter(|device| next[*device] < edits[*device].1.len())
+                .collect();
+            if pending.is_empty() {
+                return stored;
+            }
+            // A cheap deterministic pick; proptest varies `seed`.
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let device = pending[(seed % pending.len() as u64) as usize];
+            let (client_id, device_edits) = &edits[device];
+            deliver(&mut stored, client_id, &device_edits[next[device]]);
+            next[device] += 1;
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn concurrent_item_edits_converge_in_any_delivery_order(
+            initial in 0usize..8,
+            devices in prop::collection::vec(prop::collection::vec(action(), 0..8), 1..4),
+            seeds in (any::<u64>(), any::<u64>()),
+        ) {
+            let mut base = Playlist::new(Some("p".to_string()), None, Default::default(), Vec::new())
+                .unwrap();
+            let initial: Vec<PlaylistItem> =
+                (0..initial).map(|n| item(&format!("base-{n}"))).collect();
+            base.insert_items(None, initial, "").unwrap();
+
+            let mut copies = Vec::new();
+            let mut edits = Vec::new();
+            for (device, actions) in devices.iter().enumerate() {
+                let mut copy = base.clone();
+                let device_edits = act(&mut copy, device, devices.len(), actions);
+                edits.push((format!("device{device}"), device_edits));
+                copies.push(copy);
+            }
+
+            let first = interleave(base.clone(), &edits, seeds.0 | 1);
+            let second = interleave(base, &edits, seeds.1 | 1);
+            prop_assert_eq!(item_ids(&first), item_ids(&second));
+
+            // Each device's own items keep the order it gave them.
+            let order = item_ids(&first);
+            for (copy, (client_id, _)) in copies.iter().zip(&edits) {
+                let own = |ids: Vec<String>| -> Vec<String> {
+                    ids.into_iter()
+                        .filter(|id| id.starts_with(client_id.as_str()) && order.contains(id))
+                        .collect()
+                };
+                prop_assert_eq!(own(item_ids(copy)), own(order.clone()));
+            }
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Request, State},
+    http::{HeaderMap, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+
+use crate::{
+    audit::{self, ClientInfo},
+    config::RateLimitConfig,
+    errors::ApiError,
+    handlers::{bearer_token_from_headers, cookie_from_headers},
+    oidc,
+    state::AppContext,
+};
+
+/// Buckets are pruned once the map grows past this many keys.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// How long a credential that authenticated successfully may bypass an IP's
+/// failed-authentication block.
+const TRUSTED_CREDENTIAL_TTL: Duration = Duration::from_secs(60 * 60);
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// In-process token bucket rate limiter keyed by an arbitrary string (an IP
+/// address, a user id, ...). Each key may burst up to `capacity` requests and
+/// regains one every `refill_every`.
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: u32, refill_every: Duration) -> Self {
+        Self {
+            capacity: f64::from(capacity.max(1)),
+            refill_per_second: 1.0 / refill_every.as_secs_f64().max(f64::EPSILON),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `limit` requests per minute per key, bursting up to `limit`; `None`
+    /// when `limit` is 0 (disabled).
+    pub fn per_minute(limit: u32) -> Option<Self> {
+        (limit > 0).then(|| Self::new(limit, Duration::from_secs(60) / limit))
+    }
+
+    /// Checks whether `key` has a token left without taking it.
+    pub async fn peek(&self, key: &str) -> Result<(), Duration> {
+        let buckets = self.buckets.lock().await;
+        let Some(bucket) = buckets.get(key) else {
+            return Ok(());
+        };
+        self.available(self.refilled(bucket, Instant::now()))
+    }
+
+    /// Takes one token for `key`. On failure returns how long until a token
+    /// becomes available.
+    pub async fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().await;
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refilled(bucket, now) < self.capacity);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated_at = now;
+
+        self.available(bucket.tokens)?;
+        bucket.tokens -= 1.0;
+        Ok(())
+    }
+
+    fn available(&self, tokens: f64) -> Result<(), Duration> {
+        if tokens >= 1.0 {
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - tokens) / self.refill_per_second,
+            ))
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        (bucket.tokens + elapsed * self.refill_per_second).min(self.capacity)
+    }
+}
+
+/// Credentials, keyed by [`credential_key`], that recently got a successful
+/// response. Entries expire after [`TRUSTED_CREDENTIAL_TTL`] and are dropped
+/// as soon as the credential fails authentication.
+#[derive(Default)]
+pub struct TrustedCredentials {
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl TrustedCredentials {
+    pub async fn contains(&self, key: &str) -> bool {
+        let seen = self.seen.lock().await;
+        seen.get(key)
+            .is_some_and(|seen_at| seen_at.elapsed() < TRUSTED_CREDENTIAL_TTL)
+    }
+
+    pub async fn insert(&self, key: String) {
+        let now = Instant::now();
+        let mut seen = self.seen.lock().await;
+        if seen.len() > PRUNE_THRESHOLD {
+            seen.retain(|_, seen_at| now.duration_since(*seen_at) < TRUSTED_CREDENTIAL_TTL);
+        }
+        seen.insert(key, now);
+    }
+
+    pub async fn remove(&self, key: &str) {
+        self.seen.lock().await.remove(key);
+    }
+}
+
+/// Rate limits applied by [`enforce_rate_limits`]. Limiters are `None` when
+/// disabled.
+pub struct RequestLimits {
+    /// Failed authentications (`401` responses) per client IP. Only failures
+    /// it counts are written to the audit log. Once exhausted, requests from
+    /// that IP are refused until it refills unless their credential is in
+    /// `trusted_credentials`, or `refuse_auth_failures` is off.
+    pub auth_failures: RateLimiter,
+    pub refuse_auth_failures: bool,
+    pub trusted_credentials: TrustedCredentials,
+    /// Write requests per credential (bearer token or session cookie),
+    /// keyed by [`credential_key`]. WebSocket writes share the bucket of the
+    /// token the socket was opened with.
+    pub writes: Option<RateLimiter>,
+}
+
+impl RequestLimits {
+    pub fn new(config: RateLimitConfig) -> Self {
+        let (auth_failures, refuse_auth_failures) =
+            match RateLimiter::per_minute(config.auth_failures_per_minute) {
+                Some(limiter) => (limiter, true),
+                None => (
+                    RateLimiter::new(audit::AUTH_FAILURE_BURST, audit::AUTH_FAILURE_REFILL),
+                    false,
+                ),
+            };
+        Self {
+            auth_failures,
+            refuse_auth_failures,
+            trusted_credentials: TrustedCredentials::default(),
+            writes: RateLimiter::per_minute(config.writes_per_minute),
+        }
+    }
+}
+
+fn is_write(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    )
+}
+
+/// Key for a credential in the write limiter. Hashed so raw tokens are not
+/// kept in memory; unknown tokens get their own bucket and then fail
+/// authentication.
+pub fn credential_key(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    format!("{digest:x}")
+}
+
+fn request_credential(headers: &HeaderMap) -> Option<String> {
+    bearer_token_from_headers(headers)
+        .or_else(|| cookie_from_headers(headers, oidc::SESSION_COOKIE))
+}
+
+/// The credential a request carries, including the `token` query parameter
+/// `/v1/ws` and `/v1/events` accept.
+fn any_request_credential(request: &Request) -> Option<String> {
+    request_credential(request.headers()).or_else(|| {
+        request.uri().query().and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == "token")
+                .map(|(_, value)| value.to_string())
+        })
+    })
+}
+
+/// Refuses requests from IPs with too many failed authentications, unless
+/// their credential recently succeeded, and writes beyond the per-credential
+/// limit, with `429` and `Retry-After`. Failed authentications are audited
+/// here as well.
+pub async fn enforce_rate_limits(
+    State(state): State<Arc<AppContext>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let limits = &state.request_limits;
+    let client = ClientInfo::new(
+        request.extensions(),
+        request.headers(),
+        state.trust_proxy_headers,
+    );
+    let ip = client.ip.clone().unwrap_or_default();
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    // A blocked IP can still use credentials that already succeeded, so one
+    // client's failures do not lock out others behind the same address. New
+    // credentials are not tried until the block ends.
+    let credential = any_request_credential(&request).map(|token| credential_key(&token));
+    let blocked = match limits.refuse_auth_failures {
+        true => limits.auth_failures.peek(&ip).await.err(),
+        false => None,
+    };
+    let auth_failures_refused = |retry_after| {
+        ApiError::rate_limited(
+            "too many failed authentication attempts".to_string(),
+            retry_after,
+        )
+        .into_response()
+    };
+    if let Some(retry_after) = blocked {
+        let trusted = match &credential {
+            Some(key) => limits.trusted_credentials.contains(key).await,
+            None => false,
+        };
+        if !trusted {
+            return auth_failures_refused(retry_after);
+        }
+    }
+
+    if let Some(limiter) = &limits.writes
+        && is_write(request.method())
+        && let Some(token) = request_credential(request.headers())
+        && let Err(retry_after) = limiter.check(&credential_key(&token)).await
+    {
+        return ApiError::rate_limited("too many write requests".to_string(), retry_after)
+            .into_response();
+    }
+
+    let response = next.run(request).await;
+
+    if let Some(key) = credential {
+        if response.status().is_success() {
+            limits.trusted_credentials.insert(key).await;
+        } else if response.status() == StatusCode::UNAUTHORIZED {
+            limits.trusted_credentials.remove(&key).await;
+        }
+    }
+
+    if response.status() == StatusCode::UNAUTHORIZED
+        && let Some(retry_after) = blocked
+    {
+        return auth_failures_refused(retry_after);
+    }
+
+    // Counting is all that happens here; the next request from this IP is
+    // refused once the limit is used up.
+    if response.status() == StatusCode::UNAUTHORIZED
+        && limits.auth_failures.check(&ip).await.is_ok()
+    {
+        audit::record_auth_failure(&state, &client, &method, &path).await;
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        Router,
+        extract::State,
+        http::{HeaderMap, StatusCode, header},
+        middleware,
+        routing::get,
+    };
+    use sqlx::postgres::PgPoolOptions;
+
+    use super::{RateLimiter, RequestLimits, enforce_rate_limits};
+    use crate::{
+        config::{HistoryRetention, RateLimitConfig},
+        state::AppContext,
+    };
+
+    /// Serves `/` behind [`enforce_rate_limits`], answering `401` unless the
+    /// bearer token is `good`, and returns the number of requests that reached
+    /// the handler. The pool never connects, so auditing the failures only
+    /// logs an error.
+    async fn spawn_server(rate_limits: RateLimitConfig) -> (String, Arc<AtomicUsize>) {
+        async fn handler(State(calls): State<Arc<AtomicUsize>>, headers: HeaderMap) -> StatusCode {
+            calls.fetch_add(1, Ordering::SeqCst);
+            match headers.get(header::AUTHORIZATION) {
+                Some(value) if value == "Bearer good" => StatusCode::OK,
+                _ => StatusCode::UNAUTHORIZED,
+            }
+        }
+
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(50))
+            .connect_lazy("postgres://nobody@127.0.0.1:1/none")
+            .expect("pool");
+        let retention = HistoryRetention {
+            max_revisions: 1,
+            max_age_days: 1,
+        };
+        let state = Arc::new(AppContext::new(pool, retention, None, rate_limits, false));
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route("/", get(handler).post(handler))
+            .with_state(calls.clone())
+            .layer(middleware::from_fn_with_state(state, enforce_rate_limits));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let base = format!("http://{}", listener.local_addr().expect("addr"));
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+        (base, calls)
+    }
+
+    #[tokio::test]
+    async fn refuses_failing_clients_with_retry_after_but_not_trusted_tokens() {
+        let (base, calls) = spawn_server(RateLimitConfig {
+            auth_failures_per_minute: 2,
+            writes_per_minute: 0,
+        })
+        .await;
+        let client = reqwest::Client::new();
+        let get = |token: Option<&str>| {
+            let request = client.get(&base);
+            match token {
+                Some(token) => request.bearer_auth(token),
+                None => request,
+            }
+            .send()
+        };
+
+        let response = get(Some("good")).await.expect("request");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        for _ in 0..2 {
+            let response = get(Some("bad")).await.expect("request");
+            assert_eq!(response.status(), reqwest::StatusCode::UNAUTHORIZED);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Guesses from the blocked IP never reach authentication.
+        for token in [Some("bad"), Some("other"), None] {
+            let response = get(token).await.expect("request");
+            assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+            let retry_after: u64 = response.headers()[reqwest::header::RETRY_AFTER]
+                .to_str()
+                .expect("ascii")
+                .parse()
+                .expect("seconds");
+            assert!((1..=60).contains(&retry_after));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // A token that already succeeded still gets through.
+        let response = get(Some("good")).await.expect("request");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn limits_writes_per_credential() {
+        let (base, _) = spawn_server(RateLimitConfig {
+            auth_failures_per_minute: 0,
+            writes_per_minute: 1,
+        })
+        .await;
+        let client = reqwest::Client::new();
+
+        let post = || client.post(&base).bearer_auth("good").send();
+        assert_eq!(
+            post().await.expect("request").status(),
+            reqwest::StatusCode::OK
+        );
+        let response = post().await.expect("request");
+        assert_eq!(response.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+        assert!(
+            response
+                .headers()
+                .contains_key(reqwest::header::RETRY_AFTER)
+        );
+
+        // Reads are not limited.
+        let response = client
+            .get(&base)
+            .bearer_auth("good")
+            .send()
+            .await
+            .expect("request");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn limits_each_key_independently() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+
+        assert!(limiter.check("a").await.is_ok());
+        assert!(limiter.check("a").await.is_ok());
+        let retry_after = limiter.check("a").await.expect_err("bucket is empty");
+        assert!(retry_after > Duration::from_secs(55));
+
+        assert!(limiter.check("b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn disabled_auth_failure_limit_still_thins_out_auditing() {
+        let limits = RequestLimits::new(RateLimitConfig {
+            auth_failures_per_minute: 0,
+            writes_per_minute: 0,
+        });
+
+        assert!(!limits.refuse_auth_failures);
+        assert!(limits.writes.is_none());
+        for _ in 0..crate::audit::AUTH_FAILURE_BURST {
+            assert!(limits.auth_failures.check("203.0.113.9").await.is_ok());
+        }
+        assert!(limits.auth_failures.check("203.0.113.9").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn peek_does_not_consume() {
+        let limiter = RateLimiter::per_minute(1).expect("enabled");
+
+        assert!(limiter.peek("a").await.is_ok());
+        assert!(limiter.check("a").await.is_ok());
+        assert!(limiter.peek("a").await.is_err());
+        assert!(RateLimiter::per_minute(0).is_none());
+    }
+}
@@ -0,0 +1,152 @@
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+
+use crate::{
+    db::{load_versions, update_events_since},
+    errors::ApiError,
+    models::{AuthenticatedUser, Replay, TokenEvent, UpdateEvent},
+};
+
+/// How long the token a realtime stream was opened with keeps working. The
+/// stream ends when it expires, is revoked, or its rotation grace period
+/// runs out.
+#[derive(Debug)]
+pub struct TokenLifetime {
+    token_id: i64,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl TokenLifetime {
+    pub fn new(user: &AuthenticatedUser) -> Self {
+        Self {
+            token_id: user.token_id,
+            expires_at: user.expires_at,
+        }
+    }
+
+    /// Whether `event` is about this stream's token, taking the event's
+    /// expiry when it is sooner.
+    pub fn apply(&mut self, event: &TokenEvent) -> bool {
+        if event.token_id != self.token_id {
+            return false;
+        }
+        self.expires_at = Some(self.expires_at.map_or(event.expires_at, |expires_at| {
+            expires_at.min(event.expires_at)
+        }));
+        true
+    }
+
+    pub fn expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
+
+    /// Resolves once the token has expired; never for tokens without expiry.
+    pub async fn until_expired(&self) {
+        match self.expires_at {
+            Some(expires_at) => {
+                let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+                tokio::time::sleep(remaining).await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Where a realtime stream (WebSocket or SSE) stands in the user's
+/// `state_updated` events. Both transports use it to drop duplicates and to
+/// replay what they missed after falling behind.
+#[derive(Debug, Default)]
+pub struct StreamCursor {
+    /// Version of the last event delivered (or skipped by the subscriber).
+    last_version: Option<i64>,
+}
+
+impl StreamCursor {
+    /// A cursor that replays everything after `since_version` on its first
+    /// `catch_up`.
+    pub fn since(since_version: i64) -> Self {
+        Self {
+            last_version: Some(since_version),
+        }
+    }
+
+    /// A cursor at the user's current version, for streams that start live.
+    /// When the version cannot be read, the first lag resyncs the client.
+    pub async fn current(pool: &PgPool, user_id: i64) -> Self {
+        Self {
+            last_version: load_versions(pool, user_id)
+                .await
+                .ok()
+                .map(|(version, _)| version),
+        }
+    }
+
+    /// Whether a live event is new to the stream, moving the cursor past it.
+    pub fn advance(&mut self, event: &UpdateEvent) -> bool {
+        if self.last_version.is_some_and(|last| event.version <= last) {
+            return false;
+        }
+        self.last_version = Some(event.version);
+        true
+    }
+
+    /// The events after the cursor from revision history, moving the cursor
+    /// to the last of them. When history no longer covers the gap, or the
+    /// cursor never learned a version, the client has to resync instead.
+    pub async fn catch_up(&mut self, pool: &PgPool, user_id: i64) -> Result<Replay, ApiError> {
+        let replay = match self.last_version {
+            Some(since_version) => update_events_since(pool, user_id, since_version).await?,
+            None => Replay::ResyncRequired(load_versions(pool, user_id).await?.0),
+        };
+        match &replay {
+            Replay::Events(events) => {
+                if let Some(event) = events.last() {
+                    self.last_version = Some(event.version);
+                }
+            }
+            Replay::ResyncRequired(version) => self.last_version = Some(*version),
+        }
+        Ok(replay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::StreamCursor;
+    use crate::models::{Namespace, NamespaceVersions, UpdateEvent};
+
+    fn event(version: i64) -> UpdateEvent {
+        UpdateEvent {
+            event_type: "state_updated".to_string(),
+            namespace: Namespace::Settings,
+            version,
+            namespace_versions: NamespaceVersions::default(),
+            updated_at: Utc::now(),
+            source_client_id: None,
+            playlist_id: None,
+            ended_shares: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn drops_events_at_or_before_the_cursor() {
+        let mut cursor = StreamCursor::since(5);
+
+        assert!(!cursor.advance(&event(4)));
+        assert!(!cursor.advance(&event(5)));
+        assert!(cursor.advance(&event(7)));
+        assert!(!cursor.advance(&event(6)));
+        assert_eq!(cursor.last_version, Some(7));
+    }
+
+    #[test]
+    fn cursor_without_version_accepts_everything() {
+        let mut cursor = StreamCursor::default();
+
+        assert!(cursor.advance(&event(3)));
+        assert_eq!(cursor.last_version, Some(3));
+    }
+}
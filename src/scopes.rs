@@ -0,0 +1,219 @@
+use std::fmt;
+
+use serde::{Serialize, Serializer};
+use tracing::warn;
+
+use crate::{errors::ApiError, models::Namespace};
+
+/// A permission granted to an API token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Full access, including admin endpoints for admin users. Tokens created
+    /// without explicit scopes get this.
+    All,
+    /// Read every namespace, the snapshot and the history.
+    SnapshotRead,
+    /// Write every namespace and the snapshot.
+    SnapshotWrite,
+    StateRead(Namespace),
+    StateWrite(Namespace),
+    /// Open `/v1/ws` or `/v1/events` and see which devices are online.
+    WsSubscribe,
+    /// Send remote playback commands and offer or claim handoffs.
+    PlaybackControl,
+}
+
+impl Scope {
+    pub fn parse(value: &str) -> Result<Self, ApiError> {
+        let value = value.trim();
+        let scope = match value {
+            "*" => Some(Self::All),
+            "snapshot:read" => Some(Self::SnapshotRead),
+            "snapshot:write" => Some(Self::SnapshotWrite),
+            "ws:subscribe" => Some(Self::WsSubscribe),
+            "playback:control" => Some(Self::PlaybackControl),
+            _ => {
+                if let Some(namespace) = value.strip_prefix("state:read:") {
+                    Namespace::from_str_id(namespace)
+                        .filter(|namespace| *namespace != Namespace::Snapshot)
+                        .map(Self::StateRead)
+                } else if let Some(namespace) = value.strip_prefix("state:write:") {
+                    Namespace::from_str_id(namespace)
+                        .filter(|namespace| *namespace != Namespace::Snapshot)
+                        .map(Self::StateWrite)
+                } else {
+                    None
+                }
+            }
+        };
+        scope.ok_or_else(|| ApiError::bad_request(format!("unknown scope '{value}'")))
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::All => f.write_str("*"),
+            Self::SnapshotRead => f.write_str("snapshot:read"),
+            Self::SnapshotWrite => f.write_str("snapshot:write"),
+            Self::StateRead(namespace) => write!(f, "state:read:{}", namespace.as_str()),
+            Self::StateWrite(namespace) => write!(f, "state:write:{}", namespace.as_str()),
+            Self::WsSubscribe => f.write_str("ws:subscribe"),
+            Self::PlaybackControl => f.write_str("playback:control"),
+        }
+    }
+}
+
+impl Serialize for Scope {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The scopes of the token a request was authenticated with.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(transparent)]
+pub struct Scopes(Vec<Scope>);
+
+impl Scopes {
+    pub fn all() -> Self {
+        Self(vec![Scope::All])
+    }
+
+    /// Parses a scope list; an empty list is rejected rather than creating a
+    /// token that can do nothing.
+    pub fn parse<S: AsRef<str>>(values: &[S]) -> Result<Self, ApiError> {
+        let mut scopes = Vec::new();
+        for value in values {
+            let scope = Scope::parse(value.as_ref())?;
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        if scopes.is_empty() {
+            return Err(ApiError::bad_request(
+                "scopes must not be empty".to_string(),
+            ));
+        }
+        Ok(Self(scopes))
+    }
+
+    /// Reads scopes stored with a token, skipping any this server version does
+    /// not know instead of failing authentication.
+    pub fn from_stored(values: &[String]) -> Self {
+        Self(
+            values
+                .iter()
+                .filter_map(|value| match Scope::parse(value) {
+                    Ok(scope) => Some(scope),
+                    Err(_) => {
+                        warn!(scope = %value, "ignoring unknown token scope");
+                        None
+                    }
+                })
+                .collect(),
+        )
+    }
+
+    pub fn to_strings(&self) -> Vec<String> {
+        self.0.iter().map(Scope::to_string).collect()
+    }
+
+    fn has(&self, scope: Scope) -> bool {
+        self.0.contains(&Scope::All) || self.0.contains(&scope)
+    }
+
+    fn check(&self, allowed: bool, required: Scope) -> Result<(), ApiError> {
+        if allowed {
+            Ok(())
+        } else {
+            Err(ApiError::insufficient_scope(format!(
+                "token lacks the {required} scope"
+            )))
+        }
+    }
+
+    /// Requires `scope` itself (or full access).
+    pub fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        self.check(self.has(scope), scope)
+    }
+
+    /// Requires read access to `namespace`; `Namespace::Snapshot` means all of
+    /// them.
+    pub fn require_read(&self, namespace: Namespace) -> Result<(), ApiError> {
+        if namespace == Namespace::Snapshot {
+            return self.require(Scope::SnapshotRead);
+        }
+        let allowed = self.has(Scope::SnapshotRead) || self.has(Scope::StateRead(namespace));
+        self.check(allowed, Scope::StateRead(namespace))
+    }
+
+    /// Requires write access to `namespace`; `Namespace::Snapshot` means all
+    /// of them.
+    pub fn require_write(&self, namespace: Namespace) -> Result<(), ApiError> {
+        if namespace == Namespace::Snapshot {
+            return self.require(Scope::SnapshotWrite);
+        }
+        let allowed = self.has(Scope::SnapshotWrite) || self.has(Scope::StateWrite(namespace));
+        self.check(allowed, Scope::StateWrite(namespace))
+    }
+
+    /// Requires every scope in `other`, so a token can only hand out access
+    /// it already has.
+    pub fn require_all(&self, other: &Scopes) -> Result<(), ApiError> {
+        other.0.iter().try_for_each(|scope| match *scope {
+            Scope::StateRead(namespace) => self.require_read(namespace),
+            Scope::StateWrite(namespace) => self.require_write(namespace),
+            scope => self.require(scope),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Scope, Scopes};
+    use crate::models::Namespace;
+
+    #[test]
+    fn parses_and_formats_scopes() {
+        let scopes =
+            Scopes::parse(&["snapshot:read", "state:write:settings", "ws:subscribe"]).expect("ok");
+        assert_eq!(
+            scopes.to_strings(),
+            vec!["snapshot:read", "state:write:settings", "ws:subscribe"]
+        );
+        assert!(Scope::parse("state:write:snapshot").is_err());
+        assert!(Scope::parse("state:delete:settings").is_err());
+        assert!(Scopes::parse::<&str>(&[]).is_err());
+    }
+
+    #[test]
+    fn enforces_namespace_access() {
+        let widget = Scopes::parse(&["state:read:app_state", "ws:subscribe"]).expect("ok");
+        assert!(widget.require_read(Namespace::AppState).is_ok());
+        assert!(widget.require_read(Namespace::Playlists).is_err());
+        assert!(widget.require_read(Namespace::Snapshot).is_err());
+        assert!(widget.require_write(Namespace::AppState).is_err());
+        assert!(widget.require(Scope::WsSubscribe).is_ok());
+
+        let writer = Scopes::parse(&["snapshot:write"]).expect("ok");
+        assert!(writer.require_write(Namespace::Playlists).is_ok());
+        assert!(writer.require_read(Namespace::Playlists).is_err());
+
+        let full = Scopes::all();
+        assert!(full.require_write(Namespace::Snapshot).is_ok());
+        assert!(full.require(Scope::PlaybackControl).is_ok());
+
+        assert!(
+            writer
+                .require_all(&Scopes::parse(&["state:write:settings"]).expect("ok"))
+                .is_ok()
+        );
+        assert!(
+            writer
+                .require_all(&Scopes::parse(&["state:read:settings"]).expect("ok"))
+                .is_err()
+        );
+        assert!(widget.require_all(&Scopes::all()).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use std::{collections::VecDeque, convert::Infallible, sync::Arc};
+
+use axum::response::sse::Event;
+use futures_util::{Stream, stream};
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tracing::error;
+
+use crate::{
+    models::{AuthenticatedUser, Replay, ResyncRequiredEvent, UpdateEvent, UserEvent},
+    replay::{StreamCursor, TokenLifetime},
+    state::AppContext,
+};
+
+/// Per-stream state for `GET /v1/events`.
+struct EventStream {
+    state: Arc<AppContext>,
+    user_id: i64,
+    updates: broadcast::Receiver<UserEvent>,
+    /// Replayed events waiting to be sent before the live stream resumes.
+    pending: VecDeque<Event>,
+    /// Version of the last event sent. It is also the SSE event id, so a
+    /// reconnecting client's `Last-Event-ID` resumes from here.
+    cursor: StreamCursor,
+    /// The stream ends when the token it was opened with stops working.
+    token: TokenLifetime,
+}
+
+impl EventStream {
+    /// Queues the events after the cursor from revision history, or a
+    /// `resync_required` event when they cannot be replayed.
+    async fn catch_up(&mut self) {
+        match self.cursor.catch_up(&self.state.pool, self.user_id).await {
+            Ok(Replay::Events(events)) => {
+                self.pending.extend(events.iter().filter_map(update_event));
+            }
+            Ok(Replay::ResyncRequired(version)) => {
+                self.pending.extend(resync_required_event(version));
+            }
+            Err(err) => {
+                error!(
+                    user_id = self.user_id,
+                    ?err,
+                    "failed to replay missed SSE events"
+                );
+            }
+        }
+    }
+
+    async fn next_event(&mut self) -> Option<Event> {
+        loop {
+            if self.token.expired() {
+                return None;
+            }
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+
+            let result = tokio::select! {
+                _ = self.token.until_expired() => return None,
+                result = self.updates.recv() => result,
+            };
+            match result {
+                Ok(UserEvent::State(event)) => {
+                    if !self.cursor.advance(&event) {
+                        continue;
+                    }
+                    if let Some(event) = update_event(&event) {
+                        return Some(event);
+                    }
+                }
+                Ok(UserEvent::Device(event)) => {
+                    if let Some(event) = untracked_event(&event.event_type, &event) {
+                        return Some(event);
+                    }
+                }
+                Ok(UserEvent::Handoff(event)) => {
+                    if let Some(event) = untracked_event(&event.event_type, &event) {
+                        return Some(event);
+                    }
+                }
+                Ok(UserEvent::SharedPlaylist(event)) => {
+                    if let Some(event) = untracked_event(&event.event_type, &event) {
+                        return Some(event);
+                    }
+                }
+                // When it expires the token, the next call ends the stream.
+                Ok(UserEvent::Token(event)) => {
+                    if self.token.apply(&event) {
+                        return untracked_event(&event.event_type, &event);
+                    }
+                }
+                // The channel closes right after this, ending the stream.
+                Ok(UserEvent::Account(event)) => {
+                    return untracked_event(&event.event_type, &event);
+                }
+                // Commands need a `command_result` reply, which SSE cannot send.
+                Ok(UserEvent::Command(_) | UserEvent::CommandResult(_)) => {}
+                Err(broadcast::error::RecvError::Lagged(_)) => self.catch_up().await,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+fn update_event(event: &UpdateEvent) -> Option<Event> {
+    Event::default()
+        .event(&event.event_type)
+        .id(event.version.to_string())
+        .json_data(event)
+        .map_err(|err| error!("Failed to serialize SSE event: {err}"))
+        .ok()
+}
+
+/// Events that are not part of the versioned state stream carry no `id`, so
+/// they leave the client's `Last-Event-ID` on the last state version.
+fn untracked_event<T: Serialize>(event_type: &str, event: &T) -> Option<Event> {
+    Event::default()
+        .event(event_type)
+        .json_data(event)
+        .map_err(|err| error!("Failed to serialize SSE event: {err}"))
+        .ok()
+}
+
+fn resync_required_event(version: i64) -> Option<Event> {
+    let event = ResyncRequiredEvent::new(version);
+    Event::default()
+        .event(event.event_type)
+        .id(version.to_string())
+        .json_data(&event)
+        .map_err(|err| error!("Failed to serialize SSE event: {err}"))
+        .ok()
+}
+
+/// Builds the `text/event-stream` body for a user. When `since_version` is
+/// set, events written after it are replayed before live events start. The
+/// stream ends when the user's token stops working.
+pub async fn event_stream(
+    state: Arc<AppContext>,
+    user: AuthenticatedUser,
+    updates: broadcast::Receiver<UserEvent>,
+    since_version: Option<i64>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let cursor = match since_version {
+        Some(since_version) => StreamCursor::since(since_version),
+        None => StreamCursor::current(&state.pool, user.id).await,
+    };
+    let mut events = EventStream {
+        state,
+        user_id: user.id,
+        updates,
+        pending: VecDeque::new(),
+        cursor,
+        token: TokenLifetime::new(&user),
+    };
+    // `updates` is already subscribed, so anything written while catching up
+    // is buffered there and deduplicated by version.
+    if since_version.is_some() {
+        events.catch_up().await;
+    }
+
+    stream::unfold(events, |mut events| async move {
+        let event = events.next_event().await?;
+        Some((Ok(event), events))
+    })
+}
@@ -1,42 +1,131 @@
 use std::collections::HashMap;
 
+use rand::{Rng, distr::Alphanumeric};
 use sqlx::PgPool;
 use tokio::sync::{RwLock, broadcast};
 
-use crate::models::UpdateEvent;
+use crate::{
+    config::{HistoryRetention, RateLimitConfig},
+    db::list_playlist_shares,
+    fanout,
+    models::{Namespace, SharedPlaylistEvent, UpdateEvent, UserEvent},
+    oidc::OidcClient,
+    pairing,
+    rate_limit::{RateLimiter, RequestLimits},
+};
 
 /// Capacity per-user broadcast channel. Slow WebSocket clients that fall more
-/// than this many messages behind will receive a Lagged error and must refresh
-/// via a full snapshot.
+/// than this many messages behind have the missed events replayed from
+/// revision history (or are told to resync when history is too short).
 const USER_CHANNEL_CAPACITY: usize = 64;
 
 pub struct AppContext {
     pub pool: PgPool,
-    user_channels: RwLock<HashMap<i64, broadcast::Sender<UpdateEvent>>>,
+    pub history_retention: HistoryRetention,
+    /// Random id of this server process, used to skip our own cross-instance
+    /// notifications.
+    pub instance_id: String,
+    /// Set when OpenID Connect login is configured.
+    pub oidc: Option<OidcClient>,
+    /// Whether client IPs are taken from `X-Forwarded-For`.
+    pub trust_proxy_headers: bool,
+    /// Built-in limits on failed authentications and writes.
+    pub request_limits: RequestLimits,
+    /// Limits `POST /v1/pair/start` per client IP.
+    pub pairing_start_limiter: RateLimiter,
+    /// Limits `POST /v1/pair/approve` per user.
+    pub pairing_approve_limiter: RateLimiter,
+    user_channels: RwLock<HashMap<i64, broadcast::Sender<UserEvent>>>,
 }
 
 impl AppContext {
-    pub fn new(pool: PgPool) -> Self {
+    pub fn new(
+        pool: PgPool,
+        history_retention: HistoryRetention,
+        oidc: Option<OidcClient>,
+        rate_limits: RateLimitConfig,
+        trust_proxy_headers: bool,
+    ) -> Self {
         Self {
             pool,
+            history_retention,
+            oidc,
+            trust_proxy_headers,
+            request_limits: RequestLimits::new(rate_limits),
+            instance_id: rand::rng()
+                .sample_iter(Alphanumeric)
+                .take(16)
+                .map(char::from)
+                .collect(),
+            pairing_start_limiter: RateLimiter::new(pairing::START_BURST, pairing::START_REFILL),
+            pairing_approve_limiter: RateLimiter::new(
+                pairing::APPROVE_BURST,
+                pairing::APPROVE_REFILL,
+            ),
             user_channels: RwLock::new(HashMap::new()),
         }
     }
 
     /// Subscribe to update events for the given user. Creates a channel for
     /// that user if one does not already exist.
-    pub async fn subscribe_user(&self, user_id: i64) -> broadcast::Receiver<UpdateEvent> {
+    pub async fn subscribe_user(&self, user_id: i64) -> broadcast::Receiver<UserEvent> {
         let mut map = self.user_channels.write().await;
         map.entry(user_id)
             .or_insert_with(|| broadcast::channel(USER_CHANNEL_CAPACITY).0)
             .subscribe()
     }
 
-    /// Send an update event to all active WebSocket connections for this user.
-    /// If no channel exists for the user (no active subscribers), the event is
-    /// silently dropped. Stale channel entries (no remaining receivers) are
-    /// removed to prevent unbounded map growth.
-    pub async fn send_user_event(&self, user_id: i64, event: UpdateEvent) {
+    /// Send an event to all of this user's subscribers, on this instance and
+    /// (via Postgres `NOTIFY`) on every other instance.
+    ///
+    /// Playlist changes are also announced to the users the changed playlists
+    /// are shared with, and to the users whose shares the change ended.
+    pub async fn send_user_event(&self, user_id: i64, event: impl Into<UserEvent>) {
+        let event = event.into();
+        if let UserEvent::State(update) = &event {
+            self.send_shared_playlist_events(user_id, update).await;
+            for share in &update.ended_shares {
+                let event = SharedPlaylistEvent::removed(share);
+                self.publish(share.user_id, event.into()).await;
+            }
+        }
+        self.publish(user_id, event).await;
+    }
+
+    async fn publish(&self, user_id: i64, event: UserEvent) {
+        fanout::publish(&self.pool, &self.instance_id, user_id, &event).await;
+        self.send_local_event(user_id, event).await;
+    }
+
+    /// `shared_playlist_updated` for each share of the playlists `update`
+    /// may have changed: its `playlist_id`, or all of the owner's playlists
+    /// after a whole-namespace write.
+    async fn send_shared_playlist_events(&self, owner_id: i64, update: &UpdateEvent) {
+        if !matches!(update.namespace, Namespace::Playlists | Namespace::Snapshot) {
+            return;
+        }
+        // Failures are logged; the owner's event still goes out.
+        let Ok(shares) =
+            list_playlist_shares(&self.pool, owner_id, update.playlist_id.as_deref()).await
+        else {
+            return;
+        };
+        for share in &shares {
+            let event = SharedPlaylistEvent::updated(share, update.source_client_id.clone());
+            self.publish(share.user_id, event.into()).await;
+        }
+    }
+
+    /// Send an event to all active WebSocket and SSE connections for this user
+    /// on this instance. If no channel exists for the user (no active
+    /// subscribers), the event is silently dropped. Stale channel entries (no
+    /// remaining receivers) are removed to prevent unbounded map growth.
+    ///
+    /// An account event also removes the channel, so subscribers see it
+    /// closed once they have received the event.
+    pub async fn send_local_event(&self, user_id: i64, event: UserEvent) {
+        let closes_channel = matches!(event, UserEvent::Account(_));
+
         // Fast path: try to send under a read lock.
         let map = self.user_channels.read().await;
 
@@ -52,7 +141,9 @@ impl AppContext {
         // Drop the read lock before potentially taking a write lock.
         drop(map);
 
-        if should_cleanup {
+        if closes_channel {
+            self.user_channels.write().await.remove(&user_id);
+        } else if should_cleanup {
             let mut map = self.user_channels.write().await;
 
             if let Some(tx) = map.get(&user_id)
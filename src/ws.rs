@@ -1,43 +1,603 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures_util::{SinkExt, StreamExt};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use axum::{
+    body::Bytes,
+    extract::ws::{Message, WebSocket},
+};
+use chrono::{DateTime, Utc};
+use futures_util::{Sink, SinkExt, StreamExt};
+use serde::Serialize;
+use serde_json::{Value, json};
 use tokio::sync::broadcast;
 use tracing::error;
 
-use crate::models::UpdateEvent;
+use crate::{
+    db::{
+        record_command_result, register_presence, snapshot_since, touch_presence,
+        unregister_presence, update_namespace,
+    },
+    errors::ApiError,
+    models::{
+        AuthenticatedUser, CommandResponse, CommandResultEvent, DeviceEvent, Namespace,
+        NamespacePayload, Replay, ResyncRequiredEvent, SnapshotSince, UpdateEvent, UpdateResponse,
+        UserEvent, WsClientMessage, WsQuery, WsResponse,
+    },
+    replay::{StreamCursor, TokenLifetime},
+    scopes::Scope,
+    state::AppContext,
+};
+
+/// Per-connection protocol state.
+#[derive(Default)]
+struct Connection {
+    /// Device id from the connect query; commands are routed by it.
+    client_id: Option<String>,
+    /// Commands delivered to this connection that await a `command_result`,
+    /// with their expiry.
+    pending_commands: HashMap<String, DateTime<Utc>>,
+    /// Namespaces the client subscribed to; `None` means all of them.
+    namespaces: Option<Vec<Namespace>>,
+    /// Last `state_updated` event delivered (or skipped by the subscription
+    /// filter).
+    cursor: StreamCursor,
+    /// Write limiter key of the token the socket was opened with.
+    write_key: Option<String>,
+}
+
+impl Connection {
+    fn wants(&self, event: &UpdateEvent) -> bool {
+        match &self.namespaces {
+            None => true,
+            Some(namespaces) => {
+                event.namespace == Namespace::Snapshot || namespaces.contains(&event.namespace)
+            }
+        }
+    }
+}
+
+async fn send_json<S, T>(sender: &mut S, value: &T) -> bool
+where
+    S: Sink<Message> + Unpin,
+    T: Serialize,
+{
+    match serde_json::to_string(value) {
+        Ok(message) => sender.send(Message::Text(message.into())).await.is_ok(),
+        Err(err) => {
+            error!("Failed to serialize WebSocket message: {err}");
+            true
+        }
+    }
+}
+
+/// Replays the events after the connection's cursor from revision history,
+/// or tells the client to resync when they cannot be replayed. Returns
+/// `false` when the socket is gone.
+async fn catch_up<S>(
+    state: &AppContext,
+    user: &AuthenticatedUser,
+    connection: &mut Connection,
+    sender: &mut S,
+) -> bool
+where
+    S: Sink<Message> + Unpin,
+{
+    match connection.cursor.catch_up(&state.pool, user.id).await {
+        Ok(Replay::Events(events)) => {
+            for event in events {
+                if connection.wants(&event) && !send_json(sender, &event).await {
+                    return false;
+                }
+            }
+            true
+        }
+        Ok(Replay::ResyncRequired(version)) => {
+            send_json(sender, &ResyncRequiredEvent::new(version)).await
+        }
+        Err(err) => {
+            error!(
+                user_id = user.id,
+                ?err,
+                "failed to replay missed WebSocket events"
+            );
+            true
+        }
+    }
+}
+
+/// How often the server pings the client and refreshes its presence entry.
+const PRESENCE_HEARTBEAT: Duration = Duration::from_secs(30);
 
 pub async fn handle_ws_connection(
     stream: WebSocket,
-    mut updates: broadcast::Receiver<UpdateEvent>,
+    state: Arc<AppContext>,
+    user: AuthenticatedUser,
+    updates: broadcast::Receiver<UserEvent>,
+    query: WsQuery,
+    write_key: Option<String>,
+) {
+    let presence_id = match register_presence(
+        &state.pool,
+        user.id,
+        query.client_id.clone(),
+        query.device_name,
+        query.platform,
+    )
+    .await
+    {
+        Ok((presence_id, online)) => {
+            if let Some(device) = online {
+                state
+                    .send_user_event(user.id, DeviceEvent::online(device))
+                    .await;
+            }
+            Some(presence_id)
+        }
+        Err(_) => None,
+    };
+
+    run_connection(
+        stream,
+        &state,
+        &user,
+        updates,
+        Connection {
+            client_id: query.client_id,
+            write_key,
+            ..Connection::default()
+        },
+        query.since_version,
+        presence_id,
+    )
+    .await;
+
+    if let Some(presence_id) = presence_id
+        && let Ok(Some(device)) = unregister_presence(&state.pool, user.id, presence_id).await
+    {
+        state
+            .send_user_event(user.id, DeviceEvent::offline(device))
+            .await;
+    }
+}
+
+async fn run_connection(
+    stream: WebSocket,
+    state: &Arc<AppContext>,
+    user: &AuthenticatedUser,
+    mut updates: broadcast::Receiver<UserEvent>,
+    mut connection: Connection,
+    since_version: Option<i64>,
+    presence_id: Option<i64>,
 ) {
     let (mut sender, mut receiver) = stream.split();
 
+    // `updates` is already subscribed, so anything written while catching up
+    // is buffered there and deduplicated by version below.
+    if let Some(since_version) = since_version {
+        connection.cursor = StreamCursor::since(since_version);
+        if !catch_up(state, user, &mut connection, &mut sender).await {
+            return;
+        }
+    } else {
+        connection.cursor = StreamCursor::current(&state.pool, user.id).await;
+    }
+
+    let mut heartbeat = tokio::time::interval(PRESENCE_HEARTBEAT);
+    heartbeat.tick().await;
+    // Whether anything (including a pong) arrived since the last heartbeat.
+    let mut heard_from_client = false;
+    let mut token = TokenLifetime::new(user);
+
     loop {
         tokio::select! {
+            // Server events go first, so a write never overtakes the
+            // revocation of the token it was sent with.
+            biased;
+            _ = token.until_expired() => break,
             result = updates.recv() => {
                 match result {
-                    Ok(event) => {
-                        match serde_json::to_string(&event) {
-                            Ok(message) => {
-                                if sender.send(Message::Text(message.into())).await.is_err() {
-                                    break;
-                                }
-                            }
-                            Err(err) => {
-                                error!("Failed to serialize UpdateEvent for WebSocket: {err}");
-                            }
+                    Ok(UserEvent::State(event)) => {
+                        if !connection.cursor.advance(&event) {
+                            continue;
+                        }
+                        if connection.wants(&event) && !send_json(&mut sender, &event).await {
+                            break;
+                        }
+                    }
+                    Ok(
+                        event @ (UserEvent::Device(_)
+                        | UserEvent::Handoff(_)
+                        | UserEvent::SharedPlaylist(_)),
+                    ) => {
+                        if !send_json(&mut sender, &event).await {
+                            break;
+                        }
+                    }
+                    Ok(UserEvent::Command(event)) => {
+                        // Only connections that may answer a command receive it.
+                        if connection.client_id.as_deref() != Some(event.target_client_id.as_str())
+                            || user.scopes.require(Scope::PlaybackControl).is_err()
+                        {
+                            continue;
+                        }
+                        let now = Utc::now();
+                        connection.pending_commands.retain(|_, expires_at| *expires_at > now);
+                        connection
+                            .pending_commands
+                            .insert(event.command_id.clone(), event.expires_at);
+                        if !send_json(&mut sender, &event).await {
+                            break;
+                        }
+                    }
+                    Ok(UserEvent::CommandResult(_)) => {}
+                    Ok(UserEvent::Token(event)) => {
+                        if !token.apply(&event) {
+                            continue;
+                        }
+                        if !send_json(&mut sender, &event).await || token.expired() {
+                            break;
+                        }
+                    }
+                    Ok(event @ UserEvent::Account(_)) => {
+                        send_json(&mut sender, &event).await;
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        if !catch_up(state, user, &mut connection, &mut sender).await {
+                            break;
                         }
                     }
-                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
                     Err(broadcast::error::RecvError::Closed) => break,
                 }
             }
             incoming = receiver.next() => {
+                heard_from_client = true;
                 match incoming {
+                    Some(Ok(Message::Text(text))) => {
+                        let response = handle_client_message(state, user, &mut connection, &text).await;
+                        if !send_json(&mut sender, &response).await {
+                            break;
+                        }
+                    }
                     Some(Ok(Message::Close(_))) => break,
                     Some(Ok(_)) => {}
                     Some(Err(_)) | None => break,
                 }
             }
+            _ = heartbeat.tick() => {
+                if sender.send(Message::Ping(Bytes::new())).await.is_err() {
+                    break;
+                }
+                if let Some(presence_id) = presence_id
+                    && std::mem::take(&mut heard_from_client)
+                {
+                    let _ = touch_presence(&state.pool, presence_id).await;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_client_message(
+    state: &Arc<AppContext>,
+    user: &AuthenticatedUser,
+    connection: &mut Connection,
+    text: &str,
+) -> WsResponse {
+    let message = match serde_json::from_str::<WsClientMessage>(text) {
+        Ok(message) => message,
+        Err(err) => {
+            // Echo the request id back when the envelope is at least valid JSON.
+            let request_id = serde_json::from_str::<Value>(text)
+                .ok()
+                .and_then(|value| value.get("request_id")?.as_str().map(String::from));
+            return WsResponse::error(
+                request_id,
+                &ApiError::bad_request(format!("invalid message: {err}")),
+            );
+        }
+    };
+
+    let (request_id, result) = match message {
+        WsClientMessage::PutNamespace {
+            request_id,
+            namespace,
+            payload,
+        } => (
+            request_id,
+            put_namespace(
+                state,
+                user,
+                connection.write_key.as_deref(),
+                &namespace,
+                payload,
+            )
+            .await,
+        ),
+        WsClientMessage::GetSnapshot {
+            request_id,
+            since_version,
+            shared_since_version,
+        } => (
+            request_id,
+            get_snapshot(state, user, since_version, shared_since_version).await,
+        ),
+        WsClientMessage::Subscribe {
+            request_id,
+            namespaces,
+        } => (request_id, subscribe(user, connection, namespaces)),
+        WsClientMessage::Ack {
+            request_id,
+            version,
+        } => (request_id, ack(user, version)),
+        WsClientMessage::CommandResult {
+            request_id,
+            command_id,
+            ok,
+            result,
+            error,
+        } => (
+            request_id,
+            command_result(
+                state,
+                user,
+                connection,
+                CommandResponse {
+                    command_id,
+                    ok,
+                    result,
+                    error,
+                },
+            )
+            .await,
+        ),
+    };
+
+    match result {
+        Ok(result) => WsResponse::ok(request_id, result),
+        Err(err) => WsResponse::error(Some(request_id), &err),
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, ApiError> {
+    serde_json::to_value(value).map_err(|err| {
+        error!("failed to serialize WebSocket response: {err}");
+        ApiError::internal("failed to serialize response".to_string())
+    })
+}
+
+async fn put_namespace(
+    state: &Arc<AppContext>,
+    user: &AuthenticatedUser,
+    write_key: Option<&str>,
+    namespace: &str,
+    payload: NamespacePayload,
+) -> Result<Value, ApiError> {
+    let namespace = Namespace::parse_data(namespace)?;
+    user.scopes.require_write(namespace)?;
+    // WebSocket frames bypass the HTTP middleware, so count them against the
+    // token's REST write limit here.
+    if let Some(limiter) = &state.request_limits.writes
+        && let Some(write_key) = write_key
+        && let Err(retry_after) = limiter.check(write_key).await
+    {
+        return Err(ApiError::rate_limited(
+            "too many write requests".to_string(),
+            retry_after,
+        ));
+    }
+    let (snapshot, event, merge) = update_namespace(
+        &state.pool,
+        user.id,
+        namespace,
+        payload,
+        &state.history_retention,
+    )
+    .await?;
+    state.send_user_event(user.id, event).await;
+
+    to_value(&UpdateResponse::new(&snapshot, namespace, merge))
+}
+
+async fn get_snapshot(
+    state: &Arc<AppContext>,
+    user: &AuthenticatedUser,
+    since_version: Option<i64>,
+    shared_since_version: Option<i64>,
+) -> Result<Value, ApiError> {
+    user.scopes.require_read(Namespace::Snapshot)?;
+    match snapshot_since(&state.pool, user.id, since_version, shared_since_version).await? {
+        SnapshotSince::NotModified => Ok(json!({ "not_modified": true })),
+        SnapshotSince::Delta(response) => to_value(&response),
+        SnapshotSince::Full(response) => to_value(&response),
+    }
+}
+
+fn subscribe(
+    user: &AuthenticatedUser,
+    connection: &mut Connection,
+    namespaces: Option<Vec<String>>,
+) -> Result<Value, ApiError> {
+    user.scopes.require(Scope::WsSubscribe)?;
+    let namespaces = namespaces
+        .map(|namespaces| {
+            namespaces
+                .iter()
+                .map(|namespace| {
+                    let namespace = Namespace::parse_data(namespace)?;
+                    user.scopes.require_read(namespace)?;
+                    Ok(namespace)
+                })
+                .collect::<Result<Vec<_>, ApiError>>()
+        })
+        .transpose()?;
+    connection.namespaces = namespaces;
+
+    Ok(json!({ "namespaces": connection.namespaces }))
+}
+
+/// Keeps the connection busy; the server does not track what clients
+/// applied, so `version` is only echoed back.
+fn ack(user: &AuthenticatedUser, version: i64) -> Result<Value, ApiError> {
+    user.scopes.require(Scope::WsSubscribe)?;
+    Ok(json!({ "version": version }))
+}
+
+async fn command_result(
+    state: &Arc<AppContext>,
+    user: &AuthenticatedUser,
+    connection: &mut Connection,
+    response: CommandResponse,
+) -> Result<Value, ApiError> {
+    user.scopes.require(Scope::PlaybackControl)?;
+    let expires_at = connection
+        .pending_commands
+        .remove(&response.command_id)
+        .ok_or_else(|| ApiError::not_found(format!("unknown command {}", response.command_id)))?;
+    if expires_at <= Utc::now() {
+        return Err(ApiError::command_timeout(format!(
+            "command {} has expired",
+            response.command_id
+        )));
+    }
+
+    record_command_result(&state.pool, user.id, &response).await?;
+    let command_id = response.command_id.clone();
+    state
+        .send_user_event(
+            user.id,
+            CommandResultEvent {
+                event_type: "command_result".to_string(),
+                response,
+            },
+        )
+        .await;
+
+    Ok(json!({ "command_id": command_id }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+    use futures_util::{SinkExt, StreamExt};
+    use serde_json::{Value, json};
+    use tokio_tungstenite::tungstenite::Message;
+
+    use crate::{
+        app::build_router,
+        audit::AuditEntry,
+        config::{HistoryRetention, RateLimitConfig},
+        db::{
+            create_token, create_user, load_snapshot,
+            tests::{remove_user, test_pool},
+        },
+        scopes::Scopes,
+        state::AppContext,
+    };
+
+    fn put_settings(request_id: &str) -> Message {
+        let message = json!({
+            "type": "put_namespace",
+            "request_id": request_id,
+            "namespace": "settings",
+            "data": { "theme": request_id },
+        });
+        Message::text(message.to_string())
+    }
+
+    #[tokio::test]
+    async fn closes_the_socket_when_its_token_is_revoked() {
+        let pool = test_pool().await;
+        let user = create_user(
+            &pool,
+            &format!("ws-{}", rand::random::<u64>()),
+            false,
+            |_| AuditEntry::new("user.created"),
+        )
+        .await
+        .expect("user");
+        let token = create_token(&pool, user.id, None, Scopes::all(), None, |_| {
+            AuditEntry::new("token.created")
+        })
+        .await
+        .expect("token");
+
+        let retention = HistoryRetention {
+            max_revisions: 10,
+            max_age_days: 1,
+        };
+        let rate_limits = RateLimitConfig {
+            auth_failures_per_minute: 0,
+            writes_per_minute: 0,
+        };
+        let state = Arc::new(AppContext::new(
+            pool.clone(),
+            retention,
+            None,
+            rate_limits,
+            false,
+        ));
+        let app = build_router(state, Vec::new(), 1024 * 1024);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind");
+        let addr = listener.local_addr().expect("addr");
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app.into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+        });
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{addr}/v1/ws?token={}", token.token))
+                .await
+                .expect("connect");
+        socket.send(put_settings("before")).await.expect("send");
+        let mut accepted = Vec::new();
+        while let Some(Ok(message)) = socket.next().await {
+            let Message::Text(text) = message else {
+                continue;
+            };
+            let value: Value = serde_json::from_str(&text).expect("json");
+            if value["type"] == "response" {
+                assert_eq!(value["ok"], true, "{value}");
+                accepted.push(value["request_id"].clone());
+                break;
+            }
         }
+        assert_eq!(accepted, [json!("before")]);
+
+        let response = reqwest::Client::new()
+            .delete(format!("http://{addr}/v1/me/tokens/{}", token.id))
+            .bearer_auth(&token.token)
+            .send()
+            .await
+            .expect("revoke");
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        // The write may be sent before the socket notices the revocation, but
+        // it is never applied.
+        let _ = socket.send(put_settings("after")).await;
+        let mut event_types = Vec::new();
+        let closed = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(Ok(message)) = socket.next().await {
+                let Message::Text(text) = message else {
+                    continue;
+                };
+                let value: Value = serde_json::from_str(&text).expect("json");
+                assert_ne!(value["type"], "response", "{value}");
+                event_types.push(value["event_type"].clone());
+            }
+        })
+        .await;
+        assert!(closed.is_ok(), "the socket stayed open");
+        assert!(event_types.contains(&json!("token_revoked")));
+
+        let snapshot = load_snapshot(&pool, user.id).await.expect("snapshot");
+        assert_eq!(snapshot.settings["theme"], "before");
+
+        remove_user(&pool, user.id).await;
     }
 }